@@ -0,0 +1,7 @@
+fn main() {
+    // the KEDA external scaler service, see helpers/scaler.rs
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/externalscaler.proto"], &["proto"])
+        .expect("compile externalscaler.proto");
+}
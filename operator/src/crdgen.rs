@@ -1,7 +1,210 @@
+use std::{env, fs, path::PathBuf, process::exit};
+
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::CustomResourceExt;
-use mycelium::{MinecraftProxy, MinecraftSet};
+use mycelium::{
+    helpers::manager::watch_namespaces,
+    objects::{
+        conversion::{with_v1beta2, ConversionWebhook},
+        mycelium_config::NamespaceConfig,
+        rbac::operator_rbac,
+        validation::{with_fleet_validations, with_validations},
+    },
+    MinecraftFleet, MinecraftProxy, MinecraftSet,
+};
+use serde::Serialize;
+
+const USAGE: &str = "\
+usage: mycelium-crdgen [--output-dir DIR] [--rbac] [--namespace NAMESPACE] [--watch-namespaces \
+                     NS,...]
+                       [--api-port PORT] [--conversion-ca FILE | --conversion-certificate NAME] \
+                     [--helm]
+
+Prints the mycelium CRDs as YAML. MinecraftSets and MinecraftProxies are only served as
+v1beta2 too with --conversion-ca or --conversion-certificate, since the API server only calls
+the operator's conversion webhook over HTTPS (so the operator has to serve its API with TLS).
+
+  --output-dir DIR       write each document to DIR/<name>.yaml instead of stdout
+  --rbac                 also generate the operator's ServiceAccount, ClusterRole and
+                         ClusterRoleBinding (and Roles with --watch-namespaces)
+  --namespace NAMESPACE  namespace the operator runs in (default: $MYCELIUM_NAMESPACE or mycelium)
+  --watch-namespaces NS,...
+                         namespaces the operator is limited to, granted with a Role in each
+                         instead of the ClusterRole (default: $MYCELIUM_WATCH_NAMESPACES)
+  --api-port PORT        port of the mycelium-operator Service's api port (default: 8080)
+  --conversion-ca FILE   serve v1beta2, trusting the operator's certificate if it's signed by
+                         the CA in FILE (PEM)
+  --conversion-certificate NAME
+                         serve v1beta2, with the CA of this cert-manager Certificate in the
+                         operator's namespace injected by cert-manager
+  --helm                 print the chart's CRDs, which serve v1beta2 if tls.secretName is set
+";
+
+/// the conversion webhook of the chart's CRDs, trusting tls.caBundle (PEM) or
+/// the CA cert-manager injects from tls.certManagerCertificate
+const HELM_CONVERSION: &str = r#"{{- if .Values.tls.secretName }}
+  conversion:
+    strategy: Webhook
+    webhook:
+      clientConfig:
+        {{- with .Values.tls.caBundle }}
+        caBundle: {{ b64enc . }}
+        {{- end }}
+        service:
+          name: mycelium-operator
+          namespace: "{{ .Release.Namespace }}"
+          path: /convert
+          port: {{ .Values.ports.api }}
+      conversionReviewVersions:
+        - v1
+{{- end }}
+"#;
+
+const HELM_INJECT_CA: &str = r#"  {{- if and .Values.tls.secretName .Values.tls.certManagerCertificate }}
+  annotations:
+    cert-manager.io/inject-ca-from: "{{ .Release.Namespace }}/{{ .Values.tls.certManagerCertificate }}"
+  {{- end }}
+"#;
 
 fn main() {
-    println!("{}", serde_yaml::to_string(&MinecraftSet::crd()).unwrap());
-    println!("{}", serde_yaml::to_string(&MinecraftProxy::crd()).unwrap());
+    let mut output_dir: Option<PathBuf> = None;
+    let mut rbac = false;
+    let mut namespace = env::var("MYCELIUM_NAMESPACE").unwrap_or_else(|_| "mycelium".into());
+    let mut watch_namespaces = watch_namespaces();
+    let mut port = 8080;
+    let mut ca_bundle = None;
+    let mut certificate = None;
+    let mut helm = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-dir" => {
+                output_dir = Some(args.next().map(PathBuf::from).unwrap_or_else(|| usage()))
+            }
+            "--rbac" => rbac = true,
+            "--namespace" => namespace = args.next().unwrap_or_else(|| usage()),
+            "--watch-namespaces" => {
+                let namespaces = args.next().unwrap_or_else(|| usage());
+                watch_namespaces = namespaces
+                    .split(',')
+                    .filter(|ns| !ns.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "--api-port" => {
+                port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--conversion-ca" => {
+                let file = args.next().unwrap_or_else(|| usage());
+                ca_bundle =
+                    Some(fs::read(&file).unwrap_or_else(|e| panic!("read {}: {}", file, e)));
+            }
+            "--conversion-certificate" => {
+                certificate = Some(args.next().unwrap_or_else(|| usage()))
+            }
+            "--helm" => helm = true,
+            _ => usage(),
+        }
+    }
+
+    let mut documents: Vec<(&str, String)> = vec![];
+    for (file, crd) in [
+        ("minecraftsets", MinecraftSet::crd()),
+        ("minecraftproxies", MinecraftProxy::crd()),
+    ] {
+        if helm {
+            documents.push((file, helm_crd(crd)));
+            continue;
+        }
+        let crd = match ca_bundle.is_some() || certificate.is_some() {
+            true => {
+                let webhook = ConversionWebhook {
+                    namespace: namespace.clone(),
+                    port,
+                    ca_bundle: ca_bundle.clone(),
+                    certificate: certificate.clone(),
+                };
+                with_v1beta2(crd, &webhook).expect("add v1beta2")
+            }
+            false => crd,
+        };
+        documents.push((
+            file,
+            yaml(&with_validations(serde_json::to_value(crd).unwrap())),
+        ));
+    }
+    let fleet = with_fleet_validations(serde_json::to_value(MinecraftFleet::crd()).unwrap());
+    documents.push(("minecraftfleets", yaml(&fleet)));
+    documents.push(("myceliumconfigs", yaml(&NamespaceConfig::crd())));
+    if rbac {
+        let rbac = operator_rbac(&namespace, &watch_namespaces);
+        let mut objects = vec![
+            yaml(&rbac.account),
+            yaml(&rbac.cluster_role),
+            yaml(&rbac.cluster_binding),
+        ];
+        for (role, binding) in &rbac.roles {
+            objects.extend([yaml(role), yaml(binding)]);
+        }
+        documents.push(("rbac", objects.join("\n")));
+    }
+
+    match output_dir {
+        Some(dir) => {
+            fs::create_dir_all(&dir).expect("create output dir");
+            for (file, document) in documents {
+                fs::write(dir.join(format!("{}.yaml", file)), document).expect("write output");
+            }
+        }
+        None => {
+            for (_, document) in documents {
+                println!("{}", document);
+            }
+        }
+    }
+}
+
+/// A CRD for the chart, with the v1beta2 version and its conversion webhook
+/// wrapped in Helm conditionals on tls.secretName.
+fn helm_crd(crd: CustomResourceDefinition) -> String {
+    let base = yaml(&with_validations(serde_json::to_value(&crd).unwrap()));
+    let webhook = ConversionWebhook {
+        namespace: String::new(),
+        port: 0,
+        ca_bundle: None,
+        certificate: None,
+    };
+    let full = with_v1beta2(crd, &webhook).expect("add v1beta2");
+    let full = with_validations(serde_json::to_value(full).unwrap());
+    let v1beta2: Vec<String> = yaml(&[&full["spec"]["versions"][1]])
+        .trim_start_matches("---\n")
+        .lines()
+        .map(|line| format!("    {}", line))
+        .collect();
+
+    // spec.versions is the last field of the document, and conversion comes before
+    // group
+    let base = base.replacen(
+        "\nmetadata:\n",
+        &format!("\nmetadata:\n{}", HELM_INJECT_CA),
+        1,
+    );
+    let base = base.replacen("\nspec:\n", &format!("\nspec:\n{}", HELM_CONVERSION), 1);
+    format!(
+        "{}\n{{{{- if .Values.tls.secretName }}}}\n{}\n{{{{- end }}}}",
+        base.trim_end(),
+        v1beta2.join("\n")
+    )
+}
+
+fn yaml<T: Serialize>(object: &T) -> String {
+    serde_yaml::to_string(object).unwrap()
+}
+
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    exit(2)
 }
@@ -0,0 +1,189 @@
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use k8s_openapi::api::{
+    authentication::v1::{TokenReview, TokenReviewSpec},
+    authorization::v1::{ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec},
+};
+use kube::{api::PostParams, Api, Client};
+use sha2::{Digest, Sha224};
+
+use crate::{helpers::manager::MyceliumConfig, objects::mycelium_config, Error};
+
+/// what a route of the API lets a caller do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// what proxies and runners do for their own namespace, i.e. fetching the
+    /// server list and reporting heartbeats, which the namespace's
+    /// forwarding token is enough for
+    Proxy,
+    /// reading state, history and queues
+    Read,
+    /// changing things, i.e. draining, maintenance and allocations
+    Admin,
+}
+
+impl Scope {
+    /// the verb on minecraftsets a Kubernetes user or ServiceAccount needs for
+    /// the scope
+    pub fn verb(self) -> &'static str {
+        match self {
+            Scope::Proxy => "get",
+            Scope::Read => "list",
+            Scope::Admin => "patch",
+        }
+    }
+}
+
+/// the forwarding token of a namespace, which its pods get in their Secret
+pub fn namespace_token(forwarding_secret: &str, ns: &str) -> String {
+    let mut token = Sha224::new();
+    token.update(format!("{}{}", forwarding_secret, ns).as_bytes());
+    base64::encode(token.finalize())
+}
+
+/// the token of an `Authorization: Bearer` header
+pub fn bearer(req: &HttpRequest) -> Option<&str> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    header.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// compares tokens without giving away how much of one matched
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Whether `token` is the forwarding token of `ns` and that's enough for
+/// `scope`, which it only is for the Proxy routes of its own namespace.
+pub fn namespace_token_allows(
+    forwarding_secret: &str,
+    token: &str,
+    scope: Scope,
+    ns: Option<&str>,
+) -> bool {
+    scope == Scope::Proxy
+        && ns.is_some_and(|ns| same(token, &namespace_token(forwarding_secret, ns)))
+}
+
+/// Checks that a request may use a route with `scope`, for `ns` or (if None)
+/// every namespace. Namespace forwarding tokens are checked first, against the
+/// operator's secret and then the namespace's MyceliumConfig. Anything else has
+/// to be a Kubernetes token (i.e. a ServiceAccount's) that may do the scope's
+/// verb on minecraftsets there.
+pub async fn authorize(
+    client: Client,
+    config: MyceliumConfig,
+    req: &HttpRequest,
+    scope: Scope,
+    ns: Option<&str>,
+) -> Result<(), Error> {
+    if !config.api_authentication {
+        return Ok(());
+    }
+    let token = bearer(req).ok_or_else(|| Error::Unauthorized("missing bearer token".into()))?;
+    if namespace_token_allows(&config.forwarding_secret, token, scope, ns) {
+        return Ok(());
+    }
+    if let (Scope::Proxy, Some(ns)) = (scope, ns) {
+        let config = mycelium_config::effective(client.clone(), config, ns).await?;
+        if namespace_token_allows(&config.forwarding_secret, token, scope, Some(ns)) {
+            return Ok(());
+        }
+    }
+
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token.into()),
+            ..TokenReviewSpec::default()
+        },
+        ..TokenReview::default()
+    };
+    let review = Api::<TokenReview>::all(client.clone())
+        .create(&PostParams::default(), &review)
+        .await?;
+    let status = review.status.unwrap_or_default();
+    let user = match status.user.filter(|_| status.authenticated == Some(true)) {
+        Some(user) => user,
+        None => return Err(Error::Unauthorized("invalid token".into())),
+    };
+
+    let access = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: user.username.clone(),
+            uid: user.uid,
+            groups: user.groups,
+            extra: user.extra,
+            resource_attributes: Some(ResourceAttributes {
+                group: Some("mycelium.njha.dev".into()),
+                resource: Some("minecraftsets".into()),
+                verb: Some(scope.verb().into()),
+                namespace: ns.map(String::from),
+                ..ResourceAttributes::default()
+            }),
+            ..SubjectAccessReviewSpec::default()
+        },
+        ..SubjectAccessReview::default()
+    };
+    let access = Api::<SubjectAccessReview>::all(client)
+        .create(&PostParams::default(), &access)
+        .await?;
+    if access.status.is_some_and(|s| s.allowed) {
+        return Ok(());
+    }
+    Err(Error::Forbidden(format!(
+        "{} may not {} minecraftsets in {}",
+        user.username.unwrap_or_default(),
+        scope.verb(),
+        ns.unwrap_or("every namespace"),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_tokens_only_cover_proxy_routes() {
+        // the token the pods of games get
+        let token = namespace_token("secret", "games");
+
+        assert!(namespace_token_allows(
+            "secret",
+            &token,
+            Scope::Proxy,
+            Some("games")
+        ));
+        assert!(!namespace_token_allows(
+            "secret",
+            &token,
+            Scope::Proxy,
+            Some("other")
+        ));
+        assert!(!namespace_token_allows(
+            "secret",
+            &token,
+            Scope::Read,
+            Some("games")
+        ));
+        assert!(!namespace_token_allows(
+            "secret",
+            &token,
+            Scope::Admin,
+            Some("games")
+        ));
+        assert!(!namespace_token_allows(
+            "secret",
+            &token,
+            Scope::Proxy,
+            None
+        ));
+        assert!(!namespace_token_allows(
+            "secret",
+            "forged",
+            Scope::Proxy,
+            Some("games")
+        ));
+    }
+}
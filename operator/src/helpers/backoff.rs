@@ -0,0 +1,154 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use kube::{Resource, ResourceExt};
+use kube_runtime::controller::{Context, ReconcilerAction};
+
+use crate::{helpers::manager::Data, Error};
+
+/// first retry of an error that's likely to go away on its own (API and network
+/// errors)
+const TRANSIENT_BASE: Duration = Duration::from_secs(5);
+
+/// first retry of an error that needs someone to fix the spec or configuration
+const PERSISTENT_BASE: Duration = Duration::from_secs(60);
+
+/// longest wait between retries
+pub const CAP: Duration = Duration::from_secs(600);
+
+/// how long to wait before the first retry of an error
+fn base(error: &Error) -> Duration {
+    match error {
+        Error::KubeError(_) | Error::ReqwestError(_) | Error::Other(_) => TRANSIENT_BASE,
+        Error::Backoff { source, .. } => base(source),
+        Error::SerializationError(_)
+        | Error::VarError(_)
+        | Error::MyceliumError(_)
+        | Error::BadRequest(_)
+        | Error::NotFound(_)
+        | Error::Unauthorized(_)
+        | Error::Forbidden(_) => PERSISTENT_BASE,
+    }
+}
+
+/// Wait before retrying after `failures` failed reconciles in a row, doubling
+/// each time up to CAP. `jitter` (from 0 to 1) takes up to half of it off, so
+/// objects that failed together don't all retry together.
+pub fn delay(error: &Error, failures: u32, jitter: f64) -> Duration {
+    let exponential = base(error).saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)));
+    exponential
+        .min(CAP)
+        .mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// how long an object has to wait before it may be reconciled again, if at all
+pub fn throttled(last: Option<Instant>, interval: Duration, now: Instant) -> Option<Duration> {
+    let next = last? + interval;
+    (next > now).then(|| next - now)
+}
+
+/// Runs a reconciler once a permit is free and the object wasn't reconciled too
+/// recently (putting it off until then otherwise), counting its failures in a
+/// row and attaching the wait before the next attempt to the error for
+/// error_policy.
+pub async fn reconcile<K, F, Fut>(
+    obj: K,
+    ctx: Context<Data>,
+    reconcile: F,
+) -> Result<ReconcilerAction, Error>
+where
+    K: Resource<DynamicType = ()>,
+    F: FnOnce(K, Context<Data>) -> Fut,
+    Fut: Future<Output = Result<ReconcilerAction, Error>>,
+{
+    let key = format!(
+        "{}/{}/{}",
+        K::kind(&()),
+        obj.namespace().unwrap_or_default(),
+        obj.name()
+    );
+    let state = ctx.get_ref().state.clone();
+    let interval = ctx
+        .get_ref()
+        .config
+        .read()
+        .expect("config")
+        .min_reconcile_interval;
+    let last = state
+        .read()
+        .expect("reconciled")
+        .reconciled
+        .get(&key)
+        .copied();
+    if let Some(wait) = throttled(last, interval, Instant::now()) {
+        return Ok(ReconcilerAction {
+            requeue_after: Some(wait),
+        });
+    }
+    let reconciles = ctx.get_ref().reconciles.clone();
+    let _permit = reconciles
+        .acquire()
+        .await
+        .expect("reconcile permits are never closed");
+    state
+        .write()
+        .expect("reconciled")
+        .reconciled
+        .insert(key.clone(), Instant::now());
+    match reconcile(obj, ctx).await {
+        Ok(action) => {
+            state.write().expect("failures").failures.remove(&key);
+            Ok(action)
+        }
+        Err(error) => {
+            let failures = {
+                let mut state = state.write().expect("failures");
+                let failures = state.failures.entry(key).or_insert(0);
+                *failures += 1;
+                *failures
+            };
+            let jitter = f64::from(Utc::now().timestamp_subsec_nanos()) / 1e9;
+            let retry = delay(&error, failures, jitter);
+            Err(Error::Backoff {
+                source: Box::new(error),
+                retry,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_reconciles_back_off() {
+        use std::time::Duration;
+        let api = || crate::Error::Other(anyhow::anyhow!("connection reset"));
+        let spec = || crate::Error::MyceliumError("bad spec".into());
+
+        assert_eq!(delay(&api(), 1, 0.0), Duration::from_secs(5));
+        assert_eq!(delay(&api(), 3, 0.0), Duration::from_secs(20));
+        assert_eq!(delay(&api(), 3, 1.0), Duration::from_secs(10));
+        assert_eq!(delay(&spec(), 1, 0.0), Duration::from_secs(60));
+        assert_eq!(delay(&spec(), 40, 0.0), CAP);
+    }
+
+    #[test]
+    fn reconciles_are_spaced_out() {
+        use std::time::{Duration, Instant};
+        let now = Instant::now();
+        let interval = Duration::from_secs(30);
+
+        assert_eq!(throttled(None, interval, now), None);
+        assert_eq!(
+            throttled(Some(now - Duration::from_secs(10)), interval, now),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(throttled(Some(now - interval), interval, now), None);
+        assert_eq!(throttled(Some(now), Duration::ZERO, now), None);
+    }
+}
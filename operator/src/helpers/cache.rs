@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::{helpers::tls, Error, Error::MyceliumError};
+
+/// caching download proxy for server jars and plugins, served on /cache
+#[derive(Clone)]
+pub struct ArtifactCache {
+    /// directory that cached artifacts are stored in, each as `<key>` next to
+    /// `<key>.url` with its upstream URL
+    dir: PathBuf,
+    /// address of the operator as seen from runner pods (host:port, or a URL
+    /// with TLS)
+    endpoint: String,
+    /// upstream URL for each cache key, registered by the reconcilers
+    upstreams: Arc<RwLock<HashMap<String, String>>>,
+    /// one lock per key being fetched, so each artifact is only downloaded once
+    fetching: Arc<tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl ArtifactCache {
+    /// enabled by setting MYCELIUM_CACHE_DIR
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(env::var("MYCELIUM_CACHE_DIR").ok()?);
+        Some(ArtifactCache::new(
+            dir,
+            env::var("MYCELIUM_ENDPOINT").expect("MYCELIUM_ENDPOINT"),
+        ))
+    }
+
+    /// a cache in `dir`, which runners reach through the operator at `endpoint`
+    pub fn new(dir: PathBuf, endpoint: String) -> Self {
+        fs::create_dir_all(&dir).expect("create cache dir");
+        ArtifactCache {
+            dir,
+            endpoint,
+            upstreams: Arc::new(RwLock::new(HashMap::new())),
+            fetching: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers an upstream URL and returns the URL runners should download it
+    /// from instead (the filename is kept so the runner names it the same).
+    /// The URL is also written to disk, so runners can still download it
+    /// after the operator restarts and before their set is reconciled
+    /// again.
+    pub fn rewrite(&self, url: &str) -> String {
+        let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+        let file = url.split('/').next_back().unwrap_or_default();
+        let known = self
+            .upstreams
+            .write()
+            .expect("cache upstreams")
+            .insert(key.clone(), url.to_string())
+            .is_some();
+        if !known {
+            let url_file = self.dir.join(format!("{}.url", key));
+            if let Err(e) = fs::write(&url_file, url) {
+                warn!("couldn't write {}: {}", url_file.display(), e);
+            }
+        }
+        format!("{}/cache/{}/{}", tls::base_url(&self.endpoint), key, file)
+    }
+
+    /// the upstream URL of a cache key, from memory or else from disk
+    pub(crate) fn upstream(&self, key: &str) -> Option<String> {
+        if let Some(url) = self.upstreams.read().expect("cache upstreams").get(key) {
+            return Some(url.clone());
+        }
+        let url = fs::read_to_string(self.dir.join(format!("{}.url", key))).ok()?;
+        let url = url.trim().to_string();
+        self.upstreams
+            .write()
+            .expect("cache upstreams")
+            .insert(key.to_string(), url.clone());
+        Some(url)
+    }
+
+    /// Returns where the artifact for a cache key is on disk, fetching it from
+    /// upstream if it isn't there yet. Unknown keys are Error::NotFound.
+    pub async fn get(&self, key: &str) -> Result<PathBuf, Error> {
+        let path = self.path(key)?;
+        if path.exists() {
+            return Ok(path);
+        }
+        let url = self
+            .upstream(key)
+            .ok_or_else(|| Error::NotFound(format!("unknown cache key {}", key)))?;
+
+        let lock = self
+            .fetching
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+        // another request may have fetched it while we were waiting
+        let fetched = match path.exists() {
+            true => Ok(()),
+            false => self.fetch(&url, &path).await,
+        };
+        self.fetching.lock().await.remove(key);
+        fetched.map(|_| path)
+    }
+
+    /// downloads `url` to `path` a chunk at a time
+    async fn fetch(&self, url: &str, path: &Path) -> Result<(), Error> {
+        info!("caching {}", url);
+        let write = |e: std::io::Error| MyceliumError(format!("write cache: {}", e));
+        let mut response = reqwest::get(url).await?.error_for_status()?;
+        // write then rename so a partial download is never served
+        let tmp = path.with_extension("part");
+        let mut file = tokio::fs::File::create(&tmp).await.map_err(write)?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await.map_err(write)?;
+        }
+        file.flush().await.map_err(write)?;
+        tokio::fs::rename(&tmp, path).await.map_err(write)?;
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::NotFound(format!("invalid cache key {}", key)));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_keys_survive_a_restart() {
+        use actix_web::{http::StatusCode, ResponseError};
+        let dir = std::env::temp_dir().join(format!("mycelium-cache-test-{}", std::process::id()));
+        let url = "https://example.com/plugins/Thing.jar";
+        let rewritten =
+            ArtifactCache::new(dir.clone(), "mycelium-operator:8080".into()).rewrite(url);
+        let key = rewritten.split('/').rev().nth(1).unwrap().to_string();
+        assert!(rewritten.starts_with("http://mycelium-operator:8080/cache/"));
+        assert!(rewritten.ends_with("/Thing.jar"));
+
+        // a new operator knows the key before anything is reconciled again
+        let restarted = ArtifactCache::new(dir.clone(), "mycelium-operator:8080".into());
+        assert_eq!(restarted.upstream(&key).as_deref(), Some(url));
+        fs::write(dir.join(&key), b"jar").unwrap();
+        assert_eq!(
+            futures::executor::block_on(restarted.get(&key)).unwrap(),
+            dir.join(&key)
+        );
+
+        let unknown = futures::executor::block_on(restarted.get("abc123")).unwrap_err();
+        assert_eq!(unknown.status_code(), StatusCode::NOT_FOUND);
+        let invalid = futures::executor::block_on(restarted.get("../etc")).unwrap_err();
+        assert_eq!(invalid.status_code(), StatusCode::NOT_FOUND);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use futures::channel::mpsc::UnboundedSender;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use serde::Deserialize;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use tracing::{info, warn};
+
+use crate::{
+    helpers::manager::MyceliumConfig,
+    objects::{backend::PodBackend, quota::Quota, MetadataOverrides, RunnerOptions},
+    Error,
+    Error::MyceliumError,
+};
+
+/// settings that can be changed without restarting the operator, read from the
+/// YAML file at MYCELIUM_CONFIG_FILE (each image falls back to its environment
+/// variable)
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConfigFile {
+    runner_image: Option<String>,
+    itzg_server_image: Option<String>,
+    itzg_proxy_image: Option<String>,
+    #[serde(default)]
+    java_images: BTreeMap<u32, String>,
+    allowed_plugin_hosts: Option<Vec<String>>,
+    allowed_jar_types: Option<Vec<String>>,
+    resync_seconds: Option<u64>,
+    max_concurrent_reconciles: Option<usize>,
+    min_reconcile_interval_seconds: Option<u64>,
+    propagated_labels: Option<Vec<String>>,
+    propagated_annotations: Option<Vec<String>>,
+    pod_backend: Option<String>,
+    #[serde(default)]
+    quotas: BTreeMap<String, Quota>,
+    api_authentication: Option<bool>,
+    api_address: Option<String>,
+    metrics_address: Option<String>,
+    scaler_address: Option<String>,
+}
+
+/// addresses the operator listens on, only read when it starts
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listen {
+    /// the API, i.e. /servers and heartbeats
+    pub api: SocketAddr,
+    /// /metrics on its own, so a NetworkPolicy can treat it apart from the API
+    /// (on the API's address if None)
+    pub metrics: Option<SocketAddr>,
+    /// the KEDA external scaler
+    pub scaler: SocketAddr,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen {
+            api: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            metrics: None,
+            scaler: SocketAddr::from(([0, 0, 0, 0], 9090)),
+        }
+    }
+}
+
+/// how often everything is reconciled again without an event, if not configured
+const DEFAULT_RESYNC: Duration = Duration::from_secs(600);
+
+/// reconciles of sets and proxies that may run at once, if not configured
+const DEFAULT_MAX_CONCURRENT_RECONCILES: usize = 16;
+
+/// prefixes of keys that are never propagated, since they belong to the
+/// operator or kubectl
+const UNPROPAGATED_PREFIXES: [&str; 2] = ["mycelium.njha.dev/", "kubectl.kubernetes.io/"];
+
+/// a list from the config file, or else its comma separated environment
+/// variable
+fn list_setting(file: Option<Vec<String>>, var: &str) -> Option<Vec<String>> {
+    file.or_else(|| {
+        env::var(var)
+            .ok()
+            .map(|list| list.split_terminator(',').map(String::from).collect())
+    })
+}
+
+/// whether a key matches one of the patterns, exactly or by the prefix before a
+/// trailing `*`
+fn matches(patterns: &[String], key: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == pattern,
+        })
+}
+
+/// the entries of a label or annotation map whose keys may be propagated
+fn propagated(
+    patterns: &[String],
+    map: &Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    let map: BTreeMap<String, String> = map
+        .iter()
+        .flatten()
+        .filter(|(key, _)| matches(patterns, key))
+        .filter(|(key, _)| {
+            !UNPROPAGATED_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    (!map.is_empty()).then_some(map)
+}
+
+/// an address (i.e. 0.0.0.0:8080) from the config file, or else its environment
+/// variable
+pub(crate) fn address(file: Option<String>, var: &str) -> Result<Option<SocketAddr>, Error> {
+    match file.or_else(|| env::var(var).ok()) {
+        Some(address) => address.parse().map(Some).map_err(|e| {
+            MyceliumError(format!(
+                "{} isn't a valid address for {}: {}",
+                address, var, e
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// a setting from the config file, or else its environment variable
+fn setting<T: std::str::FromStr>(file: Option<T>, var: &str) -> Option<T> {
+    file.or_else(|| env::var(var).ok().and_then(|s| s.parse().ok()))
+}
+
+impl MyceliumConfig {
+    /// reads the environment and the config file, if there is one
+    pub(crate) fn load() -> Result<Self, Error> {
+        let file: ConfigFile = match env::var("MYCELIUM_CONFIG_FILE") {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| MyceliumError(format!("reading {}: {}", path, e)))?;
+                serde_yaml::from_str(&contents)
+                    .map_err(|e| MyceliumError(format!("parsing {}: {}", path, e)))?
+            }
+            Err(_) => ConfigFile::default(),
+        };
+
+        Ok(MyceliumConfig {
+            forwarding_secret: env::var("MYCELIUM_FW_TOKEN")?,
+            endpoint: env::var("MYCELIUM_ENDPOINT")?,
+            tls_ca: match env::var("MYCELIUM_TLS_CA_FILE") {
+                Ok(path) => Some(
+                    fs::read_to_string(&path)
+                        .map_err(|e| MyceliumError(format!("reading {}: {}", path, e)))?,
+                ),
+                Err(_) => None,
+            },
+            runner_image: match file.runner_image {
+                Some(image) => image,
+                None => env::var("MYCELIUM_RUNNER_IMAGE")?,
+            },
+            itzg_server_image: file
+                .itzg_server_image
+                .or_else(|| env::var("MYCELIUM_ITZG_SERVER_IMAGE").ok())
+                .unwrap_or_else(|| "itzg/minecraft-server".into()),
+            itzg_proxy_image: file
+                .itzg_proxy_image
+                .or_else(|| env::var("MYCELIUM_ITZG_PROXY_IMAGE").ok())
+                .unwrap_or_else(|| "itzg/mc-proxy".into()),
+            java_images: file.java_images,
+            allowed_plugin_hosts: list_setting(
+                file.allowed_plugin_hosts,
+                "MYCELIUM_ALLOWED_PLUGIN_HOSTS",
+            )
+            .unwrap_or_default(),
+            allowed_jar_types: list_setting(file.allowed_jar_types, "MYCELIUM_ALLOWED_JAR_TYPES")
+                .unwrap_or_default(),
+            resync: match setting(file.resync_seconds, "MYCELIUM_RESYNC_SECONDS") {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => Some(DEFAULT_RESYNC),
+            },
+            max_concurrent_reconciles: setting(
+                file.max_concurrent_reconciles,
+                "MYCELIUM_MAX_CONCURRENT_RECONCILES",
+            )
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_RECONCILES),
+            min_reconcile_interval: Duration::from_secs(
+                setting(
+                    file.min_reconcile_interval_seconds,
+                    "MYCELIUM_MIN_RECONCILE_INTERVAL_SECONDS",
+                )
+                .unwrap_or(0),
+            ),
+            propagated_labels: list_setting(file.propagated_labels, "MYCELIUM_PROPAGATED_LABELS")
+                .unwrap_or_else(|| vec!["*".into()]),
+            propagated_annotations: list_setting(
+                file.propagated_annotations,
+                "MYCELIUM_PROPAGATED_ANNOTATIONS",
+            )
+            .unwrap_or_default(),
+            pod_backend: match file
+                .pod_backend
+                .or_else(|| env::var("MYCELIUM_POD_BACKEND").ok())
+            {
+                Some(backend) => backend.parse()?,
+                None => PodBackend::default(),
+            },
+            quotas: file.quotas,
+            api_authentication: setting(file.api_authentication, "MYCELIUM_API_AUTHENTICATION")
+                .unwrap_or(true),
+            listen: Listen {
+                api: address(file.api_address, "MYCELIUM_API_ADDRESS")?
+                    .unwrap_or(Listen::default().api),
+                metrics: address(file.metrics_address, "MYCELIUM_METRICS_ADDRESS")?,
+                scaler: address(file.scaler_address, "MYCELIUM_SCALER_ADDRESS")?
+                    .unwrap_or(Listen::default().scaler),
+            },
+        })
+    }
+
+    /// the labels and annotations of a set or proxy that its generated objects
+    /// inherit
+    pub(crate) fn inherited(&self, meta: &ObjectMeta) -> MetadataOverrides {
+        MetadataOverrides {
+            labels: propagated(&self.propagated_labels, &meta.labels),
+            annotations: propagated(&self.propagated_annotations, &meta.annotations),
+        }
+    }
+
+    /// whether runner.plugins and runner.mods may download from a URL, by its
+    /// host or a parent domain being in allowedPluginHosts (any URL if
+    /// that's empty)
+    pub(crate) fn allows_download(&self, url: &str) -> bool {
+        if self.allowed_plugin_hosts.is_empty() {
+            return true;
+        }
+        let url = match reqwest::Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        let host = url.host_str().unwrap_or_default();
+        self.allowed_plugin_hosts
+            .iter()
+            .any(|allowed| host == allowed || host.ends_with(&format!(".{}", allowed)))
+    }
+
+    /// whether sets and proxies may run a jar type, any if allowedJarTypes is
+    /// empty
+    pub(crate) fn allows_jar(&self, jar_type: &str) -> bool {
+        self.allowed_jar_types.is_empty() || self.allowed_jar_types.iter().any(|t| t == jar_type)
+    }
+
+    /// The image to run for a CRD. A javaVersion picks the configured image for
+    /// it, or else the `-java<version>` variant of the runner tag
+    /// (`java<version>` for itzg images).
+    pub(crate) fn image(&self, runner: &RunnerOptions) -> String {
+        let itzg = runner.runner_mode.as_deref() == Some("itzg");
+        let image = if !itzg {
+            &self.runner_image
+        } else if runner.jar.is_proxy() {
+            &self.itzg_proxy_image
+        } else {
+            &self.itzg_server_image
+        };
+        let java = match runner.java_version {
+            Some(java) => java,
+            None => return image.clone(),
+        };
+        if let Some(image) = self.java_images.get(&java).filter(|_| !itzg) {
+            return image.clone();
+        }
+        // a tag is whatever follows the last colon, unless that colon is a registry
+        // port
+        let (repository, tag) = match image.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+            _ => (image.as_str(), None),
+        };
+        match (tag, itzg) {
+            (Some(tag), false) => format!("{}:{}-java{}", repository, tag, java),
+            _ => format!("{}:java{}", repository, java),
+        }
+    }
+}
+
+fn modified() -> Option<SystemTime> {
+    let path = env::var("MYCELIUM_CONFIG_FILE").ok()?;
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the configuration on SIGHUP or when the config file changes (i.e.
+/// when its ConfigMap is updated), then asks every controller to reconcile
+/// everything so that the new settings are applied. A config that fails to load
+/// is logged and ignored.
+pub fn watch(config: Arc<RwLock<MyceliumConfig>>, reconcile_all: Vec<UnboundedSender<()>>) {
+    let mut signals = Signals::new([SIGHUP]).expect("register SIGHUP handler");
+    thread::spawn(move || {
+        let mut last_modified = modified();
+        loop {
+            let hangup = signals.pending().count() > 0;
+            let now_modified = modified();
+            if hangup || now_modified != last_modified {
+                last_modified = now_modified;
+                match MyceliumConfig::load() {
+                    Ok(new) => {
+                        *config.write().expect("reload config") = new;
+                        info!("reloaded configuration");
+                        for trigger in &reconcile_all {
+                            let _ = trigger.unbounded_send(());
+                        }
+                    }
+                    Err(e) => warn!("keeping the current configuration, reload failed: {}", e),
+                }
+            }
+            thread::sleep(Duration::from_secs(5));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addresses_parse_or_fail_loudly() {
+        let metrics = address(Some("127.0.0.1:9100".into()), "MYCELIUM_TEST_UNSET").unwrap();
+        assert_eq!(metrics, Some("127.0.0.1:9100".parse().unwrap()));
+        let ipv6 = address(Some("[::]:8443".into()), "MYCELIUM_TEST_UNSET").unwrap();
+        assert_eq!(ipv6.map(|a| a.port()), Some(8443));
+        assert_eq!(address(None, "MYCELIUM_TEST_UNSET").unwrap(), None);
+        // a port alone is a mistake, not something to guess at
+        let err = address(Some("9100".into()), "MYCELIUM_METRICS_ADDRESS").unwrap_err();
+        assert!(err.to_string().contains("MYCELIUM_METRICS_ADDRESS"));
+
+        let listen = Listen::default();
+        assert_eq!(listen.api.to_string(), "0.0.0.0:8080");
+        assert_eq!(listen.scaler.to_string(), "0.0.0.0:9090");
+        assert_eq!(listen.metrics, None);
+    }
+}
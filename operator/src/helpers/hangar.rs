@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{objects::PluginSource, Error, Error::MyceliumError};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Version {
+    downloads: HashMap<String, Download>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Download {
+    file_info: Option<FileInfo>,
+    download_url: Option<String>,
+    external_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    name: String,
+    sha256_hash: Option<String>,
+}
+
+/// base URL of the Hangar API, overridable with MYCELIUM_HANGAR_API
+pub fn api_base() -> String {
+    std::env::var("MYCELIUM_HANGAR_API")
+        .unwrap_or_else(|_| "https://hangar.papermc.io/api/v1".into())
+}
+
+/// the newest release of a project
+pub async fn get_latest_version(slug: &str) -> Result<String, Error> {
+    let url = format!(
+        "{base}/projects/{slug}/latestrelease",
+        base = api_base(),
+        slug = slug
+    );
+    Ok(reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?
+        .trim()
+        .to_string())
+}
+
+/// Looks up the download of an `author/slug@version` plugin reference for a
+/// platform (PAPER, VELOCITY or WATERFALL), returning the version it resolved
+/// to, which is the newest release if the version is `latest` or left out.
+/// Files hosted on Hangar come with their name and sha256, external downloads
+/// only with a URL.
+pub async fn get_download(
+    reference: &str,
+    platform: &str,
+) -> Result<(String, PluginSource), Error> {
+    let (project, version) = reference.split_once('@').unwrap_or((reference, "latest"));
+    // slugs are unique on hangar, the author is there for whoever reads the spec
+    let slug = project.rsplit('/').next().unwrap_or(project);
+    let version = match version {
+        "latest" => get_latest_version(slug).await?,
+        version => version.to_string(),
+    };
+    let url = format!(
+        "{base}/projects/{slug}/versions/{version}",
+        base = api_base(),
+        slug = slug,
+        version = version
+    );
+    let resp = reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .json::<Version>()
+        .await?;
+    let download = resp.downloads.get(platform).ok_or_else(|| {
+        MyceliumError(format!(
+            "hangar plugin {} has no {} download",
+            reference, platform
+        ))
+    })?;
+    let url = download
+        .download_url
+        .clone()
+        .or_else(|| download.external_url.clone())
+        .ok_or_else(|| MyceliumError(format!("hangar plugin {} has no download URL", reference)))?;
+    let source = PluginSource {
+        url,
+        sha256: download
+            .file_info
+            .as_ref()
+            .and_then(|f| f.sha256_hash.clone()),
+        filename: download.file_info.as_ref().map(|f| f.name.clone()),
+        hangar: None,
+    };
+    Ok((version, source))
+}
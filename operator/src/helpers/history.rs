@@ -0,0 +1,110 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env, fs,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{helpers::state::State, Error};
+
+/// how often player counts are sampled
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// how long samples are kept
+const RETENTION_HOURS: i64 = 24 * 7;
+
+/// players on a MinecraftSet at a point in time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub players: u32,
+}
+
+/// rolling player counts per MinecraftSet, keyed by namespace/name
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    sets: BTreeMap<String, VecDeque<Sample>>,
+}
+
+impl History {
+    /// loads the history persisted at MYCELIUM_HISTORY_FILE, if set and
+    /// readable
+    pub fn load() -> Self {
+        env::var("MYCELIUM_HISTORY_FILE")
+            .ok()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn persist(&self) {
+        if let Ok(path) = env::var("MYCELIUM_HISTORY_FILE") {
+            let written = serde_json::to_vec(self)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| fs::write(&path, bytes).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                warn!("failed to persist player history to {}: {}", path, e);
+            }
+        }
+    }
+
+    /// samples for a set newer than `window`
+    pub fn window(&self, ns: &str, name: &str, window: chrono::Duration) -> Vec<Sample> {
+        let since = Utc::now() - window;
+        self.sets
+            .get(&format!("{}/{}", ns, name))
+            .map(|samples| samples.iter().filter(|s| s.time > since).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, key: String, players: u32) {
+        let now = Utc::now();
+        let samples = self.sets.entry(key).or_default();
+        samples.push_back(Sample { time: now, players });
+        while samples
+            .front()
+            .is_some_and(|s| now - s.time > chrono::Duration::hours(RETENTION_HOURS))
+        {
+            samples.pop_front();
+        }
+    }
+}
+
+/// Samples the player count of every set proxies have reported on, once a
+/// minute. Sets without a recent proxy heartbeat in their namespace are skipped
+/// rather than recorded as 0.
+pub fn spawn_sampler(state: Arc<RwLock<State>>) {
+    thread::spawn(move || loop {
+        thread::sleep(INTERVAL);
+        let mut state = state.write().expect("sample history");
+        let mut totals: BTreeMap<String, u32> = BTreeMap::new();
+        for (backend, set) in &state.backend_sets {
+            let (ns, backend) = backend.split_once('/').unwrap_or_default();
+            if let Some(players) = state.players(ns, backend) {
+                *totals.entry(format!("{}/{}", ns, set)).or_default() += players;
+            }
+        }
+        for (key, players) in totals {
+            state.history.record(key, players);
+        }
+        state.history.persist();
+    });
+}
+
+/// parses a window like `90m`, `24h` or `7d`
+pub fn parse_window(window: &str) -> Result<chrono::Duration, Error> {
+    let invalid = || Error::BadRequest(format!("invalid window {:?}, expected e.g. 24h", window));
+    let (count, unit) = window.split_at(window.len().saturating_sub(1));
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    match unit {
+        "m" => Ok(chrono::Duration::minutes(count)),
+        "h" => Ok(chrono::Duration::hours(count)),
+        "d" => Ok(chrono::Duration::days(count)),
+        _ => Err(invalid()),
+    }
+}
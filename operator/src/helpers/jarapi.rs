@@ -1,8 +1,13 @@
-use std::fmt::format;
+use std::{fmt::format, time::Duration};
 
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
-use crate::Error;
+use crate::{
+    objects::{ResolvedBuild, VersionTriple},
+    Error,
+    Error::MyceliumError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Versions {
@@ -20,8 +25,14 @@ struct Builds {
     builds: Vec<u32>,
 }
 
+/// base URL of the PaperMC API, overridable with MYCELIUM_PAPERMC_API (i.e. for
+/// a mock in tests)
+pub fn api_base() -> String {
+    std::env::var("MYCELIUM_PAPERMC_API").unwrap_or_else(|_| "https://papermc.io/api/v2".into())
+}
+
 pub async fn get_versions(kind: &str) -> Result<Vec<String>, Error> {
-    let url = format!("https://papermc.io/api/v2/projects/{kind}", kind = kind);
+    let url = format!("{base}/projects/{kind}", base = api_base(), kind = kind);
     // .header("User-Agent", format!("mycelium/{}", env!("CARGO_PKG_VERSION")))
     let resp = reqwest::get(url).await?.json::<Versions>().await?;
     Ok(resp.versions)
@@ -29,7 +40,8 @@ pub async fn get_versions(kind: &str) -> Result<Vec<String>, Error> {
 
 pub async fn get_builds(kind: &str, version: &str) -> Result<Vec<u32>, Error> {
     let url = format!(
-        "https://papermc.io/api/v2/projects/{kind}/versions/{version}",
+        "{base}/projects/{kind}/versions/{version}",
+        base = api_base(),
         kind = kind,
         version = version
     );
@@ -38,9 +50,250 @@ pub async fn get_builds(kind: &str, version: &str) -> Result<Vec<u32>, Error> {
     Ok(resp.builds)
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct ChannelBuilds {
+    builds: Vec<ChannelBuild>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChannelBuild {
+    build: u32,
+    channel: String,
+}
+
+/// the newest build of a version, only counting builds PaperMC marks as
+/// `default` unless `channel` is experimental
+pub async fn get_latest_build(kind: &str, version: &str, channel: &str) -> Result<u32, Error> {
+    let url = format!(
+        "{base}/projects/{kind}/versions/{version}/builds",
+        base = api_base(),
+        kind = kind,
+        version = version
+    );
+    let resp = reqwest::get(url).await?.json::<ChannelBuilds>().await?;
+    resp.builds
+        .into_iter()
+        .filter(|b| channel == "experimental" || b.channel == "default")
+        .map(|b| b.build)
+        .max()
+        .ok_or_else(|| MyceliumError(format!("no {} builds of {} {}", channel, kind, version)))
+}
+
+/// how often jars with autoUpdate are checked for a newer build
+pub const AUTO_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Fills in the build of a PaperMC jar that omits it. The build recorded in
+/// `previous` is reused until the version or channel changes, so new builds
+/// don't roll out on their own, unless the jar has autoUpdate and the last
+/// check is older than AUTO_UPDATE_INTERVAL. Vanilla jars get the download
+/// Mojang's manifest lists for their version instead, which is likewise reused
+/// until the version changes.
+pub async fn resolve_build(
+    jar: &mut VersionTriple,
+    previous: Option<&ResolvedBuild>,
+) -> Result<Option<ResolvedBuild>, Error> {
+    if jar.r#type == "vanilla" {
+        let same = previous
+            .filter(|p| p.r#type == jar.r#type && p.version == jar.version && p.url.is_some());
+        return Ok(Some(match same {
+            Some(p) => p.clone(),
+            None => {
+                let download = get_vanilla_download(&jar.version).await?;
+                ResolvedBuild {
+                    r#type: jar.r#type.clone(),
+                    version: jar.version.clone(),
+                    url: Some(download.url),
+                    checksum: download.checksum,
+                    ..ResolvedBuild::default()
+                }
+            }
+        }));
+    }
+    if !jar.build.is_empty() || jar.r#type == "custom" {
+        return Ok(None);
+    }
+    let channel = jar.channel.clone().unwrap_or_else(|| "stable".into());
+    let auto_update = jar.auto_update.unwrap_or(false);
+    let same = previous
+        .filter(|p| p.r#type == jar.r#type && p.version == jar.version && p.channel == channel);
+    let due = |p: &ResolvedBuild| {
+        auto_update
+            && p.checked_at.is_none_or(|checked| {
+                Utc::now() - checked
+                    >= chrono::Duration::from_std(AUTO_UPDATE_INTERVAL).expect("interval")
+            })
+    };
+    let resolved = match same {
+        Some(p) if !due(p) => p.clone(),
+        _ => {
+            let build = get_latest_build(&jar.r#type, &jar.version, &channel)
+                .await?
+                .to_string();
+            // keep the build being replaced around for rolling back
+            let previous_build = match same {
+                Some(p) if p.build != build => Some(p.build.clone()),
+                Some(p) => p.previous_build.clone(),
+                None => None,
+            };
+            ResolvedBuild {
+                r#type: jar.r#type.clone(),
+                version: jar.version.clone(),
+                channel,
+                build,
+                checked_at: auto_update.then(Utc::now),
+                previous_build,
+                ..ResolvedBuild::default()
+            }
+        }
+    };
+    jar.build = resolved.build.clone();
+    Ok(Some(resolved))
+}
+
 pub fn get_download_url(kind: &str, version: &str, build: &str) -> String {
     format!(
-        "https://papermc.io/api/v2/projects/{kind}/versions/{version}/builds/{build}/downloads/{kind}-{version}-{build}.jar",
-        version = version, build = build, kind = kind
+        "{base}/projects/{kind}/versions/{version}/builds/{build}/downloads/\
+         {kind}-{version}-{build}.jar",
+        base = api_base(),
+        version = version,
+        build = build,
+        kind = kind
     )
 }
+
+/// where the runner downloads a server jar from, and the checksum to verify it
+/// against
+#[derive(Clone, Debug, PartialEq)]
+pub struct JarDownload {
+    pub url: String,
+    /// `<algorithm>:<hex digest>`, i.e. `sha1:...`
+    pub checksum: Option<String>,
+}
+
+impl JarDownload {
+    /// a jar from the PaperMC API, which needs no lookups to locate
+    pub fn papermc(jar: &VersionTriple) -> Self {
+        JarDownload {
+            url: get_download_url(&jar.r#type, &jar.version, &jar.build),
+            checksum: None,
+        }
+    }
+}
+
+/// Resolves where to download a jar from, going by what resolve_build recorded
+/// for vanilla jars and only asking Mojang when it has nothing.
+pub async fn resolve(
+    jar: &VersionTriple,
+    resolved: Option<&ResolvedBuild>,
+) -> Result<JarDownload, Error> {
+    match jar.r#type.as_str() {
+        "vanilla" => match resolved
+            .filter(|r| r.version == jar.version)
+            .and_then(|r| r.url.clone())
+        {
+            Some(url) => Ok(JarDownload {
+                url,
+                checksum: resolved.and_then(|r| r.checksum.clone()),
+            }),
+            None => get_vanilla_download(&jar.version).await,
+        },
+        "custom" => Ok(JarDownload {
+            url: jar
+                .url
+                .clone()
+                .ok_or_else(|| MyceliumError("custom jar needs a url".into()))?,
+            checksum: jar
+                .sha256
+                .as_ref()
+                .map(|sha256| format!("sha256:{}", sha256)),
+        }),
+        _ => Ok(JarDownload::papermc(jar)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionManifest {
+    versions: Vec<ManifestVersion>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ManifestVersion {
+    id: String,
+    url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionPackage {
+    downloads: VersionDownloads,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionDownloads {
+    server: Option<Download>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Download {
+    sha1: String,
+    url: String,
+}
+
+/// URL of Mojang's launcher version manifest, overridable with
+/// MYCELIUM_MOJANG_MANIFEST
+pub fn mojang_manifest() -> String {
+    std::env::var("MYCELIUM_MOJANG_MANIFEST").unwrap_or_else(|_| {
+        "https://launchermeta.mojang.com/mc/game/version_manifest_v2.json".into()
+    })
+}
+
+/// looks up the vanilla server jar for a version in the launcher version
+/// manifest
+pub async fn get_vanilla_download(version: &str) -> Result<JarDownload, Error> {
+    let manifest = reqwest::get(mojang_manifest())
+        .await?
+        .json::<VersionManifest>()
+        .await?;
+    let entry = manifest
+        .versions
+        .into_iter()
+        .find(|v| v.id == version)
+        .ok_or_else(|| MyceliumError(format!("unknown vanilla version {}", version)))?;
+    let package = reqwest::get(entry.url)
+        .await?
+        .json::<VersionPackage>()
+        .await?;
+    let server = package
+        .downloads
+        .server
+        .ok_or_else(|| MyceliumError(format!("vanilla {} has no server jar", version)))?;
+    Ok(JarDownload {
+        url: server.url,
+        checksum: Some(format!("sha1:{}", server.sha1)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn vanilla_downloads_are_only_looked_up_once_per_version() {
+        let previous = ResolvedBuild {
+            r#type: "vanilla".into(),
+            version: "1.18.1".into(),
+            url: Some("https://piston-data.mojang.com/server.jar".into()),
+            checksum: Some("sha1:abc".into()),
+            ..ResolvedBuild::default()
+        };
+        let mut jar: super::VersionTriple =
+            serde_json::from_value(json!({ "type": "vanilla", "version": "1.18.1" })).unwrap();
+        let resolved =
+            futures::executor::block_on(resolve_build(&mut jar, Some(&previous))).unwrap();
+        assert_eq!(resolved.as_ref(), Some(&previous));
+        let download = futures::executor::block_on(resolve(&jar, resolved.as_ref())).unwrap();
+        assert_eq!(download.url, "https://piston-data.mojang.com/server.jar");
+        assert_eq!(download.checksum.as_deref(), Some("sha1:abc"));
+    }
+}
@@ -1,32 +1,66 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env,
+    fmt::Debug,
     sync::{Arc, RwLock},
     time::Duration,
 };
-use actix_web::body::BoxBody;
 
-use futures::{future::BoxFuture, FutureExt, StreamExt};
-use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::{Secret, Service};
-use kube::{api::ListParams, Api, Client};
+use actix_web::{body::BoxBody, HttpRequest};
+use chrono::Utc;
+use futures::{
+    channel::mpsc,
+    future::{self, BoxFuture},
+    Future, FutureExt, StreamExt,
+};
+use k8s_openapi::api::{
+    apps::v1::{Deployment, StatefulSet},
+    core::v1::{ConfigMap, Pod, Secret, Service},
+    policy::v1::PodDisruptionBudget,
+};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
 use kube_runtime::{
     controller::{Context, ReconcilerAction},
+    reflector::{ObjectRef, Store},
     Controller,
 };
 use prometheus::{default_registry, proto::MetricFamily};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Semaphore;
 use tracing::{info, warn};
 
 use crate::{
-    helpers::{metrics::Metrics, state::State},
+    helpers::{
+        auth::{self, Scope},
+        backoff,
+        cache::ArtifactCache,
+        config::{self, Listen},
+        history,
+        metrics::Metrics,
+        rcon,
+        scaler::Scaler,
+        scrape,
+        state::{BackendHealth, ProxyHeartbeat, State},
+    },
     objects,
     objects::{
-        minecraft_proxy::MinecraftProxy,
+        allocation::{self, Allocation, AllocationRequest},
+        backend::{PodBackend, Workload},
+        drain::{self, DrainStatus},
+        minecraft_fleet::{self, MinecraftFleet},
+        minecraft_proxy::{MinecraftProxy, MinecraftProxySpec},
         minecraft_set::{MinecraftSet, MinecraftSetSpec},
+        mycelium_config::NamespaceConfig,
+        queue::{self, ServerQueue},
+        quota::Quota,
+        scaling, ContainerOptions, References, RunnerOptions, StartupTimings,
     },
     Error,
 };
-use crate::objects::minecraft_proxy::MinecraftProxySpec;
 
 /// a manager that owns a Controller
 #[derive(Clone)]
@@ -37,6 +71,10 @@ pub struct Manager {
     metrics: Metrics,
     /// kube api
     client: Client,
+    /// artifact cache, if enabled
+    cache: Option<ArtifactCache>,
+    /// parsed configuration, replaced when it is reloaded
+    config: Arc<RwLock<MyceliumConfig>>,
 }
 
 impl Manager {
@@ -47,78 +85,109 @@ impl Manager {
         let client = Client::try_default().await.expect("create client");
         let metrics = Metrics::new();
         let state = Arc::new(RwLock::new(State::new()));
+        history::spawn_sampler(state.clone());
+        scrape::spawn(client.clone(), metrics.clone(), watch_namespaces());
+        allocation::spawn_expiry(client.clone(), watch_namespaces());
+        let cache = ArtifactCache::from_env();
 
         // setup configuration and state data
+        let config = MyceliumConfig::load().expect("load configuration");
         let data = Data {
             client: client.clone(),
             metrics: metrics.clone(),
             state: state.clone(),
-            config: MyceliumConfig {
-                forwarding_secret: env::var("MYCELIUM_FW_TOKEN").unwrap(),
-                runner_image: env::var("MYCELIUM_RUNNER_IMAGE").unwrap(),
-            },
+            cache: cache.clone(),
+            reconciles: Arc::new(Semaphore::new(config.max_concurrent_reconciles)),
+            config: Arc::new(RwLock::new(config)),
         };
         let set_context = Context::new(data.clone());
         let proxy_context = Context::new(data.clone());
 
-        // APIs to watch
-        let mcsets = Api::<MinecraftSet>::all(client.clone());
-        let mcproxies = Api::<MinecraftProxy>::all(client.clone());
-        let statesets = Api::<StatefulSet>::all(client.clone());
-        let secrets = Api::<Secret>::all(client.clone());
-        let services = Api::<Service>::all(client.clone());
+        // watch every namespace, or only the ones in MYCELIUM_WATCH_NAMESPACES
+        let scopes: Vec<Option<String>> = match watch_namespaces() {
+            namespaces if namespaces.is_empty() => vec![None],
+            namespaces => namespaces.into_iter().map(Some).collect(),
+        };
 
         // ensure CRD is installed
-        mcsets.list(&ListParams::default().limit(1)).await.expect(
-            "are the crds installed? install them with: mycelium-crdgen | kubectl apply -f -",
-        );
+        scoped::<MinecraftSet>(&client, scopes[0].as_deref())
+            .list(&ListParams::default().limit(1))
+            .await
+            .expect(
+                "are the crds installed? install them with: mycelium-crdgen | kubectl apply -f -",
+            );
 
-        // return the controller
-        let set_controller = Controller::new(mcsets, ListParams::default())
-            .owns(statesets.clone(), ListParams::default())
-            .owns(secrets.clone(), ListParams::default())
-            .owns(services.clone(), ListParams::default())
-            .run(
+        // a controller per kind and namespace, all reconciled again when the
+        // configuration is reloaded
+        let mut reloads = vec![];
+        let (mut set_controllers, mut proxy_controllers) = (vec![], vec![]);
+        let mut fleet_controllers = vec![];
+        for ns in &scopes {
+            fleet_controllers.push(fleet_controller(
+                &client,
+                ns.as_deref(),
+                set_context.clone(),
+            ));
+            let (set_reload, set_reloaded) = mpsc::unbounded();
+            let (proxy_reload, proxy_reloaded) = mpsc::unbounded();
+            reloads.extend([set_reload, proxy_reload]);
+            set_controllers.push(controller(
+                &client,
+                ns.as_deref(),
+                set_context.clone(),
+                set_reloaded,
                 crate::objects::minecraft_set::reconcile,
-                error_policy,
-                set_context,
-            )
-            .for_each(|res| async move {
-                match res {
-                    Ok(o) => info!("reconciled {:?}", o),
-                    Err(e) => warn!("reconcile failed: {}", e),
-                }
-            })
-            .boxed();
-
-        let proxy_controller = Controller::new(mcproxies, ListParams::default())
-            .owns(statesets.clone(), ListParams::default())
-            .owns(secrets.clone(), ListParams::default())
-            .owns(services.clone(), ListParams::default())
-            .run(
+                |set: &MinecraftSet| set.spec.references(),
+            ));
+            proxy_controllers.push(controller(
+                &client,
+                ns.as_deref(),
+                proxy_context.clone(),
+                proxy_reloaded,
                 crate::objects::minecraft_proxy::reconcile,
-                error_policy,
-                proxy_context,
-            )
-            .for_each(|res| async move {
-                match res {
-                    Ok(o) => info!("reconciled {:?}", o),
-                    Err(e) => warn!("reconcile failed: {}", e),
-                }
-            })
-            .boxed();
+                |proxy: &MinecraftProxy| proxy.spec.references(),
+            ));
+        }
+        config::watch(data.config.clone(), reloads);
+        // fleets are run alongside the sets they're made of
+        let set_controller = future::join(
+            future::join_all(set_controllers),
+            future::join_all(fleet_controllers),
+        )
+        .map(|_| ())
+        .boxed();
+        let proxy_controller = future::join_all(proxy_controllers).map(|_| ()).boxed();
 
         (
             Self {
                 state,
                 metrics,
                 client: client.clone(),
+                cache,
+                config: data.config.clone(),
             },
             set_controller,
             proxy_controller,
         )
     }
 
+    /// Checks that a request may use a route with `scope` in `ns` (every
+    /// namespace if None), see [`auth::authorize`].
+    pub async fn authorize(
+        &self,
+        req: &HttpRequest,
+        scope: Scope,
+        ns: Option<&str>,
+    ) -> Result<(), Error> {
+        let config = self.config.read().expect("authorize").clone();
+        auth::authorize(self.client.clone(), config, req, scope, ns).await
+    }
+
+    /// the addresses the API, metrics and scaler listen on
+    pub fn listen(&self) -> Listen {
+        self.config.read().expect("listen").listen.clone()
+    }
+
     /// metrics getter
     pub fn metrics(&self) -> Vec<MetricFamily> {
         default_registry().gather()
@@ -129,45 +198,540 @@ impl Manager {
         self.state.read().expect("state getter").clone()
     }
 
-    /// velocity server getter
-    pub async fn get_sets(&self, ns: String, name: String) -> Result<Vec<VelocityServerEntry>, Error> {
+    /// the KEDA external scaler, reporting on the sets this manager reconciles
+    pub fn scaler(&self) -> Scaler {
+        Scaler::new(self.client.clone(), self.state.clone())
+    }
+
+    /// artifact cache getter
+    pub fn cache(&self) -> Option<&ArtifactCache> {
+        self.cache.as_ref()
+    }
+
+    /// records a heartbeat from a proxy replica and the backends it could reach
+    pub fn heartbeat(&self, ns: &str, name: &str, report: HeartbeatReport) {
+        let now = Utc::now();
+        self.metrics
+            .proxy_heartbeat
+            .with_label_values(&[ns, name, &report.replica])
+            .set(now.timestamp());
+
+        let mut state = self.state.write().expect("heartbeat");
+        for backend in &report.reachable {
+            let health = state
+                .backends
+                .entry(format!("{}/{}/{}", ns, name, backend))
+                .or_default();
+            health.ever_connected = true;
+            health.failing_since = None;
+        }
+        for backend in &report.unreachable {
+            state
+                .backends
+                .entry(format!("{}/{}/{}", ns, name, backend))
+                .or_default()
+                .failing_since
+                .get_or_insert(now);
+        }
+        state.proxies.insert(
+            format!("{}/{}/{}", ns, name, report.replica),
+            ProxyHeartbeat {
+                last_seen: now,
+                reachable: report.reachable,
+                unreachable: report.unreachable,
+                players: report.players,
+                queues: report.queues,
+            },
+        );
+        for (set, length) in state.queue_lengths(ns) {
+            self.metrics
+                .set_queue_length
+                .with_label_values(&[ns, &set])
+                .set(length.into());
+        }
+    }
+
+    /// players waiting in the login queue of each set in a namespace
+    pub fn queues(&self, ns: &str) -> BTreeMap<String, u32> {
+        self.state.read().expect("queues").queue_lengths(ns)
+    }
+
+    /// Takes replica `ordinal` of a set off the server lists (or puts it back),
+    /// reporting the players left on it and whether it may be evicted yet.
+    pub async fn drain(
+        &self,
+        ns: String,
+        name: String,
+        ordinal: u32,
+        enabled: bool,
+    ) -> Result<DrainStatus, Error> {
+        drain::drain(
+            self.client.clone(),
+            &self.state,
+            &ns,
+            &name,
+            ordinal,
+            enabled,
+        )
+        .await
+    }
+
+    /// Flips a MinecraftSet, or every set behind the MinecraftProxy of that
+    /// name, in or out of whitelist-only mode over rcon and records it in
+    /// the set's status. Returns the sets that were changed.
+    pub async fn maintenance(
+        &self,
+        ns: String,
+        name: String,
+        enabled: bool,
+    ) -> Result<Vec<String>, Error> {
+        let mcset_api: Api<MinecraftSet> = Api::namespaced(self.client.clone(), &ns);
+        let sets = match mcset_api.get(&name).await {
+            Ok(set) => vec![set],
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let proxy_api: Api<MinecraftProxy> = Api::namespaced(self.client.clone(), &ns);
+                let proxy = proxy_api.get(&name).await?;
+                mcset_api
+                    .list(&ListParams::default().labels(&proxy_selector(&proxy.spec)))
+                    .await?
+                    .items
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns);
+        let commands = vec![format!("whitelist {}", if enabled { "on" } else { "off" })];
+        let mut changed = vec![];
+        for set in sets {
+            let set_name = set.name();
+            let password = secret_api
+                .get(&set_name)
+                .await?
+                .data
+                .and_then(|d| d.get("rcon_password").cloned())
+                .map(|p| String::from_utf8_lossy(&p.0).into_owned())
+                .ok_or_else(|| {
+                    Error::MyceliumError(format!("{} has no rcon password", set_name))
+                })?;
+            let pods = pod_api
+                .list(
+                    &ListParams::default().labels(&format!("mycelium.njha.dev/mcset={}", set_name)),
+                )
+                .await?;
+            for pod in pods.items {
+                if let Some(ip) = pod.status.and_then(|s| s.pod_ip) {
+                    rcon::run(&ip, &password, &commands).await?;
+                }
+            }
+            mcset_api
+                .patch_status(
+                    &set_name,
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({ "status": { "maintenance": enabled } })),
+                )
+                .await?;
+            info!(
+                "set maintenance = {} for MinecraftSet \"{}\" in {}",
+                enabled, set_name, ns
+            );
+            changed.push(set_name);
+        }
+        Ok(changed)
+    }
+
+    /// Allocates a ready replica of a fleet or set to a matchmaker, None if
+    /// there is none.
+    pub async fn allocate(&self, request: AllocationRequest) -> Result<Option<Allocation>, Error> {
+        allocation::allocate(self.client.clone(), &request).await
+    }
+
+    /// Releases a replica that was allocated.
+    pub async fn release(&self, ns: String, pod: String) -> Result<(), Error> {
+        allocation::release(self.client.clone(), &ns, &pod).await
+    }
+
+    /// Wakes up a MinecraftSet that scaled to zero, returning whether it was
+    /// asleep. The set is scaled back up by its next reconcile.
+    pub async fn wake(&self, ns: String, name: String) -> Result<bool, Error> {
+        let mcset_api: Api<MinecraftSet> = Api::namespaced(self.client.clone(), &ns);
+        let set = mcset_api.get(&name).await?;
+        if set.spec.scale_to_zero.is_none() {
+            return Err(Error::BadRequest(format!("{} doesn't scale to zero", name)));
+        }
+        let asleep = set.status.and_then(|s| s.idle).is_some_and(|i| i.asleep());
+        if asleep {
+            mcset_api
+                .patch(
+                    &name,
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({ "metadata": { "annotations": {
+                        scaling::WAKE_ANNOTATION: Utc::now().to_rfc3339(),
+                    } } })),
+                )
+                .await?;
+            info!("waking up MinecraftSet \"{}\" in {}", name, ns);
+        }
+        Ok(asleep)
+    }
+
+    /// Records the startup timings a runner reported in the status of its
+    /// MinecraftSet (`kind` = game) or MinecraftProxy (`kind` = proxy).
+    pub async fn startup_timings(
+        &self,
+        ns: String,
+        name: String,
+        kind: &str,
+        timings: StartupTimings,
+    ) -> Result<(), Error> {
+        let crd_kind = match kind {
+            "game" => "MinecraftSet",
+            "proxy" => "MinecraftProxy",
+            _ => return Err(Error::BadRequest(format!("unknown runner kind {}", kind))),
+        };
+        for (phase, seconds) in [
+            ("configure", timings.configure_seconds),
+            ("world_import", timings.world_import_seconds),
+            ("download", timings.download_seconds),
+            ("startup", timings.startup_seconds),
+        ] {
+            self.metrics
+                .startup_phase_duration
+                .with_label_values(&[crd_kind, phase])
+                .observe(seconds);
+        }
+
+        let status = Patch::Merge(json!({ "status": { "startupTimings": StartupTimings {
+            reported_at: Some(Utc::now()),
+            ..timings
+        } } }));
+        if crd_kind == "MinecraftSet" {
+            Api::<MinecraftSet>::namespaced(self.client.clone(), &ns)
+                .patch_status(&name, &PatchParams::default(), &status)
+                .await?;
+        } else {
+            Api::<MinecraftProxy>::namespaced(self.client.clone(), &ns)
+                .patch_status(&name, &PatchParams::default(), &status)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// player counts of a MinecraftSet over the last `window`
+    pub fn history(&self, ns: &str, name: &str, window: chrono::Duration) -> Vec<history::Sample> {
+        self.state
+            .read()
+            .expect("history")
+            .history
+            .window(ns, name, window)
+    }
+
+    /// Whether a proxy's heartbeats show it has never reached a backend,
+    /// despite trying for longer than the grace period. Fresh pods get the
+    /// grace period to boot, and a backend that has worked once is never
+    /// excluded.
+    fn never_connected(&self, ns: &str, proxy: &str, backend: &str) -> bool {
+        let state = self.state.read().expect("never_connected");
+        match state.backends.get(&format!("{}/{}/{}", ns, proxy, backend)) {
+            Some(BackendHealth {
+                ever_connected: false,
+                failing_since: Some(since),
+            }) => Utc::now() - *since > chrono::Duration::minutes(BACKEND_GRACE_MINUTES),
+            _ => false,
+        }
+    }
+
+    /// Velocity server getter, `all` includes backends the proxy never reached.
+    /// Replicas are only listed while their pod is ready, see set_servers.
+    pub async fn get_sets(
+        &self,
+        ns: String,
+        name: String,
+        all: bool,
+    ) -> Result<Vec<VelocityServerEntry>, Error> {
         let proxy_api: Api<MinecraftProxy> = Api::namespaced(self.client.clone(), &ns);
         let proxy: MinecraftProxy = proxy_api.get(&name).await?;
         let proxy_spec: MinecraftProxySpec = proxy.spec;
 
-        let label_selector = proxy_spec.selector.unwrap_or_default()
-            .match_labels.unwrap_or_default()
-            .iter().map(|i| format!("{}={}", i.0, i.1))
-            .collect::<Vec<String>>().join(",");
-
         let mcset_api: Api<MinecraftSet> = Api::namespaced(self.client.clone(), &ns);
-        let objects = mcset_api.list(&ListParams::default().labels(&label_selector)).await?;
+        let objects = mcset_api
+            .list(&ListParams::default().labels(&proxy_selector(&proxy_spec)))
+            .await?;
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns);
+        let ready: Vec<Pod> = pod_api
+            .list(&ListParams::default().labels("mycelium.njha.dev/mcset"))
+            .await?
+            .items
+            .into_iter()
+            .filter(|pod| objects::pod_ready(pod) && !drain::draining(pod))
+            .collect();
 
-        Ok(objects.items.iter().flat_map(|set: &MinecraftSet| {
-            let spec: &MinecraftSetSpec = &set.spec;
-            let proxy = spec.proxy.clone().unwrap_or_default();
+        let backend = self.config.read().expect("get_sets").pod_backend;
+        Ok(objects
+            .items
+            .iter()
+            .flat_map(|set: &MinecraftSet| {
+                let container = set.spec.container.clone().unwrap_or_default();
+                let workload = backend.workload(&set.spec.runner, &container);
+                set_servers(set, workload, &ready)
+                    .into_iter()
+                    .map(move |entry| (set, entry))
+            })
+            .filter(|(_, entry)| {
+                all || entry.asleep || !self.never_connected(&ns, &name, &entry.name)
+            })
+            .map(|(set, entry)| {
+                let mut state = self.state.write().expect("get_sets");
+                state
+                    .backend_sets
+                    .insert(format!("{}/{}", ns, entry.name), entry.set.clone());
+                let players = state.set_players(&ns, &entry.set);
+                let queue = set.spec.queue.as_ref().and_then(|options| {
+                    queue::server_queue(
+                        options,
+                        set.spec.runner.max_players,
+                        set.spec.running_replicas(),
+                        players,
+                    )
+                });
+                VelocityServerEntry {
+                    players: state.players(&ns, &entry.name),
+                    queue,
+                    ..entry
+                }
+            })
+            .collect())
+    }
+}
+
+/// The servers of a set for proxies to list, out of the `ready` pods in its
+/// namespace that aren't draining. StatefulSet replicas are reached by their
+/// headless DNS name, the GameServers of a Fleet by pod IP since their names
+/// are generated, and stateless sets through their load balanced Service. A
+/// sleeping set is listed by one server for proxies to wake it up through.
+pub fn set_servers(
+    set: &MinecraftSet,
+    workload: Workload,
+    ready: &[Pod],
+) -> Vec<VelocityServerEntry> {
+    let spec: &MinecraftSetSpec = &set.spec;
+    if spec.suspend == Some(true) {
+        return vec![];
+    }
+    let proxy = spec.proxy.clone().unwrap_or_default();
+    let set_name = set.name();
+    let ns = set.namespace().unwrap_or_default();
+    let asleep = set
+        .status
+        .as_ref()
+        .and_then(|s| s.idle.as_ref())
+        .is_some_and(|i| i.asleep());
+    let entry = |name: String, address: String| VelocityServerEntry {
+        address,
+        host: proxy.hostname.clone(),
+        name,
+        priority: proxy.priority,
+        players: None,
+        set: set_name.clone(),
+        asleep,
+        queue: None,
+    };
+    let service = format!("{}.{}.svc.cluster.local", set_name, ns);
+    match workload {
+        Workload::Deployment => vec![entry(set_name.clone(), service)],
+        Workload::Fleet if asleep => vec![entry(set_name.clone(), service)],
+        Workload::Fleet => ready
+            .iter()
+            .filter(|pod| pod.labels().get("mycelium.njha.dev/mcset") == Some(&set_name))
+            .filter_map(|pod| Some(entry(pod.name(), pod.status.as_ref()?.pod_ip.clone()?)))
+            .collect(),
+        Workload::StatefulSet => {
+            let ready: BTreeSet<String> = ready.iter().map(|pod| pod.name()).collect();
             (0..spec.replicas)
-                .map(move |val| -> VelocityServerEntry {
-                    VelocityServerEntry {
-                        address: format!(
-                            "{0}-{1}.{0}.{2}.svc.cluster.local",
-                            set.metadata.name.clone().unwrap(),
-                            val,
-                            set.metadata.namespace.clone().unwrap()
-                        ),
-                        host: proxy.hostname.clone(),
-                        name: format!("{}-{}", set.metadata.name.clone().unwrap(), val),
-                        priority: proxy.priority,
-                    }
+                .filter(|ordinal| match asleep {
+                    true => *ordinal == 0,
+                    false => ready.contains(&format!("{}-{}", set_name, ordinal)),
                 })
-        }).collect())
+                .map(|ordinal| {
+                    entry(
+                        format!("{}-{}", set_name, ordinal),
+                        format!("{0}-{1}.{0}.{2}.svc.cluster.local", set_name, ordinal, ns),
+                    )
+                })
+                .collect()
+        }
     }
 }
 
+/// namespaces in MYCELIUM_WATCH_NAMESPACES (comma separated), none meaning all
+/// of them
+pub fn watch_namespaces() -> Vec<String> {
+    env::var("MYCELIUM_WATCH_NAMESPACES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|ns| !ns.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// an Api over `ns`, or over every namespace if None
+fn scoped<K: Resource<DynamicType = ()>>(client: &Client, ns: Option<&str>) -> Api<K> {
+    match ns {
+        Some(ns) => Api::namespaced(client.clone(), ns),
+        None => Api::all(client.clone()),
+    }
+}
+
+/// Runs a controller for fleets in `ns` (or every namespace), watching the sets
+/// they own.
+fn fleet_controller(
+    client: &Client,
+    ns: Option<&str>,
+    context: Context<Data>,
+) -> BoxFuture<'static, ()> {
+    Controller::new(scoped::<MinecraftFleet>(client, ns), ListParams::default())
+        .owns(scoped::<MinecraftSet>(client, ns), ListParams::default())
+        .run(
+            move |k, ctx| backoff::reconcile(k, ctx, minecraft_fleet::reconcile),
+            error_policy,
+            context,
+        )
+        .for_each(|res| async move {
+            match res {
+                Ok(o) => info!("reconciled {:?}", o),
+                Err(e) => warn!("reconcile failed: {}", e),
+            }
+        })
+        .boxed()
+}
+
+/// Runs a controller for sets or proxies in `ns` (or every namespace), watching
+/// the objects they own, the configmaps and secrets they reference and the
+/// MyceliumConfigs of their namespaces.
+fn controller<K, Fut>(
+    client: &Client,
+    ns: Option<&str>,
+    context: Context<Data>,
+    reloaded: mpsc::UnboundedReceiver<()>,
+    reconcile: fn(K, Context<Data>) -> Fut,
+    references: fn(&K) -> References,
+) -> BoxFuture<'static, ()>
+where
+    K: Clone + Resource<DynamicType = ()> + DeserializeOwned + Debug + Send + Sync + 'static,
+    Fut: Future<Output = Result<ReconcilerAction, Error>> + Send + 'static,
+{
+    let controller = Controller::new(scoped::<K>(client, ns), ListParams::default());
+    let (cm_store, secret_store, config_store) =
+        (controller.store(), controller.store(), controller.store());
+    controller
+        .owns(scoped::<StatefulSet>(client, ns), ListParams::default())
+        .owns(scoped::<Deployment>(client, ns), ListParams::default())
+        .owns(scoped::<Secret>(client, ns), ListParams::default())
+        .owns(scoped::<Service>(client, ns), ListParams::default())
+        .owns(
+            scoped::<PodDisruptionBudget>(client, ns),
+            ListParams::default(),
+        )
+        .owns(scoped::<ConfigMap>(client, ns), ListParams::default())
+        .watches(
+            scoped::<ConfigMap>(client, ns),
+            ListParams::default(),
+            move |cm| referencing(&cm_store, &cm, |k| references(k).config_maps),
+        )
+        .watches(
+            scoped::<Secret>(client, ns),
+            ListParams::default(),
+            move |secret| referencing(&secret_store, &secret, |k| references(k).secrets),
+        )
+        .watches(
+            scoped::<NamespaceConfig>(client, ns),
+            ListParams::default(),
+            move |config| {
+                let ns = config.namespace();
+                let state = config_store.state();
+                state
+                    .iter()
+                    .filter(|k| k.namespace() == ns)
+                    .map(ObjectRef::from_obj)
+                    .collect::<Vec<_>>()
+            },
+        )
+        .reconcile_all_on(reloaded)
+        .run(
+            move |k, ctx| backoff::reconcile(k, ctx, reconcile),
+            error_policy,
+            context,
+        )
+        .for_each(|res| async move {
+            match res {
+                Ok(o) => info!("reconciled {:?}", o),
+                Err(e) => warn!("reconcile failed: {}", e),
+            }
+        })
+        .boxed()
+}
+
+/// the objects in `store` that reference `object`, which lives in their
+/// namespace
+fn referencing<K, O>(
+    store: &Store<K>,
+    object: &O,
+    refs: impl Fn(&K) -> BTreeSet<String>,
+) -> Vec<ObjectRef<K>>
+where
+    K: Resource<DynamicType = ()> + Clone,
+    O: Resource,
+{
+    let (ns, name) = (object.namespace(), object.name());
+    store
+        .state()
+        .iter()
+        .filter(|k| k.namespace() == ns && refs(k).contains(&name))
+        .map(ObjectRef::from_obj)
+        .collect()
+}
+
+/// label selector for the MinecraftSets behind a proxy
+pub(crate) fn proxy_selector(spec: &MinecraftProxySpec) -> String {
+    spec.selector
+        .clone()
+        .unwrap_or_default()
+        .match_labels
+        .unwrap_or_default()
+        .iter()
+        .map(|i| format!("{}={}", i.0, i.1))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// how long a backend may fail pings before proxies stop being told about it
+const BACKEND_GRACE_MINUTES: i64 = 2;
+
+/// a proxy replica's heartbeat, with the result of pinging each backend
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct HeartbeatReport {
+    /// pod name of the proxy replica
+    pub replica: String,
+    /// backends that answered a ping
+    pub reachable: Vec<String>,
+    /// backends that didn't answer a ping
+    pub unreachable: Vec<String>,
+    /// players connected through this replica, by backend
+    #[serde(default)]
+    pub players: BTreeMap<String, u32>,
+    /// players waiting in this replica's login queues, by set
+    #[serde(default)]
+    pub queues: BTreeMap<String, u32>,
+}
+
 pub fn error_policy(error: &Error, _ctx: Context<Data>) -> ReconcilerAction {
     warn!("reconcile failed: {:?}", error);
     ReconcilerAction {
-        requeue_after: Some(Duration::from_secs(360)),
+        requeue_after: Some(match error {
+            Error::Backoff { retry, .. } => *retry,
+            _ => backoff::CAP,
+        }),
     }
 }
 
@@ -181,14 +745,60 @@ pub struct VelocityServerEntry {
     pub name: String,
     /// priority for default list
     pub priority: Option<u32>,
+    /// players on the server across all proxies, if known
+    pub players: Option<u32>,
+    /// MinecraftSet the server belongs to
+    pub set: String,
+    /// the set is scaled to zero, and has to be woken up before players can
+    /// join
+    pub asleep: bool,
+    /// login queue of the set, for proxies to hold players in once it's full
+    pub queue: Option<ServerQueue>,
 }
 
 #[derive(Clone)]
 pub struct MyceliumConfig {
     /// velocity forwarding secret
     pub(crate) forwarding_secret: String,
+    /// address of the operator API, for plugins and runners
+    pub(crate) endpoint: String,
+    /// PEM of the CA the API's certificate is signed with, for plugins and
+    /// runners to trust
+    pub(crate) tls_ca: Option<String>,
     /// runner image
     pub(crate) runner_image: String,
+    /// image for game servers with runnerMode = itzg
+    pub(crate) itzg_server_image: String,
+    /// image for proxies with runnerMode = itzg
+    pub(crate) itzg_proxy_image: String,
+    /// runner image per runner.javaVersion, overriding the `-java<version>` tag
+    /// default
+    pub(crate) java_images: BTreeMap<u32, String>,
+    /// hosts runner.plugins and runner.mods may download from, any if empty
+    pub(crate) allowed_plugin_hosts: Vec<String>,
+    /// jar types sets and proxies may run, any if empty
+    pub(crate) allowed_jar_types: Vec<String>,
+    /// how often to reconcile everything again, undoing manual edits (never if
+    /// None)
+    pub(crate) resync: Option<Duration>,
+    /// reconciles that may run at once, only read on startup
+    pub(crate) max_concurrent_reconciles: usize,
+    /// shortest time between two reconciles of the same object
+    pub(crate) min_reconcile_interval: Duration,
+    /// label keys copied from sets and proxies onto their objects, `*` ending a
+    /// prefix
+    pub(crate) propagated_labels: Vec<String>,
+    /// annotation keys copied from sets and proxies onto their objects, `*`
+    /// ending a prefix
+    pub(crate) propagated_annotations: Vec<String>,
+    /// what runs the replicas of game servers
+    pub(crate) pod_backend: PodBackend,
+    /// quota of each namespace, `*` for the ones without their own
+    pub(crate) quotas: BTreeMap<String, Quota>,
+    /// whether the API asks for a bearer token, off only for trusted networks
+    pub(crate) api_authentication: bool,
+    /// addresses to listen on, only read when the operator starts
+    pub(crate) listen: Listen,
 }
 
 #[derive(Clone)]
@@ -199,6 +809,106 @@ pub struct Data {
     pub(crate) state: Arc<RwLock<State>>,
     /// prometheus metrics
     pub(crate) metrics: Metrics,
-    /// parsed configuration
-    pub(crate) config: MyceliumConfig,
+    /// parsed configuration, replaced when it is reloaded
+    pub(crate) config: Arc<RwLock<MyceliumConfig>>,
+    /// artifact cache, if enabled
+    pub(crate) cache: Option<ArtifactCache>,
+    /// permits for reconciles in flight, shared by both controllers
+    pub(crate) reconciles: Arc<Semaphore>,
+}
+
+impl Data {
+    /// rewrites a download URL to go through the artifact cache, if enabled
+    pub(crate) fn artifact_url(&self, url: &str) -> String {
+        objects::artifact_url(self.cache.as_ref(), url)
+    }
+
+    /// what the replicas of a set or proxy run as, with the configured pod
+    /// backend
+    pub(crate) fn workload(
+        &self,
+        runner: &RunnerOptions,
+        container: &ContainerOptions,
+    ) -> Workload {
+        self.config
+            .read()
+            .expect("config")
+            .pod_backend
+            .workload(runner, container)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{
+        backend,
+        tests::{basic_spec, config as test_config, mcset},
+    };
+
+    #[test]
+    fn fleet_sets_list_their_game_servers_by_pod_ip() {
+        let pod = |name: &str, set: &str, ip: Option<&str>| -> k8s_openapi::api::core::v1::Pod {
+            serde_json::from_value(json!({
+            "metadata": { "name": name, "namespace": "games", "labels": { "mycelium.njha.dev/mcset": set } },
+            "status": { "podIP": ip },
+        }))
+        .unwrap()
+        };
+        let ready = vec![
+            pod("survival-x7k2p-4fz9q", "survival", Some("10.0.0.7")),
+            pod("survival-x7k2p-8hd2m", "survival", None),
+            pod("lobby-0", "lobby", Some("10.0.0.9")),
+        ];
+        let set = mcset(basic_spec());
+
+        let servers = set_servers(&set, backend::Workload::Fleet, &ready);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].name, "survival-x7k2p-4fz9q");
+        assert_eq!(servers[0].address, "10.0.0.7");
+        assert_eq!(servers[0].set, "survival");
+
+        // the same set as a StatefulSet only goes by ordinals
+        assert!(set_servers(&set, backend::Workload::StatefulSet, &ready).is_empty());
+        let ready = vec![pod("survival-1", "survival", Some("10.0.0.8"))];
+        let servers = set_servers(&set, backend::Workload::StatefulSet, &ready);
+        assert_eq!(
+            servers[0].address,
+            "survival-1.survival.games.svc.cluster.local"
+        );
+
+        // a sleeping Fleet has no GameServers, so it's listed by its Service to be
+        // woken up
+        let mut asleep = basic_spec();
+        asleep["scaleToZero"] = json!({});
+        let mut set = mcset(asleep);
+        set.status = Some(
+            serde_json::from_value(json!({ "idle": { "asleepSince": "2022-01-01T00:00:00Z" } }))
+                .unwrap(),
+        );
+        let servers = set_servers(&set, backend::Workload::Fleet, &[]);
+        assert_eq!(servers[0].address, "survival.games.svc.cluster.local");
+        assert!(servers[0].asleep);
+    }
+
+    #[test]
+    fn java_version_picks_the_runner_image() {
+        let mut spec = basic_spec();
+        spec["runner"]["javaVersion"] = json!(17);
+        let set = mcset(spec);
+        assert_eq!(
+            test_config().image(&set.spec.runner),
+            "mycelium/runner:test-java17"
+        );
+
+        let mut runner = set.spec.runner.clone();
+        runner.java_version = Some(21);
+        assert_eq!(
+            test_config().image(&runner),
+            "mycelium/runner:java21-custom"
+        );
+
+        runner.runner_mode = Some("itzg".into());
+        assert_eq!(test_config().image(&runner), "itzg/minecraft-server:java21");
+    }
 }
@@ -1,4 +1,10 @@
-use prometheus::{register_histogram_vec, register_int_counter, HistogramVec, IntCounter};
+use std::collections::BTreeMap;
+
+use prometheus::{
+    core::Collector, register_gauge_vec, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, GaugeVec, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec,
+};
 
 /// prometheus metrics exposed on /metrics
 #[derive(Clone)]
@@ -7,9 +13,39 @@ pub struct Metrics {
     pub proxy_handled_events: IntCounter,
     pub set_reconcile_duration: HistogramVec,
     pub proxy_reconcile_duration: HistogramVec,
+    pub proxy_heartbeat: IntGaugeVec,
+    pub last_reconcile: IntGaugeVec,
+    pub max_players: IntGaugeVec,
+    pub last_backup: IntGaugeVec,
+    pub drift_corrections: IntCounterVec,
+    pub startup_phase_duration: HistogramVec,
+    pub set_players: IntGaugeVec,
+    pub set_capacity: IntGaugeVec,
+    pub set_tps: GaugeVec,
+    pub set_queue_length: IntGaugeVec,
 }
 
 impl Metrics {
+    /// the players last scraped from each replica of a set, by pod name
+    pub fn scraped_players(&self, ns: &str, set: &str) -> BTreeMap<String, i64> {
+        let mut players = BTreeMap::new();
+        for family in self.set_players.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| {
+                    let pair = metric.get_label().iter().find(|l| l.get_name() == name);
+                    pair.map(|l| l.get_value().to_string()).unwrap_or_default()
+                };
+                if label("namespace") == ns && label("set") == set {
+                    players.insert(
+                        format!("{}-{}", set, label("ordinal")),
+                        metric.get_gauge().get_value() as i64,
+                    );
+                }
+            }
+        }
+        players
+    }
+
     pub(crate) fn new() -> Self {
         let set_reconcile_histogram = register_histogram_vec!(
             "mcset_controller_reconcile_duration_seconds",
@@ -40,6 +76,67 @@ impl Metrics {
             .unwrap(),
             set_reconcile_duration: set_reconcile_histogram,
             proxy_reconcile_duration: proxy_reconcile_histogram,
+            proxy_heartbeat: register_int_gauge_vec!(
+                "mycelium_proxy_last_heartbeat_timestamp_seconds",
+                "when each proxy replica last checked in with the operator",
+                &["namespace", "name", "replica"]
+            )
+            .unwrap(),
+            last_reconcile: register_int_gauge_vec!(
+                "mycelium_last_reconcile_timestamp",
+                "when each resource was last reconciled successfully, in unix seconds",
+                &["kind", "namespace", "name"]
+            )
+            .unwrap(),
+            max_players: register_int_gauge_vec!(
+                "mycelium_max_players",
+                "the configured runner.maxPlayers of each resource, per replica",
+                &["kind", "namespace", "name"]
+            )
+            .unwrap(),
+            last_backup: register_int_gauge_vec!(
+                "mycelium_last_backup_timestamp_seconds",
+                "when the newest backup of each MinecraftSet was taken",
+                &["namespace", "name"]
+            )
+            .unwrap(),
+            drift_corrections: register_int_counter_vec!(
+                "mycelium_drift_corrections_total",
+                "generated objects that were changed outside the operator and applied again",
+                &["kind", "namespace", "name"]
+            )
+            .unwrap(),
+            startup_phase_duration: register_histogram_vec!(
+                "mycelium_startup_phase_duration_seconds",
+                "time spent in each phase of server startup, as reported by the runner",
+                &["kind", "phase"],
+                vec![0.5, 1., 2.5, 5., 10., 30., 60., 120., 300.]
+            )
+            .unwrap(),
+            set_players: register_int_gauge_vec!(
+                "mycelium_set_players",
+                "players online on each replica of a MinecraftSet, scraped from UnifiedMetrics",
+                &["namespace", "set", "ordinal"]
+            )
+            .unwrap(),
+            set_capacity: register_int_gauge_vec!(
+                "mycelium_set_capacity",
+                "the player limit of each replica of a MinecraftSet, scraped from UnifiedMetrics",
+                &["namespace", "set", "ordinal"]
+            )
+            .unwrap(),
+            set_tps: register_gauge_vec!(
+                "mycelium_set_tps",
+                "ticks per second of each replica of a MinecraftSet, scraped from UnifiedMetrics",
+                &["namespace", "set", "ordinal"]
+            )
+            .unwrap(),
+            set_queue_length: register_int_gauge_vec!(
+                "mycelium_set_queue_length",
+                "players the proxies hold in the login queue of each MinecraftSet",
+                &["namespace", "set"]
+            )
+            .unwrap(),
         }
     }
 }
@@ -1,6 +1,26 @@
+/// bearer tokens for the API
+pub mod auth;
+/// throttling and retrying reconciles
+pub mod backoff;
+/// download proxy for jars and plugins
+pub mod cache;
+/// operator configuration reloading
+pub mod config;
+/// plugin lookups in PaperMC's Hangar
+pub mod hangar;
+/// player count history
+pub mod history;
 pub mod jarapi;
 pub mod manager;
 pub mod metrics;
+/// console commands over rcon
+pub mod rcon;
+/// the KEDA external scaler
+pub mod scaler;
+/// player and TPS gauges scraped from the replicas
+pub mod scrape;
 pub mod state;
 /// logging and tracing
 pub mod telemetry;
+/// serving the API over TLS
+pub mod tls;
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{Error, Error::MyceliumError};
+
+/// port the runner configures RCON on
+pub const RCON_PORT: u16 = 25575;
+
+const LOGIN: i32 = 3;
+const COMMAND: i32 = 2;
+
+/// Runs console commands on a server over RCON, returning the output of each.
+pub async fn run(host: &str, password: &str, commands: &[String]) -> Result<Vec<String>, Error> {
+    let addr = format!("{}:{}", host, RCON_PORT);
+    let io = |e: std::io::Error| MyceliumError(format!("rcon {}: {}", addr, e));
+    let mut stream = timeout(Duration::from_secs(5), TcpStream::connect(&addr))
+        .await
+        .map_err(|_| MyceliumError(format!("rcon {}: connection timed out", addr)))?
+        .map_err(io)?;
+
+    let (id, _) = request(&mut stream, 1, LOGIN, password).await.map_err(io)?;
+    if id == -1 {
+        return Err(MyceliumError(format!(
+            "rcon {}: authentication failed",
+            addr
+        )));
+    }
+
+    let mut output = vec![];
+    for (i, command) in commands.iter().enumerate() {
+        let (_, body) = request(&mut stream, i as i32 + 2, COMMAND, command)
+            .await
+            .map_err(io)?;
+        output.push(body);
+    }
+    Ok(output)
+}
+
+/// sends one packet and reads the reply, returning its request id and body
+async fn request(
+    stream: &mut TcpStream,
+    id: i32,
+    kind: i32,
+    body: &str,
+) -> std::io::Result<(i32, String)> {
+    let mut packet = Vec::with_capacity(body.len() + 14);
+    packet.extend_from_slice(&(body.len() as i32 + 10).to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&kind.to_le_bytes());
+    packet.extend_from_slice(body.as_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    stream.write_all(&packet).await?;
+
+    let len = stream.read_i32_le().await?;
+    if !(10..=4106).contains(&len) {
+        return Err(std::io::Error::other(format!("bad packet length {}", len)));
+    }
+    let mut reply = vec![0; len as usize];
+    stream.read_exact(&mut reply).await?;
+    let id = i32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let body = String::from_utf8_lossy(&reply[8..reply.len() - 2]).into_owned();
+    Ok((id, body))
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::{channel::mpsc, SinkExt};
+use kube::{Api, Client};
+use proto::{
+    external_scaler_server::{ExternalScaler, ExternalScalerServer},
+    GetMetricSpecResponse, GetMetricsRequest, GetMetricsResponse, IsActiveResponse, MetricSpec,
+    MetricValue, ScaledObjectRef,
+};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::info;
+
+use crate::{helpers::state::State, objects::minecraft_set::MinecraftSet, Error};
+
+/// generated from proto/externalscaler.proto
+pub mod proto {
+    tonic::include_proto!("externalscaler");
+}
+
+/// name of the one metric reported per set
+pub const METRIC: &str = "players";
+
+/// how often a StreamIsActive stream looks at the player count
+const STREAM_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Players per replica KEDA should aim for, from the `playersPerReplica`
+/// metadata of the ScaledObject or else the set's runner.maxPlayers.
+pub fn target(metadata: &HashMap<String, String>, max_players: Option<u32>) -> Result<i64, Error> {
+    let target = match metadata.get("playersPerReplica") {
+        Some(players) => players.parse().map_err(|_| {
+            Error::MyceliumError(format!("playersPerReplica {:?} is not a number", players))
+        })?,
+        None => max_players.map(i64::from).ok_or_else(|| {
+            Error::MyceliumError("set playersPerReplica, or runner.maxPlayers on the set".into())
+        })?,
+    };
+    match target {
+        target if target > 0 => Ok(target),
+        _ => Err(Error::MyceliumError(
+            "playersPerReplica must be positive".into(),
+        )),
+    }
+}
+
+/// the MinecraftSet a ScaledObject scales, its `set` metadata or else its own
+/// name
+fn set_name(object: &ScaledObjectRef) -> &str {
+    object.scaler_metadata.get("set").unwrap_or(&object.name)
+}
+
+/// players above which the set is active, from the `activationPlayers` metadata
+/// (default 0)
+fn activation(object: &ScaledObjectRef) -> Result<u32, Error> {
+    match object.scaler_metadata.get("activationPlayers") {
+        Some(players) => players.parse().map_err(|_| {
+            Error::MyceliumError(format!("activationPlayers {:?} is not a number", players))
+        }),
+        None => Ok(0),
+    }
+}
+
+/// the error for a set whose player count isn't known
+fn unknown() -> Status {
+    Status::unavailable("no proxy has reported player counts recently")
+}
+
+fn status(e: Error) -> Status {
+    match e {
+        Error::KubeError(kube::Error::Api(e)) if e.code == 404 => Status::not_found(e.message),
+        Error::MyceliumError(message) => Status::invalid_argument(message),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// Reports the players on each MinecraftSet to KEDA, which scales the set's
+/// replicas through its scale subresource.
+#[derive(Clone)]
+pub struct Scaler {
+    client: Client,
+    state: Arc<RwLock<State>>,
+}
+
+impl Scaler {
+    pub fn new(client: Client, state: Arc<RwLock<State>>) -> Self {
+        Scaler { client, state }
+    }
+
+    /// players on the set, or None if no proxy has reported them recently
+    fn players(&self, object: &ScaledObjectRef) -> Option<u32> {
+        let state = self.state.read().expect("scaler players");
+        state.set_players(&object.namespace, set_name(object))
+    }
+
+    /// Serves the scaler on `addr` until the server fails. KEDA reaches it as
+    /// `mycelium-operator.<namespace>:9090`.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        info!("serving the KEDA external scaler on {}", addr);
+        Server::builder()
+            .add_service(ExternalScalerServer::new(self))
+            .serve(addr)
+            .await
+    }
+}
+
+#[tonic::async_trait]
+impl ExternalScaler for Scaler {
+    type StreamIsActiveStream = mpsc::Receiver<Result<IsActiveResponse, Status>>;
+
+    async fn is_active(
+        &self,
+        request: Request<ScaledObjectRef>,
+    ) -> Result<Response<IsActiveResponse>, Status> {
+        let object = request.get_ref();
+        let activation = activation(object).map_err(status)?;
+        let players = self.players(object).ok_or_else(unknown)?;
+        Ok(Response::new(IsActiveResponse {
+            result: players > activation,
+        }))
+    }
+
+    /// sends whether the set is active whenever that changes, for
+    /// `external-push` triggers
+    async fn stream_is_active(
+        &self,
+        request: Request<ScaledObjectRef>,
+    ) -> Result<Response<Self::StreamIsActiveStream>, Status> {
+        let activation = activation(request.get_ref()).map_err(status)?;
+        let (mut tx, rx) = mpsc::channel(1);
+        let (scaler, object) = (self.clone(), request.into_inner());
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                // unknown counts are left out, KEDA keeps what it was last told
+                if let Some(result) = scaler.players(&object).map(|players| players > activation) {
+                    // KEDA hung up
+                    if last != Some(result)
+                        && tx.send(Ok(IsActiveResponse { result })).await.is_err()
+                    {
+                        return;
+                    }
+                    last = Some(result);
+                }
+                tokio::time::sleep(STREAM_INTERVAL).await;
+            }
+        });
+        Ok(Response::new(rx))
+    }
+
+    async fn get_metric_spec(
+        &self,
+        request: Request<ScaledObjectRef>,
+    ) -> Result<Response<GetMetricSpecResponse>, Status> {
+        let object = request.get_ref();
+        let set = Api::<MinecraftSet>::namespaced(self.client.clone(), &object.namespace)
+            .get(set_name(object))
+            .await
+            .map_err(|e| status(e.into()))?;
+        let target_size =
+            target(&object.scaler_metadata, set.spec.runner.max_players).map_err(status)?;
+        Ok(Response::new(GetMetricSpecResponse {
+            metric_specs: vec![MetricSpec {
+                metric_name: METRIC.into(),
+                target_size,
+            }],
+        }))
+    }
+
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let object = request
+            .get_ref()
+            .scaled_object_ref
+            .clone()
+            .unwrap_or_default();
+        let players = self.players(&object).ok_or_else(unknown)?;
+        Ok(Response::new(GetMetricsResponse {
+            metric_values: vec![MetricValue {
+                metric_name: METRIC.into(),
+                metric_value: players.into(),
+            }],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::helpers::state::ProxyHeartbeat;
+
+    #[test]
+    fn scaler_reports_players_per_set() {
+        let mut state = State::new();
+        assert_eq!(state.set_players("games", "survival"), None);
+        state
+            .proxies
+            .insert("games/proxy/proxy-0".into(), ProxyHeartbeat {
+                last_seen: chrono::Utc::now(),
+                reachable: vec![],
+                unreachable: vec![],
+                players: BTreeMap::from([
+                    ("survival-0".to_string(), 3),
+                    ("survival-1".to_string(), 4),
+                ]),
+                queues: BTreeMap::new(),
+            });
+        // a set no proxy lists yet is empty, rather than unknown
+        assert_eq!(state.set_players("games", "survival"), Some(0));
+        for backend in ["survival-0", "survival-1", "creative-0"] {
+            let set = backend.split('-').next().unwrap();
+            state
+                .backend_sets
+                .insert(format!("games/{}", backend), set.into());
+        }
+        assert_eq!(state.set_players("games", "survival"), Some(7));
+        assert_eq!(state.set_players("games", "creative"), Some(0));
+        assert_eq!(state.set_players("other", "survival"), None);
+
+        let metadata = HashMap::from([("playersPerReplica".to_string(), "25".to_string())]);
+        assert_eq!(target(&metadata, Some(50)).unwrap(), 25);
+        assert_eq!(target(&HashMap::new(), Some(50)).unwrap(), 50);
+        assert!(target(&HashMap::new(), None).is_err());
+        let zero = HashMap::from([("playersPerReplica".to_string(), "0".to_string())]);
+        assert!(target(&zero, Some(50)).is_err());
+    }
+}
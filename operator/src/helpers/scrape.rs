@@ -0,0 +1,156 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::ListParams, Api, Client};
+use tracing::debug;
+
+use crate::{helpers::metrics::Metrics, objects::pod_ready};
+
+/// how often the replicas of every set are scraped
+const SCRAPE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how long a replica has to answer
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UnifiedMetrics port, if the pod has no prometheus.io/port annotation
+const DEFAULT_PORT: &str = "9970";
+
+/// UnifiedMetrics gauges of online players, the player limit and ticks per
+/// second
+const PLAYERS: &str = "minecraft_players_count";
+const CAPACITY: &str = "minecraft_players_max";
+const TPS: &str = "minecraft_tps";
+
+/// The value of a metric in the Prometheus text format, summed over its label
+/// sets. None if the metric isn't in the text.
+pub fn sample(text: &str, metric: &str) -> Option<f64> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let name = series.split(['{', ' ']).next()?;
+            (name == metric)
+                .then(|| value.parse::<f64>().ok())
+                .flatten()
+        })
+        .reduce(|a, b| a + b)
+}
+
+/// the ordinal label of a set's pod, the part of its name after the set's
+fn ordinal<'a>(pod: &'a str, set: &str) -> &'a str {
+    pod.strip_prefix(set)
+        .and_then(|o| o.strip_prefix('-'))
+        .unwrap_or(pod)
+}
+
+/// Scrapes one replica's metrics endpoint, returning its labels if it answered.
+async fn scrape(http: &reqwest::Client, metrics: &Metrics, pod: &Pod) -> Option<[String; 3]> {
+    let ns = pod.metadata.namespace.clone()?;
+    let name = pod.metadata.name.clone()?;
+    let set = pod
+        .metadata
+        .labels
+        .as_ref()?
+        .get("mycelium.njha.dev/mcset")?
+        .clone();
+    let ip = pod.status.as_ref()?.pod_ip.clone()?;
+    let annotations = pod.metadata.annotations.clone().unwrap_or_default();
+    let port = annotations
+        .get("prometheus.io/port")
+        .map_or(DEFAULT_PORT, String::as_str);
+    let response = http
+        .get(format!("http://{}:{}/metrics", ip, port))
+        .send()
+        .await;
+    let text = match response.and_then(|r| r.error_for_status()) {
+        Ok(response) => response.text().await.ok()?,
+        Err(e) => {
+            debug!("couldn't scrape {} in {}: {}", name, ns, e);
+            return None;
+        }
+    };
+    let labels = [ns, set.clone(), ordinal(&name, &set).to_string()];
+    let values: Vec<&str> = labels.iter().map(String::as_str).collect();
+    if let Some(players) = sample(&text, PLAYERS) {
+        metrics
+            .set_players
+            .with_label_values(&values)
+            .set(players as i64);
+    }
+    if let Some(capacity) = sample(&text, CAPACITY) {
+        metrics
+            .set_capacity
+            .with_label_values(&values)
+            .set(capacity as i64);
+    }
+    if let Some(tps) = sample(&text, TPS) {
+        metrics.set_tps.with_label_values(&values).set(tps);
+    }
+    Some(labels)
+}
+
+/// Scrapes the UnifiedMetrics endpoint of every ready replica of a set in
+/// `namespaces` (or every namespace if empty) on an interval, exporting
+/// players, capacity and TPS by namespace, set and ordinal. Replicas that go
+/// away or stop answering are dropped.
+pub fn spawn(client: Client, metrics: Metrics, namespaces: Vec<String>) {
+    let http = reqwest::Client::builder()
+        .timeout(SCRAPE_TIMEOUT)
+        .build()
+        .expect("scrape client");
+    let apis: Vec<Api<Pod>> = match namespaces.is_empty() {
+        true => vec![Api::all(client)],
+        false => namespaces
+            .iter()
+            .map(|ns| Api::namespaced(client.clone(), ns))
+            .collect(),
+    };
+    tokio::spawn(async move {
+        let mut scraped: BTreeSet<[String; 3]> = BTreeSet::new();
+        loop {
+            tokio::time::sleep(SCRAPE_INTERVAL).await;
+            let mut seen = BTreeSet::new();
+            for api in &apis {
+                let pods = match api
+                    .list(&ListParams::default().labels("mycelium.njha.dev/mcset"))
+                    .await
+                {
+                    Ok(pods) => pods.items,
+                    Err(e) => {
+                        debug!("couldn't list pods to scrape: {}", e);
+                        continue;
+                    }
+                };
+                let ready = pods.iter().filter(|pod| pod_ready(pod));
+                let results =
+                    futures::future::join_all(ready.map(|pod| scrape(&http, &metrics, pod))).await;
+                seen.extend(results.into_iter().flatten());
+            }
+            for labels in scraped.difference(&seen) {
+                let values: Vec<&str> = labels.iter().map(String::as_str).collect();
+                let _ = metrics.set_players.remove_label_values(&values);
+                let _ = metrics.set_capacity.remove_label_values(&values);
+                let _ = metrics.set_tps.remove_label_values(&values);
+            }
+            scraped = seen;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_metrics_are_parsed() {
+        let text = "# HELP minecraft_players_count Online players\n# TYPE minecraft_players_count \
+                    gauge\nminecraft_players_count 12.0\nminecraft_players_max \
+                    50.0\nminecraft_tps 19.87\nminecraft_world_entities_count{world=\"world\"} \
+                    100.0\nminecraft_world_entities_count{world=\"world_nether\"} 20.0\n";
+        assert_eq!(sample(text, "minecraft_players_count"), Some(12.0));
+        assert_eq!(sample(text, "minecraft_players_max"), Some(50.0));
+        assert_eq!(sample(text, "minecraft_tps"), Some(19.87));
+        assert_eq!(sample(text, "minecraft_world_entities_count"), Some(120.0));
+        assert_eq!(sample(text, "minecraft_players"), None);
+    }
+}
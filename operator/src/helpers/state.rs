@@ -1,17 +1,121 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use serde::Serialize;
 
+use crate::helpers::history::History;
+
 /// in-memory reconciler state exposed on /state
 #[derive(Clone, Serialize)]
 pub struct State {
     #[serde(deserialize_with = "from_ts")]
     pub last_event: DateTime<Utc>,
+    /// latest heartbeat from each proxy replica, keyed by
+    /// namespace/proxy/replica
+    pub proxies: BTreeMap<String, ProxyHeartbeat>,
+    /// backend reachability reported by proxies, keyed by
+    /// namespace/proxy/backend
+    pub backends: BTreeMap<String, BackendHealth>,
+    /// when each scale-down started draining, keyed by namespace/name
+    pub draining: BTreeMap<String, DateTime<Utc>>,
+    /// the MinecraftSet each backend belongs to, keyed by namespace/backend
+    pub backend_sets: BTreeMap<String, String>,
+    /// failed reconciles in a row, keyed by kind/namespace/name
+    pub failures: BTreeMap<String, u32>,
+    /// when each object was last reconciled, keyed by kind/namespace/name
+    #[serde(skip)]
+    pub reconciled: BTreeMap<String, std::time::Instant>,
+    /// hash of the last object applied and the generation (or resourceVersion)
+    /// it left, keyed by kind/namespace/name, to notice changes made behind
+    /// the operator's back (not persisted, so everything is applied again
+    /// after a restart)
+    #[serde(skip)]
+    pub applied: BTreeMap<String, (String, String)>,
+    /// player counts over time, served on /servers/{ns}/{name}/history instead
+    #[serde(skip)]
+    pub history: History,
+}
+
+/// the latest heartbeat from a proxy replica
+#[derive(Clone, Serialize)]
+pub struct ProxyHeartbeat {
+    pub last_seen: DateTime<Utc>,
+    /// backends that answered a ping
+    pub reachable: Vec<String>,
+    /// backends that didn't answer a ping
+    pub unreachable: Vec<String>,
+    /// players connected through this replica, by backend
+    pub players: BTreeMap<String, u32>,
+    /// players this replica holds in a set's login queue, by set
+    pub queues: BTreeMap<String, u32>,
+}
+
+/// whether a proxy has been able to reach a backend
+#[derive(Clone, Default, Serialize)]
+pub struct BackendHealth {
+    /// the proxy has reached this backend at least once
+    pub ever_connected: bool,
+    /// start of the current run of failed pings, if the last ping failed
+    pub failing_since: Option<DateTime<Utc>>,
 }
 
 impl State {
     pub(crate) fn new() -> Self {
         State {
             last_event: Utc::now(),
+            proxies: BTreeMap::new(),
+            backends: BTreeMap::new(),
+            draining: BTreeMap::new(),
+            backend_sets: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            reconciled: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            history: History::load(),
         }
     }
+
+    /// Players on a backend across all proxy replicas in the namespace, or None
+    /// if no proxy has sent a recent enough heartbeat to know.
+    pub fn players(&self, ns: &str, backend: &str) -> Option<u32> {
+        let prefix = format!("{}/", ns);
+        let fresh = Utc::now() - chrono::Duration::minutes(3);
+        self.proxies
+            .iter()
+            .filter(|(key, hb)| key.starts_with(&prefix) && hb.last_seen > fresh)
+            .map(|(_, hb)| hb.players.get(backend).copied().unwrap_or(0))
+            .reduce(|a, b| a + b)
+    }
+
+    /// Players waiting in each set's login queue across the proxy replicas in
+    /// the namespace that have sent a recent heartbeat.
+    pub fn queue_lengths(&self, ns: &str) -> BTreeMap<String, u32> {
+        let prefix = format!("{}/", ns);
+        let fresh = Utc::now() - chrono::Duration::minutes(3);
+        let mut lengths = BTreeMap::new();
+        let heartbeats = self
+            .proxies
+            .iter()
+            .filter(|(key, hb)| key.starts_with(&prefix) && hb.last_seen > fresh);
+        for (set, queued) in heartbeats.flat_map(|(_, hb)| &hb.queues) {
+            *lengths.entry(set.clone()).or_insert(0) += queued;
+        }
+        lengths
+    }
+
+    /// Players on every backend of a MinecraftSet, or None if no proxy in the
+    /// namespace has sent a recent enough heartbeat to know. A set no proxy
+    /// lists has none.
+    pub fn set_players(&self, ns: &str, set: &str) -> Option<u32> {
+        let prefix = format!("{}/", ns);
+        let backends = self
+            .backend_sets
+            .iter()
+            .filter(|(backend, s)| backend.starts_with(&prefix) && s.as_str() == set)
+            .map(|(backend, _)| &backend[prefix.len()..]);
+        // no backend is named "", so this starts at Some(0) exactly when a proxy is
+        // reporting
+        backends.fold(self.players(ns, ""), |total, backend| {
+            Some(total? + self.players(ns, backend)?)
+        })
+    }
 }
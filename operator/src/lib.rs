@@ -2,9 +2,11 @@
 #![allow(unused_imports)]
 
 use std::fmt::{Display, Formatter};
-pub use objects::{minecraft_proxy::MinecraftProxy, minecraft_set::MinecraftSet};
-use thiserror::Error;
-use thiserror::private::DisplayAsDisplay;
+
+pub use objects::{
+    minecraft_fleet::MinecraftFleet, minecraft_proxy::MinecraftProxy, minecraft_set::MinecraftSet,
+};
+use thiserror::{private::DisplayAsDisplay, Error};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -23,14 +25,91 @@ pub enum Error {
     #[error("MyceliumError: {0}")]
     MyceliumError(String),
 
+    /// a request to the API that doesn't make sense, i.e. an unknown history
+    /// window
+    #[error("BadRequest: {0}")]
+    BadRequest(String),
+
+    /// something the API was asked for that doesn't exist, i.e. an unknown
+    /// cache key
+    #[error("NotFound: {0}")]
+    NotFound(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
+
+    #[error("{source} (retrying in {}s)", retry.as_secs())]
+    Backoff {
+        source: Box<Error>,
+        retry: std::time::Duration,
+    },
 }
 
-impl actix_web::error::ResponseError for Error {}
+impl actix_web::error::ResponseError for Error {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            // i.e. a 404 for an unknown set or namespace, but not a 401 or 403, which means the
+            // operator's own ServiceAccount was turned away rather than the caller
+            Error::KubeError(kube::Error::Api(e))
+                if (400..500).contains(&e.code) && e.code != 401 && e.code != 403 =>
+            {
+                StatusCode::from_u16(e.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            Error::Backoff { source, .. } => source.status_code(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub mod helpers;
 /// generated types
 pub mod objects;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::history::parse_window;
+
+    #[test]
+    fn api_errors_map_to_http_statuses() {
+        use actix_web::{http::StatusCode, ResponseError};
+        let kube = |code| {
+            Error::KubeError(kube::Error::Api(kube::error::ErrorResponse {
+                status: "Failure".into(),
+                message: "".into(),
+                reason: "".into(),
+                code,
+            }))
+        };
+        assert_eq!(kube(404).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(kube(409).status_code(), StatusCode::CONFLICT);
+        // the operator's own credentials being refused isn't the caller's fault
+        assert_eq!(kube(403).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(kube(500).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            parse_window("soon").unwrap_err().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            Error::Forbidden("no".into()).status_code(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            Error::MyceliumError("oops".into()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -1,14 +1,25 @@
 use std::env;
 
 use actix_web::{
-    get, middleware,
+    body::SizedStream,
+    delete, get, middleware, post,
     web::{self, Data},
     App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use mycelium::helpers::manager::Manager;
 pub use mycelium::*;
+use mycelium::{
+    helpers::{
+        auth::Scope,
+        history::parse_window,
+        manager::{HeartbeatReport, Manager},
+        tls,
+    },
+    objects::{allocation::AllocationRequest, conversion, StartupTimings},
+};
 use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
 use serde_json::json;
+use tokio_util::io::ReaderStream;
 use tracing::{info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter, Registry};
 
@@ -27,18 +38,195 @@ async fn health(_: HttpRequest) -> impl Responder {
 }
 
 #[get("/state")]
-async fn state(c: Data<Manager>, _req: HttpRequest) -> impl Responder {
+async fn state(c: Data<Manager>, req: HttpRequest) -> actix_web::Result<impl Responder> {
+    c.authorize(&req, Scope::Read, None).await?;
     let state = c.state().await;
-    HttpResponse::Ok().json(&state)
+    Ok(HttpResponse::Ok().json(&state))
+}
+
+#[derive(Deserialize)]
+struct ServersQuery {
+    /// include backends the proxy has never been able to reach
+    all: Option<bool>,
 }
 
 #[get("/servers/{ns}/{name}")]
-async fn servers(c: Data<Manager>, path: web::Path<(String, String)>) -> actix_web::Result<impl Responder> {
+async fn servers(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<ServersQuery>,
+) -> actix_web::Result<impl Responder> {
     let inner = path.into_inner();
-    let vec = c.get_sets(inner.0, inner.1).await?;
+    c.authorize(&req, Scope::Proxy, Some(&inner.0)).await?;
+    let vec = c
+        .get_sets(inner.0, inner.1, query.all.unwrap_or(false))
+        .await?;
     Ok(HttpResponse::Ok().json(json!(vec)))
 }
 
+#[post("/servers/{ns}/{name}/heartbeat")]
+async fn heartbeat(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    report: web::Json<HeartbeatReport>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name) = path.into_inner();
+    c.authorize(&req, Scope::Proxy, Some(&ns)).await?;
+    c.heartbeat(&ns, &name, report.into_inner());
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    /// whitelist-only (true) or open (false)
+    enabled: bool,
+}
+
+#[post("/servers/{ns}/{name}/maintenance")]
+async fn maintenance(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    request: web::Json<MaintenanceRequest>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name) = path.into_inner();
+    c.authorize(&req, Scope::Admin, Some(&ns)).await?;
+    let sets = c.maintenance(ns, name, request.enabled).await?;
+    Ok(HttpResponse::Ok().json(json!({ "sets": sets })))
+}
+
+#[post("/servers/{ns}/{name}/{ordinal}/drain")]
+async fn drain(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String, u32)>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name, ordinal) = path.into_inner();
+    c.authorize(&req, Scope::Admin, Some(&ns)).await?;
+    Ok(HttpResponse::Ok().json(c.drain(ns, name, ordinal, true).await?))
+}
+
+#[delete("/servers/{ns}/{name}/{ordinal}/drain")]
+async fn undrain(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String, u32)>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name, ordinal) = path.into_inner();
+    c.authorize(&req, Scope::Admin, Some(&ns)).await?;
+    Ok(HttpResponse::Ok().json(c.drain(ns, name, ordinal, false).await?))
+}
+
+#[post("/servers/{ns}/{name}/wake")]
+async fn wake(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name) = path.into_inner();
+    c.authorize(&req, Scope::Proxy, Some(&ns)).await?;
+    let asleep = c.wake(ns, name).await?;
+    Ok(HttpResponse::Ok().json(json!({ "asleep": asleep })))
+}
+
+/// players waiting in the login queue of each set in a namespace
+#[get("/queues/{ns}")]
+async fn queues(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let ns = path.into_inner();
+    c.authorize(&req, Scope::Read, Some(&ns)).await?;
+    Ok(HttpResponse::Ok().json(c.queues(&ns)))
+}
+
+/// hands a ready replica of a fleet or set to a matchmaker
+#[post("/allocate")]
+async fn allocate(
+    c: Data<Manager>,
+    req: HttpRequest,
+    request: web::Json<AllocationRequest>,
+) -> actix_web::Result<impl Responder> {
+    c.authorize(&req, Scope::Admin, Some(&request.namespace))
+        .await?;
+    Ok(match c.allocate(request.into_inner()).await? {
+        Some(allocation) => HttpResponse::Ok().json(allocation),
+        None => HttpResponse::Conflict().json(json!({ "error": "no ready replica to allocate" })),
+    })
+}
+
+#[delete("/allocations/{ns}/{pod}")]
+async fn release(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, pod) = path.into_inner();
+    c.authorize(&req, Scope::Admin, Some(&ns)).await?;
+    c.release(ns, pod).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/servers/{ns}/{name}/timings/{kind}")]
+async fn timings(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    timings: web::Json<StartupTimings>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name, kind) = path.into_inner();
+    c.authorize(&req, Scope::Proxy, Some(&ns)).await?;
+    c.startup_timings(ns, name, &kind, timings.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// how far back to go, like 90m, 24h or 7d (default 24h)
+    window: Option<String>,
+}
+
+#[get("/servers/{ns}/{name}/history")]
+async fn history(
+    c: Data<Manager>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HistoryQuery>,
+) -> actix_web::Result<impl Responder> {
+    let (ns, name) = path.into_inner();
+    c.authorize(&req, Scope::Read, Some(&ns)).await?;
+    let window = parse_window(query.window.as_deref().unwrap_or("24h"))?;
+    Ok(HttpResponse::Ok().json(c.history(&ns, &name, window)))
+}
+
+/// converts MinecraftSets and MinecraftProxies between versions for the API
+/// server
+#[post("/convert")]
+async fn convert(review: web::Json<serde_json::Value>) -> impl Responder {
+    HttpResponse::Ok().json(conversion::review(review.into_inner()))
+}
+
+#[get("/cache/{key}/{file}")]
+async fn cache(
+    c: Data<Manager>,
+    path: web::Path<(String, String)>,
+) -> actix_web::Result<impl Responder> {
+    let (key, _file) = path.into_inner();
+    let cache = c
+        .cache()
+        .ok_or_else(|| Error::NotFound("artifact cache is disabled".into()))?;
+    let path = cache.get(&key).await?;
+    let file = tokio::fs::File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    Ok(HttpResponse::Ok()
+        .content_type("application/java-archive")
+        .body(SizedStream::new(size, ReaderStream::new(file))))
+}
+
 #[actix_rt::main]
 async fn main() -> Result<(), Error> {
     // Validate config
@@ -46,8 +234,7 @@ async fn main() -> Result<(), Error> {
     env::var("MYCELIUM_ENDPOINT")?;
 
     #[cfg(feature = "telemetry")]
-    let otlp_endpoint =
-        std::env::var("OPENTELEMETRY_ENDPOINT_URL")?;
+    let otlp_endpoint = std::env::var("OPENTELEMETRY_ENDPOINT_URL")?;
 
     #[cfg(feature = "telemetry")]
     let tracer = opentelemetry_otlp::new_pipeline()
@@ -84,25 +271,73 @@ async fn main() -> Result<(), Error> {
 
     // Start kubernetes controller
     let (manager, set_drainer, proxy_drainer) = Manager::new().await;
+    let scaler = manager.scaler();
 
-    // Start web server
+    // Start web server, over TLS if there's a certificate
+    let listen = manager.listen();
+    let tls_config = tls::server_config()?;
+    let api_manager = manager.clone();
+    let metrics_on_api = listen.metrics.is_none();
     let server = HttpServer::new(move || {
-        App::new()
-            .app_data(Data::new(manager.clone()))
+        let app = App::new()
+            .app_data(Data::new(api_manager.clone()))
             .wrap(middleware::Logger::default().exclude("/health"))
             .service(state)
             .service(servers)
-            .service(health)
-            .service(metrics)
-    })
-    .bind("0.0.0.0:8080")
-    .expect("can't bind to 0.0.0.0:8080")
+            .service(heartbeat)
+            .service(maintenance)
+            .service(drain)
+            .service(undrain)
+            .service(wake)
+            .service(queues)
+            .service(allocate)
+            .service(release)
+            .service(timings)
+            .service(history)
+            .service(convert)
+            .service(cache)
+            .service(health);
+        match metrics_on_api {
+            true => app.service(metrics),
+            false => app,
+        }
+    });
+    let server = match tls_config.clone() {
+        Some(config) => server.bind_rustls(listen.api, config),
+        None => server.bind(listen.api),
+    }
+    .unwrap_or_else(|e| panic!("can't bind to {}: {}", listen.api, e))
     .shutdown_timeout(1);
 
+    // Serve /metrics on its own if there's an address for it
+    let metrics_server = listen.metrics.map(|addr| {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(manager.clone()))
+                .service(health)
+                .service(metrics)
+        });
+        match tls_config {
+            Some(config) => server.bind_rustls(addr, config),
+            None => server.bind(addr),
+        }
+        .unwrap_or_else(|e| panic!("can't bind to {}: {}", addr, e))
+        .shutdown_timeout(1)
+        .run()
+    });
+    let metrics_server = async move {
+        match metrics_server {
+            Some(server) => server.await,
+            None => std::future::pending().await,
+        }
+    };
+
     tokio::select! {
         _ = set_drainer => warn!("set_controller exited"),
         _ = proxy_drainer => warn!("proxy_controller exited"),
         _ = server.run() => info!("actix exited"),
+        r = metrics_server => warn!("metrics server exited: {:?}", r),
+        r = scaler.serve(listen.scaler) => warn!("scaler exited: {:?}", r),
     }
     Ok(())
 }
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, ConfigMapVolumeSource, Volume, VolumeMount},
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::objects::RunnerOptions;
+
+/// where the access lists are mounted in the runner
+pub const ACCESS_PATH: &str = "/access";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct WhitelistOptions {
+    /// usernames or UUIDs allowed to join (entries added in game are kept)
+    pub players: Vec<String>,
+
+    /// kick players that aren't whitelisted when the whitelist is reloaded
+    /// (default = false)
+    pub enforce: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OpEntry {
+    /// username or UUID
+    pub player: String,
+
+    /// permission level from 1 to 4 (default = 4)
+    pub level: Option<u8>,
+
+    /// can join when the server is full (default = false)
+    pub bypasses_player_limit: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct PlayerBan {
+    /// username or UUID
+    pub player: String,
+
+    /// shown to the player when they try to join
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct IpBan {
+    /// IP address
+    pub ip: String,
+
+    /// shown to players when they try to join from it
+    pub reason: Option<String>,
+}
+
+/// fields every ban entry has, the runner fills in `created`
+fn ban_fields(entry: &mut Value, reason: &Option<String>) {
+    entry["source"] = json!("mycelium");
+    entry["expires"] = json!("forever");
+    entry["reason"] = json!(reason
+        .clone()
+        .unwrap_or_else(|| "Banned by an operator.".into()));
+}
+
+/// A whitelist/ops/ban entry for a player, in the format of the server's JSON
+/// files. Players given by name are left without a uuid for the runner to look
+/// up.
+fn player_entry(player: &str) -> Value {
+    let hex: String = player.chars().filter(|c| *c != '-').collect();
+    if hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        let uuid = format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        );
+        json!({ "uuid": uuid.to_lowercase(), "name": "" })
+    } else {
+        json!({ "name": player })
+    }
+}
+
+/// the access list files to write into the server root, by file name
+pub fn access_lists(runner: &RunnerOptions) -> BTreeMap<String, String> {
+    let mut files = BTreeMap::new();
+    if let Some(whitelist) = &runner.whitelist {
+        let entries: Vec<Value> = whitelist.players.iter().map(|p| player_entry(p)).collect();
+        files.insert(
+            "whitelist.json".to_string(),
+            Value::from(entries).to_string(),
+        );
+    }
+    if let Some(ops) = &runner.ops {
+        let entries: Vec<Value> = ops
+            .iter()
+            .map(|op| {
+                let mut entry = player_entry(&op.player);
+                entry["level"] = json!(op.level.unwrap_or(4));
+                entry["bypassesPlayerLimit"] = json!(op.bypasses_player_limit.unwrap_or(false));
+                entry
+            })
+            .collect();
+        files.insert("ops.json".to_string(), Value::from(entries).to_string());
+    }
+    if let Some(bans) = &runner.banned_players {
+        let entries: Vec<Value> = bans
+            .iter()
+            .map(|ban| {
+                let mut entry = player_entry(&ban.player);
+                ban_fields(&mut entry, &ban.reason);
+                entry
+            })
+            .collect();
+        files.insert(
+            "banned-players.json".to_string(),
+            Value::from(entries).to_string(),
+        );
+    }
+    if let Some(bans) = &runner.banned_ips {
+        let entries: Vec<Value> = bans
+            .iter()
+            .map(|ban| {
+                let mut entry = json!({ "ip": ban.ip });
+                ban_fields(&mut entry, &ban.reason);
+                entry
+            })
+            .collect();
+        files.insert(
+            "banned-ips.json".to_string(),
+            Value::from(entries).to_string(),
+        );
+    }
+    files
+}
+
+/// the ConfigMap holding the access lists, which the runner merges into the
+/// server root
+pub fn config_map(
+    name: &str,
+    owner_reference: OwnerReference,
+    runner: &RunnerOptions,
+) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(config_map_name(name)),
+            owner_references: Some(vec![owner_reference]),
+            ..ObjectMeta::default()
+        },
+        data: Some(access_lists(runner)),
+        ..ConfigMap::default()
+    }
+}
+
+pub fn config_map_name(name: &str) -> String {
+    format!("{}-access", name)
+}
+
+/// the volume and mount for the access list ConfigMap
+pub fn volume(name: &str) -> (Volume, VolumeMount) {
+    (
+        Volume {
+            name: "mycelium-access".into(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some(config_map_name(name)),
+                ..ConfigMapVolumeSource::default()
+            }),
+            ..Volume::default()
+        },
+        VolumeMount {
+            name: "mycelium-access".into(),
+            mount_path: ACCESS_PATH.into(),
+            read_only: Some(true),
+            ..VolumeMount::default()
+        },
+    )
+}
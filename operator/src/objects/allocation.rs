@@ -0,0 +1,281 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{ListParams, Patch, PatchParams, ResourceExt},
+    Api, Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    objects::{
+        drain::draining,
+        minecraft_fleet::{ALLOCATED_LABEL, DIRTY_LABEL, FLEET_LABEL},
+        minecraft_set::MinecraftSet,
+        pod_ready,
+    },
+    Error,
+};
+
+/// pod annotation with when an allocation runs out, after which the replica is
+/// released
+pub const ALLOCATED_UNTIL_ANNOTATION: &str = "mycelium.njha.dev/allocated-until";
+
+/// seconds an allocation lasts, if the request doesn't say
+const DEFAULT_TTL_SECONDS: i64 = 3600;
+
+/// how often allocations are checked for having run out
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// a matchmaker asking for a replica, from a fleet or a set
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationRequest {
+    pub namespace: String,
+    pub fleet: Option<String>,
+    pub set: Option<String>,
+    /// seconds until the replica is released, if it isn't before (default 3600)
+    pub ttl_seconds: Option<i64>,
+}
+
+/// the replica a matchmaker was given
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Allocation {
+    pub namespace: String,
+    pub set: String,
+    pub pod: String,
+    pub address: String,
+    pub port: u16,
+    pub expires: DateTime<Utc>,
+}
+
+fn allocated(pod: &Pod) -> bool {
+    pod.labels()
+        .get(ALLOCATED_LABEL)
+        .is_some_and(|a| a == "true")
+}
+
+/// Whether a pod's allocation has run out. An allocation without an expiry
+/// lasts until it is released.
+pub fn expired(pod: &Pod, now: DateTime<Utc>) -> bool {
+    let until = pod.annotations().get(ALLOCATED_UNTIL_ANNOTATION);
+    let until = until.and_then(|u| DateTime::parse_from_rfc3339(u).ok());
+    allocated(pod) && until.is_some_and(|until| until <= now)
+}
+
+/// The pods that may be allocated, in the order to try them: ready, not
+/// draining and not allocated (or allocated until before `now`), lowest ordinal
+/// first.
+pub fn candidates(pods: &[Pod], now: DateTime<Utc>) -> Vec<&Pod> {
+    let mut candidates: Vec<&Pod> = pods
+        .iter()
+        .filter(|pod| pod_ready(pod) && !draining(pod))
+        .filter(|pod| !allocated(pod) || expired(pod, now))
+        .collect();
+    candidates.sort_by_key(|pod| pod.name());
+    candidates
+}
+
+/// The sets to allocate from: the set asked for, or the fleet's servers that
+/// aren't allocated or dirty.
+async fn sets(client: Client, request: &AllocationRequest) -> Result<Vec<MinecraftSet>, Error> {
+    let api = Api::<MinecraftSet>::namespaced(client, &request.namespace);
+    match (&request.fleet, &request.set) {
+        (None, Some(set)) => Ok(vec![api.get(set).await?]),
+        (Some(fleet), None) => {
+            let selector = format!(
+                "{}={},{}!=true,{}!=true",
+                FLEET_LABEL, fleet, ALLOCATED_LABEL, DIRTY_LABEL
+            );
+            Ok(api
+                .list(&ListParams::default().labels(&selector))
+                .await?
+                .items)
+        }
+        _ => Err(Error::BadRequest(
+            "allocate from either a fleet or a set".into(),
+        )),
+    }
+}
+
+/// Allocates a ready replica, None if there is none. The pod is labeled
+/// allocated with a merge patch that fails if it changed since it was listed,
+/// so concurrent allocations never get the same replica. A fleet's server is
+/// labeled allocated too, so the fleet replaces it.
+pub async fn allocate(
+    client: Client,
+    request: &AllocationRequest,
+) -> Result<Option<Allocation>, Error> {
+    let ns = &request.namespace;
+    let ttl = request.ttl_seconds.unwrap_or(DEFAULT_TTL_SECONDS);
+    if ttl <= 0 {
+        return Err(Error::BadRequest("ttlSeconds must be positive".into()));
+    }
+    let pod_api = Api::<Pod>::namespaced(client.clone(), ns);
+    let set_api = Api::<MinecraftSet>::namespaced(client.clone(), ns);
+    for set in sets(client.clone(), request).await? {
+        let name = set.name();
+        let pods = pod_api
+            .list(&ListParams::default().labels(&format!("mycelium.njha.dev/mcset={}", name)))
+            .await?
+            .items;
+        let now = Utc::now();
+        let expires = now + chrono::Duration::seconds(ttl);
+        for pod in candidates(&pods, now) {
+            let patch = json!({ "metadata": {
+                "labels": { ALLOCATED_LABEL: "true" },
+                "annotations": { ALLOCATED_UNTIL_ANNOTATION: expires.to_rfc3339() },
+                "resourceVersion": pod.resource_version(),
+            } });
+            match pod_api
+                .patch(&pod.name(), &PatchParams::default(), &Patch::Merge(patch))
+                .await
+            {
+                Ok(_) => {}
+                // someone else got it first
+                Err(kube::Error::Api(e)) if e.code == 409 => continue,
+                Err(e) => return Err(e.into()),
+            }
+            if request.fleet.is_some() {
+                let patch = json!({ "metadata": { "labels": { ALLOCATED_LABEL: "true" } } });
+                set_api
+                    .patch(&name, &PatchParams::default(), &Patch::Merge(patch))
+                    .await?;
+            }
+            info!("allocated {} in {} until {}", pod.name(), ns, expires);
+            return Ok(Some(Allocation {
+                namespace: ns.clone(),
+                address: format!("{}.{}.{}.svc.cluster.local", pod.name(), name, ns),
+                set: name,
+                pod: pod.name(),
+                port: 25565,
+                expires,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Releases an allocated replica. A fleet's server is single-use, so it is
+/// marked dirty for the fleet to delete, while a set's replica can be allocated
+/// again.
+pub async fn release(client: Client, ns: &str, pod: &str) -> Result<(), Error> {
+    let pod_api = Api::<Pod>::namespaced(client.clone(), ns);
+    let released = pod_api
+        .patch(
+            pod,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "metadata": {
+                "labels": { ALLOCATED_LABEL: null },
+                "annotations": { ALLOCATED_UNTIL_ANNOTATION: null },
+            } })),
+        )
+        .await?;
+    let set = released
+        .labels()
+        .get("mycelium.njha.dev/mcset")
+        .cloned()
+        .unwrap_or_default();
+    let set_api = Api::<MinecraftSet>::namespaced(client, ns);
+    if set_api.get(&set).await?.labels().contains_key(FLEET_LABEL) {
+        let patch = json!({ "metadata": { "labels": { DIRTY_LABEL: "true" } } });
+        set_api
+            .patch(&set, &PatchParams::default(), &Patch::Merge(patch))
+            .await?;
+    }
+    info!("released {} in {}", pod, ns);
+    Ok(())
+}
+
+/// Releases replicas whose allocation ran out, in `namespaces` (or every
+/// namespace if empty), on an interval.
+pub fn spawn_expiry(client: Client, namespaces: Vec<String>) {
+    let apis: Vec<Api<Pod>> = match namespaces.is_empty() {
+        true => vec![Api::all(client.clone())],
+        false => namespaces
+            .iter()
+            .map(|ns| Api::namespaced(client.clone(), ns))
+            .collect(),
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(EXPIRY_INTERVAL).await;
+            for api in &apis {
+                let pods = match api
+                    .list(&ListParams::default().labels(&format!("{}=true", ALLOCATED_LABEL)))
+                    .await
+                {
+                    Ok(pods) => pods.items,
+                    Err(e) => {
+                        warn!("couldn't list allocated replicas: {}", e);
+                        continue;
+                    }
+                };
+                for pod in pods.iter().filter(|pod| expired(pod, Utc::now())) {
+                    let ns = pod.namespace().unwrap_or_default();
+                    if let Err(e) = release(client.clone(), &ns, &pod.name()).await {
+                        warn!("couldn't release {} in {}: {}", pod.name(), ns, e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation_skips_taken_replicas() {
+        let pod =
+            |name: &str, ready: &str, annotations: serde_json::Value, labels: serde_json::Value| {
+                serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(json!({
+                    "metadata": { "name": name, "labels": labels, "annotations": annotations },
+                    "status": { "conditions": [{ "type": "Ready", "status": ready }] },
+                }))
+                .unwrap()
+            };
+        let allocated = json!({ "mycelium.njha.dev/allocated": "true" });
+        let now = chrono::DateTime::parse_from_rfc3339("2022-01-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let pods = vec![
+            pod("game-3", "True", json!({}), json!({})),
+            pod("game-0", "False", json!({}), json!({})),
+            pod(
+                "game-1",
+                "True",
+                json!({ "mycelium.njha.dev/allocated-until": "2022-01-01T13:00:00Z" }),
+                allocated.clone(),
+            ),
+            pod(
+                "game-2",
+                "True",
+                json!({ "mycelium.njha.dev/allocated-until": "2022-01-01T11:00:00Z" }),
+                allocated.clone(),
+            ),
+            pod(
+                "game-4",
+                "True",
+                json!({ "mycelium.njha.dev/drain": "true" }),
+                json!({}),
+            ),
+            pod("game-5", "True", json!({}), allocated),
+        ];
+
+        let names: Vec<String> = candidates(&pods, now)
+            .iter()
+            .map(|p| p.metadata.name.clone().unwrap())
+            .collect();
+        assert_eq!(names, ["game-2", "game-3"]);
+        assert!(expired(&pods[3], now));
+        assert!(!expired(&pods[2], now));
+        // without an expiry, an allocation lasts until it is released
+        assert!(!expired(&pods[5], now));
+    }
+}
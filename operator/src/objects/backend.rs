@@ -0,0 +1,129 @@
+use std::str::FromStr;
+
+use k8s_openapi::{api::core::v1::PodTemplateSpec, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{
+    api::{Patch, PatchParams},
+    core::{ApiResource, DynamicObject, GroupVersionKind},
+    Api, Client,
+};
+use serde_json::json;
+
+use crate::{
+    objects::{ContainerOptions, RunnerOptions},
+    Error,
+    Error::MyceliumError,
+};
+
+/// what runs the replicas of game servers, set with podBackend in the operator
+/// config
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PodBackend {
+    /// StatefulSets and Deployments
+    #[default]
+    Kubernetes,
+    /// Agones Fleets of GameServers, for clusters that already run Agones
+    Agones,
+}
+
+impl FromStr for PodBackend {
+    type Err = Error;
+
+    fn from_str(backend: &str) -> Result<Self, Error> {
+        match backend {
+            "kubernetes" => Ok(PodBackend::Kubernetes),
+            "agones" => Ok(PodBackend::Agones),
+            other => Err(MyceliumError(format!(
+                "unknown podBackend {:?}, use kubernetes or agones",
+                other
+            ))),
+        }
+    }
+}
+
+/// the object generic_reconcile runs a CRD's replicas as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Workload {
+    StatefulSet,
+    Deployment,
+    Fleet,
+}
+
+impl PodBackend {
+    /// What a CRD's replicas run as. Agones only runs game servers that keep
+    /// nothing once they're gone, since GameServers can't have claims, so
+    /// proxies and servers with claims stay StatefulSets either way.
+    pub fn workload(self, runner: &RunnerOptions, container: &ContainerOptions) -> Workload {
+        let fleet = self == PodBackend::Agones
+            && !runner.jar.is_proxy()
+            && container.claim_templates().is_empty();
+        match (fleet, container.stateful.unwrap_or(true)) {
+            (true, _) => Workload::Fleet,
+            (false, true) => Workload::StatefulSet,
+            (false, false) => Workload::Deployment,
+        }
+    }
+}
+
+/// the Agones Fleet, which isn't part of k8s-openapi
+pub fn fleet_api(client: Client, ns: &str) -> Api<DynamicObject> {
+    let gvk = GroupVersionKind::gvk("agones.dev", "v1", "Fleet");
+    Api::namespaced_with(client, ns, &ApiResource::from_gvk(&gvk))
+}
+
+/// The Fleet running `replicas` GameServers of a pod template, with the name,
+/// annotations and owner of the StatefulSet it replaces. Agones gives each one
+/// a host port for the server's port. Its health checks are off since the pod's
+/// readiness probe already covers that, and the runner tells the sidecar when
+/// the server is ready.
+pub fn fleet(
+    meta: &ObjectMeta,
+    replicas: i32,
+    template: &PodTemplateSpec,
+    port: i32,
+) -> serde_json::Value {
+    let container = template
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.containers.first())
+        .map(|c| c.name.clone());
+    json!({
+        "apiVersion": "agones.dev/v1",
+        "kind": "Fleet",
+        "metadata": meta,
+        "spec": {
+            "replicas": replicas,
+            "scheduling": "Packed",
+            "template": {
+                "metadata": { "labels": template.metadata.as_ref().and_then(|m| m.labels.clone()) },
+                "spec": {
+                    "container": container,
+                    "ports": [{
+                        "name": "minecraft",
+                        "portPolicy": "Dynamic",
+                        "containerPort": port,
+                        "protocol": "TCP",
+                    }],
+                    "health": { "disabled": true },
+                    "template": template,
+                },
+            },
+        },
+    })
+}
+
+/// server-side applies the Fleet of a CRD
+pub async fn apply_fleet(
+    client: Client,
+    ns: &str,
+    name: &str,
+    fleet: &serde_json::Value,
+) -> Result<(), Error> {
+    fleet_api(client, ns)
+        .patch(
+            name,
+            &PatchParams::apply("mycelium.njha.dev"),
+            &Patch::Apply(fleet),
+        )
+        .await?;
+    Ok(())
+}
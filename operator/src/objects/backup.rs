@@ -0,0 +1,584 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use k8s_openapi::{
+    api::core::v1::{
+        EnvVar, PersistentVolumeClaim, SecretVolumeSource, TypedLocalObjectReference, Volume,
+        VolumeMount,
+    },
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+};
+use kube::{
+    api::{DeleteParams, ListParams, Patch, PatchParams, PostParams},
+    core::{ApiResource, DynamicObject, GroupVersionKind},
+    Api, Client,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{objects::storage, Error, Error::MyceliumError};
+
+/// annotation to change (i.e. to the current time) to back up every replica now
+pub const BACKUP_ANNOTATION: &str = "mycelium.njha.dev/backup";
+
+/// label with the ordinal of the replica a snapshot was taken of
+pub const ORDINAL_LABEL: &str = "mycelium.njha.dev/ordinal";
+
+/// label with why a snapshot was taken, `scheduled`, `manual` or `final`
+/// (before deletion)
+pub const REASON_LABEL: &str = "mycelium.njha.dev/backup";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupOptions {
+    /// VolumeSnapshotClass to take the snapshots with (the cluster's default if
+    /// unset)
+    pub snapshot_class: Option<String>,
+
+    /// cron schedule (in UTC, i.e. `0 4 * * *`) to snapshot every replica's
+    /// claims on
+    pub schedule: Option<String>,
+
+    /// how many scheduled backups to keep per replica (default = all of them),
+    /// manual ones are never pruned
+    pub retention: Option<u32>,
+
+    /// also have each replica archive its world and upload it here on the
+    /// schedule, or when the runner gets SIGUSR1 (mycelium runner only)
+    pub s3: Option<S3Options>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct S3Options {
+    /// URL of the S3 compatible API, i.e. `https://s3.us-west-2.amazonaws.com`
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    /// region to sign requests for (default = us-east-1)
+    pub region: Option<String>,
+
+    /// prefix of the object keys, which end in `{replica}/{time}.tar.gz`
+    pub prefix: Option<String>,
+
+    /// secret with AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, mounted into
+    /// the runner
+    pub credentials_secret: String,
+
+    /// upload only the files that changed, by sha256, with a manifest per
+    /// backup listing the whole world, instead of a full archive every time
+    pub incremental: Option<bool>,
+}
+
+/// where the S3 credentials are mounted in the runner
+pub const CREDENTIALS_PATH: &str = "/backup-credentials";
+
+/// the runner's env for uploading backups, and the volume and mount for its
+/// credentials
+pub fn s3_runner(options: &BackupOptions) -> Option<(Vec<EnvVar>, Volume, VolumeMount)> {
+    let s3 = options.s3.as_ref()?;
+    let var = |name: &str, value: Option<String>| EnvVar {
+        name: name.into(),
+        value,
+        value_from: None,
+    };
+    let env = vec![
+        var("MYCELIUM_BACKUP_S3_ENDPOINT", Some(s3.endpoint.clone())),
+        var("MYCELIUM_BACKUP_S3_BUCKET", Some(s3.bucket.clone())),
+        var("MYCELIUM_BACKUP_S3_REGION", s3.region.clone()),
+        var("MYCELIUM_BACKUP_S3_PREFIX", s3.prefix.clone()),
+        var("MYCELIUM_BACKUP_CREDENTIALS", Some(CREDENTIALS_PATH.into())),
+        var("MYCELIUM_BACKUP_SCHEDULE", options.schedule.clone()),
+        var(
+            "MYCELIUM_BACKUP_INCREMENTAL",
+            s3.incremental.map(|i| i.to_string()),
+        ),
+    ];
+    let volume = Volume {
+        name: "mycelium-backup-credentials".into(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(s3.credentials_secret.clone()),
+            ..SecretVolumeSource::default()
+        }),
+        ..Volume::default()
+    };
+    let mount = VolumeMount {
+        name: "mycelium-backup-credentials".into(),
+        mount_path: CREDENTIALS_PATH.into(),
+        read_only: Some(true),
+        ..VolumeMount::default()
+    };
+    Some((env, volume, mount))
+}
+
+/// parses a cron expression, with 5 fields or 6 with seconds first
+pub fn parse_schedule(schedule: &str) -> Result<Schedule, Error> {
+    let expression = match schedule.split_whitespace().count() {
+        5 => format!("0 {}", schedule),
+        _ => schedule.to_string(),
+    };
+    Schedule::from_str(&expression)
+        .map_err(|e| MyceliumError(format!("invalid schedule \"{}\": {}", schedule, e)))
+}
+
+/// The most recent run of `schedule` since `last` that is due by `now`. Runs
+/// that were missed (i.e. while the operator was down) are collapsed into that
+/// one.
+pub fn due(schedule: &Schedule, last: DateTime<Utc>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedule.after(&last).take_while(|t| *t <= now).last()
+}
+
+/// label on claims provisioned from a snapshot, with the tag of the snapshot
+pub const RESTORED_LABEL: &str = "mycelium.njha.dev/restored-from";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct RestoreSource {
+    /// tag of the backup to provision missing claims from, i.e.
+    /// `scheduled-20220101040000` (the end of the snapshot names), so each
+    /// replica gets its own claims back
+    pub snapshot: Option<String>,
+
+    /// world archive (.zip or .tar(.gz), i.e. a presigned link to an uploaded
+    /// backup) the runner unpacks before the first start
+    pub url: Option<String>,
+
+    /// incremental backup in backup.s3's bucket the runner downloads the world
+    /// from before the first start, as the key of its manifest under the
+    /// prefix, i.e. `survival-0/manifests/20220101040000.json`
+    pub manifest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct RestoreStatus {
+    /// the snapshot tag or URL being restored from
+    pub source: String,
+
+    /// `Provisioning` claims from snapshots, `Importing` an archive or
+    /// incremental backup, or `Restored`
+    pub phase: String,
+
+    /// how many claims (or replicas, for an archive) are restored out of how
+    /// many
+    pub message: Option<String>,
+}
+
+/// the CSI VolumeSnapshot API, which isn't part of k8s-openapi
+pub fn snapshot_api(client: Client, ns: &str) -> Api<DynamicObject> {
+    let gvk = GroupVersionKind::gvk("snapshot.storage.k8s.io", "v1", "VolumeSnapshot");
+    Api::namespaced_with(client, ns, &ApiResource::from_gvk(&gvk))
+}
+
+/// Takes a VolumeSnapshot of each claim named `{claim}-{tag}` (existing
+/// snapshots are reused, so this is safe to call on every reconcile). Returns
+/// true once every snapshot is ready to use, and an error if any failed.
+pub async fn snapshot_claims(
+    client: Client,
+    ns: &str,
+    claims: &[String],
+    tag: &str,
+    labels: &BTreeMap<String, String>,
+    snapshot_class: Option<&str>,
+) -> Result<bool, Error> {
+    let api = snapshot_api(client, ns);
+    let mut ready = true;
+    for claim in claims {
+        let snapshot_name = format!("{}-{}", claim, tag);
+        let snapshot = match api.get(&snapshot_name).await {
+            Ok(snapshot) => snapshot,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                info!("snapshotting {} as {}", claim, snapshot_name);
+                let snapshot = json!({
+                    "apiVersion": "snapshot.storage.k8s.io/v1",
+                    "kind": "VolumeSnapshot",
+                    "metadata": { "name": snapshot_name, "labels": labels },
+                    "spec": {
+                        "volumeSnapshotClassName": snapshot_class,
+                        "source": { "persistentVolumeClaimName": claim },
+                    },
+                });
+                api.patch(
+                    &snapshot_name,
+                    &PatchParams::apply("mycelium.njha.dev"),
+                    &Patch::Apply(&snapshot),
+                )
+                .await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let status = &snapshot.data["status"];
+        if let Some(message) = status["error"]["message"].as_str() {
+            return Err(MyceliumError(format!(
+                "backup {} failed, refusing to proceed: {}",
+                snapshot_name, message
+            )));
+        }
+        ready &= status["readyToUse"].as_bool().unwrap_or(false);
+    }
+    Ok(ready)
+}
+
+/// Snapshots the claims of replicas `0..replicas`, labelled with the ordinal
+/// and `reason` on top of `labels` so a replica's backups can be found to
+/// restore from. Claims that don't exist (yet) are skipped. Returns true once
+/// every snapshot is ready to use.
+#[allow(clippy::too_many_arguments)]
+pub async fn backup_replicas(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    claim_templates: &[PersistentVolumeClaim],
+    replicas: i32,
+    reason: &str,
+    time: DateTime<Utc>,
+    snapshot_class: Option<&str>,
+) -> Result<bool, Error> {
+    let existing: Vec<String> = Api::<PersistentVolumeClaim>::namespaced(client.clone(), ns)
+        .list(&ListParams::default().labels(&storage::selector_string(labels)))
+        .await?
+        .items
+        .into_iter()
+        .filter_map(|pvc| pvc.metadata.name)
+        .collect();
+    let tag = format!("{}-{}", reason, time.format("%Y%m%d%H%M%S"));
+    let mut ready = true;
+    for ordinal in 0..replicas {
+        let claims: Vec<String> = claim_templates
+            .iter()
+            .map(|tpl| {
+                format!(
+                    "{}-{}-{}",
+                    tpl.metadata.name.clone().unwrap_or_default(),
+                    name,
+                    ordinal
+                )
+            })
+            .filter(|claim| existing.contains(claim))
+            .collect();
+        let mut labels = labels.clone();
+        labels.insert(ORDINAL_LABEL.into(), ordinal.to_string());
+        labels.insert(REASON_LABEL.into(), reason.into());
+        ready &=
+            snapshot_claims(client.clone(), ns, &claims, &tag, &labels, snapshot_class).await?;
+    }
+    Ok(ready)
+}
+
+/// the snapshots taken as backups for `reason`, or for any reason
+async fn backups(
+    client: Client,
+    ns: &str,
+    labels: &BTreeMap<String, String>,
+    reason: Option<&str>,
+) -> Result<Vec<DynamicObject>, Error> {
+    let mut labels = labels.clone();
+    let selector = match reason {
+        Some(reason) => {
+            labels.insert(REASON_LABEL.into(), reason.into());
+            storage::selector_string(&labels)
+        }
+        // snapshots taken before a rollout have no reason, so they're left out
+        None => format!("{},{}", storage::selector_string(&labels), REASON_LABEL),
+    };
+    Ok(snapshot_api(client, ns)
+        .list(&ListParams::default().labels(&selector))
+        .await?
+        .items)
+}
+
+/// when the newest snapshot taken as a backup for `reason` (or any reason) was
+/// created
+pub async fn last_backup(
+    client: Client,
+    ns: &str,
+    labels: &BTreeMap<String, String>,
+    reason: Option<&str>,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    Ok(backups(client, ns, labels, reason)
+        .await?
+        .iter()
+        .filter_map(|s| s.metadata.creation_timestamp.as_ref().map(|t| t.0))
+        .max())
+}
+
+/// The snapshots to delete to keep the newest `keep` runs. Every snapshot of a
+/// run ends in the same timestamp, whichever replica and claim it's of.
+pub fn expired(names: &[String], keep: usize) -> Vec<String> {
+    let run = |name: &str| name.rsplit('-').next().unwrap_or_default().to_string();
+    let mut runs: Vec<String> = names.iter().map(|n| run(n)).collect();
+    runs.sort_unstable_by(|a, b| b.cmp(a));
+    runs.dedup();
+    let kept = &runs[..keep.min(runs.len())];
+    names
+        .iter()
+        .filter(|n| !kept.contains(&run(n)))
+        .cloned()
+        .collect()
+}
+
+/// deletes scheduled backups beyond the newest `keep` runs
+pub async fn prune_backups(
+    client: Client,
+    ns: &str,
+    labels: &BTreeMap<String, String>,
+    keep: usize,
+) -> Result<(), Error> {
+    let names: Vec<String> = backups(client.clone(), ns, labels, Some("scheduled"))
+        .await?
+        .into_iter()
+        .filter_map(|s| s.metadata.name)
+        .collect();
+    let api = snapshot_api(client, ns);
+    for name in expired(&names, keep) {
+        info!("pruning backup {}", name);
+        match api.delete(&name, &DeleteParams::default()).await {
+            Ok(_) => {}
+            Err(kube::Error::Api(e)) if e.code == 404 => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Creates the missing claims of replicas `0..replicas` from the snapshots
+/// tagged `tag` before the StatefulSet would create them empty. Ordinals
+/// without a snapshot are left to the StatefulSet. Returns how many of the
+/// restored claims are bound, and how many there are.
+pub async fn restore_claims(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    claim_templates: &[PersistentVolumeClaim],
+    replicas: i32,
+    tag: &str,
+) -> Result<(usize, usize), Error> {
+    let pvc_api = Api::<PersistentVolumeClaim>::namespaced(client.clone(), ns);
+    let snapshots = snapshot_api(client, ns);
+    for tpl in claim_templates {
+        let tpl_name = tpl.metadata.name.clone().unwrap_or_default();
+        for ordinal in 0..replicas {
+            let claim = format!("{}-{}-{}", tpl_name, name, ordinal);
+            let snapshot = format!("{}-{}", claim, tag);
+            match pvc_api.get(&claim).await {
+                Ok(_) => continue,
+                Err(kube::Error::Api(e)) if e.code == 404 => {}
+                Err(e) => return Err(e.into()),
+            }
+            match snapshots.get(&snapshot).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(e)) if e.code == 404 => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let mut claim_labels = tpl.metadata.labels.clone().unwrap_or_default();
+            claim_labels.extend(labels.clone());
+            claim_labels.insert(RESTORED_LABEL.into(), tag.into());
+            let mut spec = tpl.spec.clone().unwrap_or_default();
+            spec.data_source = Some(TypedLocalObjectReference {
+                api_group: Some("snapshot.storage.k8s.io".into()),
+                kind: "VolumeSnapshot".into(),
+                name: snapshot.clone(),
+            });
+            info!("restoring claim {} from {}", claim, snapshot);
+            let pvc = PersistentVolumeClaim {
+                metadata: ObjectMeta {
+                    name: Some(claim.clone()),
+                    labels: Some(claim_labels),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(spec),
+                ..PersistentVolumeClaim::default()
+            };
+            pvc_api.create(&PostParams::default(), &pvc).await?;
+        }
+    }
+
+    let mut restored = labels.clone();
+    restored.insert(RESTORED_LABEL.into(), tag.into());
+    let claims = pvc_api
+        .list(&ListParams::default().labels(&storage::selector_string(&restored)))
+        .await?
+        .items;
+    let bound = claims
+        .iter()
+        .filter(|c| c.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Bound"))
+        .count();
+    Ok((bound, claims.len()))
+}
+
+/// Whether the claims were already restored from `source`'s snapshot, after
+/// which claims that go missing (i.e. deleted or pruned) are left to the
+/// StatefulSet instead of being restored again.
+pub fn snapshot_restored(status: Option<&RestoreStatus>, source: &RestoreSource) -> bool {
+    status.is_some_and(|s| s.phase == "Restored" && source.snapshot.as_ref() == Some(&s.source))
+}
+
+/// How a restore is going, from the claims restored from snapshots (bound,
+/// total) or the replicas that have started after importing an archive or
+/// incremental backup.
+pub fn restore_status(
+    source: &RestoreSource,
+    claims: Option<(usize, usize)>,
+    ready_replicas: i32,
+    replicas: i32,
+) -> Option<RestoreStatus> {
+    match (source, claims) {
+        (
+            RestoreSource {
+                snapshot: Some(tag),
+                ..
+            },
+            Some((bound, total)),
+        ) => Some(RestoreStatus {
+            source: tag.clone(),
+            phase: if bound == total {
+                "Restored"
+            } else {
+                "Provisioning"
+            }
+            .into(),
+            message: Some(format!("{}/{} claims bound", bound, total)),
+        }),
+        (
+            RestoreSource {
+                url: Some(source), ..
+            },
+            _,
+        )
+        | (
+            RestoreSource {
+                manifest: Some(source),
+                ..
+            },
+            _,
+        ) => Some(RestoreStatus {
+            source: source.clone(),
+            phase: if ready_replicas >= replicas {
+                "Restored"
+            } else {
+                "Importing"
+            }
+            .into(),
+            message: Some(format!("{}/{} replicas started", ready_replicas, replicas)),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::tests::env_value;
+
+    #[test]
+    fn backup_schedule_collapses_missed_runs() {
+        let schedule = parse_schedule("0 4 * * *").unwrap();
+        let at = |s: &str| s.parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        let last = at("2022-01-01T04:00:00Z");
+
+        assert_eq!(due(&schedule, last, at("2022-01-02T03:59:00Z")), None);
+        assert_eq!(
+            due(&schedule, last, at("2022-01-04T12:00:00Z")),
+            Some(at("2022-01-04T04:00:00Z"))
+        );
+        assert!(parse_schedule("every day").is_err());
+    }
+
+    #[test]
+    fn retention_keeps_whole_runs() {
+        let names: Vec<String> = [
+            "root-survival-0-scheduled-20220101040000",
+            "root-survival-1-scheduled-20220101040000",
+            "root-survival-0-scheduled-20220102040000",
+            "root-survival-1-scheduled-20220102040000",
+            "root-survival-0-scheduled-20220103040000",
+        ]
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+
+        assert_eq!(expired(&names, 2), names[..2].to_vec());
+        assert!(expired(&names, 5).is_empty());
+    }
+
+    #[test]
+    fn s3_backups_mount_the_credentials() {
+        let options: BackupOptions = serde_json::from_value(json!({
+            "schedule": "0 4 * * *",
+            "s3": {
+                "endpoint": "https://s3.example.com",
+                "bucket": "worlds",
+                "credentialsSecret": "s3-creds",
+                "incremental": true,
+            },
+        }))
+        .unwrap();
+        let (env, volume, mount) = s3_runner(&options).unwrap();
+
+        assert_eq!(
+            env_value(&env, "MYCELIUM_BACKUP_S3_BUCKET").as_deref(),
+            Some("worlds")
+        );
+        assert_eq!(
+            env_value(&env, "MYCELIUM_BACKUP_SCHEDULE").as_deref(),
+            Some("0 4 * * *")
+        );
+        assert_eq!(
+            env_value(&env, "MYCELIUM_BACKUP_INCREMENTAL").as_deref(),
+            Some("true")
+        );
+        assert_eq!(
+            volume.secret.unwrap().secret_name.as_deref(),
+            Some("s3-creds")
+        );
+        assert_eq!(mount.mount_path, CREDENTIALS_PATH);
+        assert!(s3_runner(&BackupOptions::default()).is_none());
+    }
+
+    #[test]
+    fn restore_status_follows_the_claims_or_replicas() {
+        let snapshot = RestoreSource {
+            snapshot: Some("final-20220101040000".into()),
+            ..RestoreSource::default()
+        };
+        let status = restore_status(&snapshot, Some((1, 2)), 0, 2).unwrap();
+        assert_eq!(status.phase, "Provisioning");
+        assert_eq!(status.message.as_deref(), Some("1/2 claims bound"));
+        assert_eq!(
+            restore_status(&snapshot, Some((2, 2)), 0, 2).unwrap().phase,
+            "Restored"
+        );
+
+        let url = RestoreSource {
+            url: Some("https://example.com/w.tar.gz".into()),
+            ..RestoreSource::default()
+        };
+        assert_eq!(restore_status(&url, None, 1, 2).unwrap().phase, "Importing");
+        assert_eq!(restore_status(&url, None, 2, 2).unwrap().phase, "Restored");
+        let manifest = RestoreSource {
+            manifest: Some("survival-0/manifests/20220101040000.json".into()),
+            ..RestoreSource::default()
+        };
+        let status = restore_status(&manifest, None, 1, 2).unwrap();
+        assert_eq!(status.source, "survival-0/manifests/20220101040000.json");
+        assert_eq!(status.phase, "Importing");
+        assert!(restore_status(&RestoreSource::default(), None, 2, 2).is_none());
+
+        // snapshots are only restored from until every claim was once
+        let restored = restore_status(&snapshot, Some((2, 2)), 0, 2);
+        assert!(snapshot_restored(restored.as_ref(), &snapshot));
+        let provisioning = restore_status(&snapshot, Some((1, 2)), 0, 2);
+        assert!(!snapshot_restored(provisioning.as_ref(), &snapshot));
+        let other = RestoreSource {
+            snapshot: Some("final-20230101040000".into()),
+            ..RestoreSource::default()
+        };
+        assert!(!snapshot_restored(restored.as_ref(), &other));
+        assert!(!snapshot_restored(None, &snapshot));
+    }
+}
@@ -0,0 +1,242 @@
+use k8s_openapi::{
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+        CustomResourceConversion, CustomResourceDefinition, CustomResourceDefinitionVersion,
+        ServiceReference, WebhookClientConfig, WebhookConversion,
+    },
+    ByteString,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{Error, Error::MyceliumError};
+
+/// runner fields that v1beta2 renames, as (v1beta1, v1beta2)
+const RENAMED: [(&str, &str); 2] = [("jar", "server"), ("runnerMode", "mode")];
+
+/// Converts a MinecraftSet or MinecraftProxy to `to` (v1beta1 or v1beta2).
+/// v1beta2 renames runner.jar to runner.server and runner.runnerMode to
+/// runner.mode. Everything else, runner.jvm included, is the same in both, so
+/// objects round trip exactly.
+pub fn convert(mut object: Value, to: &str) -> Result<Value, Error> {
+    let from = object["apiVersion"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let version = |api_version: &str| {
+        api_version
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (from, to_version) = (version(&from), version(to));
+
+    if let Some(runner) = object["spec"]["runner"].as_object_mut() {
+        match (from.as_str(), to_version.as_str()) {
+            ("v1beta1", "v1beta2") => {
+                for (old, new) in RENAMED {
+                    rename(runner, old, new);
+                }
+            }
+            ("v1beta2", "v1beta1") => {
+                for (old, new) in RENAMED {
+                    rename(runner, new, old);
+                }
+            }
+            (from, to) if from == to => {}
+            (from, to) => return Err(MyceliumError(format!("can't convert {} to {}", from, to))),
+        }
+    }
+    object["apiVersion"] = json!(to);
+    Ok(object)
+}
+
+fn rename(map: &mut Map<String, Value>, from: &str, to: &str) {
+    if let Some(value) = map.remove(from) {
+        map.insert(to.to_string(), value);
+    }
+}
+
+/// answers an apiextensions.k8s.io/v1 ConversionReview
+pub fn review(review: Value) -> Value {
+    let request = &review["request"];
+    let to = request["desiredAPIVersion"].as_str().unwrap_or_default();
+    let converted: Result<Vec<Value>, Error> = request["objects"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|object| convert(object, to))
+        .collect();
+    let (objects, result) = match converted {
+        Ok(objects) => (objects, json!({ "status": "Success" })),
+        Err(e) => (
+            vec![],
+            json!({ "status": "Failure", "message": e.to_string() }),
+        ),
+    };
+    json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "ConversionReview",
+        "response": {
+            "uid": request["uid"],
+            "convertedObjects": objects,
+            "result": result,
+        },
+    })
+}
+
+/// the v1beta2 version of a CRD version generated for v1beta1, served but not
+/// stored
+fn v1beta2(
+    v1beta1: &CustomResourceDefinitionVersion,
+) -> Result<CustomResourceDefinitionVersion, Error> {
+    let mut version = serde_json::to_value(v1beta1).map_err(Error::SerializationError)?;
+    version["name"] = json!("v1beta2");
+    version["storage"] = json!(false);
+    let runner =
+        &mut version["schema"]["openAPIV3Schema"]["properties"]["spec"]["properties"]["runner"];
+    if let Some(properties) = runner["properties"].as_object_mut() {
+        for (old, new) in RENAMED {
+            rename(properties, old, new);
+        }
+    }
+    if let Some(required) = runner["required"].as_array_mut() {
+        for field in required.iter_mut() {
+            if let Some((_, new)) = RENAMED.iter().find(|(old, _)| field == old) {
+                *field = json!(new);
+            }
+        }
+    }
+    serde_json::from_value(version).map_err(Error::SerializationError)
+}
+
+/// where the API server reaches the operator's /convert webhook, which it only
+/// calls over HTTPS, so the operator has to serve its API with TLS
+pub struct ConversionWebhook {
+    /// namespace of the mycelium-operator Service
+    pub namespace: String,
+    /// port of the Service's api port
+    pub port: i32,
+    /// PEM of the CA that signed the operator's certificate
+    pub ca_bundle: Option<Vec<u8>>,
+    /// cert-manager Certificate in `namespace` whose CA cert-manager's CA
+    /// injector fills in the caBundle with instead
+    pub certificate: Option<String>,
+}
+
+/// Adds the v1beta2 version to a generated CRD, converted by the operator's
+/// /convert webhook.
+pub fn with_v1beta2(
+    mut crd: CustomResourceDefinition,
+    webhook: &ConversionWebhook,
+) -> Result<CustomResourceDefinition, Error> {
+    let v1beta1 = crd
+        .spec
+        .versions
+        .first()
+        .cloned()
+        .ok_or_else(|| MyceliumError("generated CRD has no versions".into()))?;
+    crd.spec.versions.push(v1beta2(&v1beta1)?);
+    if let Some(certificate) = &webhook.certificate {
+        crd.metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(
+                "cert-manager.io/inject-ca-from".into(),
+                format!("{}/{}", webhook.namespace, certificate),
+            );
+    }
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".into(),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                ca_bundle: webhook.ca_bundle.clone().map(ByteString),
+                service: Some(ServiceReference {
+                    name: "mycelium-operator".into(),
+                    namespace: webhook.namespace.clone(),
+                    path: Some("/convert".into()),
+                    port: Some(webhook.port),
+                }),
+                url: None,
+            }),
+            conversion_review_versions: vec!["v1".into()],
+        }),
+    });
+    Ok(crd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::tests::{basic_spec, mcset};
+
+    #[test]
+    fn v1beta2_round_trips_through_v1beta1() {
+        let mut spec = basic_spec();
+        spec["runner"]["jvm"] = json!(r#"-Xmx2G -Dmotd="a  b""#);
+        let v1beta1 = serde_json::to_value(mcset(spec)).unwrap();
+        let review = review(json!({
+            "request": {
+                "uid": "abc",
+                "desiredAPIVersion": "mycelium.njha.dev/v1beta2",
+                "objects": [v1beta1.clone()],
+            },
+        }));
+        assert_eq!(review["response"]["uid"], "abc");
+        assert_eq!(review["response"]["result"]["status"], "Success");
+        let v1beta2 = review["response"]["convertedObjects"][0].clone();
+        assert_eq!(v1beta2["apiVersion"], "mycelium.njha.dev/v1beta2");
+        assert_eq!(v1beta2["spec"]["runner"]["server"]["build"], "114");
+        assert_eq!(v1beta2["spec"]["runner"]["jvm"], r#"-Xmx2G -Dmotd="a  b""#);
+        assert!(v1beta2["spec"]["runner"].get("jar").is_none());
+
+        let back = convert(v1beta2.clone(), "mycelium.njha.dev/v1beta1").unwrap();
+        assert_eq!(back, v1beta1);
+        let again = convert(back, "mycelium.njha.dev/v1beta2").unwrap();
+        assert_eq!(again, v1beta2);
+    }
+
+    #[test]
+    fn conversion_webhook_uses_the_api_port_and_ca() {
+        use kube::CustomResourceExt;
+        use with_v1beta2;
+        use ConversionWebhook;
+
+        let webhook = ConversionWebhook {
+            namespace: "mycelium".into(),
+            port: 8443,
+            ca_bundle: Some(b"PEM".to_vec()),
+            certificate: None,
+        };
+        let crd = serde_json::to_value(with_v1beta2(crate::MinecraftSet::crd(), &webhook).unwrap())
+            .unwrap();
+        let versions: Vec<_> = crd["spec"]["versions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["name"].clone())
+            .collect();
+        assert_eq!(versions, vec![json!("v1beta1"), json!("v1beta2")]);
+        let client = &crd["spec"]["conversion"]["webhook"]["clientConfig"];
+        assert_eq!(client["service"]["port"], 8443);
+        assert_eq!(client["service"]["namespace"], "mycelium");
+        assert_eq!(client["caBundle"], "UEVN");
+        assert!(crd["metadata"].get("annotations").is_none());
+
+        // cert-manager fills in the CA of the operator's Certificate instead
+        let webhook = ConversionWebhook {
+            ca_bundle: None,
+            certificate: Some("mycelium-tls".into()),
+            ..webhook
+        };
+        let crd = serde_json::to_value(with_v1beta2(crate::MinecraftSet::crd(), &webhook).unwrap())
+            .unwrap();
+        assert!(crd["spec"]["conversion"]["webhook"]["clientConfig"]
+            .get("caBundle")
+            .is_none());
+        assert_eq!(
+            crd["metadata"]["annotations"]["cert-manager.io/inject-ca-from"],
+            "mycelium/mycelium-tls"
+        );
+    }
+}
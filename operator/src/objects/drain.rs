@@ -0,0 +1,184 @@
+use std::sync::{Arc, RwLock};
+
+use k8s_openapi::api::core::v1::{Pod, Secret};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, Client,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    helpers::{rcon, state::State},
+    Error,
+};
+
+/// pod annotation (set to "true") that takes a replica off the proxies' server
+/// lists, until it is removed or the pod is replaced
+pub const DRAIN_ANNOTATION: &str = "mycelium.njha.dev/drain";
+
+/// pod label the PodDisruptionBudget protects pods with while it is "false",
+/// flipped to "true" once a draining replica has no players left
+pub const DESTROYABLE_LABEL: &str = "mycelium.njha.dev/destroyable";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct DrainStatus {
+    /// the draining pod
+    pub pod: String,
+
+    /// players still on it, if they could be counted
+    pub players: Option<u32>,
+
+    /// whether it is empty and may be evicted
+    pub destroyable: bool,
+}
+
+/// whether a pod has the drain annotation
+pub fn draining(pod: &Pod) -> bool {
+    pod.metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(DRAIN_ANNOTATION))
+        .is_some_and(|d| d == "true")
+}
+
+/// the player count in the reply to the `list` console command
+pub fn online_players(list: &str) -> Option<u32> {
+    list.split_whitespace().find_map(|word| word.parse().ok())
+}
+
+/// the set's rcon password, if its Secret exists yet
+async fn rcon_password(client: Client, ns: &str, name: &str) -> Option<String> {
+    let secret = Api::<Secret>::namespaced(client, ns).get(name).await.ok()?;
+    let password = secret.data?.get("rcon_password").cloned()?;
+    Some(String::from_utf8_lossy(&password.0).into_owned())
+}
+
+/// Players on a replica, as the proxies last reported them, or else as the
+/// server itself answers `list` over rcon.
+async fn players(
+    state: &Arc<RwLock<State>>,
+    ns: &str,
+    pod: &Pod,
+    password: Option<&str>,
+) -> Option<u32> {
+    let name = pod.metadata.name.clone().unwrap_or_default();
+    if let Some(players) = state.read().expect("players").players(ns, &name) {
+        return Some(players);
+    }
+    let ip = pod.status.as_ref().and_then(|s| s.pod_ip.as_deref())?;
+    match rcon::run(ip, password?, &["list".to_string()]).await {
+        Ok(output) => output.first().and_then(|list| online_players(list)),
+        Err(e) => {
+            warn!("couldn't count the players on {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Counts the players on a draining pod, and makes it destroyable once there
+/// are none.
+async fn check(
+    client: Client,
+    state: &Arc<RwLock<State>>,
+    ns: &str,
+    pod: &Pod,
+    password: Option<&str>,
+) -> Result<DrainStatus, Error> {
+    let name = pod.metadata.name.clone().unwrap_or_default();
+    let labels = pod.metadata.labels.clone().unwrap_or_default();
+    let mut destroyable = labels.get(DESTROYABLE_LABEL).is_some_and(|d| d == "true");
+    let players = players(state, ns, pod, password).await;
+    if !destroyable && players == Some(0) {
+        Api::<Pod>::namespaced(client, ns)
+            .patch(
+                &name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "metadata": { "labels": { DESTROYABLE_LABEL: "true" } } })),
+            )
+            .await?;
+        info!("{} in {} is drained and may be evicted", name, ns);
+        destroyable = true;
+    }
+    Ok(DrainStatus {
+        pod: name,
+        players,
+        destroyable,
+    })
+}
+
+/// Starts (or with `enabled` false, stops) draining replica `ordinal` of a set,
+/// and reports how far along it is.
+pub async fn drain(
+    client: Client,
+    state: &Arc<RwLock<State>>,
+    ns: &str,
+    name: &str,
+    ordinal: u32,
+    enabled: bool,
+) -> Result<DrainStatus, Error> {
+    let pod_api = Api::<Pod>::namespaced(client.clone(), ns);
+    let pod_name = format!("{}-{}", name, ordinal);
+    let patch = match enabled {
+        true => json!({ "metadata": { "annotations": { DRAIN_ANNOTATION: "true" } } }),
+        false => json!({ "metadata": {
+            "annotations": { DRAIN_ANNOTATION: null },
+            "labels": { DESTROYABLE_LABEL: "false" },
+        } }),
+    };
+    let pod = pod_api
+        .patch(&pod_name, &PatchParams::default(), &Patch::Merge(patch))
+        .await?;
+    if !enabled {
+        return Ok(DrainStatus {
+            pod: pod_name,
+            players: None,
+            destroyable: false,
+        });
+    }
+    let password = rcon_password(client.clone(), ns, name).await;
+    check(client, state, ns, &pod, password.as_deref()).await
+}
+
+/// how each draining replica of a set is getting along, making the empty ones
+/// destroyable
+pub async fn reconcile(
+    client: Client,
+    state: &Arc<RwLock<State>>,
+    ns: &str,
+    name: &str,
+) -> Result<Vec<DrainStatus>, Error> {
+    let pods = Api::<Pod>::namespaced(client.clone(), ns)
+        .list(&ListParams::default().labels(&format!("mycelium.njha.dev/mcset={}", name)))
+        .await?;
+    let draining: Vec<Pod> = pods.items.into_iter().filter(draining).collect();
+    if draining.is_empty() {
+        return Ok(vec![]);
+    }
+    let password = rcon_password(client.clone(), ns, name).await;
+    let mut statuses = vec![];
+    for pod in &draining {
+        statuses.push(check(client.clone(), state, ns, pod, password.as_deref()).await?);
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn online_players_are_read_from_list() {
+        assert_eq!(
+            online_players("There are 3 of a max of 20 players online: Notch"),
+            Some(3)
+        );
+        assert_eq!(
+            online_players("There are 0 out of maximum 20 players online."),
+            Some(0)
+        );
+        assert_eq!(online_players("Unknown command"), None);
+    }
+}
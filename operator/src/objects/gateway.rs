@@ -0,0 +1,102 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{
+    api::{DeleteParams, ListParams, Patch, PatchParams},
+    core::{ApiResource, DynamicObject, GroupVersionKind},
+    Api, Client, Resource, ResourceExt,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    helpers::manager::proxy_selector,
+    objects::{minecraft_proxy::MinecraftProxy, object_to_owner_reference},
+    Error,
+    Error::MyceliumError,
+    MinecraftSet,
+};
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayOptions {
+    /// Gateway to attach the TCPRoute to
+    pub name: String,
+
+    /// namespace of the Gateway (default = the proxy's namespace)
+    pub namespace: Option<String>,
+
+    /// listener of the Gateway to attach to, if not all of them
+    pub section_name: Option<String>,
+}
+
+/// the Gateway API TCPRoute, which isn't part of k8s-openapi
+fn tcproute_api(client: Client, ns: &str) -> Api<DynamicObject> {
+    let gvk = GroupVersionKind::gvk("gateway.networking.k8s.io", "v1alpha2", "TCPRoute");
+    Api::namespaced_with(client, ns, &ApiResource::from_gvk(&gvk))
+}
+
+/// Creates a TCPRoute from the Gateway to the proxy's Service, or deletes it
+/// when the proxy no longer asks for one. TCP routes can't match on hostnames,
+/// so the forced hosts of the sets behind the proxy are put in the external-dns
+/// hostname annotation instead, pointing each of them at the Gateway.
+pub async fn reconcile_route(client: Client, mcproxy: &MinecraftProxy) -> Result<(), Error> {
+    let name = mcproxy.name();
+    let ns = mcproxy
+        .namespace()
+        .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
+    let api = tcproute_api(client.clone(), &ns);
+
+    let gateway = match &mcproxy.spec.gateway {
+        Some(gateway) => gateway,
+        None => {
+            return match api.delete(&name, &DeleteParams::default()).await {
+                Ok(_) => Ok(()),
+                Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+        }
+    };
+
+    let sets = Api::<MinecraftSet>::namespaced(client, &ns)
+        .list(&ListParams::default().labels(&proxy_selector(&mcproxy.spec)))
+        .await?;
+    let mut hostnames: Vec<String> = sets
+        .iter()
+        .filter_map(|set| set.spec.proxy.as_ref().and_then(|p| p.hostname.clone()))
+        .collect();
+    hostnames.sort();
+    hostnames.dedup();
+
+    let owner = OwnerReference {
+        controller: Some(true),
+        ..object_to_owner_reference::<MinecraftProxy>(mcproxy.meta().clone())?
+    };
+    let route = json!({
+        "apiVersion": "gateway.networking.k8s.io/v1alpha2",
+        "kind": "TCPRoute",
+        "metadata": {
+            "name": name,
+            "ownerReferences": [owner],
+            "annotations": (!hostnames.is_empty()).then(|| json!({
+                "external-dns.alpha.kubernetes.io/hostname": hostnames.join(","),
+            })),
+        },
+        "spec": {
+            "parentRefs": [{
+                "name": gateway.name,
+                "namespace": gateway.namespace,
+                "sectionName": gateway.section_name,
+            }],
+            "rules": [{
+                "backendRefs": [{ "name": name, "port": 25565 }],
+            }],
+        },
+    });
+    api.patch(
+        &name,
+        &PatchParams::apply("mycelium.njha.dev"),
+        &Patch::Apply(&route),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crate::{
+    objects::{quota, ContainerOptions, RunnerOptions},
+    Error,
+    Error::MyceliumError,
+};
+
+const MIB: f64 = 1048576.;
+
+/// least memory to leave above the heap, for metaspace, threads and direct
+/// buffers
+const MIN_HEAP_HEADROOM: f64 = 512. * MIB;
+
+/// The -Xmx of some JVM options in bytes, the last one if there are several
+/// since that's the one the JVM goes by.
+pub fn max_heap(jvm: &str) -> Option<f64> {
+    let size = jvm
+        .split_whitespace()
+        .rev()
+        .find_map(|o| o.strip_prefix("-Xmx"))?;
+    let (number, scale) = match size.chars().last()? {
+        'k' | 'K' => (&size[..size.len() - 1], 1024.),
+        'm' | 'M' => (&size[..size.len() - 1], MIB),
+        'g' | 'G' => (&size[..size.len() - 1], 1024. * MIB),
+        't' | 'T' => (&size[..size.len() - 1], 1024. * 1024. * MIB),
+        _ => (size, 1.),
+    };
+    number.parse::<u64>().ok().map(|n| n as f64 * scale)
+}
+
+/// Checks runner.jvm's -Xmx against container.resources.limits.memory. A heap
+/// that doesn't fit in the limit at all is an error, since the pod would be
+/// OOMKilled (or fail the runner's preflight) anyway. Less than a quarter of
+/// the heap (and at least 512Mi) left above it is returned as a warning, since
+/// that's what usually gets OOMKilled under load.
+pub fn check(
+    runner: &RunnerOptions,
+    container: &ContainerOptions,
+) -> Result<Option<String>, Error> {
+    let heap = match runner.jvm.as_deref().and_then(max_heap) {
+        Some(heap) => heap,
+        None => return Ok(None),
+    };
+    let limit = container
+        .resources
+        .as_ref()
+        .and_then(|r| r.limits.as_ref()?.get("memory"))
+        .and_then(quota::parse);
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(None),
+    };
+    let (heap_mib, limit_mib) = ((heap / MIB).round(), (limit / MIB).round());
+    if heap >= limit {
+        return Err(MyceliumError(format!(
+            "runner.jvm sets -Xmx to {}Mi but container.resources.limits.memory is only {}Mi, so \
+             the server would be OOMKilled",
+            heap_mib, limit_mib
+        )));
+    }
+    let headroom = (heap / 4.).max(MIN_HEAP_HEADROOM);
+    Ok((limit - heap < headroom).then(|| {
+        format!(
+            "-Xmx is {}Mi of a {}Mi memory limit, leave at least {}Mi above the heap for the rest \
+             of the JVM or the server may be OOMKilled",
+            heap_mib,
+            limit_mib,
+            (headroom / MIB).ceil()
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::objects::tests::{basic_spec, mcset};
+
+    #[test]
+    fn heaps_must_fit_their_memory_limit() {
+        assert_eq!(max_heap("-Xms1G -Xmx2G"), Some(2147483648.));
+        assert_eq!(max_heap("-Xmx512m -Dfoo=bar -Xmx3g"), Some(3221225472.));
+        assert_eq!(max_heap("-Dfoo=bar"), None);
+
+        let with_limit = |memory: &str| {
+            let mut spec = basic_spec();
+            spec["container"] = json!({ "resources": { "limits": { "memory": memory } } });
+            mcset(spec)
+        };
+        // no limit, nothing to check
+        let set = mcset(basic_spec());
+        assert_eq!(
+            check(
+                &set.spec.runner,
+                &set.spec.container.clone().unwrap_or_default()
+            )
+            .unwrap(),
+            None
+        );
+        // 2Gi of heap in 3Gi leaves plenty
+        let set = with_limit("3Gi");
+        assert_eq!(
+            check(&set.spec.runner, set.spec.container.as_ref().unwrap()).unwrap(),
+            None
+        );
+        // 2Gi of heap in 2.25Gi leaves less than the 512Mi floor
+        let set = with_limit("2304Mi");
+        let warning = check(&set.spec.runner, set.spec.container.as_ref().unwrap()).unwrap();
+        assert!(warning.unwrap().contains("leave at least 512Mi"));
+        // a heap as big as the limit is rejected
+        let set = with_limit("2G");
+        assert!(check(&set.spec.runner, set.spec.container.as_ref().unwrap()).is_err());
+    }
+}
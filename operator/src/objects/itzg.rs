@@ -0,0 +1,125 @@
+use k8s_openapi::api::core::v1::{EnvVar, EnvVarSource, SecretKeySelector};
+
+use crate::objects::{plugins::PLUGIN_CONFIGS_PATH, RunnerOptions};
+
+fn var(name: &str, value: &str) -> EnvVar {
+    EnvVar {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        value_from: None,
+    }
+}
+
+/// Translates the runner options into the env contract of the
+/// itzg/docker-minecraft-server (and itzg/mc-proxy) images. `plugins` is the
+/// comma separated plugin URL list the mycelium runner would have received, and
+/// `mods` the same for mods.
+pub fn itzg_env(
+    name: &str,
+    runner: &RunnerOptions,
+    plugins: Option<String>,
+    mods: Option<String>,
+) -> Vec<EnvVar> {
+    let jar = &runner.jar;
+    let mut env = match jar.r#type.as_str() {
+        "velocity" => vec![
+            var("TYPE", "VELOCITY"),
+            var("VELOCITY_VERSION", &jar.version),
+            var("VELOCITY_BUILD_ID", &jar.build),
+        ],
+        "waterfall" => vec![
+            var("TYPE", "WATERFALL"),
+            var("WATERFALL_VERSION", &jar.version),
+            var("WATERFALL_BUILD_ID", &jar.build),
+        ],
+        "custom" => vec![
+            var("EULA", "TRUE"),
+            var("TYPE", "CUSTOM"),
+            var("CUSTOM_SERVER", jar.url.as_deref().unwrap_or_default()),
+            var("COPY_CONFIG_DEST", "/data"),
+        ],
+        kind => vec![
+            var("EULA", "TRUE"),
+            var("TYPE", &kind.to_uppercase()),
+            var("VERSION", &jar.version),
+            var("PAPER_BUILD", &jar.build),
+            // mycelium copies /config into the server root, match that
+            var("COPY_CONFIG_DEST", "/data"),
+        ],
+    };
+    if let Some(jvm) = &runner.jvm {
+        env.push(var("JVM_OPTS", jvm));
+    }
+    if let Some(motd) = &runner.motd {
+        env.push(var("MOTD", motd));
+    }
+    if let Some(max_players) = runner.max_players {
+        env.push(var("MAX_PLAYERS", &max_players.to_string()));
+    }
+    if let Some(plugins) = plugins.filter(|p| !p.is_empty()) {
+        env.push(var("PLUGINS", &plugins));
+    }
+    if runner
+        .plugin_configs
+        .as_ref()
+        .is_some_and(|c| !c.is_empty())
+    {
+        // the image syncs this directory into /data/plugins before starting
+        env.push(var("COPY_PLUGINS_SRC", PLUGIN_CONFIGS_PATH));
+    }
+    if let Some(mods) = mods.filter(|m| !m.is_empty()) {
+        env.push(var("MODS", &mods));
+    }
+    if let Some(world) = runner.world_url().filter(|_| !jar.is_proxy()) {
+        // the image only unpacks a WORLD archive when there's no world yet, like the
+        // runner
+        env.push(var("WORLD", world));
+    }
+    if let Some(whitelist) = &runner.whitelist {
+        env.push(var("WHITELIST", &whitelist.players.join(",")));
+        env.push(var(
+            "ENFORCE_WHITELIST",
+            &whitelist
+                .enforce
+                .unwrap_or(false)
+                .to_string()
+                .to_uppercase(),
+        ));
+    }
+    if let Some(ops) = &runner.ops {
+        let players: Vec<&str> = ops.iter().map(|op| op.player.as_str()).collect();
+        env.push(var("OPS", &players.join(",")));
+    }
+
+    // the operator sends console commands over rcon, which the image enables by
+    // default
+    if !jar.is_proxy() {
+        env.push(secret_var("RCON_PASSWORD", name, "rcon_password"));
+    }
+
+    // the image doesn't know about velocity forwarding, so expose the secret
+    // for ${CFG_MYCELIUM_FW_TOKEN} substitution in mounted config files
+    env.push(var("REPLACE_ENV_VARIABLES", "TRUE"));
+    env.push(secret_var(
+        "CFG_MYCELIUM_FW_TOKEN",
+        name,
+        "forwarding_token",
+    ));
+    env
+}
+
+/// an env var read from a key of the CRD's Secret
+fn secret_var(name: &str, secret: &str, key: &str) -> EnvVar {
+    EnvVar {
+        name: name.to_string(),
+        value: None,
+        value_from: Some(EnvVarSource {
+            secret_key_ref: Some(SecretKeySelector {
+                key: key.to_string(),
+                name: Some(secret.to_string()),
+                optional: Some(false),
+            }),
+            ..EnvVarSource::default()
+        }),
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams, ResourceExt},
+    CustomResource, Resource,
+};
+use kube_runtime::controller::{Context, ReconcilerAction};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    helpers::manager::Data,
+    objects::{
+        minecraft_set::{MinecraftSet, MinecraftSetSpec},
+        object_to_owner_reference,
+    },
+    Error,
+    Error::MyceliumError,
+    Result,
+};
+
+/// label on the MinecraftSets of a fleet, set to the fleet's name
+pub const FLEET_LABEL: &str = "mycelium.njha.dev/fleet";
+
+/// label (set to "true") on a fleet's MinecraftSet that is in use, which the
+/// fleet replaces with a new spare
+pub const ALLOCATED_LABEL: &str = "mycelium.njha.dev/allocated";
+
+/// label (set to "true") on a fleet's MinecraftSet that is done being used,
+/// which the fleet deletes
+pub const DIRTY_LABEL: &str = "mycelium.njha.dev/dirty";
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[kube(
+    group = "mycelium.njha.dev",
+    version = "v1beta1",
+    kind = "MinecraftFleet"
+)]
+#[kube(shortname = "mcfleet", namespaced, status = "MinecraftFleetStatus")]
+#[kube(
+    printcolumn = r#"{"name":"Ready", "type":"integer", "description":"ready spares", "jsonPath":".status.readyServers"}"#
+)]
+#[kube(
+    printcolumn = r#"{"name":"Allocated", "type":"integer", "description":"servers in use", "jsonPath":".status.allocatedServers"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct MinecraftFleetSpec {
+    /// spares to keep on hand, servers that aren't allocated (or dirty) yet
+    pub spares: i32,
+
+    /// most servers the fleet has at once, allocated or not
+    pub max_servers: Option<i32>,
+
+    /// labels for the servers' MinecraftSets, i.e. for a proxy to select them
+    pub labels: Option<BTreeMap<String, String>>,
+
+    /// MinecraftSet each server is made from, a new one (with new claims, so a
+    /// fresh world) every time
+    pub template: MinecraftSetSpec,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MinecraftFleetStatus {
+    /// servers the fleet has, allocated or not
+    pub servers: i32,
+
+    /// spares whose replicas are all ready
+    pub ready_servers: i32,
+
+    /// servers that are allocated
+    pub allocated_servers: i32,
+}
+
+/// what the fleet controller needs to know about one of its servers
+#[derive(Debug, Clone, PartialEq)]
+pub struct FleetServer {
+    pub name: String,
+    pub ready: bool,
+    pub allocated: bool,
+    pub dirty: bool,
+    pub created: Option<DateTime<Utc>>,
+}
+
+impl FleetServer {
+    fn of(set: &MinecraftSet) -> FleetServer {
+        let label = |key: &str| set.labels().get(key).is_some_and(|v| v == "true");
+        let ready = set
+            .status
+            .as_ref()
+            .and_then(|s| s.ready_replicas)
+            .unwrap_or(0);
+        FleetServer {
+            name: set.name(),
+            ready: ready >= set.spec.replicas,
+            allocated: label(ALLOCATED_LABEL),
+            dirty: label(DIRTY_LABEL),
+            created: set.metadata.creation_timestamp.as_ref().map(|t| t.0),
+        }
+    }
+
+    fn spare(&self) -> bool {
+        !self.allocated && !self.dirty
+    }
+}
+
+/// servers to create and delete to get the fleet to its spares
+#[derive(Debug, Default, PartialEq)]
+pub struct FleetPlan {
+    pub create: i32,
+    pub delete: Vec<String>,
+}
+
+/// Plans the servers of a fleet. Dirty servers are deleted, and spares are made
+/// up for or let go of (unready and newest first) to match `spares`, without
+/// going over `max_servers`.
+pub fn plan(spec: &MinecraftFleetSpec, servers: &[FleetServer]) -> FleetPlan {
+    let mut delete: Vec<String> = servers
+        .iter()
+        .filter(|s| s.dirty)
+        .map(|s| s.name.clone())
+        .collect();
+    let mut spares: Vec<&FleetServer> = servers.iter().filter(|s| s.spare()).collect();
+    let kept = servers.len() - delete.len();
+    let wanted = spec.spares.max(0) as usize;
+    if spares.len() > wanted {
+        spares.sort_by_key(|s| (s.ready, std::cmp::Reverse(s.created)));
+        delete.extend(
+            spares[..spares.len() - wanted]
+                .iter()
+                .map(|s| s.name.clone()),
+        );
+        return FleetPlan { create: 0, delete };
+    }
+    let room = spec
+        .max_servers
+        .map_or(usize::MAX, |max| (max.max(0) as usize).saturating_sub(kept));
+    FleetPlan {
+        create: (wanted - spares.len()).min(room) as i32,
+        delete,
+    }
+}
+
+/// the MinecraftSet of a new server of the fleet, named by the API server
+fn server(fleet: &MinecraftFleet) -> Result<MinecraftSet> {
+    let name = fleet.name();
+    let mut labels = fleet.spec.labels.clone().unwrap_or_default();
+    labels.insert(FLEET_LABEL.into(), name.clone());
+    Ok(MinecraftSet {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", name)),
+            namespace: fleet.namespace(),
+            labels: Some(labels),
+            owner_references: Some(vec![object_to_owner_reference::<MinecraftFleet>(
+                fleet.metadata.clone(),
+            )?]),
+            ..ObjectMeta::default()
+        },
+        spec: fleet.spec.template.clone(),
+        status: None,
+    })
+}
+
+pub async fn reconcile(
+    fleet: MinecraftFleet,
+    ctx: Context<Data>,
+) -> Result<ReconcilerAction, Error> {
+    let name = fleet.name();
+    let ns = fleet
+        .namespace()
+        .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
+    let api = Api::<MinecraftSet>::namespaced(ctx.get_ref().client.clone(), &ns);
+
+    let sets = api
+        .list(&ListParams::default().labels(&format!("{}={}", FLEET_LABEL, name)))
+        .await?
+        .items;
+    // servers that are already going away don't count
+    let servers: Vec<FleetServer> = sets
+        .iter()
+        .filter(|set| set.meta().deletion_timestamp.is_none())
+        .map(FleetServer::of)
+        .collect();
+    let plan = plan(&fleet.spec, &servers);
+    for server in &plan.delete {
+        info!(
+            "deleting server {} of MinecraftFleet \"{}\" in {}",
+            server, name, ns
+        );
+        api.delete(server, &DeleteParams::default()).await?;
+    }
+    for _ in 0..plan.create {
+        let created = api.create(&PostParams::default(), &server(&fleet)?).await?;
+        info!(
+            "created server {} of MinecraftFleet \"{}\" in {}",
+            created.name(),
+            name,
+            ns
+        );
+    }
+
+    let kept: Vec<&FleetServer> = servers
+        .iter()
+        .filter(|s| !plan.delete.contains(&s.name))
+        .collect();
+    let status = MinecraftFleetStatus {
+        servers: kept.len() as i32 + plan.create,
+        ready_servers: kept.iter().filter(|s| s.spare() && s.ready).count() as i32,
+        allocated_servers: kept.iter().filter(|s| s.allocated).count() as i32,
+    };
+    Api::<MinecraftFleet>::namespaced(ctx.get_ref().client.clone(), &ns)
+        .patch_status(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
+    info!("Reconciled MinecraftFleet \"{}\" in {}", name, ns);
+
+    // the servers are owned, so changes to them bring the fleet back
+    Ok(ReconcilerAction {
+        requeue_after: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::tests::basic_spec;
+
+    #[test]
+    fn fleets_keep_their_spares() {
+        let at = |t: &str| {
+            chrono::DateTime::parse_from_rfc3339(t)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let server =
+            |name: &str, ready: bool, allocated: bool, dirty: bool, created: &str| FleetServer {
+                name: name.into(),
+                ready,
+                allocated,
+                dirty,
+                created: Some(at(created)),
+            };
+        let mut spec: MinecraftFleetSpec =
+            serde_json::from_value(json!({ "spares": 2, "template": basic_spec() })).unwrap();
+
+        // allocated servers are replaced, dirty ones deleted and replaced
+        let servers = vec![
+            server("a", true, true, false, "2022-01-01T00:00:00Z"),
+            server("b", true, false, false, "2022-01-01T00:00:00Z"),
+            server("c", true, true, true, "2022-01-01T00:00:00Z"),
+        ];
+        assert_eq!(plan(&spec, &servers), FleetPlan {
+            create: 1,
+            delete: vec!["c".into()]
+        });
+
+        // maxServers counts the allocated ones too
+        spec.max_servers = Some(2);
+        assert_eq!(plan(&spec, &servers), FleetPlan {
+            create: 0,
+            delete: vec!["c".into()]
+        });
+
+        // surplus spares go unready and newest first
+        spec.max_servers = None;
+        spec.spares = 1;
+        let servers = vec![
+            server("a", true, false, false, "2022-01-01T00:00:00Z"),
+            server("b", true, false, false, "2022-01-02T00:00:00Z"),
+            server("c", false, false, false, "2022-01-01T00:00:00Z"),
+        ];
+        assert_eq!(plan(&spec, &servers), FleetPlan {
+            create: 0,
+            delete: vec!["c".into(), "b".into()]
+        });
+    }
+}
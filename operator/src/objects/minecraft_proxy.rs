@@ -17,11 +17,10 @@ use k8s_openapi::{
         },
     },
     apimachinery::pkg::{
-        apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference},
+        apis::meta::v1::{Condition, LabelSelector, ObjectMeta, OwnerReference},
         util::intstr::IntOrString,
     },
 };
-use k8s_openapi::api::core::v1::{EnvVarSource, ObjectFieldSelector};
 use kube::{
     api::{Api, ListParams, Patch, PatchParams, ResourceExt},
     client::Client,
@@ -43,14 +42,22 @@ use tokio::{
 use tracing::{debug, error, event, field, info, instrument, trace, warn, Level, Span};
 
 use crate::{
-    helpers::{jarapi::get_download_url, manager::Data, telemetry},
+    helpers::{
+        jarapi::{self, get_download_url},
+        manager::Data,
+        telemetry,
+    },
     objects::{
-        generic_reconcile, make_volume, make_volume_mount, ConfigOptions, ContainerOptions,
-        RunnerOptions,
+        gateway::{self, GatewayOptions},
+        generic_reconcile, make_volume, make_volume_mount, observe, paused,
+        plugins::{self, PinnedPlugin},
+        quota, workload_status, ConfigOptions, ContainerOptions, PluginSource, References,
+        ResolvedBuild, RunnerOptions, StartupTimings,
     },
-    Error, Result,
+    Error,
+    Error::MyceliumError,
+    Result,
 };
-use crate::Error::MyceliumError;
 
 #[derive(CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
 #[kube(
@@ -59,7 +66,11 @@ use crate::Error::MyceliumError;
     kind = "MinecraftProxy",
     plural = "minecraftproxies"
 )]
-#[kube(shortname = "mcproxy", namespaced)]
+#[kube(shortname = "mcproxy", namespaced, status = "MinecraftProxyStatus")]
+#[kube(
+    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas", "labelSelectorPath":".status.selector"}"#
+)]
+#[serde(rename_all = "camelCase")]
 pub struct MinecraftProxySpec {
     /// number of identical proxies to create
     pub replicas: i32,
@@ -72,6 +83,156 @@ pub struct MinecraftProxySpec {
 
     /// what MinecraftSets to add to this proxy (only matchLabels is supported)
     pub selector: Option<LabelSelector>,
+
+    /// connection throttling, for basic bot-attack mitigation
+    pub throttle: Option<ThrottleOptions>,
+
+    /// how players joining or kicked from a server are placed: `priority`
+    /// (default) uses the try list order, `leastLoaded` picks the server
+    /// with the fewest players, `random` picks any server in the try list
+    pub fallback_strategy: Option<String>,
+
+    /// accept clients on other Minecraft versions by installing the Via*
+    /// plugins
+    pub multi_protocol: Option<MultiProtocolOptions>,
+
+    /// expose the proxy through a Gateway API Gateway with a generated TCPRoute
+    pub gateway: Option<GatewayOptions>,
+}
+
+impl MinecraftProxySpec {
+    /// configmaps and secrets the proxy mounts
+    pub fn references(&self) -> References {
+        References::of(&self.runner, self.container.as_ref())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MultiProtocolOptions {
+    /// install ViaVersion, for clients newer than the servers
+    pub via_version: Option<bool>,
+
+    /// install ViaBackwards, for clients older than the servers (requires
+    /// viaVersion)
+    pub via_backwards: Option<bool>,
+
+    /// install ViaRewind, for 1.7 and 1.8 clients (requires viaBackwards)
+    pub via_rewind: Option<bool>,
+}
+
+impl MultiProtocolOptions {
+    /// URLs of the plugins to install, overridable with MYCELIUM_PLUGIN_VIA* so
+    /// that the operator can roll every proxy forward when a new Minecraft
+    /// version comes out
+    fn plugins(&self) -> Result<Vec<String>, Error> {
+        let via_version = self.via_version.unwrap_or(false);
+        let via_backwards = self.via_backwards.unwrap_or(false);
+        let via_rewind = self.via_rewind.unwrap_or(false);
+        if (via_backwards && !via_version) || (via_rewind && !via_backwards) {
+            return Err(MyceliumError(
+                "multiProtocol: viaRewind requires viaBackwards, which requires viaVersion".into(),
+            ));
+        }
+        Ok([
+            (
+                via_version,
+                "MYCELIUM_PLUGIN_VIAVERSION",
+                "ViaVersion/ViaVersion/releases/download/4.1.1/ViaVersion-4.1.1.jar",
+            ),
+            (
+                via_backwards,
+                "MYCELIUM_PLUGIN_VIABACKWARDS",
+                "ViaVersion/ViaBackwards/releases/download/4.1.1/ViaBackwards-4.1.1.jar",
+            ),
+            (
+                via_rewind,
+                "MYCELIUM_PLUGIN_VIAREWIND",
+                "ViaVersion/ViaRewind/releases/download/2.0.2/ViaRewind-2.0.2.jar",
+            ),
+        ]
+        .into_iter()
+        .filter(|(enabled, ..)| *enabled)
+        .map(|(_, var, default)| {
+            env::var(var).unwrap_or_else(|_| format!("https://github.com/{}", default))
+        })
+        .collect())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MinecraftProxyStatus {
+    /// replicas of the StatefulSet or Deployment
+    pub replicas: Option<i32>,
+
+    /// replicas that are ready to accept players
+    pub ready_replicas: Option<i32>,
+
+    /// label selector of the pods, for the scale subresource
+    pub selector: Option<String>,
+
+    /// build picked for a jar that omits it
+    pub resolved_build: Option<ResolvedBuild>,
+
+    /// plugins resolved to the artifacts every replica downloads
+    pub pinned_plugins: Option<Vec<PinnedPlugin>>,
+
+    /// value of the refresh-plugins annotation the pins were made with
+    pub plugins_refresh: Option<String>,
+
+    /// how long the most recently started replica took to start
+    pub startup_timings: Option<StartupTimings>,
+
+    /// the QuotaExceeded condition
+    pub conditions: Option<Vec<Condition>>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleOptions {
+    /// minimum milliseconds between logins from the same IP (velocity
+    /// login-ratelimit)
+    pub login_ratelimit_ms: Option<u32>,
+
+    /// milliseconds to wait for a connection to a backend (velocity
+    /// connection-timeout)
+    pub connection_timeout_ms: Option<u32>,
+
+    /// milliseconds a connection may go without sending data (velocity
+    /// read-timeout)
+    pub read_timeout_ms: Option<u32>,
+
+    /// maximum players connected from a single IP, enforced by the mycelium
+    /// plugin
+    pub max_connections_per_ip: Option<u32>,
+}
+
+impl ThrottleOptions {
+    /// runner env for the settings that are set
+    fn env(&self) -> Vec<EnvVar> {
+        [
+            ("MYCELIUM_PROXY_LOGIN_RATELIMIT", self.login_ratelimit_ms),
+            (
+                "MYCELIUM_PROXY_CONNECTION_TIMEOUT",
+                self.connection_timeout_ms,
+            ),
+            ("MYCELIUM_PROXY_READ_TIMEOUT", self.read_timeout_ms),
+            (
+                "MYCELIUM_MAX_CONNECTIONS_PER_IP",
+                self.max_connections_per_ip,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, setting)| {
+            setting.map(|v| EnvVar {
+                name: String::from(name),
+                value: Some(v.to_string()),
+                value_from: None,
+            })
+        })
+        .collect()
+    }
 }
 
 #[instrument(skip(ctx), fields(trace_id))]
@@ -86,16 +247,78 @@ pub async fn reconcile(
     let name = ResourceExt::name(&mcproxy);
     let ns = ResourceExt::namespace(&mcproxy)
         .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
+    let workload = ctx.get_ref().workload(
+        &mcproxy.spec.runner,
+        &mcproxy.spec.container.clone().unwrap_or_default(),
+    );
+    if paused(&mcproxy) {
+        return observe::<MinecraftProxy>(ctx.get_ref().client.clone(), &ns, &name, workload).await;
+    }
 
+    // waterfall gets the bungeecord builds of the plugins
+    let platform = match mcproxy.spec.runner.jar.r#type.as_str() {
+        "waterfall" => "WATERFALL",
+        _ => "VELOCITY",
+    };
     let mut plugin = vec![];
-    if let Ok(p) = env::var("MYCELIUM_PLUGIN_VELOCITY") {
+    if let Ok(p) = env::var(format!("MYCELIUM_PLUGIN_{}", platform)) {
         plugin.push(p)
     }
-    if let Ok(p) = env::var("METRICS_PLUGIN_VELOCITY") {
+    if let Ok(p) = env::var(format!("METRICS_PLUGIN_{}", platform)) {
         plugin.push(p)
     }
+    if let Some(multi_protocol) = &mcproxy.spec.multi_protocol {
+        plugin.extend(multi_protocol.plugins()?);
+    }
+    let previous = mcproxy.status.clone().unwrap_or_default();
+    let refresh = mcproxy
+        .annotations()
+        .get(plugins::REFRESH_ANNOTATION)
+        .cloned();
+    let (spec_plugins, pinned_plugins) = plugins::pin(
+        mcproxy
+            .spec
+            .runner
+            .plugins
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(PluginSource::from)
+            .collect(),
+        platform,
+        previous.pinned_plugins.as_deref().unwrap_or_default(),
+        refresh != previous.plugins_refresh,
+    )
+    .await?;
+    let plugins: Vec<PluginSource> = spec_plugins
+        .into_iter()
+        .chain(plugin.into_iter().map(PluginSource::from))
+        .collect();
 
-    generic_reconcile(
+    let resources = mcproxy
+        .spec
+        .container
+        .as_ref()
+        .and_then(|c| c.resources.as_ref());
+    let (replicas, over_quota) = quota::enforce(
+        ctx.get_ref(),
+        &ns,
+        "mcproxy",
+        &name,
+        mcproxy.spec.replicas,
+        resources,
+    )
+    .await?;
+    if let Some(message) = &over_quota {
+        warn!("MinecraftProxy \"{}\" in {}: {}", name, ns, message);
+    }
+
+    let mut runner = mcproxy.spec.runner.clone();
+    let auto_update = runner.jar.auto_update.unwrap_or(false);
+    let resolved_build =
+        jarapi::resolve_build(&mut runner.jar, previous.resolved_build.as_ref()).await?;
+    let download = jarapi::resolve(&runner.jar, resolved_build.as_ref()).await?;
+    let action = generic_reconcile(
         vec![
             EnvVar {
                 name: String::from("MYCELIUM_RUNNER_KIND"),
@@ -104,47 +327,64 @@ pub async fn reconcile(
             },
             EnvVar {
                 name: String::from("MYCELIUM_PLUGINS"),
-                value: Some(mcproxy
-                    .spec
-                    .runner
-                    .plugins
-                    .clone()
-                    .unwrap_or_default()
-                    .into_iter()
-                    .chain(plugin.into_iter())
-                    .collect::<Vec<String>>().join(",")),
+                value: Some(
+                    serde_json::to_string(
+                        &plugins
+                            .into_iter()
+                            .map(|p| PluginSource {
+                                url: ctx.get_ref().artifact_url(&p.url),
+                                ..p
+                            })
+                            .collect::<Vec<PluginSource>>(),
+                    )
+                    .map_err(Error::SerializationError)?,
+                ),
                 value_from: None,
             },
             EnvVar {
-                name: String::from("MYCELIUM_ENDPOINT"),
-                value: Some(env::var("MYCELIUM_ENDPOINT").unwrap()),
+                name: String::from("MYCELIUM_FALLBACK_STRATEGY"),
+                value: mcproxy.spec.fallback_strategy.clone(),
                 value_from: None,
             },
-            EnvVar {
-                name: String::from("K8S_NAMESPACE"),
-                value: None,
-                value_from: Some(EnvVarSource {
-                    field_ref: Some(ObjectFieldSelector {
-                        api_version: None,
-                        field_path: "metadata.namespace".to_string()
-                    }),
-                    ..EnvVarSource::default()
-                }),
-            },
-            EnvVar {
-                name: String::from("K8S_NAME"),
-                value: Some(name.clone()),
-                value_from: None,
-            },
-        ],
+        ]
+        .into_iter()
+        .chain(mcproxy.spec.throttle.clone().unwrap_or_default().env())
+        .collect(),
         IntOrString::Int(25577),
         ctx.clone(),
         "mcproxy".to_string(),
         mcproxy.clone(),
-        mcproxy.spec.container.unwrap_or_default(),
-        mcproxy.spec.runner,
-        mcproxy.spec.replicas,
+        mcproxy.spec.container.clone().unwrap_or_default(),
+        runner,
+        download,
+        replicas,
+        None,
     )
+    .await?;
+
+    let client = ctx.get_ref().client.clone();
+    gateway::reconcile_route(client.clone(), &mcproxy).await?;
+
+    // report replicas for the scale subresource
+    let workload = workload_status(client.clone(), &ns, &name, workload).await?;
+    Api::<MinecraftProxy>::namespaced(client, &ns)
+        .patch_status(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": {
+                "replicas": workload.replicas,
+                "readyReplicas": workload.ready_replicas,
+                "selector": format!("mycelium.njha.dev/mcproxy={}", name),
+                "resolvedBuild": resolved_build,
+                "pinnedPlugins": pinned_plugins,
+                "pluginsRefresh": refresh,
+                "conditions": [quota::quota_condition(
+                    &previous.conditions.clone().unwrap_or_default(),
+                    over_quota.as_deref(),
+                    mcproxy.metadata.generation,
+                )],
+            } })),
+        )
         .await?;
 
     let duration = start.elapsed().as_millis() as f64 / 1000.0;
@@ -154,9 +394,28 @@ pub async fn reconcile(
         .with_label_values(&[])
         .observe(duration);
     ctx.get_ref().metrics.proxy_handled_events.inc();
+    ctx.get_ref()
+        .metrics
+        .last_reconcile
+        .with_label_values(&["MinecraftProxy", &ns, &name])
+        .set(Utc::now().timestamp());
+    let max_players = &ctx.get_ref().metrics.max_players;
+    match mcproxy.spec.runner.max_players {
+        Some(m) => max_players
+            .with_label_values(&["MinecraftProxy", &ns, &name])
+            .set(m.into()),
+        None => {
+            let _ = max_players.remove_label_values(&["MinecraftProxy", &ns, &name]);
+        }
+    }
     info!("Reconciled MinecraftProxy \"{}\" in {}", name, ns);
 
-    Ok(ReconcilerAction {
-        requeue_after: None,
-    })
+    // come back to check for a newer build
+    if auto_update {
+        let interval = jarapi::AUTO_UPDATE_INTERVAL;
+        return Ok(ReconcilerAction {
+            requeue_after: Some(action.requeue_after.map_or(interval, |r| r.min(interval))),
+        });
+    }
+    Ok(action)
 }
@@ -11,12 +11,12 @@ use k8s_openapi::{
     api::{
         apps::v1::{StatefulSet, StatefulSetSpec},
         core::v1::{
-            Container, EnvVar, PodSpec, PodTemplateSpec, ResourceRequirements, Service,
-            ServicePort, ServiceSpec, Volume, VolumeMount,
+            Container, EnvVar, EnvVarSource, Pod, PodSpec, PodTemplateSpec, ResourceRequirements,
+            Secret, SecretKeySelector, Service, ServicePort, ServiceSpec, Volume, VolumeMount,
         },
     },
     apimachinery::pkg::{
-        apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference},
+        apis::meta::v1::{Condition, LabelSelector, ObjectMeta, OwnerReference},
         util::intstr::IntOrString,
     },
 };
@@ -41,14 +41,53 @@ use tokio::{
 use tracing::{debug, error, event, field, info, instrument, trace, warn, Level, Span};
 
 use crate::{
-    helpers::{jarapi::get_download_url, manager::Data, telemetry},
+    helpers::{
+        jarapi::{self, get_download_url},
+        manager::Data,
+        rcon, telemetry,
+    },
     objects::{
-        generic_reconcile, make_volume, make_volume_mount, ConfigOptions, ContainerOptions,
-        RunnerOptions,
+        backend::Workload,
+        backup::{self, BackupOptions, RestoreSource, RestoreStatus},
+        drain::{self, DrainStatus},
+        generic_reconcile, make_volume, make_volume_mount, observe, paused,
+        plugins::{self, PinnedPlugin},
+        queue::QueueOptions,
+        quota, resizing_condition, restarts,
+        rollout::{self, CanaryStatus, RollbackStatus, RolloutOptions},
+        scaling::{self, IdleStatus, ScaleToZeroOptions},
+        storage, workload_conditions, workload_status, ConfigOptions, ContainerOptions,
+        PluginSource, References, ResolvedBuild, RunnerOptions, StartupTimings, WorldSource,
     },
-    Error, Result,
+    Error,
+    Error::MyceliumError,
+    Result,
 };
-use crate::Error::MyceliumError;
+
+/// how often to check on claims that are being resized
+const RESIZE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how often to count the players on replicas that are being drained
+const DRAIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// finalizer that stops (and backs up) the replicas before a set is deleted
+pub const FINALIZER: &str = "mycelium.njha.dev/cleanup";
+
+/// Whether deleting a set has to wait for FINALIZER, which is when it has
+/// claims that are backed up or deleted along with it. Anything else can go
+/// without the operator, so sets (and their namespaces) can still be deleted
+/// while it's down or uninstalled.
+pub fn needs_finalizer(spec: &MinecraftSetSpec) -> bool {
+    let container = match &spec.container {
+        Some(container) if !container.claim_templates().is_empty() => container,
+        _ => return false,
+    };
+    let policy = container.persistent_volume_claim_retention_policy.as_ref();
+    spec.backup.is_some() || policy.and_then(|p| p.when_deleted.as_deref()) == Some("Delete")
+}
+
+/// how often to check on the replicas and final backup of a set being deleted
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
 #[kube(
@@ -56,7 +95,22 @@ use crate::Error::MyceliumError;
     version = "v1beta1",
     kind = "MinecraftSet"
 )]
-#[kube(shortname = "mcset", namespaced)]
+#[kube(shortname = "mcset", namespaced, status = "MinecraftSetStatus")]
+#[kube(
+    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas", "labelSelectorPath":".status.selector"}"#
+)]
+#[kube(
+    printcolumn = r#"{"name":"Ready", "type":"integer", "description":"ready replicas", "jsonPath":".status.readyReplicas"}"#
+)]
+#[kube(
+    printcolumn = r#"{"name":"Replicas", "type":"integer", "description":"desired replicas", "jsonPath":".spec.replicas"}"#
+)]
+#[kube(
+    printcolumn = r#"{"name":"Jar", "type":"string", "description":"jar the replicas run", "jsonPath":".status.currentJar"}"#
+)]
+#[kube(
+    printcolumn = r#"{"name":"Suspended", "type":"boolean", "description":"scaled to zero by spec.suspend", "jsonPath":".spec.suspend"}"#
+)]
 pub struct MinecraftSetSpec {
     /// number of identical servers to create
     pub replicas: i32,
@@ -69,6 +123,117 @@ pub struct MinecraftSetSpec {
 
     /// options to pass to proxies that select this MinecraftSet
     pub proxy: Option<ProxyOptions>,
+
+    /// VolumeSnapshot backups of the replicas' claims, on a schedule or when
+    /// the mycelium.njha.dev/backup annotation changes
+    pub backup: Option<BackupOptions>,
+
+    /// backup to start from when the replicas' claims (or worlds) don't exist
+    /// yet, claims are only restored from a snapshot until status.restore says
+    /// they all were
+    pub restore_from: Option<RestoreSource>,
+
+    /// scale to zero and leave the proxies' server lists, keeping the claims
+    /// and config, until this is unset again
+    pub suspend: Option<bool>,
+
+    /// how a new jar build is rolled out to the replicas
+    pub rollout: Option<RolloutOptions>,
+
+    /// scale to zero while no players are on the set, until a proxy wakes it up
+    /// for a player who wants to join
+    pub scale_to_zero: Option<ScaleToZeroOptions>,
+
+    /// hold players on another set while this one is full, letting them in as
+    /// others leave in the order they came
+    pub queue: Option<QueueOptions>,
+}
+
+impl MinecraftSetSpec {
+    /// configmaps and secrets the set mounts, including the backup credentials
+    pub fn references(&self) -> References {
+        let mut refs = References::of(&self.runner, self.container.as_ref());
+        let s3 = self.backup.as_ref().and_then(|b| b.s3.as_ref());
+        refs.secrets
+            .extend(s3.map(|s3| s3.credentials_secret.clone()));
+        refs
+    }
+
+    /// replicas that should be running, none while suspended
+    pub fn running_replicas(&self) -> i32 {
+        match self.suspend {
+            Some(true) => 0,
+            _ => self.replicas,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MinecraftSetStatus {
+    /// replicas of the StatefulSet or Deployment
+    pub replicas: Option<i32>,
+
+    /// replicas that are ready to accept players
+    pub ready_replicas: Option<i32>,
+
+    /// label selector of the pods, for the scale subresource
+    pub selector: Option<String>,
+
+    /// jar (type/version/build) every replica runs, as of the last completed
+    /// rollout
+    pub current_jar: Option<String>,
+
+    /// build picked for a jar that omits it, or where a vanilla jar downloads
+    /// from
+    pub resolved_build: Option<ResolvedBuild>,
+
+    /// plugins resolved to the artifacts every replica downloads
+    pub pinned_plugins: Option<Vec<PinnedPlugin>>,
+
+    /// value of the refresh-plugins annotation the pins were made with
+    pub plugins_refresh: Option<String>,
+
+    /// Ready, Progressing, Resizing and QuotaExceeded conditions
+    pub conditions: Option<Vec<Condition>>,
+
+    /// whether the set is whitelist-only for maintenance
+    pub maintenance: Option<bool>,
+
+    /// how long the most recently started replica took to start
+    pub startup_timings: Option<StartupTimings>,
+
+    /// value of the backup annotation the last manual backup was taken for
+    pub backup_request: Option<String>,
+
+    /// when the newest backup (scheduled or manual) was taken
+    pub last_backup_time: Option<DateTime<Utc>>,
+
+    /// how restoreFrom is getting along
+    pub restore: Option<RestoreStatus>,
+
+    /// run of runner.restartSchedule the replicas were last restarted for
+    pub last_restart: Option<DateTime<Utc>>,
+
+    /// last warning (in seconds) announced for the upcoming scheduled restart
+    pub restart_warning: Option<u32>,
+
+    /// canary of the jar being rolled out, with rollout.canary
+    pub canary: Option<CanaryStatus>,
+
+    /// replicas being drained, with the players left on them
+    pub draining: Option<Vec<DrainStatus>>,
+
+    /// ControllerRevision of the last pod template every replica ran and was
+    /// ready with
+    pub last_good_revision: Option<String>,
+
+    /// rollback to lastGoodRevision in effect, after the current spec crash
+    /// looped
+    pub rollback: Option<RollbackStatus>,
+
+    /// whether the set is empty or asleep, with scaleToZero
+    pub idle: Option<IdleStatus>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
@@ -78,6 +243,12 @@ pub struct ProxyOptions {
 
     /// priority for the default try = [] list, None means don't include in list
     pub priority: Option<u32>,
+
+    /// how proxies forward player info, `velocity` (default, modern forwarding)
+    /// or `bungeecord` for waterfall proxies (legacy IP forwarding, which
+    /// is unauthenticated, so only the proxies should be able to reach the
+    /// servers)
+    pub forwarding: Option<String>,
 }
 
 #[instrument(skip(ctx), fields(trace_id))]
@@ -90,15 +261,313 @@ pub async fn reconcile(mcset: MinecraftSet, ctx: Context<Data>) -> Result<Reconc
     let ns = ResourceExt::namespace(&mcset)
         .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
 
+    // worlds are only let go of once the replicas are stopped and backed up
+    let mcset_api = Api::<MinecraftSet>::namespaced(ctx.get_ref().client.clone(), &ns);
+    if mcset.meta().deletion_timestamp.is_some() {
+        return cleanup(&mcset, &mcset_api, ctx).await;
+    }
+    if paused(&mcset) {
+        let workload = ctx.get_ref().workload(
+            &mcset.spec.runner,
+            &mcset.spec.container.clone().unwrap_or_default(),
+        );
+        return observe::<MinecraftSet>(ctx.get_ref().client.clone(), &ns, &name, workload).await;
+    }
+    let finalized = mcset.finalizers().iter().any(|f| f == FINALIZER);
+    if needs_finalizer(&mcset.spec) != finalized {
+        let mut finalizers: Vec<String> = mcset
+            .finalizers()
+            .iter()
+            .filter(|f| *f != FINALIZER)
+            .cloned()
+            .collect();
+        if !finalized {
+            finalizers.push(FINALIZER.into());
+        }
+        set_finalizers(&mcset_api, &mcset, finalizers).await?;
+    }
+
+    // vanilla servers can't load plugins
+    let vanilla = mcset.spec.runner.jar.r#type == "vanilla";
     let mut plugin = vec![];
-    if let Ok(p) = std::env::var("MYCELIUM_PLUGIN_PAPER") {
+    if let Some(p) = std::env::var("MYCELIUM_PLUGIN_PAPER")
+        .ok()
+        .filter(|_| !vanilla)
+    {
         plugin.push(p)
     }
-    if let Ok(p) = std::env::var("METRICS_PLUGIN_PAPER") {
+    if let Some(p) = std::env::var("METRICS_PLUGIN_PAPER")
+        .ok()
+        .filter(|_| !vanilla)
+    {
         plugin.push(p)
     }
+    let previous = mcset.status.clone().unwrap_or_default();
+    let refresh = mcset
+        .annotations()
+        .get(plugins::REFRESH_ANNOTATION)
+        .cloned();
+    let (spec_plugins, pinned_plugins) = plugins::pin(
+        mcset
+            .spec
+            .runner
+            .plugins
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(PluginSource::from)
+            .collect(),
+        "PAPER",
+        previous.pinned_plugins.as_deref().unwrap_or_default(),
+        refresh != previous.plugins_refresh,
+    )
+    .await?;
+    let plugins: Vec<PluginSource> = spec_plugins
+        .into_iter()
+        .chain(plugin.into_iter().map(PluginSource::from))
+        .collect();
+
+    // an empty set goes to sleep, and a proxy wakes it up for the next player
+    let idle = mcset.spec.scale_to_zero.as_ref().map(|options| {
+        let players = ctx
+            .get_ref()
+            .state
+            .read()
+            .expect("set players")
+            .set_players(&ns, &name);
+        let woken = mcset
+            .annotations()
+            .get(scaling::WAKE_ANNOTATION)
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        scaling::idle(previous.idle.as_ref(), options, players, woken, Utc::now())
+    });
+    if idle.as_ref().map(IdleStatus::asleep) != previous.idle.as_ref().map(IdleStatus::asleep) {
+        let asleep = idle.as_ref().is_some_and(IdleStatus::asleep);
+        info!(
+            "MinecraftSet \"{}\" in {} is {}",
+            name,
+            ns,
+            if asleep {
+                "going to sleep"
+            } else {
+                "waking up"
+            }
+        );
+    }
+    let wanted = match idle.as_ref().is_some_and(IdleStatus::asleep) {
+        true => 0,
+        false => mcset.spec.running_replicas(),
+    };
+    let resources = mcset
+        .spec
+        .container
+        .as_ref()
+        .and_then(|c| c.resources.as_ref());
+    let (replicas, over_quota) =
+        quota::enforce(ctx.get_ref(), &ns, "mcset", &name, wanted, resources).await?;
+    if let Some(message) = &over_quota {
+        warn!("MinecraftSet \"{}\" in {}: {}", name, ns, message);
+    }
+    let workload = ctx.get_ref().workload(
+        &mcset.spec.runner,
+        &mcset.spec.container.clone().unwrap_or_default(),
+    );
+    // canaries and rollbacks go by StatefulSet revisions
+    let stateful = workload == Workload::StatefulSet;
+    let mut runner = mcset.spec.runner.clone();
+    let restore_from = mcset.spec.restore_from.clone().unwrap_or_default();
+    if let Some(url) = &restore_from.url {
+        // an archive is imported like any other world, when there is none yet
+        runner.world_url = None;
+        runner.world_source = Some(WorldSource { url: url.clone() });
+    }
+    let auto_update = runner.jar.auto_update.unwrap_or(false);
+    let resolved_build =
+        jarapi::resolve_build(&mut runner.jar, previous.resolved_build.as_ref()).await?;
+    let download = jarapi::resolve(&runner.jar, resolved_build.as_ref()).await?;
+    let jar = runner.jar.id();
+    let generation = mcset.metadata.generation;
+
+    let claim_templates = mcset
+        .spec
+        .container
+        .as_ref()
+        .map(|c| c.claim_templates())
+        .unwrap_or_default();
+    let labels = BTreeMap::from([("mycelium.njha.dev/mcset".to_string(), name.clone())]);
+
+    // claims restored from snapshots have to exist before the StatefulSet makes
+    // them empty, which is only done until they all were once
+    let snapshot_restored = backup::snapshot_restored(previous.restore.as_ref(), &restore_from);
+    let restored_claims = match &restore_from.snapshot {
+        Some(tag) if !snapshot_restored => Some(
+            backup::restore_claims(
+                ctx.get_ref().client.clone(),
+                &ns,
+                &name,
+                &labels,
+                &claim_templates,
+                replicas,
+                tag,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+
+    // the runner uploads its own backups, with the credentials mounted next to the
+    // world
+    let mut container = mcset.spec.container.clone().unwrap_or_default();
+    let mut backup_env = vec![];
+    if let Some((env, volume, mount)) = mcset.spec.backup.as_ref().and_then(backup::s3_runner) {
+        backup_env = env;
+        // incremental backups are downloaded with the same credentials
+        if let Some(manifest) = &restore_from.manifest {
+            backup_env.push(EnvVar {
+                name: "MYCELIUM_RESTORE_MANIFEST".into(),
+                value: Some(manifest.clone()),
+                value_from: None,
+            });
+        }
+        container
+            .extra_volumes
+            .get_or_insert_with(Vec::new)
+            .push(volume);
+        container
+            .extra_volume_mounts
+            .get_or_insert_with(Vec::new)
+            .push(mount);
+    }
+    if restore_from.manifest.is_some() && backup_env.is_empty() {
+        return Err(MyceliumError(
+            "restoreFrom.manifest needs backup.s3 to download from".into(),
+        ));
+    }
+
+    // scheduled restarts are rolled out by changing the pod template, after a
+    // countdown
+    let mut last_restart = previous.last_restart;
+    let mut restart_warning = previous.restart_warning;
+    let mut next_restart = None;
+    if let Some(schedule) = &mcset.spec.runner.restart_schedule {
+        let schedule = backup::parse_schedule(schedule)?;
+        let warnings = mcset
+            .spec
+            .runner
+            .restart_warnings
+            .clone()
+            .unwrap_or_else(|| restarts::DEFAULT_WARNINGS.to_vec());
+        let now = Utc::now();
+        let last = last_restart
+            .or_else(|| mcset.metadata.creation_timestamp.as_ref().map(|t| t.0))
+            .unwrap_or(now);
+        let plan = restarts::plan(&schedule, last, restart_warning, &warnings, now);
+        if let Some(seconds) = plan.warning {
+            console(
+                ctx.get_ref().client.clone(),
+                &ns,
+                &name,
+                &restarts::announcement(seconds),
+            )
+            .await;
+            restart_warning = Some(seconds);
+        }
+        if let Some(run) = plan.restart {
+            info!(
+                "restarting the replicas of MinecraftSet \"{}\" in {} for {}",
+                name, ns, run
+            );
+            last_restart = Some(run);
+            restart_warning = None;
+        }
+        next_restart = plan.next.and_then(|t| (t - now).to_std().ok());
+    }
+    if let Some(time) = last_restart.filter(|_| mcset.spec.runner.restart_schedule.is_some()) {
+        container
+            .metadata
+            .get_or_insert_with(Default::default)
+            .pod
+            .get_or_insert_with(Default::default)
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(restarts::RESTARTED_AT_ANNOTATION.into(), time.to_rfc3339());
+    }
+
+    // a new build only goes to the highest ordinal until that canary has baked
+    let on_delete = container
+        .update_strategy
+        .as_ref()
+        .and_then(|s| s.r#type.as_deref())
+        == Some("OnDelete");
+    let new_build = previous
+        .current_jar
+        .as_ref()
+        .is_some_and(|current| *current != jar);
+    let mut canary = None;
+    let canary_options = mcset.spec.rollout.as_ref().and_then(|r| r.canary.as_ref());
+    if let Some(options) =
+        canary_options.filter(|_| stateful && replicas > 1 && !on_delete && new_build)
+    {
+        let pod = rollout::canary_pod(ctx.get_ref().client.clone(), &ns, &name, replicas - 1, &jar)
+            .await?;
+        let status = rollout::evaluate(previous.canary.as_ref(), &jar, options, &pod, Utc::now());
+        if status.phase != "Passed" {
+            container.update_strategy =
+                Some(rollout::hold(container.update_strategy.as_ref(), replicas));
+        }
+        canary = Some(status);
+    }
 
-    generic_reconcile(
+    // a spec whose pods crash loop goes back to the last template that ran, until
+    // it changes
+    let auto_rollback = stateful
+        && mcset
+            .spec
+            .rollout
+            .as_ref()
+            .and_then(|r| r.auto_rollback)
+            .unwrap_or(true);
+    let mut rollback = previous
+        .rollback
+        .clone()
+        .filter(|r| auto_rollback && Some(r.generation) == generation);
+    if let Some(good) = previous
+        .last_good_revision
+        .as_ref()
+        .filter(|_| auto_rollback && rollback.is_none())
+    {
+        let pods =
+            rollout::crash_looping(ctx.get_ref().client.clone(), &ns, &name, &labels, good).await?;
+        if !pods.is_empty() {
+            warn!(
+                "rolling MinecraftSet \"{}\" in {} back to {}, {} crash looped",
+                name,
+                ns,
+                good,
+                pods.join(", ")
+            );
+            rollback = Some(RollbackStatus {
+                revision: good.clone(),
+                generation: generation.unwrap_or_default(),
+                pods,
+            });
+        }
+    }
+    let rollback_template = match &rollback {
+        Some(r) => {
+            rollout::revision_template(ctx.get_ref().client.clone(), &ns, &r.revision).await?
+        }
+        None => None,
+    };
+    if rollback.is_some() && rollback_template.is_none() {
+        warn!(
+            "can't roll MinecraftSet \"{}\" in {} back, its known-good revision is gone",
+            name, ns
+        );
+        rollback = None;
+    }
+    let action = generic_reconcile(
         vec![
             EnvVar {
                 name: String::from("MYCELIUM_RUNNER_KIND"),
@@ -107,28 +576,202 @@ pub async fn reconcile(mcset: MinecraftSet, ctx: Context<Data>) -> Result<Reconc
             },
             EnvVar {
                 name: String::from("MYCELIUM_PLUGINS"),
-                value: Some(mcset
-                    .spec
-                    .runner
-                    .plugins
-                    .clone()
-                    .unwrap_or_default()
-                    .into_iter()
-                    .chain(plugin.into_iter())
-                    .collect::<Vec<String>>().join(",")),
+                value: Some(
+                    serde_json::to_string(
+                        &plugins
+                            .into_iter()
+                            .map(|p| PluginSource {
+                                url: ctx.get_ref().artifact_url(&p.url),
+                                ..p
+                            })
+                            .collect::<Vec<PluginSource>>(),
+                    )
+                    .map_err(Error::SerializationError)?,
+                ),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_FORWARDING"),
+                value: mcset.spec.proxy.as_ref().and_then(|p| p.forwarding.clone()),
+                value_from: None,
+            },
+            // vanilla can't take forwarded player info, so it authenticates players itself
+            // unless it sits behind a proxy, which has to do it instead
+            EnvVar {
+                name: String::from("MYCELIUM_ONLINE_MODE"),
+                value: vanilla.then(|| mcset.spec.proxy.is_none().to_string()),
                 value_from: None,
             },
-        ],
+            EnvVar {
+                name: String::from("MYCELIUM_RCON_PASSWORD"),
+                value: None,
+                value_from: Some(EnvVarSource {
+                    secret_key_ref: Some(SecretKeySelector {
+                        key: "rcon_password".to_string(),
+                        name: Some(name.clone()),
+                        optional: Some(false),
+                    }),
+                    ..EnvVarSource::default()
+                }),
+            },
+        ]
+        .into_iter()
+        .chain(backup_env)
+        .collect(),
         IntOrString::Int(25565),
         ctx.clone(),
         "mcset".to_string(),
         mcset.clone(),
-        mcset.spec.container.unwrap_or_default(),
-        mcset.spec.runner,
-        mcset.spec.replicas,
+        container,
+        runner,
+        download,
+        replicas,
+        rollback_template,
     )
     .await?;
 
+    // report how the underlying workload is doing
+    let client = ctx.get_ref().client.clone();
+    let workload = workload_status(client.clone(), &ns, &name, workload).await?;
+    let previous_conditions = previous.conditions.unwrap_or_default();
+    let mut conditions =
+        workload_conditions(&previous_conditions, &workload, replicas, &jar, generation);
+    let mut resizing = vec![];
+    for tpl in &claim_templates {
+        resizing.extend(storage::resizing_claims(client.clone(), &ns, &name, &labels, tpl).await?);
+    }
+    conditions.push(resizing_condition(
+        &previous_conditions,
+        &resizing,
+        generation,
+    ));
+    conditions.push(quota::quota_condition(
+        &previous_conditions,
+        over_quota.as_deref(),
+        generation,
+    ));
+    if let Some(canary) = &canary {
+        conditions.push(rollout::canary_condition(
+            &previous_conditions,
+            canary,
+            generation,
+        ));
+    }
+    if let Some(rollback) = &rollback {
+        conditions.push(rollout::rollback_condition(
+            &previous_conditions,
+            rollback,
+            generation,
+        ));
+    }
+    let last_good_revision = workload
+        .revision
+        .clone()
+        .filter(|_| workload.available_replicas >= replicas && rollback.is_none())
+        .or(previous.last_good_revision);
+
+    // snapshot the replicas on schedule, or when the backup annotation changes
+    let backup_request = mcset.annotations().get(backup::BACKUP_ANNOTATION).cloned();
+    let mut next_backup = None;
+    if let Some(options) = mcset
+        .spec
+        .backup
+        .as_ref()
+        .filter(|_| !claim_templates.is_empty())
+    {
+        let live = workload.replicas;
+        let class = options.snapshot_class.as_deref();
+        let now = Utc::now();
+        if backup_request.is_some() && backup_request != previous.backup_request {
+            backup::backup_replicas(
+                client.clone(),
+                &ns,
+                &name,
+                &labels,
+                &claim_templates,
+                live,
+                "manual",
+                now,
+                class,
+            )
+            .await?;
+        }
+        if let Some(schedule) = &options.schedule {
+            let schedule = backup::parse_schedule(schedule)?;
+            // a new set waits for the first run after it was created
+            let last = backup::last_backup(client.clone(), &ns, &labels, Some("scheduled"))
+                .await?
+                .or_else(|| mcset.metadata.creation_timestamp.as_ref().map(|t| t.0))
+                .unwrap_or(now);
+            if let Some(time) = backup::due(&schedule, last, now) {
+                backup::backup_replicas(
+                    client.clone(),
+                    &ns,
+                    &name,
+                    &labels,
+                    &claim_templates,
+                    live,
+                    "scheduled",
+                    time,
+                    class,
+                )
+                .await?;
+            }
+            next_backup = schedule
+                .after(&now)
+                .next()
+                .and_then(|t| (t - now).to_std().ok());
+        }
+        if let Some(keep) = options.retention {
+            backup::prune_backups(client.clone(), &ns, &labels, keep as usize).await?;
+        }
+    }
+    let restore = match snapshot_restored {
+        true => previous.restore.clone(),
+        false => backup::restore_status(
+            &restore_from,
+            restored_claims,
+            workload.ready_replicas,
+            replicas,
+        ),
+    };
+    let last_backup = match mcset.spec.backup {
+        Some(_) => backup::last_backup(client.clone(), &ns, &labels, None).await?,
+        None => None,
+    };
+    let draining = drain::reconcile(client.clone(), &ctx.get_ref().state, &ns, &name).await?;
+    // a rolled back set still runs the jar it had before
+    let rolled_out = rollback.is_none()
+        && conditions
+            .iter()
+            .any(|c| c.type_ == "Progressing" && c.status == "False");
+    Api::<MinecraftSet>::namespaced(client, &ns)
+        .patch_status(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": {
+                "replicas": workload.replicas,
+                "readyReplicas": workload.ready_replicas,
+                "selector": format!("mycelium.njha.dev/mcset={}", name),
+                "currentJar": if rolled_out { Some(jar) } else { previous.current_jar },
+                "resolvedBuild": resolved_build,
+                "pinnedPlugins": pinned_plugins,
+                "pluginsRefresh": refresh,
+                "conditions": conditions,
+                "backupRequest": backup_request,
+                "lastBackupTime": last_backup,
+                "restore": restore,
+                "lastRestart": last_restart,
+                "restartWarning": restart_warning,
+                "canary": canary,
+                "draining": (!draining.is_empty()).then_some(&draining),
+                "lastGoodRevision": last_good_revision,
+                "rollback": rollback,
+                "idle": idle,
+            } })),
+        )
+        .await?;
+
     let duration = start.elapsed().as_millis() as f64 / 1000.0;
     ctx.get_ref()
         .metrics
@@ -136,12 +779,308 @@ pub async fn reconcile(mcset: MinecraftSet, ctx: Context<Data>) -> Result<Reconc
         .with_label_values(&[])
         .observe(duration);
     ctx.get_ref().metrics.set_handled_events.inc();
+    ctx.get_ref()
+        .metrics
+        .last_reconcile
+        .with_label_values(&["MinecraftSet", &ns, &name])
+        .set(Utc::now().timestamp());
+    let backup_gauge = &ctx.get_ref().metrics.last_backup;
+    match last_backup {
+        Some(time) => backup_gauge
+            .with_label_values(&[&ns, &name])
+            .set(time.timestamp()),
+        None => {
+            let _ = backup_gauge.remove_label_values(&[&ns, &name]);
+        }
+    }
+    let max_players = &ctx.get_ref().metrics.max_players;
+    match mcset.spec.runner.max_players {
+        Some(m) => max_players
+            .with_label_values(&["MinecraftSet", &ns, &name])
+            .set(m.into()),
+        None => {
+            let _ = max_players.remove_label_values(&["MinecraftSet", &ns, &name]);
+        }
+    }
     info!("Reconciled MinecraftSet \"{}\" in {}", name, ns);
 
-    // TODO: Do we need to check back if this succeeded & no changes were made?
-    // i.e. Do we want to revert manual edits to StatefulSets or Services on a
-    // timer?
+    // come back for the next scheduled backup, restart or warning, or to check on a
+    // rollout
+    let baking = canary.as_ref().is_some_and(|c| c.phase == "Baking");
+    let watching = auto_rollback
+        && rollback.is_none()
+        && last_good_revision.is_some()
+        && workload.revision.is_none();
+    let next_check = (baking || watching).then_some(rollout::CHECK_INTERVAL);
+    // player counts aren't watched either
+    let next_drain = draining
+        .iter()
+        .any(|d| !d.destroyable)
+        .then_some(DRAIN_INTERVAL);
+    let next_idle = idle
+        .as_ref()
+        .is_some_and(|i| !i.asleep())
+        .then_some(scaling::IDLE_INTERVAL);
+    let action = ReconcilerAction {
+        requeue_after: [
+            action.requeue_after,
+            next_backup,
+            next_restart,
+            next_check,
+            next_drain,
+            next_idle,
+        ]
+        .into_iter()
+        .flatten()
+        .min(),
+    };
+
+    // claim status isn't watched, so check back on a resize
+    if !resizing.is_empty() {
+        return Ok(ReconcilerAction {
+            requeue_after: Some(
+                action
+                    .requeue_after
+                    .map_or(RESIZE_INTERVAL, |r| r.min(RESIZE_INTERVAL)),
+            ),
+        });
+    }
+
+    // come back to check for a newer build
+    if auto_update {
+        let interval = jarapi::AUTO_UPDATE_INTERVAL;
+        return Ok(ReconcilerAction {
+            requeue_after: Some(action.requeue_after.map_or(interval, |r| r.min(interval))),
+        });
+    }
+
+    Ok(action)
+}
+
+/// replaces the finalizers, failing if the set changed since it was read
+async fn set_finalizers(
+    api: &Api<MinecraftSet>,
+    mcset: &MinecraftSet,
+    finalizers: Vec<String>,
+) -> Result<(), Error> {
+    api.patch(
+        &mcset.name(),
+        &PatchParams::default(),
+        &Patch::Merge(json!({ "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": mcset.resource_version(),
+        } })),
+    )
+    .await?;
+    Ok(())
+}
+
+/// runs a console command on every replica over rcon, logging failures
+async fn console(client: Client, ns: &str, name: &str, command: &str) {
+    let password = match Api::<Secret>::namespaced(client.clone(), ns)
+        .get(name)
+        .await
+    {
+        Ok(secret) => secret.data.and_then(|d| d.get("rcon_password").cloned()),
+        Err(_) => None,
+    };
+    let password = match password {
+        Some(password) => String::from_utf8_lossy(&password.0).into_owned(),
+        None => return,
+    };
+    let pods = Api::<Pod>::namespaced(client, ns)
+        .list(&ListParams::default().labels(&format!("mycelium.njha.dev/mcset={}", name)))
+        .await
+        .map(|pods| pods.items)
+        .unwrap_or_default();
+    for ip in pods
+        .into_iter()
+        .filter_map(|p| p.status.and_then(|s| s.pod_ip))
+    {
+        if let Err(e) = rcon::run(&ip, &password, &[command.to_string()]).await {
+            warn!("couldn't run `{}` on {}: {}", command, ip, e);
+        }
+    }
+}
+
+/// Runs when a set is deleted. The replicas are saved and scaled down, so they
+/// stop gracefully, and their claims are snapshotted if backups are on, before
+/// the finalizer is removed and the workload and (depending on the retention
+/// policy) claims are collected.
+async fn cleanup(
+    mcset: &MinecraftSet,
+    api: &Api<MinecraftSet>,
+    ctx: Context<Data>,
+) -> Result<ReconcilerAction, Error> {
+    if !mcset.finalizers().iter().any(|f| f == FINALIZER) {
+        return Ok(ReconcilerAction {
+            requeue_after: None,
+        });
+    }
+    let client = ctx.get_ref().client.clone();
+    let name = mcset.name();
+    let ns = mcset.namespace().unwrap_or_default();
+    let waiting = Ok(ReconcilerAction {
+        requeue_after: Some(CLEANUP_INTERVAL),
+    });
+
+    let sts_api = Api::<StatefulSet>::namespaced(client.clone(), &ns);
+    let live = match sts_api.get(&name).await {
+        Ok(live) => Some(live),
+        Err(kube::Error::Api(e)) if e.code == 404 => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(live) = live {
+        if live.spec.as_ref().and_then(|s| s.replicas) != Some(0) {
+            // only a precaution, the replicas save when they stop
+            console(client.clone(), &ns, &name, "save-all flush").await;
+            info!(
+                "stopping the replicas of MinecraftSet \"{}\" in {} before deleting it",
+                name, ns
+            );
+            sts_api
+                .patch(
+                    &name,
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({ "spec": { "replicas": 0 } })),
+                )
+                .await?;
+            return waiting;
+        }
+        if live.status.map_or(0, |s| s.replicas) > 0 {
+            return waiting;
+        }
+    }
+
+    let claim_templates = mcset
+        .spec
+        .container
+        .as_ref()
+        .map(|c| c.claim_templates())
+        .unwrap_or_default();
+    if let Some(options) = mcset
+        .spec
+        .backup
+        .as_ref()
+        .filter(|_| !claim_templates.is_empty())
+    {
+        let labels = BTreeMap::from([("mycelium.njha.dev/mcset".to_string(), name.clone())]);
+        let replicas = mcset
+            .spec
+            .replicas
+            .max(mcset.status.as_ref().and_then(|s| s.replicas).unwrap_or(0));
+        // named after the deletion, so every attempt finds the same snapshots
+        let time = mcset
+            .meta()
+            .deletion_timestamp
+            .as_ref()
+            .map_or_else(Utc::now, |t| t.0);
+        let ready = backup::backup_replicas(
+            client,
+            &ns,
+            &name,
+            &labels,
+            &claim_templates,
+            replicas,
+            "final",
+            time,
+            options.snapshot_class.as_deref(),
+        )
+        .await?;
+        if !ready {
+            return waiting;
+        }
+    }
+
+    let metrics = &ctx.get_ref().metrics;
+    let _ = metrics
+        .last_reconcile
+        .remove_label_values(&["MinecraftSet", &ns, &name]);
+    let _ = metrics
+        .max_players
+        .remove_label_values(&["MinecraftSet", &ns, &name]);
+    let _ = metrics.last_backup.remove_label_values(&[&ns, &name]);
+    let finalizers = mcset
+        .finalizers()
+        .iter()
+        .filter(|f| *f != FINALIZER)
+        .cloned()
+        .collect();
+    set_finalizers(api, mcset, finalizers).await?;
+    info!("cleaned up MinecraftSet \"{}\" in {}", name, ns);
     Ok(ReconcilerAction {
         requeue_after: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::tests::{basic_spec, mcset};
+
+    #[test]
+    fn only_sets_with_claims_to_save_get_a_finalizer() {
+        let needs = |spec: serde_json::Value| needs_finalizer(&mcset(spec).spec);
+        let claim = json!({ "metadata": { "name": "root" } });
+
+        assert!(!needs(basic_spec()));
+        let mut spec = basic_spec();
+        spec["container"] = json!({ "volumeClaimTemplate": claim });
+        assert!(!needs(spec.clone()));
+
+        spec["backup"] = json!({});
+        assert!(needs(spec.clone()));
+        spec["container"]["storage"] = json!({ "ephemeral": {} });
+        assert!(!needs(spec));
+
+        let mut spec = basic_spec();
+        spec["container"] = json!({
+            "volumeClaimTemplate": claim,
+            "persistentVolumeClaimRetentionPolicy": { "whenDeleted": "Delete" },
+        });
+        assert!(needs(spec.clone()));
+        spec["container"]["persistentVolumeClaimRetentionPolicy"]["whenDeleted"] = json!("Retain");
+        assert!(!needs(spec));
+    }
+
+    #[test]
+    fn suspended_sets_run_no_replicas() {
+        let mut spec = basic_spec();
+        assert_eq!(mcset(spec.clone()).spec.running_replicas(), 2);
+
+        spec["suspend"] = json!(true);
+        let set = mcset(spec.clone());
+        assert_eq!(set.spec.running_replicas(), 0);
+
+        // the replica count comes back when the set is resumed
+        spec["suspend"] = json!(false);
+        assert_eq!(mcset(spec).spec.running_replicas(), 2);
+    }
+
+    #[test]
+    fn references_cover_every_mount() {
+        let mut spec = basic_spec();
+        spec["runner"]["config"] = json!([{ "name": "server-properties", "path": "." }]);
+        spec["runner"]["pluginConfigs"] =
+            json!([{ "plugin": "LuckPerms", "configMap": "luckperms" }]);
+        spec["runner"]["pluginVolumes"] =
+            json!([{ "secret": "licensed-plugin", "path": "plugin.jar" }]);
+        spec["container"] = json!({
+            "extraVolumes": [{ "name": "motd", "configMap": { "name": "motd" } }],
+        });
+        spec["backup"] = json!({
+            "s3": { "endpoint": "https://s3.example.com", "bucket": "worlds", "credentialsSecret": "s3" },
+        });
+        let refs = mcset(spec).spec.references();
+
+        assert_eq!(refs.config_maps.into_iter().collect::<Vec<_>>(), [
+            "luckperms",
+            "motd",
+            "server-properties"
+        ]);
+        assert_eq!(refs.secrets.into_iter().collect::<Vec<_>>(), [
+            "licensed-plugin",
+            "s3"
+        ]);
+    }
+}
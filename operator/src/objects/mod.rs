@@ -1,6 +1,6 @@
 use std::{
     array::IntoIter,
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fmt::Debug,
     iter::Map,
     ops::Range,
@@ -13,27 +13,33 @@ use chrono::{DateTime, Utc};
 use futures::{future::BoxFuture, FutureExt, StreamExt};
 use k8s_openapi::{
     api::{
-        apps::v1::{StatefulSet, StatefulSetSpec},
+        apps::v1::{
+            Deployment, DeploymentSpec, RollingUpdateStatefulSetStrategy, StatefulSet,
+            StatefulSetSpec, StatefulSetUpdateStrategy,
+        },
         core::v1::{
-            ConfigMapVolumeSource, Container, EnvVar, PersistentVolumeClaim, PodSecurityContext,
-            PodSpec, PodTemplateSpec, ResourceRequirements, SecurityContext, Service, ServicePort,
-            ServiceSpec, Volume, VolumeMount,
+            Affinity, ConfigMap, ConfigMapVolumeSource, Container, EmptyDirVolumeSource, EnvVar,
+            EnvVarSource, ExecAction, Handler, Lifecycle, ObjectFieldSelector,
+            PersistentVolumeClaim, Pod, PodSecurityContext, PodSpec, PodTemplateSpec, Probe,
+            ResourceRequirements, Secret, SecretKeySelector, SecurityContext, Service, ServicePort,
+            ServiceSpec, TCPSocketAction, Toleration, TopologySpreadConstraint, Volume,
+            VolumeMount,
         },
+        policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec},
     },
     apimachinery::pkg::{
-        apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference},
+        api::resource::Quantity,
+        apis::meta::v1::{Condition, LabelSelector, ObjectMeta, OwnerReference, Time},
         util::intstr::IntOrString,
     },
 };
-use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
-use k8s_openapi::api::core::v1::{EnvVarSource, Secret, SecretKeySelector};
-use k8s_openapi::api::policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec};
 use kube::{
-    api::{ListParams, Patch, PatchParams},
+    api::{DeleteParams, ListParams, Patch, PatchParams},
     Api, Client, Resource, ResourceExt,
 };
 use kube_runtime::{
     controller::{Context, ReconcilerAction},
+    events::{self, EventType, Recorder, Reporter},
     Controller,
 };
 use prometheus::{
@@ -41,20 +47,140 @@ use prometheus::{
     HistogramOpts, HistogramVec, IntCounter,
 };
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha224};
 use tracing::{debug, error, event, field, info, instrument, trace, warn, Level, Span};
-use sha2::{Sha224, Digest};
 
 use crate::{
-    helpers::{manager::Data, metrics::Metrics, state::State},
-    objects::minecraft_set::MinecraftSetSpec,
-    Error, MinecraftProxy, MinecraftSet,
+    helpers::{
+        auth,
+        cache::ArtifactCache,
+        hangar,
+        jarapi::{self, JarDownload},
+        manager::{Data, MyceliumConfig},
+        metrics::Metrics,
+        state::State,
+    },
+    objects::{backend::Workload, minecraft_set::MinecraftSetSpec},
+    Error,
+    Error::MyceliumError,
+    MinecraftProxy, MinecraftSet,
 };
-use crate::Error::MyceliumError;
-use crate::helpers::jarapi::get_download_url;
 
+/// whitelist, ops and bans rendered for the runner
+pub mod access;
+/// replicas handed out to matchmakers
+pub mod allocation;
+/// what runs the replicas, StatefulSets or Agones Fleets
+pub mod backend;
+/// volume snapshots of server data
+pub mod backup;
+/// conversion between CRD versions
+pub mod conversion;
+/// taking single replicas out of rotation
+pub mod drain;
+/// Gateway API routes to proxies
+pub mod gateway;
+/// JVM heap against the container's memory limit
+pub mod heap;
+/// itzg/docker-minecraft-server compatibility
+pub mod itzg;
+/// warm spares of single-use servers
+pub mod minecraft_fleet;
 pub mod minecraft_proxy;
 pub mod minecraft_set;
+/// per-namespace policy on top of the operator config
+pub mod mycelium_config;
+/// plugins mounted from configmaps, secrets and claims
+pub mod plugins;
+/// login queues for full sets
+pub mod queue;
+/// per-namespace limits on replicas and resources
+pub mod quota;
+/// permissions the operator needs
+pub mod rbac;
+/// scheduled rolling restarts
+pub mod restarts;
+/// canary rollouts and rollbacks
+pub mod rollout;
+/// player-aware scale-down and scaling to zero
+pub mod scaling;
+/// persistent volume claim management
+pub mod storage;
+#[cfg(test)]
+pub(crate) mod tests;
+/// CEL validation rules for the generated CRDs
+pub mod validation;
+
+/// a plugin to install, either a bare URL or a [`PluginSource`]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum PluginEntry {
+    Url(String),
+    Source(PluginSource),
+}
+
+impl JsonSchema for PluginEntry {
+    fn schema_name() -> String {
+        "PluginEntry".into()
+    }
+
+    // a string or an object can't be expressed in a structural schema
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some(
+                    "plugin URL, or an object with url or hangar, sha256 and filename".into(),
+                ),
+                ..Default::default()
+            })),
+            extensions: [(
+                "x-kubernetes-preserve-unknown-fields".to_string(),
+                true.into(),
+            )]
+            .into(),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct PluginSource {
+    /// URL to download the plugin jar from
+    #[serde(default)]
+    pub url: String,
+
+    /// expected sha256 of the jar, the server won't start if the download
+    /// doesn't match
+    pub sha256: Option<String>,
+
+    /// file name to save the jar as in the plugins directory (default = end of
+    /// the URL)
+    pub filename: Option<String>,
+
+    /// Hangar plugin to download instead of a URL, as `author/slug@version`
+    pub hangar: Option<String>,
+}
+
+impl From<String> for PluginSource {
+    fn from(url: String) -> Self {
+        PluginSource {
+            url,
+            ..PluginSource::default()
+        }
+    }
+}
+
+impl From<PluginEntry> for PluginSource {
+    fn from(entry: PluginEntry) -> Self {
+        match entry {
+            PluginEntry::Url(url) => url.into(),
+            PluginEntry::Source(source) => source,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
 pub struct ConfigOptions {
@@ -63,59 +189,514 @@ pub struct ConfigOptions {
 
     /// location relative to the Minecraft root to mount the configmap
     pub path: String,
+
+    /// mount only this key of the configmap, as a single file at `path` (i.e.
+    /// `plugins/LuckPerms/config.yml`), instead of the whole configmap as a
+    /// directory
+    pub key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ContainerOptions {
-    /// should the container be stateful? (default = true)
+    /// should the container be stateful? (default = true) stateless servers are
+    /// run as a Deployment with ephemeral storage behind a load balanced
+    /// Service
     pub stateful: Option<bool>,
 
     /// resource requirements for the java pod
     pub resources: Option<ResourceRequirements>,
 
+    /// storage mode for the minecraft root (overrides volume,
+    /// volumeClaimTemplate and worldVolumeClaimTemplate if set, so
+    /// ephemeral servers get no claims at all)
+    pub storage: Option<StorageOptions>,
+
     /// volume to mount to the minecraft root (only useful for replicas = 1)
     pub volume: Option<Volume>,
 
-    /// volume claim template to use for the minecraft root (overrides the volume field if set)
+    /// volume claim template to use for the minecraft root (overrides the
+    /// volume field if set)
     pub volume_claim_template: Option<PersistentVolumeClaim>,
 
+    /// volume claim template for the worlds, mounted at /worlds and used as the
+    /// server's world container, so worlds can live on different storage
+    /// than the plugins and configs (servers only, needs a StatefulSet)
+    pub world_volume_claim_template: Option<PersistentVolumeClaim>,
+
+    /// world every replica starts from, mounted read-only from a shared claim
+    /// and copied over the pod's world on each start, so identical lobbies
+    /// don't each keep their own (pair it with ephemeral storage to keep
+    /// logs and caches per pod, servers only)
+    pub shared_world: Option<storage::SharedWorldOptions>,
+
+    /// what happens to claims from volumeClaimTemplate on scale-down and
+    /// deletion, enforced by the StatefulSetAutoDeletePVC feature gate
+    /// where there is one and by the operator otherwise
+    pub persistent_volume_claim_retention_policy: Option<ClaimRetentionPolicy>,
+
+    /// snapshot the replicas' claims before a jar change rolls out or an
+    /// orphaned claim is pruned, and refuse to proceed if the snapshot
+    /// fails (default = false)
+    pub backup_before_update: Option<bool>,
+
+    /// have the operator delete claims for ordinals above replicas after a
+    /// scale-down, for clusters without the StatefulSetAutoDeletePVC
+    /// feature gate (default = false)
+    pub prune_orphaned_claims: Option<bool>,
+
+    /// StatefulSets always remove their highest ordinals, so on scale-down keep
+    /// the replicas above the new count running (hidden from proxies) until
+    /// their players leave or this many seconds pass (default = 300, 0 to
+    /// scale down immediately). Stateless sets remove their emptiest
+    /// replicas first instead, and Agones keeps allocated GameServers.
+    pub drain_timeout_seconds: Option<i64>,
+
+    /// when the replicas of a StatefulSet pick up changes to the pod template,
+    /// i.e. to stage a new jar on the highest ordinals first
+    pub update_strategy: Option<UpdateStrategy>,
+
+    /// seconds a new replica has to stay ready (i.e. done loading) before it
+    /// counts as available and the rollout moves on to the next one
+    /// (default = 0)
+    pub min_ready_seconds: Option<i32>,
+
     /// nodes that the java pod can be scheduled on
     pub node_selector: Option<BTreeMap<String, String>>,
 
+    /// node and pod (anti-)affinity for the java pod
+    pub affinity: Option<Affinity>,
+
+    /// taints the java pod tolerates (i.e. to allow or keep off spot nodes)
+    pub tolerations: Option<Vec<Toleration>>,
+
+    /// how replicas are spread across zones or nodes
+    pub topology_spread_constraints: Option<Vec<TopologySpreadConstraint>>,
+
+    /// PriorityClass of the java pod, i.e. to let proxies preempt batch
+    /// workloads
+    pub priority_class_name: Option<String>,
+
+    /// scheduler for the java pod, if not the default one
+    pub scheduler_name: Option<String>,
+
+    /// seconds the server gets to save and stop before it is killed (default =
+    /// 300 for servers, since big worlds take a while to save, and 60 for
+    /// proxies)
+    pub termination_grace_period_seconds: Option<i64>,
+
+    /// command run in the java container before it is stopped, within the grace
+    /// period (i.e. `["sh", "-c", "sleep 10"]` to give proxies time to move
+    /// players)
+    pub pre_stop: Option<Vec<String>>,
+
+    /// extra labels and annotations for the generated resources
+    pub metadata: Option<ChildMetadata>,
+
+    /// also create a Service per replica (named like the pod), to reach
+    /// replicas directly without a proxy (stateful only)
+    pub replica_services: Option<ReplicaServiceOptions>,
+
+    /// cache downloaded jars and plugins in a volume shared by the replicas, so
+    /// each one is only downloaded once
+    pub download_cache: Option<storage::DownloadCacheOptions>,
+
     /// pod security context for the minecraft server (should be restrictive)
     pub security_context: Option<PodSecurityContext>,
+
+    /// additional volumes to add to the pod
+    pub extra_volumes: Option<Vec<Volume>>,
+
+    /// additional mounts for the minecraft container (i.e. for extraVolumes)
+    pub extra_volume_mounts: Option<Vec<VolumeMount>>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicaServiceOptions {
+    /// Service type, `NodePort` (default), `LoadBalancer` or `ClusterIP`
+    pub r#type: Option<String>,
+
+    /// node port of replica 0, replica n gets basePort + n (default = assigned
+    /// by Kubernetes)
+    pub base_node_port: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct ChildMetadata {
+    /// for the Service (i.e. external-dns hostnames or load balancer settings)
+    pub service: Option<MetadataOverrides>,
+
+    /// for the StatefulSet or Deployment
+    pub workload: Option<MetadataOverrides>,
+
+    /// for the pod template
+    pub pod: Option<MetadataOverrides>,
+}
+
+/// labels and annotations added to a generated resource, the operator's own
+/// keys win
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct MetadataOverrides {
+    pub labels: Option<BTreeMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+impl ContainerOptions {
+    /// the claim templates a server's StatefulSet gets, none for ephemeral or
+    /// stateless ones
+    pub fn claim_templates(&self) -> Vec<PersistentVolumeClaim> {
+        let ephemeral = self.storage.as_ref().is_some_and(|s| s.ephemeral.is_some());
+        if ephemeral || !self.stateful.unwrap_or(true) {
+            return vec![];
+        }
+        self.volume_claim_template
+            .iter()
+            .chain(self.world_volume_claim_template.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+impl MetadataOverrides {
+    /// `ours` with the extra labels and annotations merged in
+    fn apply(overrides: Option<&MetadataOverrides>, mut ours: ObjectMeta) -> ObjectMeta {
+        if let Some(overrides) = overrides {
+            let fields = [
+                (&mut ours.labels, &overrides.labels),
+                (&mut ours.annotations, &overrides.annotations),
+            ];
+            for (field, extra) in fields {
+                if let Some(extra) = extra {
+                    let mut merged = extra.clone();
+                    merged.extend(field.take().unwrap_or_default());
+                    *field = Some(merged);
+                }
+            }
+        }
+        ours
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClaimRetentionPolicy {
+    /// `Retain` (default) or `Delete` claims when the StatefulSet is scaled
+    /// down
+    pub when_scaled: Option<String>,
+
+    /// `Retain` (default) or `Delete` claims when the StatefulSet is deleted
+    pub when_deleted: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct UpdateStrategy {
+    /// `RollingUpdate` (default) to replace the replicas one at a time, or
+    /// `OnDelete` to only update a replica when its pod is deleted
+    /// (scheduled restarts included)
+    pub r#type: Option<String>,
+
+    /// with RollingUpdate, only replicas with an ordinal of at least this are
+    /// updated, lower it to advance the rollout (default = 0)
+    pub partition: Option<i32>,
+}
+
+impl UpdateStrategy {
+    /// the StatefulSet update strategy, or an error for an unknown type
+    fn statefulset(&self) -> Result<StatefulSetUpdateStrategy, Error> {
+        let type_ = self
+            .r#type
+            .clone()
+            .unwrap_or_else(|| "RollingUpdate".into());
+        let rolling_update = match (type_.as_str(), self.partition) {
+            ("RollingUpdate", partition) => {
+                partition.map(|partition| RollingUpdateStatefulSetStrategy {
+                    partition: Some(partition.max(0)),
+                })
+            }
+            ("OnDelete", None) => None,
+            ("OnDelete", Some(_)) => {
+                return Err(MyceliumError(
+                    "updateStrategy.partition needs type RollingUpdate".into(),
+                ))
+            }
+            (other, _) => {
+                return Err(MyceliumError(format!(
+                    "unknown updateStrategy type {}",
+                    other
+                )))
+            }
+        };
+        Ok(StatefulSetUpdateStrategy {
+            type_: Some(type_),
+            rolling_update,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct StorageOptions {
+    /// back the minecraft root with an emptyDir that is lost when the pod is
+    /// deleted
+    pub ephemeral: Option<EphemeralStorage>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EphemeralStorage {
+    /// maximum size of the emptyDir before the pod is evicted
+    pub size_limit: Option<Quantity>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct RunnerOptions {
     /// server jar to download and run
     pub jar: VersionTriple,
 
-    /// space separated options to pass to the JVM (i.e. -Dsomething=something -Dother=other)
+    /// `mycelium` (default) to use the mycelium runner, or `itzg` to run the
+    /// itzg/minecraft-server (or itzg/mc-proxy) image with an equivalent
+    /// environment
+    pub runner_mode: Option<String>,
+
+    /// space separated options to pass to the JVM (i.e. -Dsomething=something
+    /// -Dother=other), where the mycelium runner fills in ${POD_NAME} and
+    /// ${POD_ORDINAL} (StatefulSets only) like in motd, serverProperties
+    /// and velocityConfig
     pub jvm: Option<String>,
 
+    /// server.properties settings applied over the defaults on every start,
+    /// i.e. `level-name: world-${POD_ORDINAL}` (servers only, not with the
+    /// itzg runner)
+    pub server_properties: Option<BTreeMap<String, String>>,
+
+    /// Java major version to run the server with (i.e. 17 or 21), which picks
+    /// the runner image built for it
+    pub java_version: Option<u32>,
+
+    /// MiB that must be free on the data volume for the runner to start the
+    /// server, not checked if unset (not with the itzg runner)
+    pub min_free_disk_mb: Option<u64>,
+
+    /// message of the day shown in the server list
+    pub motd: Option<String>,
+
+    /// player limit shown in the server list and enforced on join
+    pub max_players: Option<u32>,
+
     /// configmaps to mount inside the minecraft root
     pub config: Option<Vec<ConfigOptions>>,
 
-    /// list of plugin URLs to download on server start
-    pub plugins: Option<Vec<String>>,
+    /// plugins to download on server start, as URLs or `{url, sha256,
+    /// filename}` entries, or `{hangar: author/slug@version}` for plugins
+    /// published on Hangar
+    pub plugins: Option<Vec<PluginEntry>>,
+
+    /// mods to download into `mods/` on server start (for modded servers), as
+    /// URLs or `{url, sha256, filename}` entries
+    pub mods: Option<Vec<PluginEntry>>,
+
+    /// configmaps copied into `plugins/<plugin>/` after the plugins are
+    /// downloaded
+    pub plugin_configs: Option<Vec<plugins::PluginConfig>>,
+
+    /// plugins already in the cluster, mounted into the plugins directory
+    /// instead of downloaded
+    pub plugin_volumes: Option<Vec<plugins::PluginVolume>>,
+
+    /// world to unpack into the world directory on first boot, while it has no
+    /// level.dat
+    pub world_source: Option<WorldSource>,
+
+    /// URL of a .zip or .tar(.gz) world archive to unpack on first boot, the
+    /// same as worldSource.url
+    pub world_url: Option<String>,
+
+    /// players allowed to join, written to whitelist.json with white-list=true
+    pub whitelist: Option<access::WhitelistOptions>,
+
+    /// server operators, written to ops.json (ops granted in game are kept)
+    pub ops: Option<Vec<access::OpEntry>>,
+
+    /// players banned from every replica, written to banned-players.json (bans
+    /// made in game are kept)
+    pub banned_players: Option<Vec<access::PlayerBan>>,
+
+    /// addresses banned from every replica, written to banned-ips.json
+    pub banned_ips: Option<Vec<access::IpBan>>,
+
+    /// velocity.toml settings by dotted key (i.e. `advanced.haproxy-protocol`)
+    /// with TOML values (i.e. `true`, `"legacy"`), applied over the
+    /// defaults (proxies only)
+    pub velocity_config: Option<BTreeMap<String, String>>,
+
+    /// cron schedule (in UTC, i.e. `0 6 * * *`) to restart the replicas on, one
+    /// at a time (servers only)
+    pub restart_schedule: Option<String>,
+
+    /// seconds before a scheduled restart to warn players in chat (default 300,
+    /// 60 and 10)
+    pub restart_warnings: Option<Vec<u32>>,
+
+    /// console commands the runner sends on a schedule, i.e. a nightly
+    /// `save-all` (not with the itzg runner)
+    pub scheduled_commands: Option<Vec<ScheduledCommand>>,
+
+    /// console commands or shell snippets the runner runs once the server is
+    /// done loading and before it is stopped (not with the itzg runner)
+    pub hooks: Option<Hooks>,
+}
+
+impl RunnerOptions {
+    /// the world archive to unpack on first boot, from worldUrl or worldSource
+    pub fn world_url(&self) -> Option<&str> {
+        self.world_url
+            .as_deref()
+            .or_else(|| self.world_source.as_ref().map(|w| w.url.as_str()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StartupTimings {
+    /// pod that reported the timings
+    pub replica: String,
+
+    /// when the timings were reported
+    pub reported_at: Option<DateTime<Utc>>,
+
+    /// seconds spent copying config and configuring the server
+    pub configure_seconds: f64,
+
+    /// seconds spent importing the world, if there was one to import
+    pub world_import_seconds: f64,
+
+    /// seconds spent downloading the jar and plugins
+    pub download_seconds: f64,
+
+    /// seconds from starting java until the server was done loading
+    pub startup_seconds: f64,
 }
 
+/// The build picked for a jar without one, kept until its version or channel
+/// changes. For vanilla jars it's the download Mojang's manifest points to
+/// instead, kept until the version changes so Mojang isn't asked on every
+/// reconcile.
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedBuild {
+    pub r#type: String,
+    pub version: String,
+    pub channel: String,
+    pub build: String,
+
+    /// when autoUpdate last checked for a newer build
+    pub checked_at: Option<DateTime<Utc>>,
+
+    /// build autoUpdate replaced, to pin `build` to when rolling back
+    pub previous_build: Option<String>,
+
+    /// where a vanilla jar downloads from
+    pub url: Option<String>,
+
+    /// `<algorithm>:<hex digest>` of a vanilla jar, i.e. `sha1:...`
+    pub checksum: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct ScheduledCommand {
+    /// cron schedule (in UTC, i.e. `0 4 * * *`) to send the command on
+    pub schedule: String,
+
+    /// console command without the slash, i.e. `say Welcome!` or `whitelist
+    /// reload`
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    /// run in order once the server is done loading, i.e. `whitelist reload`
+    pub post_start: Option<Vec<Hook>>,
+
+    /// run in order when the pod is asked to stop, before the server is, i.e. a
+    /// `broadcast` followed by `kickall` (within the pod's
+    /// terminationGracePeriodSeconds)
+    pub pre_stop: Option<Vec<Hook>>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Hook {
+    /// console command without the slash, sent to the server
+    pub command: Option<String>,
+
+    /// shell snippet run with `sh -c` in the server root, instead of a command
+    pub shell: Option<String>,
+
+    /// seconds to wait after it before the next hook, i.e. so players can read
+    /// a broadcast
+    pub wait_seconds: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct WorldSource {
+    /// URL of a .zip or .tar(.gz) archive containing a world (with a level.dat)
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct VersionTriple {
-    /// type of jar (currently only `paper` or `velocity` is supported)
+    /// type of jar (currently `paper`, `vanilla`, `velocity`, `waterfall` or
+    /// `custom`)
     pub r#type: String,
 
-    /// version according to the PaperMC API
+    /// version according to the PaperMC API (ignored for custom)
+    #[serde(default)]
     pub version: String,
 
-    /// build according to the PaperMC API
+    /// build according to the PaperMC API (ignored for vanilla and custom), or
+    /// empty to use the newest build on `channel`
+    #[serde(default)]
     pub build: String,
+
+    /// release channel to pick the newest build from when `build` is omitted,
+    /// `stable` (default) or `experimental`
+    pub channel: Option<String>,
+
+    /// check for a newer build on `channel` every hour and roll it out, instead
+    /// of keeping the first build resolved (only when `build` is omitted)
+    pub auto_update: Option<bool>,
+
+    /// where to download a custom jar from (i.e. a Paper fork or a private
+    /// mirror)
+    pub url: Option<String>,
+
+    /// hex encoded SHA-256 the runner verifies a custom jar against
+    pub sha256: Option<String>,
+}
+
+impl VersionTriple {
+    /// type/version/build, as recorded in the mycelium.njha.dev/jar annotation.
+    /// Custom jars are identified by their checksum, or their URL without
+    /// one.
+    pub fn id(&self) -> String {
+        match (self.r#type.as_str(), &self.sha256, &self.url) {
+            ("custom", Some(sha256), _) => format!("custom/{}", sha256),
+            ("custom", None, Some(url)) => format!("custom/{}", url),
+            _ => format!("{}/{}/{}", self.r#type, self.version, self.build),
+        }
+    }
+
+    /// whether the jar is a proxy rather than a game server
+    pub fn is_proxy(&self) -> bool {
+        matches!(self.r#type.as_str(), "velocity" | "waterfall")
+    }
 }
 
 pub fn make_volume_mount(co: &ConfigOptions) -> VolumeMount {
-    return VolumeMount {
+    VolumeMount {
         name: co.name.clone(),
         mount_path: String::from(
             Path::new("/config/")
@@ -123,8 +704,9 @@ pub fn make_volume_mount(co: &ConfigOptions) -> VolumeMount {
                 .to_str()
                 .expect("mount path"),
         ),
+        sub_path: co.key.clone(),
         ..VolumeMount::default()
-    };
+    }
 }
 
 pub fn make_volume(co: &ConfigOptions) -> Volume {
@@ -138,30 +720,212 @@ pub fn make_volume(co: &ConfigOptions) -> Volume {
     }
 }
 
+/// configmaps and secrets of the user's that a spec mounts, which are watched
+/// so that editing one reconciles whatever uses it
+#[derive(Default, Debug, PartialEq)]
+pub struct References {
+    pub config_maps: BTreeSet<String>,
+    pub secrets: BTreeSet<String>,
+}
+
+impl References {
+    /// what the runner and container options of a set or proxy mount
+    pub fn of(runner: &RunnerOptions, container: Option<&ContainerOptions>) -> References {
+        let mut refs = References::default();
+        refs.config_maps
+            .extend(runner.config.iter().flatten().map(|c| c.name.clone()));
+        refs.config_maps.extend(
+            runner
+                .plugin_configs
+                .iter()
+                .flatten()
+                .map(|c| c.config_map.clone()),
+        );
+        for plugin in runner.plugin_volumes.iter().flatten() {
+            refs.config_maps.extend(plugin.config_map.clone());
+            refs.secrets.extend(plugin.secret.clone());
+        }
+        for volume in container
+            .and_then(|c| c.extra_volumes.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            refs.config_maps
+                .extend(volume.config_map.as_ref().and_then(|cm| cm.name.clone()));
+            refs.secrets
+                .extend(volume.secret.as_ref().and_then(|s| s.secret_name.clone()));
+        }
+        refs
+    }
+}
+
+/// annotation on the pod template with a hash of its env and the configmaps it
+/// mounts, so editing one of them rolls the replicas
+pub const CONFIG_HASH_ANNOTATION: &str = "mycelium.njha.dev/config-hash";
+
+/// names of the configmaps a pod template mounts
+pub fn mounted_config_maps(template: &PodTemplateSpec) -> Vec<String> {
+    let mut names: Vec<String> = template
+        .spec
+        .iter()
+        .flat_map(|spec| spec.volumes.iter().flatten())
+        .filter_map(|v| v.config_map.as_ref().and_then(|cm| cm.name.clone()))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Hashes the env of a pod template and the contents of its configmaps. The
+/// configmaps are read by the server on start, so a change has to restart the
+/// replicas to take effect.
+pub fn config_hash(template: &PodTemplateSpec, config_maps: &[ConfigMap]) -> String {
+    let mut hash = Sha224::new();
+    for container in template.spec.iter().flat_map(|spec| &spec.containers) {
+        for env in container.env.iter().flatten() {
+            hash.update(serde_json::to_vec(env).unwrap_or_default());
+        }
+    }
+    let mut config_maps: Vec<&ConfigMap> = config_maps.iter().collect();
+    config_maps.sort_by_key(|cm| cm.metadata.name.clone());
+    for cm in config_maps {
+        hash.update(cm.metadata.name.clone().unwrap_or_default());
+        hash.update(serde_json::to_vec(&cm.data).unwrap_or_default());
+        hash.update(serde_json::to_vec(&cm.binary_data).unwrap_or_default());
+    }
+    format!("{:x}", hash.finalize())
+}
+
 pub fn object_to_owner_reference<K: Resource<DynamicType = ()>>(
     meta: ObjectMeta,
 ) -> Result<OwnerReference, Error> {
     Ok(OwnerReference {
         api_version: K::api_version(&()).to_string(),
         kind: K::kind(&()).to_string(),
-        name: meta.name.ok_or_else(|| MyceliumError("failed to get name".into()))?,
-        uid: meta.uid.ok_or_else(|| MyceliumError("failed to get uid".into()))?,
+        name: meta
+            .name
+            .ok_or_else(|| MyceliumError("failed to get name".into()))?,
+        uid: meta
+            .uid
+            .ok_or_else(|| MyceliumError("failed to get uid".into()))?,
         ..OwnerReference::default()
     })
 }
 
-pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
+/// label on per-replica Services, set to the name of their CRD
+const REPLICA_SERVICE_LABEL: &str = "mycelium.njha.dev/replica-service";
+
+/// seconds a server gets to stop before it is killed, if not configured
+const DEFAULT_SERVER_GRACE_SECONDS: i64 = 300;
+
+/// seconds a proxy gets to stop before it is killed, if not configured
+const DEFAULT_PROXY_GRACE_SECONDS: i64 = 60;
+
+/// file the runner creates once the server is done loading, see READY_FILE in
+/// the runner
+const READY_FILE: &str = "/tmp/mycelium-ready";
+
+/// Readiness of the java container. The mycelium runner creates READY_FILE once
+/// the server logs that it's done loading, itzg servers are pinged with
+/// mc-health, and itzg proxies only get their port checked.
+fn readiness_probe(itzg: bool, proxy: bool, port: &IntOrString) -> Probe {
+    let command = |command: &[&str]| ExecAction {
+        command: Some(command.iter().map(|s| s.to_string()).collect()),
+    };
+    let (exec, tcp_socket) = match (itzg, proxy) {
+        (false, _) => (Some(command(&["test", "-f", READY_FILE])), None),
+        (true, false) => (Some(command(&["mc-health"])), None),
+        (true, true) => (
+            None,
+            Some(TCPSocketAction {
+                port: port.clone(),
+                host: None,
+            }),
+        ),
+    };
+    Probe {
+        exec,
+        tcp_socket,
+        period_seconds: Some(5),
+        ..Probe::default()
+    }
+}
+
+/// the objects generic_reconcile applies for a MinecraftSet or MinecraftProxy,
+/// built without talking to the cluster so that they can be unit tested
+#[derive(Debug)]
+pub struct DesiredObjects {
+    /// labels selecting the pods of this CRD
+    pub labels: BTreeMap<String, String>,
+    /// jar identifier (type/version/build) the pods run
+    pub jar: String,
+    /// whether the pods run as a StatefulSet (true) or a Deployment (false)
+    pub stateful: bool,
+    /// volume claim templates of the StatefulSet
+    pub claim_templates: Vec<PersistentVolumeClaim>,
+    pub statefulset: StatefulSet,
+    pub deployment: Deployment,
+    pub pdb: PodDisruptionBudget,
+    pub service: Service,
+    /// one Service per replica, if replicaServices is set
+    pub replica_services: Vec<Service>,
+    /// claim for the shared download cache, if one is provisioned
+    pub download_cache: Option<PersistentVolumeClaim>,
+    pub secret: Secret,
+    /// whitelist, ops and bans for the runner
+    pub access: ConfigMap,
+}
+
+/// rewrites a download URL to go through the artifact cache, if enabled
+pub fn artifact_url(cache: Option<&ArtifactCache>, url: &str) -> String {
+    match cache {
+        Some(cache) => cache.rewrite(url),
+        None => url.to_string(),
+    }
+}
+
+/// Publishes a Warning event on a CRD. Events are informational, so failing to
+/// publish one is only logged rather than failing the reconcile.
+async fn publish_warning<T: Resource<DynamicType = ()>>(
+    client: Client,
+    crd: &T,
+    reason: &str,
+    note: String,
+) {
+    let reporter = Reporter {
+        controller: "mycelium-operator".into(),
+        instance: None,
+    };
+    let event = events::Event {
+        type_: EventType::Warning,
+        reason: reason.into(),
+        note: Some(note),
+        action: "Reconciling".into(),
+        secondary: None,
+    };
+    if let Err(e) = Recorder::new(client, reporter, crd.object_ref(&()))
+        .publish(event)
+        .await
+    {
+        warn!("couldn't publish {} event: {}", reason, e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn desired_objects<T: Resource<DynamicType = ()>>(
     env: Vec<EnvVar>,
     port: IntOrString,
-    ctx: Context<Data>,
-    shortname: String,
-    crd: T,
-    container: ContainerOptions,
-    runner: RunnerOptions,
+    config: &MyceliumConfig,
+    cache: Option<&ArtifactCache>,
+    shortname: &str,
+    crd: &T,
+    container: &ContainerOptions,
+    runner: &RunnerOptions,
+    download: &JarDownload,
     replicas: i32,
-) -> Result<(), Error> {
-    let name = ResourceExt::name(&crd);
-    let ns = ResourceExt::namespace(&crd)
+) -> Result<DesiredObjects, Error> {
+    let name = ResourceExt::name(crd);
+    let ns = ResourceExt::namespace(crd)
         .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
 
     let owner_reference = OwnerReference {
@@ -169,29 +933,54 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
         ..object_to_owner_reference::<T>(crd.meta().clone())?
     };
 
-    let client = ctx.get_ref().client.clone();
-    // Note: This will only error with PoisonError, which is unrecoverable and so we
-    // should panic.
-    ctx.get_ref().state.write().expect("last_event").last_event = Utc::now();
-
-    let labels = BTreeMap::from_iter(IntoIter::new([(
-        format!("mycelium.njha.dev/{}", shortname),
-        name.clone(),
-    )]));
-    let configs = runner.config.unwrap_or_default();
+    let labels = BTreeMap::from([(format!("mycelium.njha.dev/{}", shortname), name.clone())]);
+    let jar = runner.jar.id();
+    let configs = runner.config.clone().unwrap_or_default();
     let mut volume_mounts: Vec<VolumeMount> = configs.iter().map(make_volume_mount).collect();
-    let mut volumes: Vec<Volume> = configs.iter().map(make_volume).collect();
-    let mut tpl_volume: Vec<PersistentVolumeClaim> = vec![];
+    // a configmap mounted more than once (i.e. by key) is still one volume
+    let mut volumes: Vec<Volume> = vec![];
+    for volume in configs.iter().map(make_volume) {
+        if !volumes.iter().any(|v| v.name == volume.name) {
+            volumes.push(volume);
+        }
+    }
+    let mut claim_templates: Vec<PersistentVolumeClaim> = vec![];
 
-    if let Some(volume_tpl) = container.volume_claim_template {
+    // stateless servers always get ephemeral storage, since a Deployment can't
+    // have volume claim templates
+    let stateful = container.stateful.unwrap_or(true);
+    let ephemeral = container
+        .storage
+        .clone()
+        .and_then(|s| s.ephemeral)
+        .or_else(|| (!stateful).then(EphemeralStorage::default));
+    let ephemeral_root = ephemeral.is_some();
+    if let Some(ephemeral) = ephemeral {
+        volumes.push(Volume {
+            name: "data".to_string(),
+            empty_dir: Some(EmptyDirVolumeSource {
+                medium: None,
+                size_limit: ephemeral.size_limit,
+            }),
+            ..Volume::default()
+        });
         volume_mounts.push(VolumeMount {
             mount_path: "/data".to_string(),
-            name: volume_tpl.metadata.clone().name
+            name: "data".to_string(),
+            ..VolumeMount::default()
+        });
+    } else if let Some(volume_tpl) = container.volume_claim_template.clone() {
+        volume_mounts.push(VolumeMount {
+            mount_path: "/data".to_string(),
+            name: volume_tpl
+                .metadata
+                .clone()
+                .name
                 .ok_or_else(|| MyceliumError("volumeClaimTemplate name".into()))?,
             ..VolumeMount::default()
         });
-        tpl_volume.push(volume_tpl);
-    } else if let Some(volume) = container.volume {
+        claim_templates.push(volume_tpl);
+    } else if let Some(volume) = container.volume.clone() {
         let name = volume.name.clone();
         volumes.push(volume);
         volume_mounts.push(VolumeMount {
@@ -200,41 +989,410 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
             ..VolumeMount::default()
         });
     }
+    // worlds go on their own claim, mounted next to the root rather than inside it
+    let world_container = match container.world_volume_claim_template.clone() {
+        Some(world_tpl) if !ephemeral_root && !runner.jar.is_proxy() => {
+            let world_name = world_tpl
+                .metadata
+                .name
+                .clone()
+                .ok_or_else(|| MyceliumError("worldVolumeClaimTemplate name".into()))?;
+            if claim_templates
+                .iter()
+                .any(|t| t.metadata.name.as_deref() == Some(world_name.as_str()))
+            {
+                return Err(MyceliumError(format!(
+                    "worldVolumeClaimTemplate {} has the same name as volumeClaimTemplate",
+                    world_name
+                )));
+            }
+            volume_mounts.push(VolumeMount {
+                mount_path: storage::WORLDS_PATH.to_string(),
+                name: world_name,
+                ..VolumeMount::default()
+            });
+            claim_templates.push(world_tpl);
+            Some(storage::WORLDS_PATH)
+        }
+        _ => None,
+    };
+    let (access_volume, access_mount) = access::volume(&name);
+    volumes.push(access_volume);
+    volume_mounts.push(access_mount);
+    for (volume, mount) in plugins::volumes(runner)
+        .into_iter()
+        .chain(plugins::config_volumes(runner))
+    {
+        volumes.push(volume);
+        volume_mounts.push(mount);
+    }
+    if let Some(cache) = &container.download_cache {
+        let (volume, mount) = storage::download_cache_volume(&name, cache);
+        volumes.push(volume);
+        volume_mounts.push(mount);
+    }
+    let shared_world = container
+        .shared_world
+        .as_ref()
+        .filter(|_| !runner.jar.is_proxy());
+    if let Some(shared) = shared_world {
+        let (volume, mount) = storage::shared_world_volume(shared);
+        volumes.push(volume);
+        volume_mounts.push(mount);
+    }
+    volumes.extend(container.extra_volumes.clone().unwrap_or_default());
+    volume_mounts.extend(container.extra_volume_mounts.clone().unwrap_or_default());
 
-    let env: Vec<EnvVar> = vec![
-        EnvVar {
-            name: String::from("MYCELIUM_JVM_OPTS"),
-            value: runner.jvm,
-            value_from: None,
-        },
-        EnvVar {
-            name: String::from("MYCELIUM_FW_TOKEN"),
-            value: None,
-            value_from: Some(EnvVarSource {
-                secret_key_ref: Some(SecretKeySelector {
-                    key: "forwarding_token".to_string(),
-                    name: Some(name.clone()),
-                    optional: Some(false)
+    if !config.allows_jar(&runner.jar.r#type) {
+        return Err(MyceliumError(format!(
+            "jar type {} is not in allowedJarTypes",
+            runner.jar.r#type
+        )));
+    }
+    let itzg = runner.runner_mode.as_deref() == Some("itzg");
+    let image = config.image(runner);
+
+    // hangar plugins come from hangar, wherever it stores the jars
+    let hangar_api = hangar::api_base();
+    for entry in runner.plugins.iter().chain(runner.mods.iter()).flatten() {
+        let url = match entry {
+            PluginEntry::Source(PluginSource {
+                hangar: Some(_), ..
+            }) => &hangar_api,
+            PluginEntry::Source(PluginSource { url, .. }) | PluginEntry::Url(url) => url,
+        };
+        if !config.allows_download(url) {
+            return Err(MyceliumError(format!(
+                "{} is not on a host in allowedPluginHosts",
+                url
+            )));
+        }
+    }
+
+    let mods: Vec<PluginSource> = runner
+        .mods
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .map(PluginSource::from)
+        .map(|m| PluginSource {
+            url: artifact_url(cache, &m.url),
+            ..m
+        })
+        .collect();
+
+    // bad schedules are caught here rather than ignored by every replica
+    for command in runner.scheduled_commands.iter().flatten() {
+        backup::parse_schedule(&command.schedule)?;
+    }
+    let hooks = runner
+        .hooks
+        .iter()
+        .flat_map(|h| h.post_start.iter().chain(h.pre_stop.iter()).flatten());
+    for hook in hooks {
+        if hook.command.is_some() == hook.shell.is_some() {
+            return Err(MyceliumError(
+                "hooks need exactly one of command or shell".into(),
+            ));
+        }
+    }
+
+    let env: Vec<EnvVar> = if itzg {
+        let plugins = env
+            .iter()
+            .find(|e| e.name == "MYCELIUM_PLUGINS")
+            .and_then(|e| e.value.as_deref())
+            .and_then(|p| serde_json::from_str::<Vec<PluginSource>>(p).ok())
+            .map(|p| p.into_iter().map(|p| p.url).collect::<Vec<_>>().join(","));
+        let mods = mods
+            .iter()
+            .map(|m| m.url.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut env = itzg::itzg_env(&name, runner, plugins, Some(mods));
+        if let Some(worlds) = world_container {
+            env.push(EnvVar {
+                name: String::from("EXTRA_ARGS"),
+                value: Some(format!("--universe {}", worlds)),
+                value_from: None,
+            });
+        }
+        if shared_world.is_some() {
+            // the image copies WORLD in before starting, FORCE_WORLD_COPY makes that every
+            // start
+            env.retain(|e| e.name != "WORLD");
+            for (key, value) in [
+                ("WORLD", storage::SHARED_WORLD_PATH),
+                ("FORCE_WORLD_COPY", "TRUE"),
+            ] {
+                env.push(EnvVar {
+                    name: String::from(key),
+                    value: Some(value.into()),
+                    value_from: None,
+                });
+            }
+        }
+        env
+    } else {
+        vec![
+            EnvVar {
+                name: String::from("MYCELIUM_JVM_OPTS"),
+                value: runner.jvm.clone(),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_FW_TOKEN"),
+                value: None,
+                value_from: Some(EnvVarSource {
+                    secret_key_ref: Some(SecretKeySelector {
+                        key: "forwarding_token".to_string(),
+                        name: Some(name.clone()),
+                        optional: Some(false),
+                    }),
+                    ..EnvVarSource::default()
                 }),
-                ..EnvVarSource::default()
-            }),
-        },
-        EnvVar {
-            name: String::from("MYCELIUM_RUNNER_JAR_URL"),
-            value: Some(get_download_url(
-                &runner.jar.r#type,
-                &runner.jar.version,
-                &runner.jar.build,
-            )),
-            value_from: None,
-        },
-    ].into_iter().chain(env).collect();
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_DOWNLOAD_CACHE"),
+                value: container
+                    .download_cache
+                    .as_ref()
+                    .map(|_| storage::DOWNLOAD_CACHE_PATH.into()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_MODS"),
+                value: Some(serde_json::to_string(&mods).map_err(Error::SerializationError)?),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_WORLD_CONTAINER"),
+                value: world_container.map(String::from),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_SHARED_WORLD"),
+                value: shared_world.map(|_| storage::SHARED_WORLD_PATH.into()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_WORLD_URL"),
+                value: runner.world_url().map(|url| artifact_url(cache, url)),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_ENDPOINT"),
+                value: Some(config.endpoint.clone()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_TLS_CA"),
+                value: config.tls_ca.clone(),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("K8S_NAMESPACE"),
+                value: Some(ns.clone()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("K8S_NAME"),
+                value: Some(name.clone()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_ACCESS_PATH"),
+                value: Some(access::ACCESS_PATH.into()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_WHITELIST_ENFORCE"),
+                value: runner
+                    .whitelist
+                    .as_ref()
+                    .and_then(|w| w.enforce)
+                    .map(|e| e.to_string()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_VELOCITY_CONFIG"),
+                value: runner
+                    .velocity_config
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(Error::SerializationError)?,
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_SCHEDULED_COMMANDS"),
+                value: runner
+                    .scheduled_commands
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(Error::SerializationError)?,
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_HOOKS"),
+                value: runner
+                    .hooks
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(Error::SerializationError)?,
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_SERVER_PROPERTIES"),
+                value: runner
+                    .server_properties
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(Error::SerializationError)?,
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("POD_NAME"),
+                value: None,
+                value_from: Some(EnvVarSource {
+                    field_ref: Some(ObjectFieldSelector {
+                        field_path: "metadata.name".into(),
+                        api_version: None,
+                    }),
+                    ..EnvVarSource::default()
+                }),
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_MOTD"),
+                value: runner.motd.clone(),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_MAX_PLAYERS"),
+                value: runner.max_players.map(|m| m.to_string()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_JAVA_VERSION"),
+                value: runner.java_version.map(|v| v.to_string()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_MIN_FREE_DISK_MB"),
+                value: runner.min_free_disk_mb.map(|m| m.to_string()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_RUNNER_JAR_TYPE"),
+                value: Some(runner.jar.r#type.clone()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_RUNNER_JAR_VERSION"),
+                value: Some(runner.jar.version.clone()),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_RUNNER_JAR_URL"),
+                value: Some(artifact_url(cache, &download.url)),
+                value_from: None,
+            },
+            EnvVar {
+                name: String::from("MYCELIUM_RUNNER_JAR_CHECKSUM"),
+                value: download.checksum.clone(),
+                value_from: None,
+            },
+        ]
+        .into_iter()
+        .chain(env)
+        .collect()
+    };
+    let overrides = container.metadata.clone().unwrap_or_default();
+    // what the set or proxy propagates goes under the overrides, which go under our
+    // own keys
+    let inherited = config.inherited(crd.meta());
+    let metadata = |overrides: Option<&MetadataOverrides>, ours: ObjectMeta| {
+        MetadataOverrides::apply(Some(&inherited), MetadataOverrides::apply(overrides, ours))
+    };
+    let template = PodTemplateSpec {
+        metadata: Some(metadata(overrides.pod.as_ref(), ObjectMeta {
+            // game servers are kept from being evicted until they're drained
+            labels: Some(
+                labels
+                    .clone()
+                    .into_iter()
+                    .chain(
+                        (!runner.jar.is_proxy())
+                            .then(|| (drain::DESTROYABLE_LABEL.to_string(), "false".to_string())),
+                    )
+                    .collect(),
+            ),
+            annotations: Some(
+                vec![
+                    ("prometheus.io/port".into(), "9970".into()),
+                    ("prometheus.io/scrape".into(), "true".into()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            ..ObjectMeta::default()
+        })),
+        spec: Some(PodSpec {
+            security_context: container.security_context.clone(),
+            containers: vec![Container {
+                name: name.clone(),
+                tty: Some(true),
+                stdin: Some(true),
+                image: Some(image),
+                image_pull_policy: Some(String::from("IfNotPresent")),
+                resources: container.resources.clone(),
+                env: Some(env),
+                volume_mounts: Some(volume_mounts),
+                readiness_probe: Some(readiness_probe(itzg, runner.jar.is_proxy(), &port)),
+                lifecycle: container.pre_stop.clone().map(|command| Lifecycle {
+                    pre_stop: Some(Handler {
+                        exec: Some(ExecAction {
+                            command: Some(command),
+                        }),
+                        ..Handler::default()
+                    }),
+                    post_start: None,
+                }),
+                ..Container::default()
+            }],
+            termination_grace_period_seconds: Some(
+                container
+                    .termination_grace_period_seconds
+                    .unwrap_or(if runner.jar.is_proxy() {
+                        DEFAULT_PROXY_GRACE_SECONDS
+                    } else {
+                        DEFAULT_SERVER_GRACE_SECONDS
+                    }),
+            ),
+            volumes: Some(volumes),
+            node_selector: container.node_selector.clone(),
+            affinity: container.affinity.clone(),
+            tolerations: container.tolerations.clone(),
+            topology_spread_constraints: container.topology_spread_constraints.clone(),
+            priority_class_name: container.priority_class_name.clone(),
+            scheduler_name: container.scheduler_name.clone(),
+            ..PodSpec::default()
+        }),
+    };
+
     let statefulset = StatefulSet {
-        metadata: ObjectMeta {
+        metadata: metadata(overrides.workload.as_ref(), ObjectMeta {
             name: Some(name.clone()),
+            annotations: Some(BTreeMap::from([(
+                "mycelium.njha.dev/jar".to_string(),
+                jar.clone(),
+            )])),
             owner_references: Some(vec![owner_reference.clone()]),
             ..ObjectMeta::default()
-        },
+        }),
         spec: Some(StatefulSetSpec {
             selector: LabelSelector {
                 match_labels: Some(labels.clone()),
@@ -242,37 +1400,42 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
             },
             service_name: name.clone(),
             replicas: Some(replicas),
-            template: PodTemplateSpec {
-                metadata: Some(ObjectMeta {
-                    labels: Some(labels.clone()),
-                    annotations: Some(vec![("prometheus.io/port".into(), "9970".into()),
-                                           ("prometheus.io/scrape".into(), "true".into())]
-                        .into_iter().collect()),
-                    ..ObjectMeta::default()
-                }),
-                spec: Some(PodSpec {
-                    security_context: container.security_context,
-                    containers: vec![Container {
-                        name: name.clone(),
-                        tty: Some(true),
-                        stdin: Some(true),
-                        image: Some(String::from(&ctx.get_ref().config.runner_image)),
-                        image_pull_policy: Some(String::from("IfNotPresent")),
-                        resources: container.resources,
-                        env: Some(env),
-                        volume_mounts: Some(volume_mounts),
-                        ..Container::default()
-                    }],
-                    volumes: Some(volumes),
-                    ..PodSpec::default()
-                }),
-            },
-            volume_claim_templates: Some(tpl_volume),
+            template: template.clone(),
+            volume_claim_templates: Some(claim_templates.clone()),
+            update_strategy: container
+                .update_strategy
+                .as_ref()
+                .map(UpdateStrategy::statefulset)
+                .transpose()?,
+            min_ready_seconds: container.min_ready_seconds,
             ..StatefulSetSpec::default()
         }),
         status: None,
     };
 
+    let deployment = Deployment {
+        metadata: metadata(overrides.workload.as_ref(), ObjectMeta {
+            name: Some(name.clone()),
+            annotations: Some(BTreeMap::from([(
+                "mycelium.njha.dev/jar".to_string(),
+                jar.clone(),
+            )])),
+            owner_references: Some(vec![owner_reference.clone()]),
+            ..ObjectMeta::default()
+        }),
+        spec: Some(DeploymentSpec {
+            selector: LabelSelector {
+                match_labels: Some(labels.clone()),
+                ..LabelSelector::default()
+            },
+            replicas: Some(replicas),
+            template,
+            min_ready_seconds: container.min_ready_seconds,
+            ..DeploymentSpec::default()
+        }),
+        status: None,
+    };
+
     let pdb = PodDisruptionBudget {
         metadata: ObjectMeta {
             name: Some(name.clone()),
@@ -284,10 +1447,15 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
             min_available: None,
             selector: Some(LabelSelector {
                 match_expressions: None,
-                match_labels: Some(labels
-                    .iter()
-                    .chain(vec![("mycelium.njha.dev/destroyable".to_string(), "false".to_string())])
-                    .collect()
+                match_labels: Some(
+                    labels
+                        .clone()
+                        .into_iter()
+                        .chain(vec![(
+                            drain::DESTROYABLE_LABEL.to_string(),
+                            "false".to_string(),
+                        )])
+                        .collect(),
                 ),
             }),
         }),
@@ -295,19 +1463,20 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
     };
 
     let service = Service {
-        metadata: ObjectMeta {
+        metadata: metadata(overrides.service.as_ref(), ObjectMeta {
             name: Some(name.clone()),
             owner_references: Some(vec![owner_reference.clone()]),
             ..ObjectMeta::default()
-        },
+        }),
         spec: Some(ServiceSpec {
             // https://kubernetes.io/docs/concepts/services-networking/service/#headless-services
-            cluster_ip: Some(String::from("None")),
-            selector: Some(labels),
+            // stateless servers are interchangeable, so they get a load balanced address instead
+            cluster_ip: stateful.then(|| String::from("None")),
+            selector: Some(labels.clone()),
             ports: Some(vec![ServicePort {
                 protocol: Some(String::from("TCP")),
                 port: 25565,
-                target_port: Some(port),
+                target_port: Some(port.clone()),
                 ..ServicePort::default()
             }]),
             ..ServiceSpec::default()
@@ -315,36 +1484,663 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
         status: None,
     };
 
-    let mut token = sha2::Sha224::new();
-    token.update(format!("{}{}", ctx.get_ref().config.forwarding_secret, ns.clone()).as_bytes());
-    let token = base64::encode(token.finalize());
+    // selected by pod name, which the StatefulSet controller labels every pod with
+    let replica_services = match container.replica_services.as_ref().filter(|_| stateful) {
+        Some(options) => (0..replicas)
+            .map(|ordinal| {
+                let replica = format!("{}-{}", name, ordinal);
+                Service {
+                    metadata: metadata(overrides.service.as_ref(), ObjectMeta {
+                        name: Some(replica.clone()),
+                        labels: Some(BTreeMap::from([(
+                            REPLICA_SERVICE_LABEL.to_string(),
+                            name.clone(),
+                        )])),
+                        owner_references: Some(vec![owner_reference.clone()]),
+                        ..ObjectMeta::default()
+                    }),
+                    spec: Some(ServiceSpec {
+                        type_: Some(options.r#type.clone().unwrap_or_else(|| "NodePort".into())),
+                        selector: Some(BTreeMap::from([(
+                            "statefulset.kubernetes.io/pod-name".to_string(),
+                            replica,
+                        )])),
+                        ports: Some(vec![ServicePort {
+                            protocol: Some(String::from("TCP")),
+                            port: 25565,
+                            target_port: Some(port.clone()),
+                            node_port: options.base_node_port.map(|base| base + ordinal),
+                            ..ServicePort::default()
+                        }]),
+                        ..ServiceSpec::default()
+                    }),
+                    status: None,
+                }
+            })
+            .collect(),
+        None => vec![],
+    };
+
+    let token = auth::namespace_token(&config.forwarding_secret, &ns);
+    let mut rcon_password = sha2::Sha224::new();
+    rcon_password.update(format!("rcon{}{}/{}", config.forwarding_secret, ns, name).as_bytes());
+    let rcon_password = base64::encode(rcon_password.finalize());
+    let mut access = access::config_map(&name, owner_reference.clone(), runner);
+    access.metadata = MetadataOverrides::apply(Some(&inherited), access.metadata);
+    let download_cache = container
+        .download_cache
+        .as_ref()
+        .and_then(|cache| storage::download_cache_claim(&name, owner_reference.clone(), cache));
     let secret = Secret {
-        metadata: ObjectMeta {
+        metadata: MetadataOverrides::apply(Some(&inherited), ObjectMeta {
             name: Some(name.clone()),
             owner_references: Some(vec![owner_reference]),
             ..ObjectMeta::default()
-        },
-        string_data: Some(vec![("forwarding_token".into(), token)]
-            .into_iter().collect()),
+        }),
+        string_data: Some(
+            vec![
+                ("forwarding_token".into(), token),
+                ("rcon_password".into(), rcon_password),
+            ]
+            .into_iter()
+            .collect(),
+        ),
         ..Secret::default()
     };
 
-    kube::Api::<PodDisruptionBudget>::namespaced(client.clone(), &ns)
-        .patch(
+    Ok(DesiredObjects {
+        labels,
+        jar,
+        stateful,
+        claim_templates,
+        statefulset,
+        deployment,
+        pdb,
+        service,
+        replica_services,
+        download_cache,
+        secret,
+        access,
+    })
+}
+
+/// replica counts and jar of the StatefulSet, Deployment or Fleet behind a CRD
+#[derive(Debug, Default)]
+pub struct WorkloadStatus {
+    pub replicas: i32,
+    pub ready_replicas: i32,
+    /// ready for minReadySeconds, the same as ready where StatefulSets don't
+    /// report it
+    pub available_replicas: i32,
+    pub updated_replicas: i32,
+    /// the mycelium.njha.dev/jar annotation
+    pub jar: Option<String>,
+    /// ControllerRevision every replica of a StatefulSet runs, none mid-rollout
+    pub revision: Option<String>,
+}
+
+/// reads back the workload generic_reconcile applied
+pub async fn workload_status(
+    client: Client,
+    ns: &str,
+    name: &str,
+    workload: Workload,
+) -> Result<WorkloadStatus, Error> {
+    let (meta, replicas, ready, available, updated, revision) = if workload == Workload::Fleet {
+        let fleet = match backend::fleet_api(client, ns).get(name).await {
+            Ok(fleet) => fleet,
+            Err(kube::Error::Api(e)) if e.code == 404 => return Ok(WorkloadStatus::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let count = |field: &str| fleet.data["status"][field].as_i64().map(|n| n as i32);
+        // allocated GameServers are still up, they just have players
+        let ready = count("readyReplicas").unwrap_or(0) + count("allocatedReplicas").unwrap_or(0);
+        // Fleets don't report how far a rollout got, only what is up
+        (
+            fleet.metadata,
+            count("replicas").unwrap_or(0),
+            Some(ready),
+            Some(ready),
+            Some(ready),
+            None,
+        )
+    } else if workload == Workload::StatefulSet {
+        let sts = match kube::Api::<StatefulSet>::namespaced(client, ns)
+            .get(name)
+            .await
+        {
+            Ok(sts) => sts,
+            Err(kube::Error::Api(e)) if e.code == 404 => return Ok(WorkloadStatus::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let status = sts.status.unwrap_or_default();
+        let revision = status
+            .current_revision
+            .filter(|current| status.update_revision.as_ref() == Some(current));
+        let available = status.available_replicas.or(status.ready_replicas);
+        (
+            sts.metadata,
+            status.replicas,
+            status.ready_replicas,
+            available,
+            status.updated_replicas,
+            revision,
+        )
+    } else {
+        let deploy = match kube::Api::<Deployment>::namespaced(client, ns)
+            .get(name)
+            .await
+        {
+            Ok(deploy) => deploy,
+            Err(kube::Error::Api(e)) if e.code == 404 => return Ok(WorkloadStatus::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let status = deploy.status.unwrap_or_default();
+        let replicas = status.replicas.unwrap_or(0);
+        (
+            deploy.metadata,
+            replicas,
+            status.ready_replicas,
+            status.available_replicas,
+            status.updated_replicas,
+            None,
+        )
+    };
+    Ok(WorkloadStatus {
+        replicas,
+        ready_replicas: ready.unwrap_or(0),
+        available_replicas: available.unwrap_or(0),
+        updated_replicas: updated.unwrap_or(0),
+        jar: meta
+            .annotations
+            .and_then(|a| a.get("mycelium.njha.dev/jar").cloned()),
+        revision,
+    })
+}
+
+/// whether a pod is ready to take players and isn't on its way out
+pub fn pod_ready(pod: &Pod) -> bool {
+    let conditions = pod.status.as_ref().and_then(|s| s.conditions.as_ref());
+    pod.metadata.deletion_timestamp.is_none()
+        && conditions
+            .into_iter()
+            .flatten()
+            .any(|c| c.type_ == "Ready" && c.status == "True")
+}
+
+/// annotation (set to "true") that leaves an object's generated resources
+/// alone, i.e. to hand-tune its StatefulSet while debugging, with only the
+/// status kept up to date
+pub const PAUSED_ANNOTATION: &str = "mycelium.njha.dev/paused";
+
+/// whether a set or proxy has the paused annotation
+pub fn paused<K: Resource>(obj: &K) -> bool {
+    obj.meta()
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(PAUSED_ANNOTATION))
+        .is_some_and(|p| p == "true")
+}
+
+/// Reconciles a paused set or proxy by only reporting its replicas, for the
+/// scale subresource. Nothing is requeued, removing the annotation brings it
+/// back.
+pub async fn observe<K>(
+    client: Client,
+    ns: &str,
+    name: &str,
+    workload: Workload,
+) -> Result<ReconcilerAction, Error>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    let workload = workload_status(client.clone(), ns, name, workload).await?;
+    Api::<K>::namespaced(client, ns)
+        .patch_status(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(serde_json::json!({ "status": {
+            "replicas": workload.replicas,
+            "readyReplicas": workload.ready_replicas,
+        } })),
+        )
+        .await?;
+    info!("{} \"{}\" in {} is paused", K::kind(&()), name, ns);
+    Ok(ReconcilerAction {
+        requeue_after: None,
+    })
+}
+
+/// Ready and Progressing conditions for a workload that should run `replicas`
+/// replicas of `jar`, keeping the transition time of conditions that didn't
+/// change
+pub fn workload_conditions(
+    previous: &[Condition],
+    workload: &WorkloadStatus,
+    replicas: i32,
+    jar: &str,
+    generation: Option<i64>,
+) -> Vec<Condition> {
+    let ready = workload.ready_replicas >= replicas;
+    // a rollout is only done once the new replicas have been ready for
+    // minReadySeconds
+    let progressing = workload.updated_replicas < replicas
+        || workload.available_replicas < replicas
+        || workload.jar.as_deref() != Some(jar);
+    [
+        (
+            "Ready",
+            ready,
+            if ready {
+                "ReplicasReady"
+            } else {
+                "ReplicasNotReady"
+            },
+            format!("{}/{} replicas ready", workload.ready_replicas, replicas),
+        ),
+        (
+            "Progressing",
+            progressing,
+            if progressing {
+                "RollingOut"
+            } else {
+                "RolloutComplete"
+            },
+            format!(
+                "{}/{} replicas run {}, {} available",
+                workload.updated_replicas, replicas, jar, workload.available_replicas,
+            ),
+        ),
+    ]
+    .into_iter()
+    .map(|(type_, status, reason, message)| {
+        condition(previous, type_, status, reason, message, generation)
+    })
+    .collect()
+}
+
+/// the Resizing condition, true while claims are still being expanded after the
+/// storage request in volumeClaimTemplate grew
+pub fn resizing_condition(
+    previous: &[Condition],
+    resizing: &[String],
+    generation: Option<i64>,
+) -> Condition {
+    let message = if resizing.is_empty() {
+        "no claims are being resized".to_string()
+    } else {
+        format!("resizing {}", resizing.join(", "))
+    };
+    let reason = if resizing.is_empty() {
+        "ClaimsResized"
+    } else {
+        "ClaimsResizing"
+    };
+    condition(
+        previous,
+        "Resizing",
+        !resizing.is_empty(),
+        reason,
+        message,
+        generation,
+    )
+}
+
+/// a condition, keeping the transition time of the previous one if the status
+/// is the same
+fn condition(
+    previous: &[Condition],
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: String,
+    generation: Option<i64>,
+) -> Condition {
+    let status = if status { "True" } else { "False" }.to_string();
+    let last_transition_time = previous
+        .iter()
+        .find(|c| c.type_ == type_ && c.status == status)
+        .map(|c| c.last_transition_time.clone())
+        .unwrap_or_else(|| Time(Utc::now()));
+    Condition {
+        type_: type_.to_string(),
+        status,
+        reason: reason.to_string(),
+        message,
+        last_transition_time,
+        observed_generation: generation,
+    }
+}
+
+/// deletes an object, if it exists
+async fn delete_if_exists<K>(api: &Api<K>, name: &str) -> Result<(), Error>
+where
+    K: Clone + serde::de::DeserializeOwned + Debug,
+{
+    match api.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
+    env: Vec<EnvVar>,
+    port: IntOrString,
+    ctx: Context<Data>,
+    shortname: String,
+    crd: T,
+    container: ContainerOptions,
+    runner: RunnerOptions,
+    download: JarDownload,
+    replicas: i32,
+    rollback: Option<PodTemplateSpec>,
+) -> Result<ReconcilerAction, Error> {
+    let data = ctx.get_ref();
+    let heap_warning = heap::check(&runner, &container)?;
+    let ns = ResourceExt::namespace(&crd)
+        .ok_or_else(|| MyceliumError("failed to get namespace".into()))?;
+    let config = data.config.read().expect("config").clone();
+    let config = mycelium_config::effective(data.client.clone(), config, &ns).await?;
+    let workload = data.workload(&runner, &container);
+    let container_port = match &port {
+        IntOrString::Int(port) => *port,
+        IntOrString::String(_) => 25565,
+    };
+    let DesiredObjects {
+        labels,
+        jar,
+        stateful,
+        claim_templates,
+        mut statefulset,
+        mut deployment,
+        pdb,
+        service,
+        replica_services,
+        download_cache,
+        secret,
+        access,
+    } = desired_objects(
+        env,
+        port,
+        &config,
+        data.cache.as_ref(),
+        &shortname,
+        &crd,
+        &container,
+        &runner,
+        &download,
+        replicas,
+    )?;
+    let name = ResourceExt::name(&crd);
+
+    let client = data.client.clone();
+    // Note: This will only error with PoisonError, which is unrecoverable and so we
+    // should panic.
+    data.state.write().expect("last_event").last_event = Utc::now();
+
+    // roll the replicas when a configmap they mount changes, the access lists are
+    // applied in this reconcile so they're taken from what's about to be
+    // applied
+    let cm_api = kube::Api::<ConfigMap>::namespaced(client.clone(), &ns);
+    let mut config_maps = vec![access.clone()];
+    let template = statefulset
+        .spec
+        .as_ref()
+        .map(|s| s.template.clone())
+        .unwrap_or_default();
+    for cm in mounted_config_maps(&template) {
+        if cm == access::config_map_name(&name) {
+            continue;
+        }
+        // a missing configmap keeps the pods from starting, which is reported there
+        match cm_api.get(&cm).await {
+            Ok(cm) => config_maps.push(cm),
+            Err(kube::Error::Api(e)) if e.code == 404 => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let hash = config_hash(&template, &config_maps);
+    let templates = [
+        statefulset.spec.as_mut().map(|s| &mut s.template),
+        deployment.spec.as_mut().map(|s| &mut s.template),
+    ];
+    for template in templates.into_iter().flatten() {
+        template
+            .metadata
+            .get_or_insert_with(ObjectMeta::default)
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(CONFIG_HASH_ANNOTATION.into(), hash.clone());
+    }
+    // a rolled back StatefulSet runs the known-good template as it was, hash
+    // included
+    if let (Some(template), Some(spec)) = (rollback, statefulset.spec.as_mut()) {
+        spec.template = template;
+    }
+
+    // objects someone else changed since they were last applied, which are put back
+    // below
+    let mut drifted = vec![];
+    let pdb_api = kube::Api::<PodDisruptionBudget>::namespaced(client.clone(), &ns);
+    if apply(&data.state, &pdb_api, &ns, &name, &pdb).await? {
+        drifted.push("PodDisruptionBudget");
+    }
+
+    let mut waiting_for_backup = false;
+    let mut draining = false;
+    if workload == Workload::Fleet {
+        // left over from before the set moved to Agones, its pods go with it
+        delete_if_exists(
+            &kube::Api::<StatefulSet>::namespaced(client.clone(), &ns),
             &name,
-            &PatchParams::apply("mycelium.njha.dev"),
-            &Patch::Apply(&pdb),
-        ).await?;
+        )
+        .await?;
+        delete_if_exists(
+            &kube::Api::<Deployment>::namespaced(client.clone(), &ns),
+            &name,
+        )
+        .await?;
+        let template = match stateful {
+            true => statefulset.spec.as_ref().map(|s| &s.template),
+            false => deployment.spec.as_ref().map(|s| &s.template),
+        };
+        let fleet = backend::fleet(
+            &statefulset.metadata,
+            replicas,
+            &template.cloned().unwrap_or_default(),
+            container_port,
+        );
+        backend::apply_fleet(client.clone(), &ns, &name, &fleet).await?;
+    } else if stateful {
+        // left over from when the CRD was stateless, or ran on Agones
+        delete_if_exists(
+            &kube::Api::<Deployment>::namespaced(client.clone(), &ns),
+            &name,
+        )
+        .await?;
+        delete_if_exists(&backend::fleet_api(client.clone(), &ns), &name).await?;
 
-    kube::Api::<StatefulSet>::namespaced(client.clone(), &ns)
-        .patch(
+        // volume claim templates can't be changed in place, so a resize deletes the
+        // StatefulSet and the deletion event brings us back here to recreate it
+        let mut recreating = false;
+        for tpl in &claim_templates {
+            recreating |= storage::expand_claims(client.clone(), &ns, &name, &labels, tpl).await?;
+        }
+
+        // back up every replica before a jar change rolls out
+        let backup = container.backup_before_update.unwrap_or(false);
+        let live = match kube::Api::<StatefulSet>::namespaced(client.clone(), &ns)
+            .get(&name)
+            .await
+        {
+            Ok(live) => Some(live),
+            Err(kube::Error::Api(e)) if e.code == 404 => None,
+            Err(e) => return Err(e.into()),
+        };
+        let live_replicas = live
+            .as_ref()
+            .and_then(|live| live.spec.as_ref())
+            .and_then(|s| s.replicas)
+            .unwrap_or(0);
+        if let (true, Some(live)) = (backup, live) {
+            if live.annotations().get("mycelium.njha.dev/jar") != Some(&jar) {
+                let claims: Vec<String> = claim_templates
+                    .iter()
+                    .flat_map(|tpl| {
+                        let tpl_name = tpl.metadata.name.clone().unwrap_or_default();
+                        let name = name.clone();
+                        (0..live_replicas).map(move |i| format!("{}-{}-{}", tpl_name, name, i))
+                    })
+                    .collect();
+                let tag = format!("pre-{:.8x}", Sha224::digest(jar.as_bytes()));
+                waiting_for_backup |=
+                    !backup::snapshot_claims(client.clone(), &ns, &claims, &tag, &labels, None)
+                        .await?;
+            }
+        }
+
+        // hold a scale-down while the replicas being removed still have players
+        let held = match container
+            .drain_timeout_seconds
+            .unwrap_or(scaling::DEFAULT_DRAIN_TIMEOUT_SECONDS)
+        {
+            timeout if timeout > 0 => scaling::held_replicas(
+                &data.state,
+                &ns,
+                &name,
+                live_replicas,
+                replicas,
+                chrono::Duration::seconds(timeout),
+            ),
+            _ => replicas,
+        };
+        draining = held > replicas;
+
+        if !recreating && !waiting_for_backup {
+            // k8s-openapi predates persistentVolumeClaimRetentionPolicy, so add it by hand
+            let mut statefulset =
+                serde_json::to_value(&statefulset).map_err(Error::SerializationError)?;
+            statefulset["spec"]["replicas"] = serde_json::json!(held);
+            // ephemeral servers have no claims for the policy to act on
+            let policy = container
+                .persistent_volume_claim_retention_policy
+                .as_ref()
+                .filter(|_| !claim_templates.is_empty());
+            if let Some(policy) = policy {
+                statefulset["spec"]["persistentVolumeClaimRetentionPolicy"] =
+                    serde_json::json!(policy);
+            }
+            let sts_api = kube::Api::<StatefulSet>::namespaced(client.clone(), &ns);
+            if apply(&data.state, &sts_api, &ns, &name, &statefulset).await? {
+                drifted.push("StatefulSet");
+            }
+        }
+        // do what the retention policy asks for even without the feature gate
+        let policy = container
+            .persistent_volume_claim_retention_policy
+            .clone()
+            .unwrap_or_default();
+        if policy.when_deleted.as_deref() == Some("Delete") {
+            let owner = object_to_owner_reference::<T>(crd.meta().clone())?;
+            for tpl in &claim_templates {
+                storage::adopt_claims(client.clone(), &ns, &name, &labels, tpl, &owner).await?;
+            }
+        }
+        if container.prune_orphaned_claims.unwrap_or(false)
+            || policy.when_scaled.as_deref() == Some("Delete")
+        {
+            let pvc_api = kube::Api::<PersistentVolumeClaim>::namespaced(client.clone(), &ns);
+            for tpl in &claim_templates {
+                let orphans =
+                    storage::orphaned_claims(client.clone(), &ns, &name, &labels, tpl, held)
+                        .await?;
+                for orphan in orphans {
+                    let claim = orphan.name();
+                    if backup {
+                        // tag with the claim uid so a reused ordinal is backed up again
+                        let tag = format!("final-{:.8}", orphan.uid().unwrap_or_default());
+                        let ready = backup::snapshot_claims(
+                            client.clone(),
+                            &ns,
+                            std::slice::from_ref(&claim),
+                            &tag,
+                            &labels,
+                            None,
+                        )
+                        .await?;
+                        if !ready {
+                            waiting_for_backup = true;
+                            continue;
+                        }
+                    }
+                    info!("deleting orphaned claim {}", claim);
+                    pvc_api.delete(&claim, &DeleteParams::default()).await?;
+                }
+            }
+        }
+    } else {
+        // left over from when the CRD was stateful (its claims are kept), or ran on
+        // Agones
+        delete_if_exists(
+            &kube::Api::<StatefulSet>::namespaced(client.clone(), &ns),
             &name,
-            &PatchParams::apply("mycelium.njha.dev"),
-            &Patch::Apply(&statefulset),
         )
         .await?;
+        delete_if_exists(&backend::fleet_api(client.clone(), &ns), &name).await?;
+        let deployment_api = kube::Api::<Deployment>::namespaced(client.clone(), &ns);
+        let live_replicas = match deployment_api.get(&name).await {
+            Ok(live) => live.spec.and_then(|s| s.replicas).unwrap_or(0),
+            Err(kube::Error::Api(e)) if e.code == 404 => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if live_replicas > replicas {
+            // so the ReplicaSet removes the emptiest replicas
+            let pod_api = kube::Api::<Pod>::namespaced(client.clone(), &ns);
+            let selector = labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(",");
+            let pods: Vec<(String, Option<String>)> = pod_api
+                .list(&ListParams::default().labels(&selector))
+                .await?
+                .items
+                .into_iter()
+                .map(|pod| {
+                    let cost = pod
+                        .annotations()
+                        .get(scaling::DELETION_COST_ANNOTATION)
+                        .cloned();
+                    (pod.name(), cost)
+                })
+                .collect();
+            let players = data.metrics.scraped_players(&ns, &name);
+            for (pod, cost) in scaling::deletion_costs(&pods, &players) {
+                let patch = serde_json::json!({ "metadata": { "annotations": { scaling::DELETION_COST_ANNOTATION: cost } } });
+                pod_api
+                    .patch(&pod, &PatchParams::default(), &Patch::Merge(patch))
+                    .await?;
+            }
+        }
+        if apply(&data.state, &deployment_api, &ns, &name, &deployment).await? {
+            drifted.push("Deployment");
+        }
+    }
 
-    kube::Api::<Service>::namespaced(client.clone(), &ns)
+    // clusterIP can't change in place, so switching between headless (stateful) and
+    // load balanced (stateless) recreates the Service
+    let service_api = kube::Api::<Service>::namespaced(client.clone(), &ns);
+    let live_headless = match service_api.get(&name).await {
+        Ok(live) => Some(live.spec.and_then(|s| s.cluster_ip).as_deref() == Some("None")),
+        Err(kube::Error::Api(e)) if e.code == 404 => None,
+        Err(e) => return Err(e.into()),
+    };
+    if live_headless.is_some_and(|headless| headless != stateful) {
+        delete_if_exists(&service_api, &name).await?;
+    }
+    service_api
         .patch(
             &name,
             &PatchParams::apply("mycelium.njha.dev"),
@@ -352,13 +2148,224 @@ pub async fn generic_reconcile<T: Resource<DynamicType = ()>>(
         )
         .await?;
 
-    kube::Api::<Secret>::namespaced(client.clone(), &ns)
+    // per-replica services, removing those of replicas that are gone
+    for replica_service in &replica_services {
+        let replica = replica_service
+            .metadata
+            .name
+            .clone()
+            .expect("replica service name");
+        service_api
+            .patch(
+                &replica,
+                &PatchParams::apply("mycelium.njha.dev"),
+                &Patch::Apply(replica_service),
+            )
+            .await?;
+    }
+    let existing = service_api
+        .list(&ListParams::default().labels(&format!("{}={}", REPLICA_SERVICE_LABEL, name)))
+        .await?;
+    for stale in existing.iter() {
+        if !replica_services
+            .iter()
+            .any(|r| r.metadata.name == stale.metadata.name)
+        {
+            service_api
+                .delete(&stale.name(), &DeleteParams::default())
+                .await?;
+        }
+    }
+
+    let secret_api = kube::Api::<Secret>::namespaced(client.clone(), &ns);
+    if apply(&data.state, &secret_api, &ns, &name, &secret).await? {
+        drifted.push("Secret");
+    }
+    if apply(
+        &data.state,
+        &cm_api,
+        &ns,
+        &access::config_map_name(&name),
+        &access,
+    )
+    .await?
+    {
+        drifted.push("ConfigMap");
+    }
+
+    if !drifted.is_empty() {
+        let kind = T::kind(&()).to_string();
+        data.metrics
+            .drift_corrections
+            .with_label_values(&[&kind, &ns, &name])
+            .inc_by(drifted.len() as u64);
+        let note = format!(
+            "reverted changes made outside the operator to the {}",
+            drifted.join(", ")
+        );
+        warn!("{} {} in {}: {}", kind, name, ns, note);
+        publish_warning(client.clone(), &crd, "DriftCorrected", note).await;
+    }
+    if let Some(note) = heap_warning {
+        warn!("{} {} in {}: {}", T::kind(&()), name, ns, note);
+        publish_warning(client.clone(), &crd, "HeapNearMemoryLimit", note).await;
+    }
+
+    if let Some(claim) = &download_cache {
+        kube::Api::<PersistentVolumeClaim>::namespaced(client.clone(), &ns)
+            .patch(
+                &storage::download_cache_claim_name(&name),
+                &PatchParams::apply("mycelium.njha.dev"),
+                &Patch::Apply(claim),
+            )
+            .await?;
+    }
+
+    // snapshots and player counts aren't watched, so check back on them, and come
+    // back anyway after a while to undo manual edits to the generated objects
+    let waiting = if waiting_for_backup {
+        Some(Duration::from_secs(10))
+    } else {
+        draining.then(|| Duration::from_secs(30))
+    };
+    let resync = data.config.read().expect("config").resync;
+    Ok(ReconcilerAction {
+        requeue_after: [waiting, resync].into_iter().flatten().min(),
+    })
+}
+
+/// annotation with a hash of what the operator last applied to an object
+pub const APPLIED_HASH_ANNOTATION: &str = "mycelium.njha.dev/applied-hash";
+
+/// generation of an object, or its resourceVersion if it has none (secrets and
+/// configmaps), which only changes when someone writes to it
+fn version<K: Resource>(object: &K) -> String {
+    object
+        .meta()
+        .generation
+        .map(|g| g.to_string())
+        .or_else(|| object.meta().resource_version.clone())
+        .unwrap_or_default()
+}
+
+/// Whether an object has to be applied (again), given the live object and the
+/// hash and version the operator last left it at since it started, and whether
+/// that undoes someone else's change. Objects are always applied the first time
+/// the operator sees them, since the applied-hash annotation says nothing about
+/// edits made while it was down.
+pub(crate) fn plan_apply(
+    desired: &Value,
+    hash: &str,
+    live: Option<(&Value, String)>,
+    previous: Option<&(String, String)>,
+) -> (bool, bool) {
+    let (live, live_version) = match live {
+        Some(live) => live,
+        None => return (true, false),
+    };
+    let ours = live["metadata"]["annotations"][APPLIED_HASH_ANNOTATION] == hash;
+    // secrets are read back with their stringData encoded into data
+    let mut desired = desired.clone();
+    if let Some(Value::Object(string_data)) =
+        desired.as_object_mut().and_then(|d| d.remove("stringData"))
+    {
+        for (key, value) in string_data {
+            let encoded = base64::encode(value.as_str().unwrap_or_default());
+            desired["data"][key] = Value::String(encoded);
+        }
+    }
+    // metadata edits don't bump the generation, so the fields themselves are
+    // compared too
+    let matches = contains(live, &desired);
+    match previous {
+        None => (true, ours && !matches),
+        Some((h, v)) => {
+            let same = h == hash;
+            let untouched = *v == live_version && matches;
+            (!(ours && same && untouched), same && !untouched)
+        }
+    }
+}
+
+/// whether every field set in `desired` has the same value in `live`, which
+/// also has the defaults and the fields of other managers (quantities are
+/// compared by value, since the API server may write them back in another form,
+/// i.e. 1024Mi as 1Gi)
+pub(crate) fn contains(live: &Value, desired: &Value) -> bool {
+    match (live, desired) {
+        (_, Value::Null) => true,
+        (Value::Object(live), Value::Object(desired)) => desired
+            .iter()
+            .all(|(key, value)| contains(live.get(key).unwrap_or(&Value::Null), value)),
+        (Value::Array(live), Value::Array(desired)) => desired
+            .iter()
+            .all(|value| live.iter().any(|item| contains(item, value))),
+        (Value::String(live), Value::String(desired)) => {
+            live == desired
+                || quota::parse(&Quantity(live.clone()))
+                    .zip(quota::parse(&Quantity(desired.clone())))
+                    .is_some_and(|(live, desired)| live == desired)
+        }
+        (live, desired) => live == desired,
+    }
+}
+
+/// Server-side applies an object unless plan_apply says it's already as
+/// desired, which saves a write on every reconcile. Returns whether someone
+/// else changed it since the same thing was last applied.
+async fn apply<K, P>(
+    state: &RwLock<State>,
+    api: &Api<K>,
+    ns: &str,
+    name: &str,
+    desired: &P,
+) -> Result<bool, Error>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Serialize + Debug,
+    P: Serialize + Debug,
+{
+    let mut desired = serde_json::to_value(desired).map_err(Error::SerializationError)?;
+    let hash = format!(
+        "{:x}",
+        Sha224::digest(serde_json::to_vec(&desired).map_err(Error::SerializationError)?)
+    );
+    desired["metadata"]["annotations"][APPLIED_HASH_ANNOTATION] = serde_json::json!(hash);
+
+    let key = format!("{}/{}/{}", K::kind(&()), ns, name);
+    let previous = state.read().expect("applied").applied.get(&key).cloned();
+    let live = match api.get(name).await {
+        Ok(live) => Some(live),
+        Err(kube::Error::Api(e)) if e.code == 404 => None,
+        Err(e) => return Err(e.into()),
+    };
+    let live_value = live
+        .as_ref()
+        .map(serde_json::to_value)
+        .transpose()
+        .map_err(Error::SerializationError)?;
+    // objects the operator deletes itself (i.e. to resize claims) are recreated,
+    // not drift
+    let (needed, drifted) = plan_apply(
+        &desired,
+        &hash,
+        live_value.as_ref().zip(live.as_ref().map(version)),
+        previous.as_ref(),
+    );
+    if !needed {
+        return Ok(false);
+    }
+
+    let applied = api
         .patch(
-            &name,
+            name,
             &PatchParams::apply("mycelium.njha.dev"),
-            &Patch::Apply(&secret),
+            &Patch::Apply(&desired),
         )
         .await?;
-
-    Ok(())
+    state
+        .write()
+        .expect("applied")
+        .applied
+        .insert(key, (hash, version(&applied)));
+    Ok(drifted)
 }
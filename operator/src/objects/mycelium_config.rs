@@ -0,0 +1,115 @@
+use k8s_openapi::api::core::v1::{Secret, SecretKeySelector};
+use kube::{
+    api::{Api, ListParams, ResourceExt},
+    Client, CustomResource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{helpers::manager::MyceliumConfig, Error, Error::MyceliumError};
+
+/// Policy for the sets and proxies of one namespace, on top of the operator
+/// config. Every field is optional and falls back to the operator config, so a
+/// namespace without a MyceliumConfig is reconciled the way it always was.
+#[derive(CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[kube(
+    group = "mycelium.njha.dev",
+    version = "v1beta1",
+    kind = "MyceliumConfig",
+    struct = "NamespaceConfig"
+)]
+#[kube(shortname = "mcconfig", namespaced)]
+#[serde(rename_all = "camelCase")]
+pub struct MyceliumConfigSpec {
+    /// jar types (i.e. paper, velocity) sets and proxies in the namespace may
+    /// run, any if omitted
+    pub allowed_jar_types: Option<Vec<String>>,
+
+    /// runner image for the namespace, instead of the operator's
+    pub runner_image: Option<String>,
+
+    /// hosts runner.plugins and runner.mods may download from, replacing the
+    /// operator's allowedPluginHosts (any if empty)
+    pub allowed_plugin_hosts: Option<Vec<String>>,
+
+    /// key of a Secret in the namespace holding the velocity forwarding secret,
+    /// instead of the operator's
+    pub forwarding_secret: Option<SecretKeySelector>,
+}
+
+impl MyceliumConfig {
+    /// The operator config with a namespace's MyceliumConfig on top, given the
+    /// forwarding secret its forwardingSecret points to.
+    pub(crate) fn for_namespace(
+        &self,
+        spec: &MyceliumConfigSpec,
+        forwarding_secret: Option<String>,
+    ) -> MyceliumConfig {
+        let mut config = self.clone();
+        if let Some(types) = &spec.allowed_jar_types {
+            config.allowed_jar_types = types.clone();
+        }
+        if let Some(image) = &spec.runner_image {
+            config.runner_image = image.clone();
+        }
+        if let Some(hosts) = &spec.allowed_plugin_hosts {
+            config.allowed_plugin_hosts = hosts.clone();
+        }
+        if let Some(secret) = forwarding_secret {
+            config.forwarding_secret = secret;
+        }
+        config
+    }
+}
+
+/// reads one key of a Secret
+async fn secret_value(
+    client: Client,
+    ns: &str,
+    selector: &SecretKeySelector,
+) -> Result<String, Error> {
+    let name = selector.name.clone().unwrap_or_default();
+    let secret = Api::<Secret>::namespaced(client, ns).get(&name).await?;
+    secret
+        .data
+        .and_then(|d| d.get(&selector.key).cloned())
+        .map(|value| String::from_utf8_lossy(&value.0).into_owned())
+        .ok_or_else(|| MyceliumError(format!("secret {} has no key {}", name, selector.key)))
+}
+
+/// The config to reconcile the sets and proxies of a namespace with: the
+/// operator `config`, with the namespace's MyceliumConfig on top if it has one.
+/// A namespace should have at most one; if it has more, the first by name is
+/// used.
+pub async fn effective(
+    client: Client,
+    config: MyceliumConfig,
+    ns: &str,
+) -> Result<MyceliumConfig, Error> {
+    let api = Api::<NamespaceConfig>::namespaced(client.clone(), ns);
+    let mut configs = match api.list(&ListParams::default()).await {
+        Ok(configs) => configs.items,
+        // the CRD isn't installed yet, i.e. partway through an upgrade
+        Err(kube::Error::Api(e)) if e.code == 404 => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    configs.sort_by_key(|c| c.name());
+    let namespace_config = match configs.first() {
+        Some(namespace_config) => namespace_config,
+        None => return Ok(config),
+    };
+    if configs.len() > 1 {
+        warn!(
+            "{} has {} MyceliumConfigs, only using {}",
+            ns,
+            configs.len(),
+            namespace_config.name()
+        );
+    }
+    let forwarding_secret = match &namespace_config.spec.forwarding_secret {
+        Some(selector) => Some(secret_value(client, ns, selector).await?),
+        None => None,
+    };
+    Ok(config.for_namespace(&namespace_config.spec, forwarding_secret))
+}
@@ -0,0 +1,291 @@
+use k8s_openapi::api::core::v1::{
+    ConfigMapVolumeSource, KeyToPath, PersistentVolumeClaimVolumeSource, SecretVolumeSource,
+    Volume, VolumeMount,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    helpers::hangar,
+    objects::{PluginSource, RunnerOptions},
+    Error,
+};
+
+/// where plugins are loaded from in the runner
+pub const PLUGINS_PATH: &str = "/data/plugins";
+
+/// annotation to change (i.e. to the current time) to resolve floating plugins
+/// again
+pub const REFRESH_ANNOTATION: &str = "mycelium.njha.dev/refresh-plugins";
+
+/// where plugin configs are mounted for the runner to copy into the plugins
+/// directory
+pub const PLUGIN_CONFIGS_PATH: &str = "/plugin-configs";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginVolume {
+    /// configmap holding the jar (in binaryData) under `path`
+    pub config_map: Option<String>,
+
+    /// secret holding the jar under `path`
+    pub secret: Option<String>,
+
+    /// existing claim (i.e. a shared plugin library) holding the jar at `path`
+    pub persistent_volume_claim: Option<String>,
+
+    /// key of the configmap or secret, or path inside the claim, of the plugin
+    /// jar
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginConfig {
+    /// name of the plugin's directory, i.e. `LuckPerms` for
+    /// `plugins/LuckPerms/`
+    pub plugin: String,
+
+    /// configmap whose keys are copied into the plugin's directory
+    pub config_map: String,
+}
+
+/// a plugin resolved to a concrete artifact, kept in the status so every
+/// replica gets the same one
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct PinnedPlugin {
+    /// the URL or hangar reference in the spec
+    pub source: String,
+
+    /// version a hangar reference resolved to
+    pub version: Option<String>,
+
+    /// URL the replicas download
+    pub url: String,
+
+    /// sha256 of the artifact, which the runner verifies
+    pub sha256: String,
+
+    /// file name in the plugins directory, if not the end of the URL
+    pub filename: Option<String>,
+}
+
+impl PluginVolume {
+    /// name of the jar in the plugins directory
+    fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// Volumes and mounts for the in-cluster plugins. Each jar is mounted on its
+/// own into the plugins directory, so the downloaded plugins next to it stay
+/// visible.
+pub fn volumes(runner: &RunnerOptions) -> Vec<(Volume, VolumeMount)> {
+    runner
+        .plugin_volumes
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, plugin)| {
+            let name = format!("mycelium-plugin-{}", i);
+            // configmaps and secrets only project the one key, claims are mounted at a
+            // subPath
+            let items = Some(vec![KeyToPath {
+                key: plugin.path.clone(),
+                path: plugin.file_name().into(),
+                mode: None,
+            }]);
+            let volume = Volume {
+                name: name.clone(),
+                config_map: plugin.config_map.clone().map(|cm| ConfigMapVolumeSource {
+                    name: Some(cm),
+                    items: items.clone(),
+                    ..ConfigMapVolumeSource::default()
+                }),
+                secret: plugin.secret.clone().map(|secret| SecretVolumeSource {
+                    secret_name: Some(secret),
+                    items: items.clone(),
+                    ..SecretVolumeSource::default()
+                }),
+                persistent_volume_claim: plugin.persistent_volume_claim.clone().map(|claim| {
+                    PersistentVolumeClaimVolumeSource {
+                        claim_name: claim,
+                        read_only: Some(true),
+                    }
+                }),
+                ..Volume::default()
+            };
+            let mount = VolumeMount {
+                name,
+                mount_path: format!("{}/{}", PLUGINS_PATH, plugin.file_name()),
+                sub_path: Some(match plugin.persistent_volume_claim {
+                    Some(_) => plugin.path.clone(),
+                    None => plugin.file_name().into(),
+                }),
+                read_only: Some(true),
+                ..VolumeMount::default()
+            };
+            (volume, mount)
+        })
+        .collect()
+}
+
+/// Volumes and mounts for the plugin configs. They're mounted outside the
+/// server root and copied in by the runner once the plugins are downloaded, so
+/// they win over the defaults a plugin writes on first start.
+pub fn config_volumes(runner: &RunnerOptions) -> Vec<(Volume, VolumeMount)> {
+    runner
+        .plugin_configs
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, config)| {
+            let name = format!("mycelium-plugin-config-{}", i);
+            let volume = Volume {
+                name: name.clone(),
+                config_map: Some(ConfigMapVolumeSource {
+                    name: Some(config.config_map.clone()),
+                    ..ConfigMapVolumeSource::default()
+                }),
+                ..Volume::default()
+            };
+            let mount = VolumeMount {
+                name,
+                mount_path: format!("{}/{}", PLUGIN_CONFIGS_PATH, config.plugin),
+                read_only: Some(true),
+                ..VolumeMount::default()
+            };
+            (volume, mount)
+        })
+        .collect()
+}
+
+async fn sha256(url: &str) -> Result<String, Error> {
+    let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+/// Resolves the plugins in a spec to the artifacts the replicas download.
+/// Hangar references and URLs without a sha256 can change upstream, so they're
+/// pinned to the version and hash seen first. Pins in `previous` are reused
+/// until the entry changes or `refresh` is set, so a release in the middle of a
+/// rollout can't leave replicas on different versions.
+pub async fn pin(
+    plugins: Vec<PluginSource>,
+    platform: &str,
+    previous: &[PinnedPlugin],
+    refresh: bool,
+) -> Result<(Vec<PluginSource>, Vec<PinnedPlugin>), Error> {
+    let mut sources = Vec::with_capacity(plugins.len());
+    let mut pins = vec![];
+    for plugin in plugins {
+        // a URL with a hash is pinned by the spec already
+        if plugin.hangar.is_none() && plugin.sha256.is_some() {
+            sources.push(plugin);
+            continue;
+        }
+        let source = plugin.hangar.clone().unwrap_or_else(|| plugin.url.clone());
+        let pinned = match previous
+            .iter()
+            .find(|p| p.source == source)
+            .filter(|_| !refresh)
+        {
+            Some(pinned) => pinned.clone(),
+            None => {
+                let (version, download) = match &plugin.hangar {
+                    Some(reference) => {
+                        let (version, download) = hangar::get_download(reference, platform).await?;
+                        (Some(version), download)
+                    }
+                    None => (None, plugin.clone()),
+                };
+                let sha256 = match download.sha256 {
+                    Some(sha256) => sha256,
+                    None => sha256(&download.url).await?,
+                };
+                PinnedPlugin {
+                    source,
+                    version,
+                    url: download.url,
+                    sha256,
+                    filename: download.filename,
+                }
+            }
+        };
+        sources.push(PluginSource {
+            url: pinned.url.clone(),
+            sha256: plugin.sha256.or_else(|| Some(pinned.sha256.clone())),
+            filename: plugin.filename.or_else(|| pinned.filename.clone()),
+            hangar: None,
+        });
+        pins.push(pinned);
+    }
+    Ok((sources, pins))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::objects::tests::{basic_spec, mcset};
+
+    #[test]
+    fn plugins_accept_urls_and_entries() {
+        let mut spec = basic_spec();
+        spec["runner"]["plugins"] = json!([
+            "https://example.com/a.jar",
+            { "url": "https://example.com/download?id=2", "sha256": "ab", "filename": "b.jar" },
+            { "hangar": "Lucko/LuckPerms@5.4.102" },
+        ]);
+        let plugins: Vec<PluginSource> = mcset(spec)
+            .spec
+            .runner
+            .plugins
+            .unwrap()
+            .into_iter()
+            .map(PluginSource::from)
+            .collect();
+
+        assert_eq!(plugins[0].url, "https://example.com/a.jar");
+        assert_eq!(plugins[0].sha256, None);
+        assert_eq!(plugins[1].filename.as_deref(), Some("b.jar"));
+        assert_eq!(
+            plugins[2].hangar.as_deref(),
+            Some("Lucko/LuckPerms@5.4.102")
+        );
+    }
+
+    #[test]
+    fn pinned_plugins_are_reused_until_refreshed() {
+        let previous = vec![PinnedPlugin {
+            source: "Lucko/LuckPerms@latest".into(),
+            version: Some("5.4.102".into()),
+            url: "https://hangarcdn.papermc.io/LuckPerms-5.4.102.jar".into(),
+            sha256: "ab".into(),
+            filename: Some("LuckPerms-5.4.102.jar".into()),
+        }];
+        let spec = vec![
+            PluginSource {
+                hangar: Some("Lucko/LuckPerms@latest".into()),
+                ..PluginSource::default()
+            },
+            PluginSource {
+                url: "https://example.com/a.jar".into(),
+                sha256: Some("cd".into()),
+                ..PluginSource::default()
+            },
+        ];
+        let (sources, pins) =
+            futures::executor::block_on(pin(spec, "PAPER", &previous, false)).unwrap();
+
+        assert_eq!(
+            sources[0].url,
+            "https://hangarcdn.papermc.io/LuckPerms-5.4.102.jar"
+        );
+        assert_eq!(sources[0].sha256.as_deref(), Some("ab"));
+        assert_eq!(sources[1].sha256.as_deref(), Some("cd"));
+        assert_eq!(pins, previous);
+    }
+}
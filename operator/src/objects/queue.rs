@@ -0,0 +1,109 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueOptions {
+    /// MinecraftSet (behind the same proxy) players wait on while this one is
+    /// full
+    pub limbo: String,
+
+    /// players the set takes across its replicas before new ones are queued
+    /// (default = runner.maxPlayers times the replicas)
+    pub max_players: Option<u32>,
+}
+
+/// the queue of a set, as proxies are told about it on each of the set's
+/// servers
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ServerQueue {
+    /// set whose servers players wait on
+    pub limbo: String,
+    /// players the set takes before new ones wait in line
+    pub capacity: u32,
+    /// whether the set was at capacity when the proxy asked
+    pub full: bool,
+}
+
+/// The queue proxies should hold a set's players in, if it has one. None
+/// without a maxPlayers, either on the queue or the runner, since then there's
+/// no capacity to go by.
+pub fn server_queue(
+    options: &QueueOptions,
+    max_players: Option<u32>,
+    replicas: i32,
+    players: Option<u32>,
+) -> Option<ServerQueue> {
+    let capacity = options
+        .max_players
+        .or_else(|| max_players.map(|max| max * replicas.max(0) as u32))?;
+    Some(ServerQueue {
+        limbo: options.limbo.clone(),
+        capacity,
+        full: players.is_some_and(|players| players >= capacity),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::helpers::state::{ProxyHeartbeat, State};
+
+    #[test]
+    fn full_sets_queue_their_players() {
+        let options = QueueOptions {
+            limbo: "lobby".into(),
+            max_players: None,
+        };
+        // without a player limit there's nothing to queue for
+        assert_eq!(server_queue(&options, None, 2, Some(40)), None);
+        let queue = server_queue(&options, Some(20), 2, Some(40)).unwrap();
+        assert_eq!(
+            (queue.limbo.as_str(), queue.capacity, queue.full),
+            ("lobby", 40, true)
+        );
+        assert!(!server_queue(&options, Some(20), 2, Some(39)).unwrap().full);
+        // unknown counts aren't full, proxies go by their own until they hear otherwise
+        assert!(!server_queue(&options, Some(20), 2, None).unwrap().full);
+        let limited = QueueOptions {
+            max_players: Some(30),
+            ..options
+        };
+        assert!(server_queue(&limited, Some(20), 2, Some(30)).unwrap().full);
+
+        let mut state = State::new();
+        let heartbeat = |last_seen, queued: u32| ProxyHeartbeat {
+            last_seen,
+            reachable: vec![],
+            unreachable: vec![],
+            players: BTreeMap::new(),
+            queues: BTreeMap::from([
+                ("survival".to_string(), queued),
+                ("creative".to_string(), 0),
+            ]),
+        };
+        state.proxies.insert(
+            "games/proxy/proxy-0".into(),
+            heartbeat(chrono::Utc::now(), 3),
+        );
+        state.proxies.insert(
+            "games/proxy/proxy-1".into(),
+            heartbeat(chrono::Utc::now(), 2),
+        );
+        state.proxies.insert(
+            "games/proxy/proxy-2".into(),
+            heartbeat(chrono::Utc::now() - chrono::Duration::hours(1), 9),
+        );
+        state.proxies.insert(
+            "other/proxy/proxy-0".into(),
+            heartbeat(chrono::Utc::now(), 1),
+        );
+        let lengths = state.queue_lengths("games");
+        assert_eq!(
+            lengths,
+            BTreeMap::from([("creative".to_string(), 0), ("survival".to_string(), 5)])
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use k8s_openapi::{
+    api::core::v1::{Pod, ResourceRequirements},
+    apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::Condition},
+};
+use kube::{api::ListParams, Api, ResourceExt};
+use serde::Deserialize;
+
+use crate::{helpers::manager::Data, objects::condition, Error};
+
+/// limits on what the mycelium pods of a namespace may use together, set per
+/// namespace (or `*` for every other one) under quotas in the operator config
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    /// replicas of every set and proxy together
+    pub max_replicas: Option<i32>,
+    /// CPU requested by every replica together
+    pub max_cpu: Option<Quantity>,
+    /// memory requested by every replica together
+    pub max_memory: Option<Quantity>,
+}
+
+/// what some pods use, counting requests (or limits, where there are no
+/// requests)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub replicas: i32,
+    /// cores
+    pub cpu: f64,
+    /// bytes
+    pub memory: f64,
+}
+
+/// a quantity in cores or bytes, i.e. 500m = 0.5 and 1Gi = 1073741824
+pub fn parse(quantity: &Quantity) -> Option<f64> {
+    const BINARY: [(&str, f64); 6] = [
+        ("Ki", 1024.),
+        ("Mi", 1048576.),
+        ("Gi", 1073741824.),
+        ("Ti", 1099511627776.),
+        ("Pi", 1125899906842624.),
+        ("Ei", 1152921504606846976.),
+    ];
+    const DECIMAL: [(&str, f64); 9] = [
+        ("n", 1e-9),
+        ("u", 1e-6),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+    ];
+    let quantity = quantity.0.trim();
+    for (suffix, scale) in BINARY.iter().chain(DECIMAL.iter()) {
+        if let Some(number) = quantity.strip_suffix(suffix) {
+            return number.parse::<f64>().ok().map(|n| n * scale);
+        }
+    }
+    // plain numbers, and exponents like 1e3
+    quantity.parse().ok()
+}
+
+/// what one container uses
+fn container_usage(resources: Option<&ResourceRequirements>) -> Usage {
+    let amount = |name: &str| {
+        let resources = resources?;
+        let requested = resources.requests.as_ref().and_then(|r| r.get(name));
+        requested
+            .or_else(|| resources.limits.as_ref()?.get(name))
+            .and_then(parse)
+    };
+    Usage {
+        replicas: 1,
+        cpu: amount("cpu").unwrap_or(0.),
+        memory: amount("memory").unwrap_or(0.),
+    }
+}
+
+/// what some pods use together, leaving out ones that are finished or going
+/// away
+pub fn pods_usage<'a>(pods: impl IntoIterator<Item = &'a Pod>) -> Usage {
+    let running = pods.into_iter().filter(|pod| {
+        let phase = pod.status.as_ref().and_then(|s| s.phase.as_deref());
+        pod.metadata.deletion_timestamp.is_none()
+            && !matches!(phase, Some("Succeeded") | Some("Failed"))
+    });
+    running.fold(Usage::default(), |total, pod| {
+        let containers = pod.spec.iter().flat_map(|spec| &spec.containers);
+        let pod = containers.fold(Usage::default(), |pod, c| {
+            let c = container_usage(c.resources.as_ref());
+            Usage {
+                replicas: 1,
+                cpu: pod.cpu + c.cpu,
+                memory: pod.memory + c.memory,
+            }
+        });
+        Usage {
+            replicas: total.replicas + pod.replicas,
+            cpu: total.cpu + pod.cpu,
+            memory: total.memory + pod.memory,
+        }
+    })
+}
+
+/// The replicas a set or proxy may run out of `wanted`, each using `each`, next
+/// to what `others` in the namespace use, and why it's fewer if so. Replicas
+/// that are already running (`current`) are kept, so a quota only ever holds
+/// back a scale-up.
+pub fn allowed(
+    quota: &Quota,
+    others: Usage,
+    each: Usage,
+    current: i32,
+    wanted: i32,
+) -> (i32, Option<String>) {
+    let mut allowed = wanted;
+    let mut reasons = vec![];
+    if let Some(max) = quota.max_replicas {
+        let room = (max - others.replicas).max(0);
+        if room < wanted {
+            allowed = allowed.min(room);
+            reasons.push(format!(
+                "maxReplicas {} with {} used elsewhere",
+                max, others.replicas
+            ));
+        }
+    }
+    let resources = [
+        ("maxCpu", quota.max_cpu.as_ref(), others.cpu, each.cpu),
+        (
+            "maxMemory",
+            quota.max_memory.as_ref(),
+            others.memory,
+            each.memory,
+        ),
+    ];
+    for (field, max, used, each) in resources {
+        let (max, quantity) = match max.and_then(|m| Some((parse(m)?, m))) {
+            Some(max) if each > 0. => max,
+            _ => continue,
+        };
+        // a little slack for the rounding of fractional cores
+        let room = ((max - used) / each + 1e-9).floor().max(0.) as i32;
+        if room < wanted {
+            allowed = allowed.min(room);
+            reasons.push(format!("{} {}", field, quantity.0));
+        }
+    }
+    let allowed = allowed.max(current.min(wanted));
+    let message = (allowed < wanted).then(|| {
+        format!(
+            "only {} of {} replicas fit in the namespace's quota ({})",
+            allowed,
+            wanted,
+            reasons.join(", ")
+        )
+    });
+    (allowed, message)
+}
+
+/// Holds a set or proxy (with pod label `mycelium.njha.dev/<shortname>=<name>`)
+/// to the quota of its namespace, going by the mycelium pods that exist.
+/// Returns the replicas it may run and why that's fewer than `wanted`, if it
+/// is.
+pub async fn enforce(
+    data: &Data,
+    ns: &str,
+    shortname: &str,
+    name: &str,
+    wanted: i32,
+    resources: Option<&ResourceRequirements>,
+) -> Result<(i32, Option<String>), Error> {
+    let quota = {
+        let config = data.config.read().expect("quota");
+        config
+            .quotas
+            .get(ns)
+            .or_else(|| config.quotas.get("*"))
+            .cloned()
+    };
+    let quota = match quota {
+        Some(quota) => quota,
+        None => return Ok((wanted, None)),
+    };
+    let api = Api::<Pod>::namespaced(data.client.clone(), ns);
+    let mut pods = vec![];
+    for label in ["mycelium.njha.dev/mcset", "mycelium.njha.dev/mcproxy"] {
+        pods.extend(api.list(&ListParams::default().labels(label)).await?.items);
+    }
+    let own_label = format!("mycelium.njha.dev/{}", shortname);
+    let (own, others): (Vec<&Pod>, Vec<&Pod>) = pods
+        .iter()
+        .partition(|pod| pod.labels().get(&own_label).is_some_and(|n| n == name));
+    let current = pods_usage(own).replicas;
+    Ok(allowed(
+        &quota,
+        pods_usage(others),
+        container_usage(resources),
+        current,
+        wanted,
+    ))
+}
+
+/// the QuotaExceeded condition, true while the quota holds back replicas
+pub fn quota_condition(
+    previous: &[Condition],
+    exceeded: Option<&str>,
+    generation: Option<i64>,
+) -> Condition {
+    let (reason, message) = match exceeded {
+        Some(message) => ("QuotaExceeded", message.to_string()),
+        None => (
+            "WithinQuota",
+            "every replica fits in the namespace's quota".to_string(),
+        ),
+    };
+    condition(
+        previous,
+        "QuotaExceeded",
+        exceeded.is_some(),
+        reason,
+        message,
+        generation,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn quotas_hold_back_scale_ups() {
+        use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+        assert_eq!(parse(&Quantity("500m".into())), Some(0.5));
+        assert_eq!(parse(&Quantity("2".into())), Some(2.));
+        assert_eq!(parse(&Quantity("1Gi".into())), Some(1073741824.));
+        assert_eq!(parse(&Quantity("1e3".into())), Some(1000.));
+        assert_eq!(parse(&Quantity("lots".into())), None);
+
+        let pods: Vec<k8s_openapi::api::core::v1::Pod> =
+            [("1", "2Gi", None), ("500m", "1Gi", Some("Succeeded"))]
+                .iter()
+                .map(|(cpu, memory, phase)| {
+                    serde_json::from_value(json!({
+                        "metadata": { "name": "survival-0" },
+                        "spec": { "containers": [{ "name": "survival", "resources": {
+                            "requests": { "cpu": cpu },
+                            "limits": { "cpu": cpu, "memory": memory },
+                        } }] },
+                        "status": { "phase": phase },
+                    }))
+                    .unwrap()
+                })
+                .collect();
+        // finished pods don't count, and limits stand in for missing requests
+        let used = pods_usage(&pods);
+        assert_eq!(used, Usage {
+            replicas: 1,
+            cpu: 1.,
+            memory: 2147483648.
+        });
+
+        let each = Usage {
+            replicas: 1,
+            cpu: 0.5,
+            memory: 1073741824.,
+        };
+        let quota = Quota {
+            max_replicas: Some(10),
+            max_cpu: Some(Quantity("3".into())),
+            max_memory: None,
+        };
+        assert_eq!(allowed(&quota, used, each, 0, 3), (3, None));
+        let (fit, message) = allowed(&quota, used, each, 0, 6);
+        assert_eq!(fit, 4);
+        assert!(message.unwrap().contains("maxCpu 3"));
+        // replicas that already run are never taken away
+        assert_eq!(allowed(&quota, used, each, 5, 6).0, 5);
+        let tight = Quota {
+            max_replicas: Some(2),
+            ..quota
+        };
+        assert_eq!(allowed(&tight, used, each, 0, 3).0, 1);
+        assert_eq!(allowed(&Quota::default(), used, each, 0, 50), (50, None));
+
+        let exceeded = quota_condition(&[], Some("only 1 of 3 replicas fit"), Some(2));
+        assert_eq!(
+            (exceeded.status.as_str(), exceeded.reason.as_str()),
+            ("True", "QuotaExceeded")
+        );
+        assert_eq!(quota_condition(&[], None, Some(2)).status, "False");
+    }
+}
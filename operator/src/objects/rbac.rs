@@ -0,0 +1,202 @@
+use k8s_openapi::api::{
+    core::v1::ServiceAccount,
+    rbac::v1::{ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject},
+};
+use kube::api::ObjectMeta;
+
+/// name of the operator's ServiceAccount and its roles and bindings
+const NAME: &str = "mycelium-operator";
+
+fn rule(group: &str, resources: &[&str], verbs: &[&str]) -> PolicyRule {
+    PolicyRule {
+        api_groups: Some(vec![group.to_string()]),
+        resources: Some(resources.iter().map(|r| r.to_string()).collect()),
+        verbs: verbs.iter().map(|v| v.to_string()).collect(),
+        ..PolicyRule::default()
+    }
+}
+
+/// What the operator reads and writes in the namespaces it watches. Keep this
+/// in step with generic_reconcile and the Manager, since crdgen --rbac
+/// generates the chart's ClusterRole from it.
+pub fn namespaced_rules() -> Vec<PolicyRule> {
+    const APPLY: &[&str] = &["get", "list", "watch", "create", "patch"];
+    vec![
+        rule("mycelium.njha.dev", &["*"], &["*"]),
+        // StatefulSets are deleted (orphaning pods) to resize their claim templates, and
+        // either workload is deleted when `stateful` switches to the other one
+        rule("apps", &["statefulsets"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        rule("apps", &["deployments"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        // known-good pod templates are read back from here for a rollback
+        rule("apps", &["controllerrevisions"], &["get"]),
+        // per-replica Services are deleted on scale-down
+        rule("", &["services"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        rule("", &["secrets", "configmaps"], APPLY),
+        rule("policy", &["poddisruptionbudgets"], APPLY),
+        rule("", &["events"], &["create", "patch"]),
+        rule("events.k8s.io", &["events"], &["create", "patch"]),
+        // draining replicas are annotated and labeled destroyable once empty
+        rule("", &["pods"], &["get", "list", "patch"]),
+        rule("", &["persistentvolumeclaims"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        rule("snapshot.storage.k8s.io", &["volumesnapshots"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        rule("gateway.networking.k8s.io", &["tcproutes"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+        // with podBackend = agones, and deleted when a set moves off of it
+        rule("agones.dev", &["fleets"], &[
+            "get", "list", "watch", "create", "patch", "delete",
+        ]),
+    ]
+}
+
+/// what the operator reads outside of namespaces, wherever it watches
+pub fn cluster_rules() -> Vec<PolicyRule> {
+    vec![
+        rule("storage.k8s.io", &["storageclasses"], &["get"]),
+        // API callers with Kubernetes tokens are checked with these
+        rule("authentication.k8s.io", &["tokenreviews"], &["create"]),
+        rule("authorization.k8s.io", &["subjectaccessreviews"], &[
+            "create",
+        ]),
+    ]
+}
+
+/// the operator's permissions, either all cluster-wide or split into Roles per
+/// namespace
+pub struct OperatorRbac {
+    pub account: ServiceAccount,
+    pub cluster_role: ClusterRole,
+    pub cluster_binding: ClusterRoleBinding,
+    /// a Role and RoleBinding in each watched namespace, if it doesn't watch
+    /// all of them
+    pub roles: Vec<(Role, RoleBinding)>,
+}
+
+/// The ServiceAccount the operator (in `namespace`) runs as and its
+/// permissions. If it only watches some namespaces (MYCELIUM_WATCH_NAMESPACES),
+/// everything namespaced is granted by a Role in each of them, and the
+/// ClusterRole keeps what has no namespace.
+pub fn operator_rbac(namespace: &str, watch_namespaces: &[String]) -> OperatorRbac {
+    let account = ServiceAccount {
+        metadata: ObjectMeta {
+            name: Some(NAME.into()),
+            namespace: Some(namespace.into()),
+            ..ObjectMeta::default()
+        },
+        automount_service_account_token: Some(true),
+        ..ServiceAccount::default()
+    };
+    let subjects = Some(vec![Subject {
+        kind: "ServiceAccount".into(),
+        name: NAME.into(),
+        namespace: Some(namespace.into()),
+        ..Subject::default()
+    }]);
+    let cluster_rules = match watch_namespaces.is_empty() {
+        true => namespaced_rules()
+            .into_iter()
+            .chain(cluster_rules())
+            .collect(),
+        false => cluster_rules(),
+    };
+    let cluster_role = ClusterRole {
+        metadata: ObjectMeta {
+            name: Some(NAME.into()),
+            ..ObjectMeta::default()
+        },
+        rules: Some(cluster_rules),
+        ..ClusterRole::default()
+    };
+    let cluster_binding = ClusterRoleBinding {
+        metadata: ObjectMeta {
+            name: Some(NAME.into()),
+            ..ObjectMeta::default()
+        },
+        role_ref: RoleRef {
+            api_group: "rbac.authorization.k8s.io".into(),
+            kind: "ClusterRole".into(),
+            name: NAME.into(),
+        },
+        subjects: subjects.clone(),
+    };
+    let roles = watch_namespaces
+        .iter()
+        .map(|ns| {
+            let metadata = ObjectMeta {
+                name: Some(NAME.into()),
+                namespace: Some(ns.clone()),
+                ..ObjectMeta::default()
+            };
+            let role = Role {
+                metadata: metadata.clone(),
+                rules: Some(namespaced_rules()),
+            };
+            let binding = RoleBinding {
+                metadata,
+                role_ref: RoleRef {
+                    api_group: "rbac.authorization.k8s.io".into(),
+                    kind: "Role".into(),
+                    name: NAME.into(),
+                },
+                subjects: subjects.clone(),
+            };
+            (role, binding)
+        })
+        .collect();
+    OperatorRbac {
+        account,
+        cluster_role,
+        cluster_binding,
+        roles,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watched_namespaces_get_roles() {
+        use super::operator_rbac;
+        let cluster = operator_rbac("mycelium", &[]);
+        assert!(cluster.roles.is_empty());
+        let cluster_rules = cluster.cluster_role.rules.unwrap();
+        assert!(cluster_rules
+            .iter()
+            .any(|r| r.resources == Some(vec!["statefulsets".to_string()])));
+
+        let scoped = operator_rbac("mycelium", &["games".to_string(), "lobby".to_string()]);
+        let namespaces: Vec<_> = scoped
+            .roles
+            .iter()
+            .map(|(role, _)| role.metadata.namespace.clone().unwrap())
+            .collect();
+        assert_eq!(namespaces, ["games", "lobby"]);
+        let (_, binding) = &scoped.roles[0];
+        assert_eq!(
+            binding.subjects.as_ref().unwrap()[0].namespace.as_deref(),
+            Some("mycelium")
+        );
+        // only what has no namespace is left cluster-wide
+        let cluster_rules = scoped.cluster_role.rules.unwrap();
+        let resources: Vec<_> = cluster_rules
+            .iter()
+            .flat_map(|r| r.resources.clone().unwrap())
+            .collect();
+        assert_eq!(resources, [
+            "storageclasses",
+            "tokenreviews",
+            "subjectaccessreviews"
+        ]);
+    }
+}
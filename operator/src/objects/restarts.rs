@@ -0,0 +1,139 @@
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+
+use crate::objects::backup;
+
+/// pod template annotation with the scheduled restart the replicas were last
+/// restarted for, which rolls the workload one replica at a time when it
+/// changes
+pub const RESTARTED_AT_ANNOTATION: &str = "mycelium.njha.dev/restarted-at";
+
+/// seconds before a scheduled restart that players are warned, if not
+/// configured
+pub const DEFAULT_WARNINGS: [u32; 3] = [300, 60, 10];
+
+/// what to do about a restart schedule right now
+#[derive(Debug, PartialEq, Default)]
+pub struct RestartPlan {
+    /// run of the schedule to restart the replicas for
+    pub restart: Option<DateTime<Utc>>,
+
+    /// warning (in seconds before the restart) to announce
+    pub warning: Option<u32>,
+
+    /// when the next warning or restart is due
+    pub next: Option<DateTime<Utc>>,
+}
+
+/// the earliest warning not announced yet that is still ahead, or else the run
+/// itself
+fn next_event(
+    run: DateTime<Utc>,
+    warnings: &[u32],
+    warned: Option<u32>,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    warnings
+        .iter()
+        .filter(|w| warned.is_none_or(|s| **w < s))
+        .map(|w| run - Duration::seconds(i64::from(*w)))
+        .filter(|t| *t > now)
+        .min()
+        .map_or(run, |t| t.min(run))
+}
+
+/// Plans the restarts of `schedule` since `last`, the run the replicas were
+/// restarted for. `warned` is the last warning announced for the upcoming run.
+/// Only the latest of the warnings that are due is announced, so a late
+/// reconcile doesn't count down all at once, and missed runs are collapsed like
+/// missed backups.
+pub fn plan(
+    schedule: &Schedule,
+    last: DateTime<Utc>,
+    warned: Option<u32>,
+    warnings: &[u32],
+    now: DateTime<Utc>,
+) -> RestartPlan {
+    if let Some(run) = backup::due(schedule, last, now) {
+        return RestartPlan {
+            restart: Some(run),
+            warning: None,
+            next: schedule
+                .after(&now)
+                .next()
+                .map(|t| next_event(t, warnings, None, now)),
+        };
+    }
+    let run = match schedule.after(&last).next() {
+        Some(run) => run,
+        None => return RestartPlan::default(),
+    };
+    let left = (run - now).num_seconds();
+    let warning = warnings
+        .iter()
+        .copied()
+        .filter(|w| i64::from(*w) >= left && warned.is_none_or(|s| *w < s))
+        .min();
+    RestartPlan {
+        restart: None,
+        warning,
+        next: Some(next_event(run, warnings, warning.or(warned), now)),
+    }
+}
+
+/// the console command announcing a restart in `seconds`
+pub fn announcement(seconds: u32) -> String {
+    let (count, unit) = match seconds {
+        s if s >= 60 && s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("say The server restarts in {} {}{}", count, unit, plural)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_count_down_then_roll() {
+        let schedule = backup::parse_schedule("0 6 * * *").unwrap();
+        let at = |s: &str| s.parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        let last = at("2022-01-01T06:00:00Z");
+        let warnings = DEFAULT_WARNINGS;
+
+        // nothing to say yet, come back for the first warning
+        let planned = plan(&schedule, last, None, &warnings, at("2022-01-02T05:00:00Z"));
+        assert_eq!(planned, RestartPlan {
+            restart: None,
+            warning: None,
+            next: Some(at("2022-01-02T05:55:00Z")),
+        });
+
+        // only the latest warning that's due is announced
+        let planned = plan(&schedule, last, None, &warnings, at("2022-01-02T05:59:30Z"));
+        assert_eq!(planned.warning, Some(60));
+        assert_eq!(planned.next, Some(at("2022-01-02T05:59:50Z")));
+        let planned = plan(
+            &schedule,
+            last,
+            Some(60),
+            &warnings,
+            at("2022-01-02T05:59:40Z"),
+        );
+        assert_eq!(planned.warning, None);
+
+        let planned = plan(
+            &schedule,
+            last,
+            Some(10),
+            &warnings,
+            at("2022-01-02T06:00:01Z"),
+        );
+        assert_eq!(planned.restart, Some(at("2022-01-02T06:00:00Z")));
+        assert_eq!(planned.next, Some(at("2022-01-03T05:55:00Z")));
+
+        assert_eq!(announcement(300), "say The server restarts in 5 minutes");
+        assert_eq!(announcement(1), "say The server restarts in 1 second");
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::{
+    api::{
+        apps::v1::{ControllerRevision, StatefulSet},
+        core::v1::{Pod, PodTemplateSpec},
+    },
+    apimachinery::pkg::apis::meta::v1::Condition,
+};
+use kube::{api::ListParams, Api, Client};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    objects::{condition, pod_ready, storage::selector_string, UpdateStrategy},
+    Error,
+};
+
+/// how often to check on a canary that is baking or a rollout that might crash
+/// loop, since pods aren't watched
+pub const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// seconds a canary has to stay ready before the rest are updated, if not
+/// configured
+const DEFAULT_BAKE_SECONDS: i64 = 300;
+
+/// seconds a canary may take to become ready, if not configured
+const DEFAULT_READY_TIMEOUT_SECONDS: i64 = 600;
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RolloutOptions {
+    /// update one replica to a new jar build first, and the rest only once it
+    /// has proven itself (StatefulSets with more than one replica only)
+    pub canary: Option<CanaryOptions>,
+
+    /// when the pods of a new pod template crash loop, go back to the last
+    /// template every replica ran until the spec changes again
+    /// (StatefulSets only, default = true)
+    pub auto_rollback: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryOptions {
+    /// seconds the canary has to stay ready before the rest are updated
+    /// (default = 300)
+    pub bake_seconds: Option<i64>,
+
+    /// restarts of the canary's containers that are tolerated before it fails
+    /// (default = 0)
+    pub max_restarts: Option<i32>,
+
+    /// seconds the canary may take to become ready before it fails (default =
+    /// 600)
+    pub ready_timeout_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryStatus {
+    /// jar (type/version/build) the canary runs
+    pub jar: String,
+
+    /// `Baking` while the canary is watched, then `Passed` or `Failed`
+    pub phase: String,
+
+    /// when the canary was first seen running the jar
+    pub started: Option<DateTime<Utc>>,
+
+    /// since when the canary has been ready without interruption
+    pub ready_since: Option<DateTime<Utc>>,
+
+    /// how the canary is doing, or why it failed
+    pub message: Option<String>,
+}
+
+/// what the operator can see of the canary's pod
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CanaryPod {
+    /// whether the pod runs the latest pod template
+    pub updated: bool,
+    pub ready: bool,
+    /// container restarts since the pod was created
+    pub restarts: i32,
+}
+
+/// The update strategy that holds every replica but the highest ordinal on the
+/// old pod template, keeping a configured partition that holds back even more.
+pub fn hold(strategy: Option<&UpdateStrategy>, replicas: i32) -> UpdateStrategy {
+    let partition = strategy
+        .and_then(|s| s.partition)
+        .unwrap_or(0)
+        .max(replicas - 1);
+    UpdateStrategy {
+        r#type: Some("RollingUpdate".into()),
+        partition: Some(partition),
+    }
+}
+
+/// Moves the canary of `jar` along. It bakes once its pod runs the jar, passes
+/// after being ready for the bake time, and fails if it restarts too often or
+/// isn't ready in time. A canary that passed or failed stays that way until the
+/// jar changes.
+pub fn evaluate(
+    previous: Option<&CanaryStatus>,
+    jar: &str,
+    options: &CanaryOptions,
+    pod: &CanaryPod,
+    now: DateTime<Utc>,
+) -> CanaryStatus {
+    let mut status = previous
+        .filter(|c| c.jar == jar)
+        .cloned()
+        .unwrap_or_else(|| CanaryStatus {
+            jar: jar.to_string(),
+            phase: "Baking".into(),
+            ..CanaryStatus::default()
+        });
+    if status.phase != "Baking" {
+        return status;
+    }
+    if !pod.updated {
+        status.message = Some("waiting for the canary to be updated".into());
+        return status;
+    }
+    let started = *status.started.get_or_insert(now);
+    let max_restarts = options.max_restarts.unwrap_or(0);
+    if pod.restarts > max_restarts {
+        status.phase = "Failed".into();
+        status.message = Some(format!("the canary restarted {} times", pod.restarts));
+        return status;
+    }
+    if !pod.ready {
+        status.ready_since = None;
+        let timeout = options
+            .ready_timeout_seconds
+            .unwrap_or(DEFAULT_READY_TIMEOUT_SECONDS);
+        if now - started >= Duration::seconds(timeout) {
+            status.phase = "Failed".into();
+            status.message = Some(format!("the canary wasn't ready within {}s", timeout));
+        } else {
+            status.message = Some("waiting for the canary to be ready".into());
+        }
+        return status;
+    }
+    let ready_since = *status.ready_since.get_or_insert(now);
+    let bake = options.bake_seconds.unwrap_or(DEFAULT_BAKE_SECONDS);
+    let baked = (now - ready_since).num_seconds();
+    if baked >= bake {
+        status.phase = "Passed".into();
+        status.message = Some(format!("the canary was ready for {}s", bake));
+    } else {
+        status.message = Some(format!("the canary has been ready for {}/{}s", baked, bake));
+    }
+    status
+}
+
+/// The canary's pod, replica `ordinal` of the StatefulSet. It only counts as
+/// updated once the StatefulSet has `jar` and has rolled the pod to its latest
+/// revision.
+pub async fn canary_pod(
+    client: Client,
+    ns: &str,
+    name: &str,
+    ordinal: i32,
+    jar: &str,
+) -> Result<CanaryPod, Error> {
+    let sts = match Api::<StatefulSet>::namespaced(client.clone(), ns)
+        .get(name)
+        .await
+    {
+        Ok(sts) => sts,
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(CanaryPod::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let pod = match Api::<Pod>::namespaced(client, ns)
+        .get(&format!("{}-{}", name, ordinal))
+        .await
+    {
+        Ok(pod) => pod,
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(CanaryPod::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let sts_status = sts.status.unwrap_or_default();
+    let current = sts
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get("mycelium.njha.dev/jar"))
+        .map(String::as_str)
+        == Some(jar)
+        && sts_status.observed_generation == sts.metadata.generation;
+    let revision = pod
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|l| l.get("controller-revision-hash"));
+    let statuses = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.container_statuses.as_ref());
+    Ok(CanaryPod {
+        updated: current && revision.is_some() && revision == sts_status.update_revision.as_ref(),
+        ready: pod_ready(&pod),
+        restarts: statuses
+            .into_iter()
+            .flatten()
+            .map(|c| c.restart_count)
+            .sum(),
+    })
+}
+
+/// the Canary condition, false once the canary failed
+pub fn canary_condition(
+    previous: &[Condition],
+    canary: &CanaryStatus,
+    generation: Option<i64>,
+) -> Condition {
+    let reason = format!("Canary{}", canary.phase);
+    let message = format!(
+        "{}: {}",
+        canary.jar,
+        canary.message.clone().unwrap_or_default()
+    );
+    condition(
+        previous,
+        "Canary",
+        canary.phase != "Failed",
+        &reason,
+        message,
+        generation,
+    )
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+pub struct RollbackStatus {
+    /// ControllerRevision of the StatefulSet with the known-good pod template
+    pub revision: String,
+
+    /// generation of the spec that crash looped, the rollback ends when it
+    /// changes
+    pub generation: i64,
+
+    /// pods that were crash looping
+    pub pods: Vec<String>,
+}
+
+/// Pods of the StatefulSet's update revision that are in CrashLoopBackOff, none
+/// if that revision is the known-good one.
+pub async fn crash_looping(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    known_good: &str,
+) -> Result<Vec<String>, Error> {
+    let sts = match Api::<StatefulSet>::namespaced(client.clone(), ns)
+        .get(name)
+        .await
+    {
+        Ok(sts) => sts,
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let update_revision = match sts.status.and_then(|s| s.update_revision) {
+        Some(revision) if revision != known_good => revision,
+        _ => return Ok(vec![]),
+    };
+    let pods = Api::<Pod>::namespaced(client, ns)
+        .list(&ListParams::default().labels(&selector_string(labels)))
+        .await?;
+    Ok(pods
+        .items
+        .into_iter()
+        .filter(|pod| {
+            pod.metadata
+                .labels
+                .as_ref()
+                .and_then(|l| l.get("controller-revision-hash"))
+                == Some(&update_revision)
+        })
+        .filter(|pod| {
+            let statuses = pod
+                .status
+                .as_ref()
+                .and_then(|s| s.container_statuses.as_ref());
+            statuses.into_iter().flatten().any(|c| {
+                let waiting = c.state.as_ref().and_then(|s| s.waiting.as_ref());
+                waiting.and_then(|w| w.reason.as_deref()) == Some("CrashLoopBackOff")
+            })
+        })
+        .filter_map(|pod| pod.metadata.name)
+        .collect())
+}
+
+/// the pod template in the data of a StatefulSet's ControllerRevision, which is
+/// a patch replacing the StatefulSet's template
+pub fn template_of(data: &Value) -> Option<PodTemplateSpec> {
+    let mut template = data.get("spec")?.get("template")?.clone();
+    template.as_object_mut()?.remove("$patch");
+    serde_json::from_value(template).ok()
+}
+
+/// the pod template of a ControllerRevision, if it still exists
+pub async fn revision_template(
+    client: Client,
+    ns: &str,
+    revision: &str,
+) -> Result<Option<PodTemplateSpec>, Error> {
+    match Api::<ControllerRevision>::namespaced(client, ns)
+        .get(revision)
+        .await
+    {
+        Ok(revision) => Ok(revision.data.and_then(|data| template_of(&data.0))),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// the RollbackPerformed condition, for as long as a rollback is in effect
+pub fn rollback_condition(
+    previous: &[Condition],
+    rollback: &RollbackStatus,
+    generation: Option<i64>,
+) -> Condition {
+    let message = format!(
+        "rolled back to {} after {} crash looped, change the spec to try again",
+        rollback.revision,
+        rollback.pods.join(", "),
+    );
+    condition(
+        previous,
+        "RollbackPerformed",
+        true,
+        "CrashLoopBackOff",
+        message,
+        generation,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn canary_bakes_before_the_rest_update() {
+        let options = CanaryOptions {
+            bake_seconds: Some(60),
+            ..CanaryOptions::default()
+        };
+        let start = chrono::Utc::now();
+        let ready = CanaryPod {
+            updated: true,
+            ready: true,
+            restarts: 0,
+        };
+
+        let pending = evaluate(None, "paper/1.20/1", &options, &CanaryPod::default(), start);
+        assert_eq!(pending.phase, "Baking");
+        assert_eq!(pending.started, None);
+        let baking = evaluate(Some(&pending), "paper/1.20/1", &options, &ready, start);
+        assert_eq!(baking.ready_since, Some(start));
+        let later = start + chrono::Duration::seconds(60);
+        assert_eq!(
+            evaluate(Some(&baking), "paper/1.20/1", &options, &ready, later).phase,
+            "Passed"
+        );
+
+        let crashed = CanaryPod {
+            restarts: 1,
+            ..ready
+        };
+        let failed = evaluate(Some(&baking), "paper/1.20/1", &options, &crashed, later);
+        assert_eq!(failed.phase, "Failed");
+        // a failed canary stays failed until the jar changes
+        assert_eq!(
+            evaluate(Some(&failed), "paper/1.20/1", &options, &ready, later).phase,
+            "Failed"
+        );
+        assert_eq!(
+            evaluate(Some(&failed), "paper/1.20/2", &options, &ready, later).phase,
+            "Baking"
+        );
+
+        assert_eq!(hold(None, 3).partition, Some(2));
+    }
+
+    #[test]
+    fn rollback_template_comes_from_the_revision_patch() {
+        let data = json!({ "spec": { "template": {
+        "$patch": "replace",
+        "metadata": { "labels": { "mycelium.njha.dev/mcset": "survival" } },
+        "spec": { "containers": [{ "name": "minecraft", "image": "mycelium/runner:old" }] },
+    } } });
+        let template = template_of(&data).unwrap();
+        assert_eq!(
+            template.metadata.unwrap().labels.unwrap()["mycelium.njha.dev/mcset"],
+            "survival"
+        );
+        assert_eq!(
+            template.spec.unwrap().containers[0].image.as_deref(),
+            Some("mycelium/runner:old")
+        );
+        assert!(template_of(&json!({ "spec": {} })).is_none());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::state::State;
+
+/// set annotation with the time a proxy last asked for the set to be woken up,
+/// see POST /servers/{ns}/{name}/wake
+pub const WAKE_ANNOTATION: &str = "mycelium.njha.dev/wake";
+
+/// how often to count the players on a set that may go to sleep, since counts
+/// aren't watched
+pub const IDLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// seconds a set has to be empty before it goes to sleep, if not configured
+const DEFAULT_IDLE_SECONDS: i64 = 600;
+
+/// seconds a StatefulSet scale-down waits for players to leave the removed
+/// ordinals, if not configured
+pub const DEFAULT_DRAIN_TIMEOUT_SECONDS: i64 = 300;
+
+/// annotation a ReplicaSet goes by to pick the pods it removes on scale-down,
+/// lowest first
+pub const DELETION_COST_ANNOTATION: &str = "controller.kubernetes.io/pod-deletion-cost";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScaleToZeroOptions {
+    /// seconds the set has to be empty before it is scaled to zero (default =
+    /// 600)
+    pub idle_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IdleStatus {
+    /// since when the proxies have reported no players on the set
+    pub empty_since: Option<DateTime<Utc>>,
+
+    /// when the set was scaled to zero, unset once it is woken up
+    pub asleep_since: Option<DateTime<Utc>>,
+}
+
+impl IdleStatus {
+    pub fn asleep(&self) -> bool {
+        self.asleep_since.is_some()
+    }
+}
+
+/// Moves a set with scaleToZero along. It goes to sleep after being empty for
+/// the idle time, and wakes up when a wake request comes in after it fell
+/// asleep. Unknown player counts (no proxy heartbeats) never count as empty.
+pub fn idle(
+    previous: Option<&IdleStatus>,
+    options: &ScaleToZeroOptions,
+    players: Option<u32>,
+    woken: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> IdleStatus {
+    let previous = previous.cloned().unwrap_or_default();
+    if let Some(asleep_since) = previous.asleep_since {
+        if woken.is_some_and(|woken| woken > asleep_since) {
+            return IdleStatus::default();
+        }
+        return previous;
+    }
+    let empty_since = match players {
+        Some(0) => previous.empty_since.unwrap_or(now),
+        _ => return IdleStatus::default(),
+    };
+    let idle = options.idle_seconds.unwrap_or(DEFAULT_IDLE_SECONDS);
+    match now - empty_since >= Duration::seconds(idle) {
+        true => IdleStatus {
+            empty_since: None,
+            asleep_since: Some(now),
+        },
+        false => IdleStatus {
+            empty_since: Some(empty_since),
+            asleep_since: None,
+        },
+    }
+}
+
+/// The replica count to apply to a StatefulSet that is being scaled from `live`
+/// down to `replicas`. StatefulSets always remove the highest ordinals, so
+/// instead of letting them kill pods full of players, keep every ordinal up to
+/// the highest one that still has players (proxies already stop sending new
+/// players there) until `timeout` passes. Ordinals with no known players are
+/// released right away.
+pub fn held_replicas(
+    state: &RwLock<State>,
+    ns: &str,
+    name: &str,
+    live: i32,
+    replicas: i32,
+    timeout: Duration,
+) -> i32 {
+    let key = format!("{}/{}", ns, name);
+    // Note: This will only error with PoisonError, which is unrecoverable and so we
+    // should panic.
+    let mut state = state.write().expect("held_replicas");
+    if live <= replicas {
+        state.draining.remove(&key);
+        return replicas;
+    }
+
+    let now = Utc::now();
+    let started = *state.draining.entry(key.clone()).or_insert(now);
+    if now - started > timeout {
+        state.draining.remove(&key);
+        return replicas;
+    }
+
+    let held = (replicas..live)
+        .rev()
+        .find(|ordinal| {
+            state
+                .players(ns, &format!("{}-{}", name, ordinal))
+                .unwrap_or(0)
+                > 0
+        })
+        .map_or(replicas, |ordinal| ordinal + 1);
+    if held == replicas {
+        state.draining.remove(&key);
+    }
+    held
+}
+
+/// The pod-deletion-cost to set on pods of a Deployment that is being scaled
+/// down, by pod name, leaving out the ones that already have it. A replica's
+/// cost is its player count, so the emptiest replicas are removed first; ones
+/// that were never scraped cost nothing.
+pub fn deletion_costs(
+    pods: &[(String, Option<String>)],
+    players: &BTreeMap<String, i64>,
+) -> BTreeMap<String, String> {
+    pods.iter()
+        .filter_map(|(pod, current)| {
+            let cost = players.get(pod).copied().unwrap_or(0).to_string();
+            (current.as_ref() != Some(&cost)).then(|| (pod.clone(), cost))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::state::ProxyHeartbeat;
+
+    #[test]
+    fn scale_down_holds_occupied_ordinals() {
+        let state = RwLock::new(State::new());
+        state
+            .write()
+            .unwrap()
+            .proxies
+            .insert("games/proxy/proxy-0".into(), ProxyHeartbeat {
+                last_seen: chrono::Utc::now(),
+                reachable: vec![],
+                unreachable: vec![],
+                players: BTreeMap::from([("survival-2".to_string(), 3)]),
+                queues: BTreeMap::new(),
+            });
+        let timeout = chrono::Duration::minutes(10);
+
+        // 4 -> 1 keeps survival-2 (and so survival-1) until its players leave
+        assert_eq!(held_replicas(&state, "games", "survival", 4, 1, timeout), 3);
+        assert!(state
+            .read()
+            .unwrap()
+            .draining
+            .contains_key("games/survival"));
+        // other namespaces don't see these players
+        assert_eq!(held_replicas(&state, "other", "survival", 4, 1, timeout), 1);
+        // once the timeout passes the scale-down goes through anyway
+        assert_eq!(
+            held_replicas(
+                &state,
+                "games",
+                "survival",
+                4,
+                1,
+                chrono::Duration::zero() - timeout
+            ),
+            1
+        );
+        assert!(!state
+            .read()
+            .unwrap()
+            .draining
+            .contains_key("games/survival"));
+    }
+
+    #[test]
+    fn stateless_scale_down_removes_the_emptiest_replicas() {
+        let pods = vec![
+            ("lobby-a".to_string(), None),
+            ("lobby-b".to_string(), Some("7".to_string())),
+            ("lobby-c".to_string(), Some("2".to_string())),
+        ];
+        let players = BTreeMap::from([("lobby-a".to_string(), 12), ("lobby-b".to_string(), 7)]);
+        // lobby-b is up to date, lobby-c wasn't scraped and goes first
+        assert_eq!(
+            deletion_costs(&pods, &players),
+            BTreeMap::from([
+                ("lobby-a".to_string(), "12".to_string()),
+                ("lobby-c".to_string(), "0".to_string())
+            ]),
+        );
+    }
+
+    #[test]
+    fn empty_sets_sleep_until_woken() {
+        let at = |t: &str| {
+            chrono::DateTime::parse_from_rfc3339(t)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let options = ScaleToZeroOptions {
+            idle_seconds: Some(600),
+        };
+
+        // players, or counts nobody reported, keep the set awake
+        assert_eq!(
+            idle(None, &options, Some(3), None, at("2022-01-01T00:00:00Z")),
+            IdleStatus::default()
+        );
+        assert_eq!(
+            idle(None, &options, None, None, at("2022-01-01T00:00:00Z")),
+            IdleStatus::default()
+        );
+
+        let empty = idle(None, &options, Some(0), None, at("2022-01-01T00:00:00Z"));
+        assert_eq!(empty.empty_since, Some(at("2022-01-01T00:00:00Z")));
+        assert!(!empty.asleep());
+        let still = idle(
+            Some(&empty),
+            &options,
+            Some(0),
+            None,
+            at("2022-01-01T00:09:00Z"),
+        );
+        assert_eq!(still, empty);
+        let joined = idle(
+            Some(&empty),
+            &options,
+            Some(1),
+            None,
+            at("2022-01-01T00:09:00Z"),
+        );
+        assert_eq!(joined, IdleStatus::default());
+
+        let asleep = idle(
+            Some(&empty),
+            &options,
+            Some(0),
+            None,
+            at("2022-01-01T00:10:00Z"),
+        );
+        assert_eq!(asleep.asleep_since, Some(at("2022-01-01T00:10:00Z")));
+
+        // wake requests from before it fell asleep don't count
+        let woken = Some(at("2022-01-01T00:05:00Z"));
+        assert_eq!(
+            idle(
+                Some(&asleep),
+                &options,
+                Some(0),
+                woken,
+                at("2022-01-01T00:11:00Z")
+            ),
+            asleep
+        );
+        let woken = Some(at("2022-01-01T00:12:00Z"));
+        let awake = idle(
+            Some(&asleep),
+            &options,
+            None,
+            woken,
+            at("2022-01-01T00:12:30Z"),
+        );
+        assert!(!awake.asleep());
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::{
+    api::{
+        apps::v1::StatefulSet,
+        core::v1::{
+            HostPathVolumeSource, PersistentVolumeClaim, PersistentVolumeClaimSpec,
+            PersistentVolumeClaimVolumeSource, ResourceRequirements, Volume, VolumeMount,
+        },
+        storage::v1::StorageClass,
+    },
+    apimachinery::pkg::{
+        api::resource::Quantity,
+        apis::meta::v1::{ObjectMeta, OwnerReference},
+    },
+};
+use kube::{
+    api::{DeleteParams, ListParams, Patch, PatchParams, PropagationPolicy},
+    Api, Client,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{Error, Error::MyceliumError};
+
+/// where the download cache is mounted in the runner
+pub const DOWNLOAD_CACHE_PATH: &str = "/download-cache";
+
+/// where worldVolumeClaimTemplate is mounted, the server's world container
+pub const WORLDS_PATH: &str = "/worlds";
+
+/// where a shared world is mounted read-only for the runner to copy from
+pub const SHARED_WORLD_PATH: &str = "/shared-world";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedWorldOptions {
+    /// existing ReadWriteMany or ReadOnlyMany claim holding the world
+    pub claim_name: String,
+
+    /// directory of the world (the one with level.dat) inside the claim, if not
+    /// its root
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadCacheOptions {
+    /// size of the ReadWriteMany claim the operator provisions for the cache
+    /// (default = 2Gi)
+    pub size: Option<Quantity>,
+
+    /// storage class of the claim, which has to support ReadWriteMany
+    pub storage_class_name: Option<String>,
+
+    /// cache in this directory on each node instead of a shared claim (it has
+    /// to be writable by the runner's user)
+    pub host_path: Option<String>,
+}
+
+pub fn download_cache_claim_name(name: &str) -> String {
+    format!("{}-download-cache", name)
+}
+
+/// the claim backing a shared download cache, unless it's on the nodes
+pub fn download_cache_claim(
+    name: &str,
+    owner_reference: OwnerReference,
+    options: &DownloadCacheOptions,
+) -> Option<PersistentVolumeClaim> {
+    if options.host_path.is_some() {
+        return None;
+    }
+    let size = options
+        .size
+        .clone()
+        .unwrap_or_else(|| Quantity("2Gi".into()));
+    Some(PersistentVolumeClaim {
+        metadata: ObjectMeta {
+            name: Some(download_cache_claim_name(name)),
+            owner_references: Some(vec![owner_reference]),
+            ..ObjectMeta::default()
+        },
+        spec: Some(PersistentVolumeClaimSpec {
+            access_modes: Some(vec!["ReadWriteMany".into()]),
+            storage_class_name: options.storage_class_name.clone(),
+            resources: Some(ResourceRequirements {
+                requests: Some(BTreeMap::from([("storage".to_string(), size)])),
+                ..ResourceRequirements::default()
+            }),
+            ..PersistentVolumeClaimSpec::default()
+        }),
+        ..PersistentVolumeClaim::default()
+    })
+}
+
+/// the volume and mount for the download cache
+pub fn download_cache_volume(name: &str, options: &DownloadCacheOptions) -> (Volume, VolumeMount) {
+    let volume = match &options.host_path {
+        Some(path) => Volume {
+            name: "mycelium-download-cache".into(),
+            host_path: Some(HostPathVolumeSource {
+                path: path.clone(),
+                type_: Some("DirectoryOrCreate".into()),
+            }),
+            ..Volume::default()
+        },
+        None => Volume {
+            name: "mycelium-download-cache".into(),
+            persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                claim_name: download_cache_claim_name(name),
+                read_only: None,
+            }),
+            ..Volume::default()
+        },
+    };
+    let mount = VolumeMount {
+        name: "mycelium-download-cache".into(),
+        mount_path: DOWNLOAD_CACHE_PATH.into(),
+        ..VolumeMount::default()
+    };
+    (volume, mount)
+}
+
+/// the read-only volume and mount for a shared world
+pub fn shared_world_volume(options: &SharedWorldOptions) -> (Volume, VolumeMount) {
+    let volume = Volume {
+        name: "mycelium-shared-world".into(),
+        persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+            claim_name: options.claim_name.clone(),
+            read_only: Some(true),
+        }),
+        ..Volume::default()
+    };
+    let mount = VolumeMount {
+        name: "mycelium-shared-world".into(),
+        mount_path: SHARED_WORLD_PATH.into(),
+        sub_path: options.path.clone(),
+        read_only: Some(true),
+        ..VolumeMount::default()
+    };
+    (volume, mount)
+}
+
+/// the storage request of a volume claim (template), i.e. "64Gi"
+pub fn storage_request(pvc: &PersistentVolumeClaim) -> Option<String> {
+    pvc.spec
+        .as_ref()?
+        .resources
+        .as_ref()?
+        .requests
+        .as_ref()?
+        .get("storage")
+        .map(|q| q.0.clone())
+}
+
+/// a label selector string for `ListParams::labels`
+pub fn selector_string(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Grows the existing claims of a StatefulSet when the storage request in its
+/// volume claim template changes. Volume claim templates are immutable, so the
+/// StatefulSet is deleted with its pods orphaned and recreated on the next
+/// reconcile. Returns true if the StatefulSet is being recreated.
+pub async fn expand_claims(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    tpl: &PersistentVolumeClaim,
+) -> Result<bool, Error> {
+    let sts_api = Api::<StatefulSet>::namespaced(client.clone(), ns);
+    let existing = match sts_api.get(name).await {
+        Ok(sts) => sts,
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let tpl_name = tpl.metadata.name.clone().unwrap_or_default();
+    let desired = match storage_request(tpl) {
+        Some(desired) => desired,
+        None => return Ok(false),
+    };
+    let current = existing
+        .spec
+        .and_then(|s| s.volume_claim_templates)
+        .unwrap_or_default()
+        .iter()
+        .find(|t| t.metadata.name.as_deref() == Some(tpl_name.as_str()))
+        .and_then(storage_request);
+    if current.is_none() || current.as_deref() == Some(desired.as_str()) {
+        return Ok(false);
+    }
+
+    let pvc_api = Api::<PersistentVolumeClaim>::namespaced(client.clone(), ns);
+    let sc_api = Api::<StorageClass>::all(client.clone());
+    let prefix = format!("{}-{}-", tpl_name, name);
+    let pvcs = pvc_api
+        .list(&ListParams::default().labels(&selector_string(labels)))
+        .await?;
+    let claims: Vec<&PersistentVolumeClaim> = pvcs
+        .items
+        .iter()
+        .filter(|p| {
+            p.metadata
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with(&prefix)
+        })
+        .collect();
+
+    // check every claim can grow before touching any, so a resize doesn't stop
+    // halfway
+    for pvc in &claims {
+        if let Some(class) = pvc.spec.as_ref().and_then(|s| s.storage_class_name.clone()) {
+            let sc = sc_api.get(&class).await?;
+            if sc.allow_volume_expansion != Some(true) {
+                return Err(MyceliumError(format!(
+                    "can't resize {}: storage class {} does not allow volume expansion",
+                    pvc.metadata.name.clone().unwrap_or_default(),
+                    class
+                )));
+            }
+        }
+    }
+    for pvc in claims {
+        let pvc_name = pvc.metadata.name.clone().unwrap_or_default();
+        if storage_request(pvc).as_deref() != Some(desired.as_str()) {
+            info!("expanding {} to {}", pvc_name, desired);
+            pvc_api
+                .patch(
+                    &pvc_name,
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({
+                        "spec": { "resources": { "requests": { "storage": desired } } }
+                    })),
+                )
+                .await?;
+        }
+    }
+
+    info!(
+        "recreating statefulset {} to update its volume claim template",
+        name
+    );
+    sts_api
+        .delete(name, &DeleteParams {
+            propagation_policy: Some(PropagationPolicy::Orphan),
+            ..DeleteParams::default()
+        })
+        .await?;
+    Ok(true)
+}
+
+/// Claims created from `tpl` that are still being expanded, by the storage
+/// provider or on the node (which can need the pod restarted).
+pub async fn resizing_claims(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    tpl: &PersistentVolumeClaim,
+) -> Result<Vec<String>, Error> {
+    let pvc_api = Api::<PersistentVolumeClaim>::namespaced(client, ns);
+    let prefix = format!(
+        "{}-{}-",
+        tpl.metadata.name.clone().unwrap_or_default(),
+        name
+    );
+    let pvcs = pvc_api
+        .list(&ListParams::default().labels(&selector_string(labels)))
+        .await?;
+    Ok(pvcs
+        .items
+        .into_iter()
+        .filter(|pvc| {
+            pvc.metadata
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with(&prefix)
+        })
+        .filter(|pvc| {
+            pvc.status
+                .as_ref()
+                .and_then(|s| s.conditions.as_ref())
+                .is_some_and(|conditions| {
+                    conditions.iter().any(|c| {
+                        c.status == "True"
+                            && matches!(c.type_.as_str(), "Resizing" | "FileSystemResizePending")
+                    })
+                })
+        })
+        .filter_map(|pvc| pvc.metadata.name)
+        .collect())
+}
+
+/// Claims created from `tpl` whose ordinal is at or above `replicas`, i.e. the
+/// ones left behind when a StatefulSet is scaled down.
+pub async fn orphaned_claims(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    tpl: &PersistentVolumeClaim,
+    replicas: i32,
+) -> Result<Vec<PersistentVolumeClaim>, Error> {
+    let pvc_api = Api::<PersistentVolumeClaim>::namespaced(client, ns);
+    let prefix = format!(
+        "{}-{}-",
+        tpl.metadata.name.clone().unwrap_or_default(),
+        name
+    );
+    let pvcs = pvc_api
+        .list(&ListParams::default().labels(&selector_string(labels)))
+        .await?;
+    Ok(pvcs
+        .items
+        .into_iter()
+        .filter(|pvc| {
+            let ordinal = pvc
+                .metadata
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .strip_prefix(&prefix)
+                .and_then(|o| o.parse::<i32>().ok());
+            matches!(ordinal, Some(o) if o >= replicas)
+        })
+        .collect())
+}
+
+/// Makes the CRD an owner of every claim of its StatefulSet, so they're garbage
+/// collected with it. This is whenDeleted = Delete for clusters without the
+/// StatefulSetAutoDeletePVC feature gate.
+pub async fn adopt_claims(
+    client: Client,
+    ns: &str,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    tpl: &PersistentVolumeClaim,
+    owner: &OwnerReference,
+) -> Result<(), Error> {
+    let pvc_api = Api::<PersistentVolumeClaim>::namespaced(client, ns);
+    let prefix = format!(
+        "{}-{}-",
+        tpl.metadata.name.clone().unwrap_or_default(),
+        name
+    );
+    let pvcs = pvc_api
+        .list(&ListParams::default().labels(&selector_string(labels)))
+        .await?;
+    for pvc in pvcs.items {
+        let claim = pvc.metadata.name.clone().unwrap_or_default();
+        let mut owners = pvc.metadata.owner_references.unwrap_or_default();
+        if !claim.starts_with(&prefix) || owners.iter().any(|o| o.uid == owner.uid) {
+            continue;
+        }
+        owners.push(owner.clone());
+        info!("adopting claim {} for deletion with {}", claim, name);
+        pvc_api
+            .patch(
+                &claim,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "metadata": { "ownerReferences": owners } })),
+            )
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1208 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, EnvVar, Volume, VolumeMount},
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use serde_json::json;
+
+use super::{
+    backend, config_hash, desired_objects, drain, mounted_config_maps,
+    mycelium_config::MyceliumConfigSpec, paused, plugins, pod_ready, resizing_condition,
+    workload_conditions, ContainerOptions, DesiredObjects, PluginSource, WorkloadStatus,
+};
+use crate::{
+    helpers::{auth, config::Listen, jarapi::JarDownload, manager::MyceliumConfig},
+    MinecraftSet,
+};
+
+pub(crate) fn mcset(spec: serde_json::Value) -> MinecraftSet {
+    serde_json::from_value(json!({
+        "apiVersion": "mycelium.njha.dev/v1beta1",
+        "kind": "MinecraftSet",
+        "metadata": { "name": "survival", "namespace": "games", "uid": "1234" },
+        "spec": spec,
+    }))
+    .unwrap()
+}
+
+pub(crate) fn config() -> MyceliumConfig {
+    MyceliumConfig {
+        forwarding_secret: "secret".into(),
+        endpoint: "mycelium-operator:8080".into(),
+        tls_ca: None,
+        runner_image: "mycelium/runner:test".into(),
+        itzg_server_image: "itzg/minecraft-server".into(),
+        itzg_proxy_image: "itzg/mc-proxy".into(),
+        java_images: BTreeMap::from([(21, "mycelium/runner:java21-custom".into())]),
+        allowed_plugin_hosts: vec![],
+        allowed_jar_types: vec![],
+        resync: None,
+        max_concurrent_reconciles: 1,
+        min_reconcile_interval: std::time::Duration::ZERO,
+        propagated_labels: vec!["*".into()],
+        propagated_annotations: vec![],
+        pod_backend: backend::PodBackend::Kubernetes,
+        quotas: BTreeMap::new(),
+        api_authentication: true,
+        listen: Listen::default(),
+    }
+}
+
+pub(crate) fn desired(set: &MinecraftSet) -> DesiredObjects {
+    desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        set,
+        &set.spec.container.clone().unwrap_or_default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    )
+    .unwrap()
+}
+
+pub(crate) fn env_value(env: &[EnvVar], name: &str) -> Option<String> {
+    env.iter()
+        .find(|e| e.name == name)
+        .and_then(|e| e.value.clone())
+}
+
+pub(crate) fn basic_spec() -> serde_json::Value {
+    json!({
+        "replicas": 2,
+        "runner": {
+            "jar": { "type": "paper", "version": "1.18.1", "build": "114" },
+            "jvm": "-Xmx2G",
+        },
+    })
+}
+
+#[test]
+fn stateful_set_with_claim_template() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "volumeClaimTemplate": {
+            "metadata": { "name": "root" },
+            "spec": { "resources": { "requests": { "storage": "64Gi" } } },
+        },
+    });
+    let desired = desired(&mcset(spec));
+
+    assert!(desired.stateful);
+    assert_eq!(desired.jar, "paper/1.18.1/114");
+    let sts_spec = desired.statefulset.spec.unwrap();
+    assert_eq!(sts_spec.replicas, Some(2));
+    assert_eq!(sts_spec.service_name, "survival");
+    assert_eq!(sts_spec.volume_claim_templates.unwrap().len(), 1);
+
+    let pod = sts_spec.template.spec.unwrap();
+    let container = &pod.containers[0];
+    assert_eq!(container.image.as_deref(), Some("mycelium/runner:test"));
+    assert!(container
+        .volume_mounts
+        .as_ref()
+        .unwrap()
+        .iter()
+        .any(|m| m.name == "root" && m.mount_path == "/data"));
+    let env = container.env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_JVM_OPTS").as_deref(),
+        Some("-Xmx2G")
+    );
+    // the runner only checks free disk space when a set asks for it
+    assert_eq!(env_value(&env, "MYCELIUM_MIN_FREE_DISK_MB"), None);
+    assert!(env_value(&env, "MYCELIUM_RUNNER_JAR_URL")
+        .unwrap()
+        .ends_with("/builds/114/downloads/paper-1.18.1-114.jar"));
+
+    let svc = desired.service.spec.unwrap();
+    assert_eq!(svc.cluster_ip.as_deref(), Some("None"));
+    assert_eq!(
+        desired.statefulset.metadata.owner_references.unwrap()[0].uid,
+        "1234"
+    );
+}
+
+#[test]
+fn stateless_set_uses_ephemeral_deployment() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "stateful": false });
+    let desired = desired(&mcset(spec));
+
+    assert!(!desired.stateful);
+    assert!(desired.claim_templates.is_empty());
+    let pod = desired.deployment.spec.unwrap().template.spec.unwrap();
+    assert!(pod
+        .volumes
+        .unwrap()
+        .iter()
+        .any(|v| v.name == "data" && v.empty_dir.is_some()));
+    assert_eq!(desired.service.spec.unwrap().cluster_ip, None);
+}
+
+#[test]
+fn ephemeral_storage_overrides_claim_template() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "storage": { "ephemeral": { "sizeLimit": "10Gi" } },
+        "volumeClaimTemplate": { "metadata": { "name": "root" } },
+        "worldVolumeClaimTemplate": { "metadata": { "name": "worlds" } },
+    });
+    let desired = desired(&mcset(spec));
+
+    assert!(desired.claim_templates.is_empty());
+    let pod = desired.statefulset.spec.unwrap().template.spec.unwrap();
+    let data = pod
+        .volumes
+        .unwrap()
+        .into_iter()
+        .find(|v| v.name == "data")
+        .unwrap();
+    assert_eq!(data.empty_dir.unwrap().size_limit.unwrap().0, "10Gi");
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(env_value(&env, "MYCELIUM_WORLD_CONTAINER"), None);
+}
+
+#[test]
+fn worlds_get_their_own_claim() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "volumeClaimTemplate": { "metadata": { "name": "root" } },
+        "worldVolumeClaimTemplate": {
+            "metadata": { "name": "worlds" },
+            "spec": { "storageClassName": "fast" },
+        },
+    });
+    let separate = desired(&mcset(spec.clone()));
+
+    assert_eq!(separate.claim_templates.len(), 2);
+    let pod = separate.statefulset.spec.unwrap().template.spec.unwrap();
+    let mounts = pod.containers[0].volume_mounts.clone().unwrap();
+    assert!(mounts
+        .iter()
+        .any(|m| m.name == "root" && m.mount_path == "/data"));
+    assert!(mounts
+        .iter()
+        .any(|m| m.name == "worlds" && m.mount_path == "/worlds"));
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_WORLD_CONTAINER").as_deref(),
+        Some("/worlds")
+    );
+
+    spec["container"]["worldVolumeClaimTemplate"]["metadata"]["name"] = json!("root");
+    let set = mcset(spec);
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        &set,
+        &set.spec.container.clone().unwrap_or_default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn shared_world_is_mounted_read_only() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "storage": { "ephemeral": {} },
+        "sharedWorld": { "claimName": "lobby-world", "path": "hub" },
+    });
+    let pod = desired(&mcset(spec.clone()))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+
+    let volumes = pod.volumes.unwrap();
+    let volume = volumes
+        .iter()
+        .find(|v| v.name == "mycelium-shared-world")
+        .unwrap();
+    assert_eq!(
+        volume.persistent_volume_claim.as_ref().unwrap().read_only,
+        Some(true)
+    );
+    let mounts = pod.containers[0].volume_mounts.clone().unwrap();
+    let mount = mounts
+        .iter()
+        .find(|m| m.name == "mycelium-shared-world")
+        .unwrap();
+    assert_eq!(mount.sub_path.as_deref(), Some("hub"));
+    assert_eq!(mount.read_only, Some(true));
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_SHARED_WORLD").as_deref(),
+        Some("/shared-world")
+    );
+
+    spec["runner"]["runnerMode"] = json!("itzg");
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(env_value(&env, "WORLD").as_deref(), Some("/shared-world"));
+}
+
+#[test]
+fn extra_volumes_are_passed_through() {
+    let set = mcset(basic_spec());
+    let container = ContainerOptions {
+        extra_volumes: Some(vec![Volume {
+            name: "packs".into(),
+            ..Volume::default()
+        }]),
+        extra_volume_mounts: Some(vec![VolumeMount {
+            name: "packs".into(),
+            mount_path: "/packs".into(),
+            ..VolumeMount::default()
+        }]),
+        ..ContainerOptions::default()
+    };
+    let desired = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        &set,
+        &container,
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        1,
+    )
+    .unwrap();
+
+    let pod = desired.statefulset.spec.unwrap().template.spec.unwrap();
+    assert!(pod.volumes.unwrap().iter().any(|v| v.name == "packs"));
+    assert!(pod.containers[0]
+        .volume_mounts
+        .as_ref()
+        .unwrap()
+        .iter()
+        .any(|m| m.mount_path == "/packs"));
+}
+
+#[test]
+fn itzg_mode_uses_itzg_env() {
+    let mut spec = basic_spec();
+    spec["runner"]["runnerMode"] = json!("itzg");
+    spec["runner"]["worldSource"] = json!({ "url": "https://example.com/hub.zip" });
+    let desired = desired(&mcset(spec));
+
+    let pod = desired.statefulset.spec.unwrap().template.spec.unwrap();
+    let container = &pod.containers[0];
+    assert_eq!(container.image.as_deref(), Some("itzg/minecraft-server"));
+    let env = container.env.clone().unwrap();
+    assert_eq!(env_value(&env, "EULA").as_deref(), Some("TRUE"));
+    assert_eq!(env_value(&env, "TYPE").as_deref(), Some("PAPER"));
+    assert_eq!(env_value(&env, "VERSION").as_deref(), Some("1.18.1"));
+    assert_eq!(env_value(&env, "JVM_OPTS").as_deref(), Some("-Xmx2G"));
+    assert_eq!(env_value(&env, "MYCELIUM_JVM_OPTS"), None);
+    assert_eq!(
+        env_value(&env, "WORLD").as_deref(),
+        Some("https://example.com/hub.zip")
+    );
+}
+
+#[test]
+fn world_url_is_the_same_as_world_source() {
+    let world_env = |runner: serde_json::Value, var: &str| {
+        let mut spec = basic_spec();
+        for (key, value) in runner.as_object().unwrap() {
+            spec["runner"][key] = value.clone();
+        }
+        let pod = desired(&mcset(spec))
+            .statefulset
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap();
+        env_value(pod.containers[0].env.as_ref().unwrap(), var)
+    };
+    let url = "https://example.com/hub.zip";
+    assert_eq!(
+        world_env(json!({ "worldUrl": url }), "MYCELIUM_WORLD_URL").as_deref(),
+        Some(url)
+    );
+    assert_eq!(
+        world_env(
+            json!({ "worldSource": { "url": url } }),
+            "MYCELIUM_WORLD_URL"
+        )
+        .as_deref(),
+        Some(url)
+    );
+    assert_eq!(
+        world_env(json!({ "worldUrl": url, "runnerMode": "itzg" }), "WORLD").as_deref(),
+        Some(url)
+    );
+    assert_eq!(world_env(json!({}), "MYCELIUM_WORLD_URL"), None);
+}
+
+#[test]
+fn forwarding_token_is_per_namespace() {
+    let token = |ns: &str| {
+        let mut set = mcset(basic_spec());
+        set.metadata.namespace = Some(ns.into());
+        desired(&set).secret.string_data.unwrap()["forwarding_token"].clone()
+    };
+    assert_eq!(token("games"), token("games"));
+    assert_ne!(token("games"), token("other"));
+    // which is what the API takes from the pods of the namespace
+    assert_eq!(token("games"), auth::namespace_token("secret", "games"));
+}
+
+#[test]
+fn conditions_follow_the_rollout() {
+    let rolling = WorkloadStatus {
+        replicas: 2,
+        ready_replicas: 1,
+        available_replicas: 1,
+        updated_replicas: 1,
+        jar: Some("paper/1.18.1/114".into()),
+        revision: None,
+    };
+    let conditions = workload_conditions(&[], &rolling, 2, "paper/1.18.1/114", Some(3));
+    let status = |conditions: &[k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition],
+                  t: &str| {
+        conditions.iter().find(|c| c.type_ == t).unwrap().clone()
+    };
+    assert_eq!(status(&conditions, "Ready").status, "False");
+    assert_eq!(status(&conditions, "Progressing").status, "True");
+    assert_eq!(status(&conditions, "Ready").observed_generation, Some(3));
+
+    // ready replicas have to stay ready for minReadySeconds too
+    let settling = WorkloadStatus {
+        ready_replicas: 2,
+        updated_replicas: 2,
+        jar: rolling.jar.clone(),
+        ..WorkloadStatus::default()
+    };
+    let settled = workload_conditions(&conditions, &settling, 2, "paper/1.18.1/114", Some(3));
+    assert_eq!(status(&settled, "Progressing").status, "True");
+
+    let done = WorkloadStatus {
+        ready_replicas: 2,
+        available_replicas: 2,
+        updated_replicas: 2,
+        ..rolling
+    };
+    let next = workload_conditions(&conditions, &done, 2, "paper/1.18.1/114", Some(3));
+    assert_eq!(status(&next, "Ready").status, "True");
+    assert_eq!(status(&next, "Progressing").reason, "RolloutComplete");
+
+    // unchanged conditions keep their transition time
+    let again = workload_conditions(&next, &done, 2, "paper/1.18.1/114", Some(3));
+    assert_eq!(
+        status(&again, "Ready").last_transition_time,
+        status(&next, "Ready").last_transition_time
+    );
+
+    // a new jar is progressing until the annotation catches up
+    let upgrade = workload_conditions(&next, &done, 2, "paper/1.18.2/200", Some(4));
+    assert_eq!(status(&upgrade, "Progressing").status, "True");
+}
+
+#[test]
+fn custom_jars_are_identified_by_checksum() {
+    let mut spec = basic_spec();
+    spec["runner"]["jar"] = json!({
+        "type": "custom",
+        "url": "https://mirror.example/pufferfish-1.18.1.jar",
+        "sha256": "ab".repeat(32),
+    });
+    let desired = desired(&mcset(spec));
+
+    assert_eq!(desired.jar, format!("custom/{}", "ab".repeat(32)));
+}
+
+#[test]
+fn scheduling_options_reach_the_pod() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "nodeSelector": { "pool": "games" },
+        "tolerations": [{ "key": "spot", "operator": "Exists", "effect": "NoSchedule" }],
+        "topologySpreadConstraints": [{
+            "maxSkew": 1,
+            "topologyKey": "topology.kubernetes.io/zone",
+            "whenUnsatisfiable": "DoNotSchedule",
+        }],
+        "priorityClassName": "production",
+        "schedulerName": "gameserver-scheduler",
+    });
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+
+    assert_eq!(pod.node_selector.unwrap()["pool"], "games");
+    assert_eq!(pod.tolerations.unwrap()[0].key.as_deref(), Some("spot"));
+    assert_eq!(pod.topology_spread_constraints.unwrap()[0].max_skew, 1);
+    assert_eq!(pod.priority_class_name.as_deref(), Some("production"));
+    assert_eq!(pod.scheduler_name.as_deref(), Some("gameserver-scheduler"));
+}
+
+#[test]
+fn metadata_overrides_keep_operator_keys() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "metadata": {
+            "service": { "annotations": { "external-dns.alpha.kubernetes.io/hostname": "mc.example.com" } },
+            "workload": { "labels": { "team": "games" } },
+            "pod": { "labels": { "team": "games", "mycelium.njha.dev/mcset": "other" } },
+        },
+    });
+    let desired = desired(&mcset(spec));
+
+    let service_annotations = desired.service.metadata.annotations.unwrap();
+    assert_eq!(
+        service_annotations["external-dns.alpha.kubernetes.io/hostname"],
+        "mc.example.com"
+    );
+    assert_eq!(
+        desired.statefulset.metadata.labels.unwrap()["team"],
+        "games"
+    );
+    assert!(desired
+        .statefulset
+        .metadata
+        .annotations
+        .unwrap()
+        .contains_key("mycelium.njha.dev/jar"));
+    let pod_labels = desired
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .metadata
+        .unwrap()
+        .labels
+        .unwrap();
+    assert_eq!(pod_labels["team"], "games");
+    assert_eq!(pod_labels["mycelium.njha.dev/mcset"], "survival");
+}
+
+#[test]
+fn set_labels_are_propagated_under_overrides() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "metadata": { "pod": { "labels": { "team": "games" } } } });
+    let mut set = mcset(spec);
+    set.metadata.labels = Some(BTreeMap::from([
+        ("team".into(), "platform".into()),
+        ("cost-center".into(), "1234".into()),
+        ("mycelium.njha.dev/proxy".into(), "main".into()),
+    ]));
+    set.metadata.annotations = Some(BTreeMap::from([("owner".into(), "alice".into())]));
+    let desired = desired(&set);
+
+    let pod_labels = desired
+        .statefulset
+        .spec
+        .clone()
+        .unwrap()
+        .template
+        .metadata
+        .unwrap()
+        .labels
+        .unwrap();
+    assert_eq!(pod_labels["team"], "games");
+    assert_eq!(pod_labels["cost-center"], "1234");
+    assert!(!pod_labels.contains_key("mycelium.njha.dev/proxy"));
+    assert_eq!(
+        desired.statefulset.metadata.labels.unwrap()["team"],
+        "platform"
+    );
+    assert_eq!(
+        desired.service.metadata.labels.unwrap()["cost-center"],
+        "1234"
+    );
+    assert_eq!(
+        desired.secret.metadata.labels.unwrap()["cost-center"],
+        "1234"
+    );
+    // annotations aren't propagated unless configured
+    assert!(desired.secret.metadata.annotations.is_none());
+}
+
+#[test]
+fn replica_services_get_stable_node_ports() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "replicaServices": { "baseNodePort": 30100 } });
+    let services = desired(&mcset(spec)).replica_services;
+
+    assert_eq!(services.len(), 2);
+    let second = services[1].spec.clone().unwrap();
+    assert_eq!(services[1].metadata.name.as_deref(), Some("survival-1"));
+    assert_eq!(second.type_.as_deref(), Some("NodePort"));
+    assert_eq!(
+        second.selector.unwrap()["statefulset.kubernetes.io/pod-name"],
+        "survival-1"
+    );
+    assert_eq!(second.ports.unwrap()[0].node_port, Some(30101));
+}
+
+#[test]
+fn whitelist_is_rendered_for_the_runner() {
+    let mut spec = basic_spec();
+    spec["runner"]["whitelist"] = json!({
+        "players": ["Notch", "069a79f444e94726a5befca90e38aaf5"],
+        "enforce": true,
+    });
+    let desired = desired(&mcset(spec));
+
+    assert_eq!(
+        desired.access.metadata.name.as_deref(),
+        Some("survival-access")
+    );
+    let whitelist: serde_json::Value =
+        serde_json::from_str(&desired.access.data.unwrap()["whitelist.json"]).unwrap();
+    assert_eq!(whitelist[0], json!({ "name": "Notch" }));
+    assert_eq!(whitelist[1]["uuid"], "069a79f4-44e9-4726-a5be-fca90e38aaf5");
+    let env = desired
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap()
+        .containers[0]
+        .env
+        .clone()
+        .unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_WHITELIST_ENFORCE").as_deref(),
+        Some("true")
+    );
+}
+
+#[test]
+fn ops_default_to_level_4() {
+    let mut spec = basic_spec();
+    spec["runner"]["ops"] = json!([{ "player": "Notch" }, { "player": "jeb_", "level": 2 }]);
+    let data = desired(&mcset(spec)).access.data.unwrap();
+
+    let ops: serde_json::Value = serde_json::from_str(&data["ops.json"]).unwrap();
+    assert_eq!(
+        ops[0],
+        json!({ "name": "Notch", "level": 4, "bypassesPlayerLimit": false })
+    );
+    assert_eq!(ops[1]["level"], 2);
+    assert!(!data.contains_key("whitelist.json"));
+}
+
+#[test]
+fn config_keys_mount_as_single_files() {
+    let mut spec = basic_spec();
+    spec["runner"]["config"] = json!([
+        { "name": "luckperms", "path": "plugins/LuckPerms/config.yml", "key": "config.yml" },
+        { "name": "luckperms", "path": "plugins/LuckPerms/groups.yml", "key": "groups.yml" },
+    ]);
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+
+    let mounts = pod.containers[0].volume_mounts.clone().unwrap();
+    let mount = mounts
+        .iter()
+        .find(|m| m.sub_path.as_deref() == Some("groups.yml"))
+        .unwrap();
+    assert_eq!(mount.mount_path, "/config/plugins/LuckPerms/groups.yml");
+    let volumes = pod.volumes.unwrap();
+    assert_eq!(volumes.iter().filter(|v| v.name == "luckperms").count(), 1);
+}
+
+#[test]
+fn plugin_volumes_mount_single_jars() {
+    let mut spec = basic_spec();
+    spec["runner"]["pluginVolumes"] = json!([
+        { "configMap": "small-plugins", "path": "Motd.jar" },
+        { "persistentVolumeClaim": "plugin-library", "path": "luckperms/LuckPerms-5.4.jar" },
+    ]);
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+
+    let mounts = pod.containers[0].volume_mounts.clone().unwrap();
+    let library = mounts
+        .iter()
+        .find(|m| m.name == "mycelium-plugin-1")
+        .unwrap();
+    assert_eq!(library.mount_path, "/data/plugins/LuckPerms-5.4.jar");
+    assert_eq!(
+        library.sub_path.as_deref(),
+        Some("luckperms/LuckPerms-5.4.jar")
+    );
+    let volumes = pod.volumes.unwrap();
+    let small = volumes
+        .iter()
+        .find(|v| v.name == "mycelium-plugin-0")
+        .unwrap();
+    assert_eq!(
+        small.config_map.as_ref().unwrap().items.as_ref().unwrap()[0].key,
+        "Motd.jar"
+    );
+}
+
+#[test]
+fn mods_are_passed_to_both_runners() {
+    let mut spec = basic_spec();
+    spec["runner"]["mods"] = json!(["https://example.com/lithium.jar"]);
+    let pod = desired(&mcset(spec.clone()))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    let mods: Vec<PluginSource> =
+        serde_json::from_str(&env_value(&env, "MYCELIUM_MODS").unwrap()).unwrap();
+    assert_eq!(mods[0].url, "https://example.com/lithium.jar");
+
+    spec["runner"]["runnerMode"] = json!("itzg");
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MODS").as_deref(),
+        Some("https://example.com/lithium.jar")
+    );
+}
+
+#[test]
+fn plugin_configs_mount_outside_the_server_root() {
+    let mut spec = basic_spec();
+    spec["runner"]["pluginConfigs"] =
+        json!([{ "plugin": "LuckPerms", "configMap": "luckperms-config" }]);
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+
+    let mounts = pod.containers[0].volume_mounts.clone().unwrap();
+    let mount = mounts
+        .iter()
+        .find(|m| m.name == "mycelium-plugin-config-0")
+        .unwrap();
+    assert_eq!(mount.mount_path, "/plugin-configs/LuckPerms");
+}
+
+#[test]
+fn plugins_must_come_from_allowed_hosts() {
+    let mut config = config();
+    config.allowed_plugin_hosts = vec!["github.com".into(), "hangar.papermc.io".into()];
+    assert!(config.allows_download("https://objects.github.com/a.jar"));
+    assert!(!config.allows_download("https://evilgithub.com/a.jar"));
+
+    let mut spec = basic_spec();
+    spec["runner"]["plugins"] =
+        json!([{ "hangar": "Lucko/LuckPerms@5.4.102" }, "https://example.com/a.jar"]);
+    let set = mcset(spec);
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config,
+        None,
+        "mcset",
+        &set,
+        &ContainerOptions::default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("https://example.com/a.jar"));
+}
+
+#[test]
+fn download_cache_is_shared_by_the_replicas() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "downloadCache": { "size": "5Gi" } });
+    let shared = desired(&mcset(spec.clone()));
+
+    let claim = shared.download_cache.unwrap();
+    assert_eq!(
+        claim.metadata.name.as_deref(),
+        Some("survival-download-cache")
+    );
+    assert_eq!(claim.spec.unwrap().access_modes.unwrap(), vec![
+        "ReadWriteMany".to_string()
+    ]);
+    let pod = shared.statefulset.spec.unwrap().template.spec.unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_DOWNLOAD_CACHE").as_deref(),
+        Some("/download-cache")
+    );
+
+    spec["container"] = json!({ "downloadCache": { "hostPath": "/var/cache/mycelium" } });
+    let on_nodes = desired(&mcset(spec));
+    assert!(on_nodes.download_cache.is_none());
+    let volumes = on_nodes
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap()
+        .volumes
+        .unwrap();
+    assert!(volumes.iter().any(|v| v.host_path.is_some()));
+}
+
+#[test]
+fn resizing_condition_names_the_claims() {
+    let resizing = resizing_condition(&[], &["root-survival-0".to_string()], Some(2));
+    assert_eq!(resizing.status, "True");
+    assert_eq!(resizing.message, "resizing root-survival-0");
+
+    let done = resizing_condition(&[resizing], &[], Some(2));
+    assert_eq!(done.status, "False");
+    assert_eq!(done.reason, "ClaimsResized");
+}
+
+#[test]
+fn scheduled_commands_are_passed_to_the_runner() {
+    let mut spec = basic_spec();
+    spec["runner"]["scheduledCommands"] = json!([
+        { "schedule": "0 4 * * *", "command": "save-all" },
+    ]);
+    let desired = desired(&mcset(spec.clone()));
+
+    let pod = desired.statefulset.spec.unwrap().template.spec.unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_SCHEDULED_COMMANDS").as_deref(),
+        Some(r#"[{"schedule":"0 4 * * *","command":"save-all"}]"#)
+    );
+
+    spec["runner"]["scheduledCommands"][0]["schedule"] = json!("nightly");
+    let set = mcset(spec);
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        &set,
+        &set.spec.container.clone().unwrap_or_default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn hooks_are_passed_to_the_runner() {
+    let mut spec = basic_spec();
+    spec["runner"]["hooks"] = json!({
+        "postStart": [{ "command": "whitelist reload" }],
+        "preStop": [{ "command": "broadcast Restarting!", "waitSeconds": 5 }, { "command": "kickall" }],
+    });
+    let desired = desired(&mcset(spec.clone()));
+
+    let pod = desired.statefulset.spec.unwrap().template.spec.unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    let hooks: serde_json::Value =
+        serde_json::from_str(&env_value(&env, "MYCELIUM_HOOKS").unwrap()).unwrap();
+    assert_eq!(hooks["postStart"][0]["command"], "whitelist reload");
+    assert_eq!(hooks["preStop"][0]["waitSeconds"], 5);
+    assert_eq!(hooks["preStop"][1]["command"], "kickall");
+
+    spec["runner"]["hooks"]["postStart"][0]["shell"] = json!("echo started");
+    let set = mcset(spec);
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        &set,
+        &set.spec.container.clone().unwrap_or_default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn config_edits_change_the_hash() {
+    let mut spec = basic_spec();
+    spec["runner"]["config"] = json!([{ "name": "server-properties", "path": "." }]);
+    let desired = desired(&mcset(spec));
+    let template = desired.statefulset.spec.unwrap().template;
+    assert_eq!(mounted_config_maps(&template), [
+        "server-properties",
+        "survival-access"
+    ]);
+
+    let cm = |motd: &str| -> ConfigMap {
+        serde_json::from_value(json!({
+            "metadata": { "name": "server-properties" },
+            "data": { "server.properties": format!("motd={}", motd) },
+        }))
+        .unwrap()
+    };
+    let before = config_hash(&template, &[cm("hello")]);
+    assert_eq!(before, config_hash(&template, &[cm("hello")]));
+    assert_ne!(before, config_hash(&template, &[cm("goodbye")]));
+}
+
+#[test]
+fn paused_annotation_must_be_true() {
+    let mut set = mcset(basic_spec());
+    assert!(!paused(&set));
+
+    let annotations = set.metadata.annotations.get_or_insert_with(BTreeMap::new);
+    annotations.insert(super::PAUSED_ANNOTATION.into(), "false".into());
+    assert!(!paused(&set));
+
+    let annotations = set.metadata.annotations.get_or_insert_with(BTreeMap::new);
+    annotations.insert(super::PAUSED_ANNOTATION.into(), "true".into());
+    assert!(paused(&set));
+}
+
+#[test]
+fn update_strategy_stages_rollouts() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "updateStrategy": { "partition": 1 } });
+    let strategy = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .update_strategy
+        .unwrap();
+    assert_eq!(strategy.type_.as_deref(), Some("RollingUpdate"));
+    assert_eq!(strategy.rolling_update.unwrap().partition, Some(1));
+
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "updateStrategy": { "type": "OnDelete", "partition": 1 } });
+    let set = mcset(spec);
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &config(),
+        None,
+        "mcset",
+        &set,
+        &set.spec.container.clone().unwrap_or_default(),
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn servers_are_ready_once_done_loading() {
+    let mut spec = basic_spec();
+    spec["container"] = json!({ "minReadySeconds": 30 });
+    let sts_spec = desired(&mcset(spec)).statefulset.spec.unwrap();
+    assert_eq!(sts_spec.min_ready_seconds, Some(30));
+    let probe = sts_spec.template.spec.unwrap().containers[0]
+        .readiness_probe
+        .clone()
+        .unwrap();
+    assert_eq!(probe.exec.unwrap().command.unwrap(), [
+        "test",
+        "-f",
+        "/tmp/mycelium-ready"
+    ]);
+}
+
+#[test]
+fn only_ready_pods_take_players() {
+    let pod = |ready: &str, deleting: bool| -> k8s_openapi::api::core::v1::Pod {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": "survival-0",
+                "deletionTimestamp": deleting.then_some("2022-01-01T00:00:00Z"),
+            },
+            "status": { "conditions": [{ "type": "Ready", "status": ready }] },
+        }))
+        .unwrap()
+    };
+    assert!(pod_ready(&pod("True", false)));
+    assert!(!pod_ready(&pod("False", false)));
+    assert!(!pod_ready(&pod("True", true)));
+}
+
+#[test]
+fn servers_are_protected_until_drained() {
+    let desired = desired(&mcset(basic_spec()));
+    let pod_labels = desired
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .metadata
+        .unwrap()
+        .labels
+        .unwrap();
+    assert_eq!(pod_labels[drain::DESTROYABLE_LABEL], "false");
+    let selector = desired
+        .pdb
+        .spec
+        .unwrap()
+        .selector
+        .unwrap()
+        .match_labels
+        .unwrap();
+    assert_eq!(selector[drain::DESTROYABLE_LABEL], "false");
+}
+
+#[test]
+fn runner_gets_the_pod_name_for_templating() {
+    let mut spec = basic_spec();
+    spec["runner"]["serverProperties"] = json!({ "level-name": "world-${POD_ORDINAL}" });
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    let env = pod.containers[0].env.clone().unwrap();
+    let pod_name = env
+        .iter()
+        .find(|e| e.name == "POD_NAME")
+        .unwrap()
+        .value_from
+        .clone()
+        .unwrap();
+    assert_eq!(pod_name.field_ref.unwrap().field_path, "metadata.name");
+    assert_eq!(
+        env_value(&env, "MYCELIUM_SERVER_PROPERTIES").as_deref(),
+        Some(r#"{"level-name":"world-${POD_ORDINAL}"}"#)
+    );
+}
+
+#[test]
+fn servers_get_time_to_save() {
+    let pod = desired(&mcset(basic_spec()))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    assert_eq!(pod.termination_grace_period_seconds, Some(300));
+    assert!(pod.containers[0].lifecycle.is_none());
+
+    let mut spec = basic_spec();
+    spec["container"] = json!({
+        "terminationGracePeriodSeconds": 900,
+        "preStop": ["sh", "-c", "sleep 10"],
+    });
+    let pod = desired(&mcset(spec))
+        .statefulset
+        .spec
+        .unwrap()
+        .template
+        .spec
+        .unwrap();
+    assert_eq!(pod.termination_grace_period_seconds, Some(900));
+    let pre_stop = pod.containers[0]
+        .lifecycle
+        .clone()
+        .unwrap()
+        .pre_stop
+        .unwrap();
+    assert_eq!(pre_stop.exec.unwrap().command.unwrap(), [
+        "sh", "-c", "sleep 10"
+    ]);
+}
+
+#[test]
+fn agones_runs_servers_without_claims() {
+    use backend::{PodBackend, Workload};
+    let workload = |backend: PodBackend, spec: serde_json::Value| {
+        let set = mcset(spec);
+        backend.workload(&set.spec.runner, &set.spec.container.unwrap_or_default())
+    };
+    let mut claimed = basic_spec();
+    claimed["container"] = json!({ "volumeClaimTemplate": { "metadata": { "name": "root" } } });
+    let mut ephemeral = claimed.clone();
+    ephemeral["container"]["storage"] = json!({ "ephemeral": { "sizeLimit": "10Gi" } });
+    let mut stateless = basic_spec();
+    stateless["container"] = json!({ "stateful": false });
+    let mut proxy = basic_spec();
+    proxy["runner"]["jar"]["type"] = json!("velocity");
+
+    assert_eq!(
+        workload(PodBackend::Kubernetes, ephemeral.clone()),
+        Workload::StatefulSet
+    );
+    assert_eq!(
+        workload(PodBackend::Kubernetes, stateless.clone()),
+        Workload::Deployment
+    );
+    assert_eq!(workload(PodBackend::Agones, claimed), Workload::StatefulSet);
+    assert_eq!(
+        workload(PodBackend::Agones, ephemeral.clone()),
+        Workload::Fleet
+    );
+    assert_eq!(workload(PodBackend::Agones, stateless), Workload::Fleet);
+    assert_eq!(workload(PodBackend::Agones, proxy), Workload::StatefulSet);
+    assert!("openmatch".parse::<PodBackend>().is_err());
+
+    let sts = desired(&mcset(ephemeral)).statefulset;
+    let spec = sts.spec.clone().unwrap();
+    let fleet = backend::fleet(&sts.metadata, 2, &spec.template, 25565);
+    assert_eq!(fleet["metadata"]["name"], "survival");
+    assert_eq!(fleet["spec"]["replicas"], 2);
+    let server = &fleet["spec"]["template"]["spec"];
+    assert_eq!(server["container"], "survival");
+    assert_eq!(server["ports"][0]["containerPort"], 25565);
+    assert_eq!(server["ports"][0]["portPolicy"], "Dynamic");
+    assert_eq!(
+        fleet["spec"]["template"]["metadata"]["labels"]["mycelium.njha.dev/mcset"],
+        "survival"
+    );
+}
+
+#[test]
+fn namespace_configs_override_the_operator() {
+    let spec: MyceliumConfigSpec = serde_json::from_value(json!({
+        "allowedJarTypes": ["velocity"],
+        "runnerImage": "tenant/runner:test",
+        "allowedPluginHosts": ["plugins.example.com"],
+        "forwardingSecret": { "name": "forwarding", "key": "secret" },
+    }))
+    .unwrap();
+    let tenant = config().for_namespace(&spec, Some("tenant".into()));
+    assert_eq!(tenant.runner_image, "tenant/runner:test");
+    assert_eq!(tenant.forwarding_secret, "tenant");
+    assert!(tenant.allows_download("https://cdn.plugins.example.com/a.jar"));
+    assert!(!tenant.allows_download("https://example.org/a.jar"));
+    // the rest is the operator's
+    assert_eq!(tenant.endpoint, "mycelium-operator:8080");
+    assert_eq!(
+        config()
+            .for_namespace(&MyceliumConfigSpec::default(), None)
+            .runner_image,
+        "mycelium/runner:test"
+    );
+
+    // paper isn't allowed in the namespace
+    let set = mcset(basic_spec());
+    let container = set.spec.container.clone().unwrap_or_default();
+    let result = desired_objects(
+        vec![],
+        IntOrString::Int(25565),
+        &tenant,
+        None,
+        "mcset",
+        &set,
+        &container,
+        &set.spec.runner,
+        &JarDownload::papermc(&set.spec.runner.jar),
+        set.spec.replicas,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn objects_are_applied_again_after_a_restart() {
+    use super::{plan_apply, APPLIED_HASH_ANNOTATION};
+
+    let desired = json!({
+        "metadata": { "labels": { "mcset": "survival" }, "annotations": { APPLIED_HASH_ANNOTATION: "abc" } },
+        "spec": { "replicas": 2, "resources": { "limits": { "memory": "1024Mi" } } },
+    });
+    let mut live = desired.clone();
+    live["metadata"]["resourceVersion"] = json!("7");
+    // the API server writes quantities back in its own form and fills in defaults
+    live["spec"]["resources"]["limits"]["memory"] = json!("1Gi");
+    live["spec"]["revisionHistoryLimit"] = json!(10);
+    let seen = ("abc".to_string(), "3".to_string());
+
+    assert_eq!(plan_apply(&desired, "abc", None, None), (true, false));
+    assert_eq!(
+        plan_apply(&desired, "abc", Some((&live, "3".into())), Some(&seen)),
+        (false, false)
+    );
+    // nothing to go on after a restart, so it's applied, but only edits count as
+    // drift
+    assert_eq!(
+        plan_apply(&desired, "abc", Some((&live, "3".into())), None),
+        (true, false)
+    );
+    let mut edited = live.clone();
+    edited["spec"]["replicas"] = json!(5);
+    assert_eq!(
+        plan_apply(&desired, "abc", Some((&edited, "4".into())), None),
+        (true, true)
+    );
+    // a new spec isn't drift
+    assert_eq!(
+        plan_apply(&desired, "def", Some((&edited, "4".into())), None),
+        (true, false)
+    );
+    assert_eq!(
+        plan_apply(&desired, "def", Some((&live, "3".into())), Some(&seen)),
+        (true, false)
+    );
+
+    // metadata edits don't bump the generation
+    let mut relabeled = live.clone();
+    relabeled["metadata"]["labels"]["mcset"] = json!("lobby");
+    assert_eq!(
+        plan_apply(&desired, "abc", Some((&relabeled, "3".into())), Some(&seen)),
+        (true, true)
+    );
+
+    // secrets come back with their stringData in data
+    let secret = json!({ "metadata": { "annotations": { APPLIED_HASH_ANNOTATION: "abc" } }, "stringData": { "token": "hi" } });
+    let live = json!({ "metadata": { "annotations": { APPLIED_HASH_ANNOTATION: "abc" } }, "data": { "token": "aGk=" } });
+    assert_eq!(
+        plan_apply(
+            &secret,
+            "abc",
+            Some((&live, "9".into())),
+            Some(&("abc".into(), "9".into()))
+        ),
+        (false, false)
+    );
+}
@@ -0,0 +1,206 @@
+use serde_json::{json, Value};
+
+/// jar types the runner knows how to download and configure
+pub const JAR_TYPES: [&str; 5] = ["paper", "vanilla", "velocity", "waterfall", "custom"];
+
+/// CEL rules as (path from the spec, rule, message). Paths list each property
+/// name with its alternatives, for fields v1beta2 renamed.
+fn rules() -> Vec<(Vec<&'static [&'static str]>, String, &'static str)> {
+    let jar_types = JAR_TYPES
+        .iter()
+        .map(|t| format!("'{}'", t))
+        .collect::<Vec<_>>()
+        .join(", ");
+    vec![
+        (vec![], "self.replicas >= 0".into(), "replicas must not be negative"),
+        (
+            vec![&["runner"], &["jar", "server"]],
+            format!("self.type in [{}]", jar_types),
+            "unsupported jar type",
+        ),
+        (
+            vec![&["runner"], &["jar", "server"]],
+            "self.type != 'custom' || has(self.url)".into(),
+            "custom jars need a url",
+        ),
+        (
+            vec![&["runner"], &["jar", "server"]],
+            "!has(self.autoUpdate) || !self.autoUpdate || !has(self.build) || self.build == ''".into(),
+            "autoUpdate needs build to be omitted",
+        ),
+        (
+            vec![&["runner"], &["jar", "server"], &["channel"]],
+            "self in ['stable', 'experimental']".into(),
+            "channel must be stable or experimental",
+        ),
+        (
+            vec![&["runner"], &["jar", "server"], &["sha256"]],
+            "self.matches('^[0-9a-fA-F]{64}$')".into(),
+            "sha256 must be 64 hex characters",
+        ),
+        (
+            vec![&["runner"], &["runnerMode", "mode"]],
+            "self in ['mycelium', 'itzg']".into(),
+            "runner mode must be mycelium or itzg",
+        ),
+        (
+            vec![&["proxy"], &["hostname"]],
+            r"size(self) <= 253 && self.matches('^[a-z0-9]([-a-z0-9]*[a-z0-9])?(\\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*$')".into(),
+            "hostname must be a valid DNS name",
+        ),
+        (
+            vec![&["proxy"], &["forwarding"]],
+            "self in ['velocity', 'bungeecord']".into(),
+            "forwarding must be velocity or bungeecord",
+        ),
+        (
+            vec![&["runner"], &["ops"]],
+            "self.all(op, !has(op.level) || (op.level >= 1 && op.level <= 4))".into(),
+            "op levels must be between 1 and 4",
+        ),
+        (
+            vec![&["runner"], &["pluginVolumes"]],
+            "self.all(p, [has(p.configMap), has(p.secret), has(p.persistentVolumeClaim)].filter(s, s).size() == 1)".into(),
+            "plugin volumes need exactly one of configMap, secret or persistentVolumeClaim",
+        ),
+        (
+            vec![&["runner"], &["javaVersion"]],
+            "self >= 8".into(),
+            "javaVersion must be a Java major version, i.e. 17",
+        ),
+        (
+            vec![&["fallbackStrategy"]],
+            "self in ['priority', 'leastLoaded', 'random']".into(),
+            "fallbackStrategy must be priority, leastLoaded or random",
+        ),
+        (
+            vec![&["container"], &["persistentVolumeClaimRetentionPolicy"]],
+            "(!has(self.whenScaled) || self.whenScaled in ['Retain', 'Delete']) && \
+             (!has(self.whenDeleted) || self.whenDeleted in ['Retain', 'Delete'])"
+                .into(),
+            "claim retention policies must be Retain or Delete",
+        ),
+        (
+            vec![&["runner"]],
+            "!has(self.worldUrl) || !has(self.worldSource)".into(),
+            "runner.worldUrl is the same as worldSource.url, set only one",
+        ),
+        (
+            vec![&["restoreFrom"]],
+            "[has(self.snapshot), has(self.url), has(self.manifest)].filter(x, x).size() == 1"
+                .into(),
+            "restoreFrom needs exactly one of snapshot, url or manifest",
+        ),
+        (
+            vec![&["container"], &["drainTimeoutSeconds"]],
+            "self >= 0".into(),
+            "drainTimeoutSeconds must not be negative",
+        ),
+        (
+            vec![&["container"], &["terminationGracePeriodSeconds"]],
+            "self >= 0".into(),
+            "terminationGracePeriodSeconds must not be negative",
+        ),
+        (
+            vec![&["container"], &["preStop"]],
+            "size(self) > 0".into(),
+            "preStop needs a command",
+        ),
+        (
+            vec![&["scaleToZero"], &["idleSeconds"]],
+            "self > 0".into(),
+            "idleSeconds must be positive",
+        ),
+        (
+            vec![&["queue"], &["limbo"]],
+            "size(self) > 0".into(),
+            "queues need a limbo set",
+        ),
+        (
+            vec![&["queue"], &["maxPlayers"]],
+            "self > 0".into(),
+            "maxPlayers must be positive",
+        ),
+        (
+            vec![&["runner"], &["hooks"], &["postStart"]],
+            "self.all(h, has(h.command) != has(h.shell))".into(),
+            "hooks need exactly one of command or shell",
+        ),
+        (
+            vec![&["runner"], &["hooks"], &["preStop"]],
+            "self.all(h, has(h.command) != has(h.shell))".into(),
+            "hooks need exactly one of command or shell",
+        ),
+    ]
+}
+
+/// CEL rules for a MinecraftFleet, besides the ones for its template
+fn fleet_rules() -> Vec<(Vec<&'static [&'static str]>, String, &'static str)> {
+    vec![
+        (
+            vec![],
+            "self.spares >= 0".into(),
+            "spares must not be negative",
+        ),
+        (
+            vec![&["maxServers"]],
+            "self >= 0".into(),
+            "maxServers must not be negative",
+        ),
+    ]
+}
+
+/// Adds x-kubernetes-validations rules to every version of a generated CRD, so
+/// that bad specs are rejected at apply time. k8s-openapi predates the field,
+/// so this works on JSON.
+pub fn with_validations(crd: Value) -> Value {
+    add_rules(crd, &[], rules())
+}
+
+/// with_validations for a MinecraftFleet, whose template gets the MinecraftSet
+/// rules
+pub fn with_fleet_validations(crd: Value) -> Value {
+    let crd = add_rules(crd, &[], fleet_rules());
+    add_rules(crd, &["template"], rules())
+}
+
+/// adds rules to the schema at `at` (property names from the spec) in every
+/// version
+fn add_rules(
+    mut crd: Value,
+    at: &[&str],
+    rules: Vec<(Vec<&'static [&'static str]>, String, &'static str)>,
+) -> Value {
+    let versions = crd["spec"]["versions"].as_array_mut().map(|v| v.iter_mut());
+    for version in versions.into_iter().flatten() {
+        let mut spec = &mut version["schema"]["openAPIV3Schema"]["properties"]["spec"];
+        for name in at {
+            spec = &mut spec["properties"][*name];
+        }
+        for (path, rule, message) in &rules {
+            if let Some(schema) = find(spec, path) {
+                let validations = schema
+                    .as_object_mut()
+                    .expect("schema object")
+                    .entry("x-kubernetes-validations")
+                    .or_insert_with(|| json!([]));
+                validations
+                    .as_array_mut()
+                    .expect("validations array")
+                    .push(json!({ "rule": rule, "message": message }));
+            }
+        }
+    }
+    crd
+}
+
+fn find<'a>(schema: &'a mut Value, path: &[&[&str]]) -> Option<&'a mut Value> {
+    match path.split_first() {
+        None => Some(schema),
+        Some((names, rest)) => {
+            let properties = schema.get_mut("properties")?.as_object_mut()?;
+            let name = names.iter().find(|n| properties.contains_key(**n))?;
+            find(properties.get_mut(*name)?, rest)
+        }
+    }
+}
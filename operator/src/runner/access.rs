@@ -0,0 +1,110 @@
+use std::{
+    env,
+    fs::{read_to_string, File},
+    io::{Error, Write},
+    path::Path,
+    process::Command,
+};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+
+/// Merges the access lists the operator rendered (see objects::access) into the
+/// server root. Entries already in the server's files are kept, entries from
+/// the spec win.
+pub fn apply_access_lists(data_path: &Path) -> Result<(), Error> {
+    let access_path = match env::var("MYCELIUM_ACCESS_PATH") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let access_path = Path::new(&access_path);
+
+    if let Some(whitelist) = read_list(&access_path.join("whitelist.json")) {
+        merge_players(&data_path.join("whitelist.json"), whitelist)?;
+        let enforce = env::var("MYCELIUM_WHITELIST_ENFORCE").unwrap_or_else(|_| "false".into());
+        crate::set_properties(&data_path.join("server.properties"), &[
+            ("white-list", "true"),
+            ("enforce-whitelist", &enforce),
+        ])?;
+    }
+    if let Some(ops) = read_list(&access_path.join("ops.json")) {
+        merge_players(&data_path.join("ops.json"), ops)?;
+    }
+    if let Some(bans) = read_list(&access_path.join("banned-players.json")) {
+        merge_players(
+            &data_path.join("banned-players.json"),
+            bans.into_iter().map(created).collect(),
+        )?;
+    }
+    if let Some(bans) = read_list(&access_path.join("banned-ips.json")) {
+        merge(
+            &data_path.join("banned-ips.json"),
+            bans.into_iter().map(created).collect(),
+            "ip",
+        )?;
+    }
+    Ok(())
+}
+
+/// stamps a ban entry with the time, in the format the server writes
+fn created(mut entry: Value) -> Value {
+    entry["created"] = json!(Utc::now().format("%Y-%m-%d %H:%M:%S %z").to_string());
+    entry
+}
+
+fn read_list(path: &Path) -> Option<Vec<Value>> {
+    let contents = read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// merges player entries into a server list file by uuid, looking up missing
+/// uuids
+fn merge_players(path: &Path, entries: Vec<Value>) -> Result<(), Error> {
+    let mut resolved = vec![];
+    for mut entry in entries {
+        if entry["uuid"].is_null() {
+            let name = entry["name"].as_str().unwrap_or_default().to_string();
+            match lookup_uuid(&name) {
+                Some(uuid) => entry["uuid"] = json!(uuid),
+                None => {
+                    eprintln!("[runner] can't find a uuid for {}, skipping it", name);
+                    continue;
+                }
+            }
+        }
+        resolved.push(entry);
+    }
+    merge(path, resolved, "uuid")
+}
+
+/// merges entries into a server list file, replacing existing entries with the
+/// same `key`
+fn merge(path: &Path, entries: Vec<Value>, key: &str) -> Result<(), Error> {
+    let mut merged = read_list(path).unwrap_or_default();
+    for entry in entries {
+        merged.retain(|e| e[key] != entry[key]);
+        merged.push(entry);
+    }
+    let mut f = File::create(path)?;
+    f.write_all(Value::from(merged).to_string().as_bytes())?;
+    Ok(())
+}
+
+/// asks Mojang for the uuid of a username, formatted with dashes
+fn lookup_uuid(name: &str) -> Option<String> {
+    let url = format!("https://api.mojang.com/users/profiles/minecraft/{}", name);
+    let output = Command::new("curl").args(["-fsS", &url]).output().ok()?;
+    let profile: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let id = profile["id"].as_str()?;
+    if id.len() != 32 {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &id[..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..]
+    ))
+}
@@ -0,0 +1,31 @@
+use std::{
+    env,
+    process::{Command, Stdio},
+};
+
+/// Tells the Agones SDK sidecar the server is ready for players, when the
+/// replica is a GameServer (see PodBackend in the operator). Agones only sets
+/// AGONES_SDK_HTTP_PORT there, so this does nothing anywhere else.
+pub fn ready() {
+    let port = match env::var("AGONES_SDK_HTTP_PORT") {
+        Ok(port) => port,
+        Err(_) => return,
+    };
+    let url = format!("http://localhost:{}/ready", port);
+    let status = Command::new("curl")
+        .args([
+            "-fsS",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "-d",
+            "{}",
+            &url,
+        ])
+        .stdout(Stdio::null())
+        .status();
+    if !status.is_ok_and(|s| s.success()) {
+        eprintln!("[runner] failed to mark the GameServer ready at {}", url);
+    }
+}
@@ -0,0 +1,36 @@
+use std::{env, fs, path::Path};
+
+use mycelium::helpers::tls;
+
+/// where MYCELIUM_TLS_CA is written for curl to read
+const CA_PATH: &str = "/tmp/mycelium-ca.pem";
+
+/// the operator API's base URL from MYCELIUM_ENDPOINT
+pub fn base_url() -> Option<String> {
+    env::var("MYCELIUM_ENDPOINT")
+        .ok()
+        .map(|endpoint| tls::base_url(&endpoint))
+}
+
+/// Extra curl arguments for a URL, trusting the operator's CA (MYCELIUM_TLS_CA)
+/// if the URL is on the operator API and it serves TLS.
+pub fn curl_args(url: &str) -> Vec<String> {
+    let ca = match env::var("MYCELIUM_TLS_CA") {
+        Ok(ca) if !ca.is_empty() => ca,
+        _ => return vec![],
+    };
+    if !env::var("MYCELIUM_ENDPOINT").is_ok_and(|endpoint| tls::trusts_operator_ca(&endpoint, url))
+    {
+        return vec![];
+    }
+    if !Path::new(CA_PATH).exists() {
+        if let Err(e) = fs::write(CA_PATH, ca) {
+            eprintln!(
+                "[runner] failed to write the operator's CA to {}: {}",
+                CA_PATH, e
+            );
+            return vec![];
+        }
+    }
+    vec!["--cacert".into(), CA_PATH.into()]
+}
@@ -0,0 +1,636 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{
+        copy, create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, remove_file,
+        rename, write, File,
+    },
+    io::{Error, ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+    process::{ChildStdin, Command, Stdio},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use signal_hook::{consts::SIGUSR1, iterator::Signals};
+
+use crate::commands;
+
+/// how long to wait for the server to confirm a save-all
+const SAVE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// An S3 compatible bucket to upload world archives to. The credentials are
+/// read from the mounted Secret's AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+pub struct Target {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    prefix: String,
+    credentials: PathBuf,
+    incremental: bool,
+}
+
+impl Target {
+    /// the bucket from MYCELIUM_BACKUP_S3_*, if backups are turned on
+    pub fn from_env() -> Option<Target> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        Some(Target {
+            endpoint: var("MYCELIUM_BACKUP_S3_ENDPOINT")?,
+            bucket: var("MYCELIUM_BACKUP_S3_BUCKET")?,
+            region: var("MYCELIUM_BACKUP_S3_REGION").unwrap_or_else(|| "us-east-1".into()),
+            prefix: var("MYCELIUM_BACKUP_S3_PREFIX").unwrap_or_default(),
+            credentials: PathBuf::from(
+                var("MYCELIUM_BACKUP_CREDENTIALS").unwrap_or_else(|| "/backup-credentials".into()),
+            ),
+            incremental: var("MYCELIUM_BACKUP_INCREMENTAL").is_some_and(|i| i == "true"),
+        })
+    }
+
+    fn credential(&self, key: &str) -> Result<String, Error> {
+        Ok(read_to_string(self.credentials.join(key))?
+            .trim()
+            .to_string())
+    }
+
+    /// Uploads a file with a SigV4 signed PUT. S3 won't take a chunked upload
+    /// without a length, so the archive is written to disk first instead of
+    /// piped.
+    fn upload(&self, file: &Path, key: &str) -> Result<(), Error> {
+        println!("[runner] uploading backup to {}", key);
+        self.request("PUT", &sha256_file(file)?, key, &[
+            "-T",
+            file.to_str().unwrap(),
+        ])
+    }
+
+    /// downloads an object to `file` with a SigV4 signed GET
+    fn download(&self, key: &str, file: &Path) -> Result<(), Error> {
+        // the hash of an empty payload
+        let payload = format!("{:x}", Sha256::digest(b""));
+        self.request("GET", &payload, key, &["-o", file.to_str().unwrap()])
+    }
+
+    fn request(&self, method: &str, payload: &str, key: &str, args: &[&str]) -> Result<(), Error> {
+        let endpoint = self.endpoint.trim_end_matches('/');
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint, |(_, rest)| rest);
+        let path = format!("/{}/{}", self.bucket, key);
+        let url = format!("{}{}", endpoint, path);
+        let headers = sign(
+            &SigningRequest {
+                method,
+                host,
+                path: &path,
+                payload,
+                region: &self.region,
+                access_key: &self.credential("AWS_ACCESS_KEY_ID")?,
+                secret_key: &self.credential("AWS_SECRET_ACCESS_KEY")?,
+            },
+            Utc::now(),
+        );
+
+        let mut curl = Command::new("curl");
+        curl.arg("-fsS").args(args).arg(&url);
+        for (name, value) in headers {
+            curl.args(["-H", &format!("{}: {}", name, value)]);
+        }
+        let status = curl.stdin(Stdio::null()).status()?;
+        if !status.success() {
+            return Err(Error::other(format!(
+                "{} {} exited with {}",
+                method, url, status
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// what goes into the signature of a request
+struct SigningRequest<'a> {
+    method: &'a str,
+    host: &'a str,
+    path: &'a str,
+    payload: &'a str,
+    region: &'a str,
+    access_key: &'a str,
+    secret_key: &'a str,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The headers that sign a PUT or GET of an object with AWS Signature Version
+/// 4. Keys are only made of characters that don't need escaping in the path.
+fn sign(request: &SigningRequest, now: DateTime<Utc>) -> Vec<(&'static str, String)> {
+    let time = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, request.region);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        request.method,
+        request.path,
+        request.host,
+        request.payload,
+        time,
+        signed_headers,
+        request.payload
+    );
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        time,
+        scope,
+        Sha256::digest(canonical.as_bytes())
+    );
+    let key = [date.as_str(), request.region, "s3", "aws4_request"]
+        .iter()
+        .fold(
+            format!("AWS4{}", request.secret_key).into_bytes(),
+            |key, part| hmac(&key, part),
+        );
+    let signature: String = hmac(&key, &to_sign)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    vec![
+        ("x-amz-content-sha256", request.payload.to_string()),
+        ("x-amz-date", time),
+        (
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                request.access_key, scope, signed_headers, signature
+            ),
+        ),
+    ]
+}
+
+/// the server console, where save-off, save-all and save-on are sent
+pub struct Console {
+    stdin: Arc<Mutex<ChildStdin>>,
+    saved: Receiver<()>,
+}
+
+impl Console {
+    /// `saved` gets a message whenever the server logs that it saved the game
+    pub fn new(stdin: Arc<Mutex<ChildStdin>>, saved: Receiver<()>) -> Console {
+        Console { stdin, saved }
+    }
+
+    fn send(&self, command: &str) -> Result<(), Error> {
+        let mut stdin = self.stdin.lock().unwrap();
+        writeln!(stdin, "{}", command)?;
+        stdin.flush()
+    }
+
+    /// turns off autosave and flushes the world to disk
+    fn freeze(&self) -> Result<(), Error> {
+        while self.saved.try_recv().is_ok() {}
+        self.send("save-off")?;
+        self.send("save-all flush")?;
+        self.saved
+            .recv_timeout(SAVE_TIMEOUT)
+            .map_err(|_| Error::other("the server didn't confirm save-all"))
+    }
+}
+
+/// the world directories of a level (paper keeps the nether and end next to it)
+fn worlds(container: &Path, level: &str) -> Result<Vec<String>, Error> {
+    let worlds: Vec<String> = [
+        level.to_string(),
+        format!("{}_nether", level),
+        format!("{}_the_end", level),
+    ]
+    .into_iter()
+    .filter(|w| container.join(w).is_dir())
+    .collect();
+    if worlds.is_empty() {
+        return Err(Error::other(format!("no world named {} to back up", level)));
+    }
+    Ok(worlds)
+}
+
+/// Archives the world directories under `container` and uploads them, with
+/// autosave turned off for as long as tar is reading the world so it's
+/// consistent.
+fn backup(console: &Console, target: &Target, container: &Path, level: &str) -> Result<(), Error> {
+    let replica = env::var("HOSTNAME").unwrap_or_else(|_| "server".into());
+    let worlds = worlds(container, level)?;
+    let archive = container.join(".mycelium-backup.tar.gz");
+
+    if let Err(e) = console.freeze() {
+        console.send("save-on")?;
+        return Err(e);
+    }
+    let status = Command::new("tar")
+        .args([
+            "-czf",
+            archive.to_str().unwrap(),
+            "-C",
+            container.to_str().unwrap(),
+        ])
+        .args(&worlds)
+        .status();
+    // the world can be written to again as soon as it's archived
+    console.send("save-on")?;
+    let status = status?;
+    if !status.success() {
+        let _ = remove_file(&archive);
+        return Err(Error::other(format!("tar exited with {}", status)));
+    }
+
+    let key = format!(
+        "{}{}/{}.tar.gz",
+        target.prefix,
+        replica,
+        Utc::now().format("%Y%m%d%H%M%S")
+    );
+    let result = target.upload(&archive, &key);
+    remove_file(&archive)?;
+    result
+}
+
+/// A backup in the incremental format, uploaded to
+/// `{prefix}{replica}/manifests/{time}.json`. It lists every file of the world
+/// with the sha256 it's stored under in `{prefix}{replica}/objects/`, so a
+/// restore only needs the one manifest, and links to the backup it was made on
+/// top of.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// key of the previous manifest in the chain
+    parent: Option<String>,
+    /// path under the world container to sha256
+    files: BTreeMap<String, String>,
+}
+
+/// What a replica has uploaded so far, kept next to the world so unchanged
+/// files are neither hashed nor uploaded again.
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    /// key of the newest manifest
+    last: Option<String>,
+    /// path under the world container to (size, modified, sha256) when it was
+    /// last hashed
+    files: BTreeMap<String, (u64, u64, String)>,
+    /// objects already in the bucket
+    uploaded: BTreeSet<String>,
+}
+
+/// every file under `dir`, relative to `base`
+fn walk(base: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(base, &path, files)?;
+        } else if path.file_name().is_some_and(|n| n != "session.lock") {
+            files.push(path.strip_prefix(base).unwrap().to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// the sha256 of a file, in hex
+fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Lists `files` (under `container`) in a manifest on top of the index's last
+/// one, hashing only the files whose size or modification time changed, and
+/// copies the ones that aren't in the bucket yet into `staging`, named by their
+/// sha256. Files that are gone are dropped from the index.
+fn stage(
+    container: &Path,
+    files: &[PathBuf],
+    index: &mut Index,
+    staging: &Path,
+) -> Result<Manifest, Error> {
+    let mut manifest = Manifest {
+        parent: index.last.clone(),
+        files: BTreeMap::new(),
+    };
+    for file in files {
+        let path = container.join(file);
+        let meta = metadata(&path)?;
+        let modified = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = file.to_string_lossy().to_string();
+        let sha256 = match index.files.get(&name) {
+            Some((size, time, sha256)) if *size == meta.len() && *time == modified => {
+                sha256.clone()
+            }
+            _ => {
+                let sha256 = sha256_file(&path)?;
+                index
+                    .files
+                    .insert(name.clone(), (meta.len(), modified, sha256.clone()));
+                sha256
+            }
+        };
+        // a failed upload is staged again next time
+        if !index.uploaded.contains(&sha256) && !staging.join(&sha256).exists() {
+            copy(&path, staging.join(&sha256))?;
+        }
+        manifest.files.insert(name, sha256);
+    }
+    index
+        .files
+        .retain(|name, _| manifest.files.contains_key(name));
+    Ok(manifest)
+}
+
+/// Backs up only the files that changed since the last backup. While autosave
+/// is off, changed files are hashed and copied aside, then uploaded with the
+/// world back to normal.
+fn incremental(
+    console: &Console,
+    target: &Target,
+    container: &Path,
+    level: &str,
+) -> Result<(), Error> {
+    let replica = env::var("HOSTNAME").unwrap_or_else(|_| "server".into());
+    let base = format!("{}{}", target.prefix, replica);
+    let index_path = container.join(".mycelium-backup-index.json");
+    let mut index: Index = read_to_string(&index_path)
+        .ok()
+        .and_then(|i| serde_json::from_str(&i).ok())
+        .unwrap_or_default();
+    let staging = container.join(".mycelium-backup");
+    create_dir_all(&staging)?;
+
+    let mut files = vec![];
+    for world in worlds(container, level)? {
+        walk(container, &container.join(world), &mut files)?;
+    }
+    if let Err(e) = console.freeze() {
+        console.send("save-on")?;
+        return Err(e);
+    }
+    let staged = stage(container, &files, &mut index, &staging);
+    console.send("save-on")?;
+    let manifest = staged?;
+
+    for object in read_dir(&staging)? {
+        let object = object?.path();
+        let sha256 = object.file_name().unwrap().to_string_lossy().to_string();
+        target.upload(&object, &format!("{}/objects/{}", base, sha256))?;
+        remove_file(&object)?;
+        index.uploaded.insert(sha256);
+    }
+    let key = format!(
+        "{}/manifests/{}.json",
+        base,
+        Utc::now().format("%Y%m%d%H%M%S")
+    );
+    // outside of staging, whose files are all uploaded as objects
+    let manifest_path = container.join(".mycelium-backup-manifest.json");
+    write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+    target.upload(&manifest_path, &key)?;
+    remove_file(&manifest_path)?;
+
+    index.last = Some(key);
+    write(&index_path, serde_json::to_vec(&index)?)?;
+    Ok(())
+}
+
+/// Lays the files of a manifest out under `into` from `objects`, where they
+/// are named by their sha256, which is checked.
+fn place(manifest: &Manifest, objects: &Path, into: &Path) -> Result<(), Error> {
+    for (name, sha256) in &manifest.files {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("backup file {} is outside the world", name),
+            ));
+        }
+        let object = objects.join(sha256);
+        if sha256_file(&object)? != *sha256 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("object {} of {} doesn't match its hash", sha256, name),
+            ));
+        }
+        let path = into.join(relative);
+        create_dir_all(path.parent().unwrap_or(into))?;
+        copy(&object, &path)?;
+    }
+    Ok(())
+}
+
+/// Restores the worlds from an incremental backup before the first start,
+/// unless a world already exists. `key` is the manifest's key under the
+/// bucket's prefix, and lists every file, so it's all that's needed of the
+/// chain. The files are downloaded and put together next to the world, then
+/// renamed into place.
+pub fn restore(target: &Target, key: &str, container: &Path, level: &str) -> Result<(), Error> {
+    if container.join(level).join("level.dat").exists() {
+        println!("[runner] world already exists, skipping restore of {}", key);
+        return Ok(());
+    }
+    let key = format!("{}{}", target.prefix, key);
+    let base = match key.rsplit_once("/manifests/") {
+        Some((base, _)) => base.to_string(),
+        None => {
+            return Err(Error::other(format!(
+                "{} isn't the key of a backup manifest",
+                key
+            )))
+        }
+    };
+
+    let staging = container.join(".mycelium-restore");
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    let objects = staging.join("objects");
+    create_dir_all(&objects)?;
+    println!("[runner] restoring world from {}", key);
+    let manifest_path = staging.join("manifest.json");
+    target.download(&key, &manifest_path)?;
+    let manifest: Manifest = serde_json::from_str(&read_to_string(&manifest_path)?)?;
+    for sha256 in manifest.files.values().collect::<BTreeSet<_>>() {
+        target.download(
+            &format!("{}/objects/{}", base, sha256),
+            &objects.join(sha256),
+        )?;
+    }
+
+    let restored = staging.join("world");
+    place(&manifest, &objects, &restored)?;
+    for world in read_dir(&restored)? {
+        let world = world?;
+        let to = container.join(world.file_name());
+        if to.exists() {
+            remove_dir_all(&to)?;
+        }
+        rename(world.path(), to)?;
+    }
+    remove_dir_all(&staging)?;
+    println!("[runner] restored {} files", manifest.files.len());
+    Ok(())
+}
+
+/// Backs up the world whenever the runner gets SIGUSR1, and on
+/// MYCELIUM_BACKUP_SCHEDULE (a cron expression in UTC) if it's set. Failures
+/// are logged, the server keeps running.
+pub fn spawn(console: Console, target: Target, container: PathBuf, level: String) {
+    let schedule = env::var("MYCELIUM_BACKUP_SCHEDULE")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .and_then(|s| {
+            commands::parse_schedule(&s)
+                .map_err(|e| eprintln!("[runner] ignoring backup schedule {}: {}", s, e))
+                .ok()
+        });
+
+    let (trigger, requests): (Sender<()>, Receiver<()>) = channel();
+    let mut signals = Signals::new([SIGUSR1]).expect("listen for SIGUSR1");
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let _ = trigger.send(());
+        }
+    });
+
+    thread::spawn(move || loop {
+        let wait = schedule
+            .as_ref()
+            .and_then(|s| s.upcoming(Utc).next())
+            .and_then(|next| (next - Utc::now()).to_std().ok())
+            .unwrap_or(Duration::MAX);
+        match requests.recv_timeout(wait) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let result = match target.incremental {
+            true => incremental(&console, &target, &container, &level),
+            false => backup(&console, &target, &container, &level),
+        };
+        match result {
+            Ok(()) => println!("[runner] backup finished"),
+            Err(e) => eprintln!("[runner] backup failed: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+
+    use super::*;
+    use crate::temp_dir;
+
+    fn staged(staging: &Path) -> BTreeSet<String> {
+        read_dir(staging)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn only_new_contents_are_staged() {
+        let dir = temp_dir("backup-stage");
+        let (container, staging) = (dir.join("data"), dir.join("staging"));
+        create_dir_all(container.join("world/region")).unwrap();
+        create_dir_all(&staging).unwrap();
+        write(container.join("world/level.dat"), "level").unwrap();
+        write(container.join("world/region/r.0.0.mca"), "region").unwrap();
+        // the same contents are stored once
+        write(container.join("world/region/r.0.1.mca"), "region").unwrap();
+        let files = |container: &Path| {
+            let mut files = vec![];
+            walk(container, &container.join("world"), &mut files).unwrap();
+            files
+        };
+
+        let mut index = Index::default();
+        let first = stage(&container, &files(&container), &mut index, &staging).unwrap();
+        assert_eq!(first.parent, None);
+        assert_eq!(first.files.len(), 3);
+        assert_eq!(
+            first.files["world/level.dat"],
+            sha256_file(&container.join("world/level.dat")).unwrap()
+        );
+        assert_eq!(staged(&staging).len(), 2);
+
+        // uploaded objects aren't staged again, only the changed file is
+        for object in staged(&staging) {
+            remove_file(staging.join(&object)).unwrap();
+            index.uploaded.insert(object);
+        }
+        index.last = Some("survival-0/manifests/1.json".into());
+        write(container.join("world/level.dat"), "level, later").unwrap();
+        std::fs::remove_file(container.join("world/region/r.0.1.mca")).unwrap();
+        let second = stage(&container, &files(&container), &mut index, &staging).unwrap();
+        assert_eq!(
+            second.parent.as_deref(),
+            Some("survival-0/manifests/1.json")
+        );
+        assert_eq!(second.files.len(), 2);
+        assert_eq!(
+            staged(&staging),
+            BTreeSet::from([second.files["world/level.dat"].clone()])
+        );
+        assert_eq!(
+            second.files["world/region/r.0.0.mca"],
+            first.files["world/region/r.0.0.mca"]
+        );
+        // deleted files are forgotten
+        assert!(!index.files.contains_key("world/region/r.0.1.mca"));
+
+        // the manifest is all a restore needs, given the objects
+        let objects = dir.join("objects");
+        create_dir_all(&objects).unwrap();
+        for (name, sha256) in &second.files {
+            copy(container.join(name), objects.join(sha256)).unwrap();
+        }
+        let restored = dir.join("restored");
+        place(&second, &objects, &restored).unwrap();
+        assert_eq!(
+            read(restored.join("world/level.dat")).unwrap(),
+            b"level, later"
+        );
+        assert_eq!(
+            read(restored.join("world/region/r.0.0.mca")).unwrap(),
+            b"region"
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_or_escaping_backups_are_not_restored() {
+        let dir = temp_dir("backup-place");
+        let objects = dir.join("objects");
+        create_dir_all(&objects).unwrap();
+        write(objects.join("abc"), "not abc").unwrap();
+        let manifest = |name: &str| Manifest {
+            parent: None,
+            files: BTreeMap::from([(name.to_string(), "abc".to_string())]),
+        };
+        let into = dir.join("restored");
+        let corrupt = place(&manifest("world/level.dat"), &objects, &into).unwrap_err();
+        assert_eq!(corrupt.kind(), ErrorKind::InvalidData);
+        let escaping = place(&manifest("../level.dat"), &objects, &into).unwrap_err();
+        assert!(escaping.to_string().contains("outside the world"));
+        remove_dir_all(&dir).unwrap();
+    }
+}
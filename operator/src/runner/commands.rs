@@ -0,0 +1,88 @@
+use std::{
+    env,
+    io::Write,
+    process::ChildStdin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::Utc;
+use cron::Schedule;
+use serde::Deserialize;
+
+/// a console command to send on a schedule, see ScheduledCommand in the
+/// operator
+#[derive(Deserialize)]
+struct ScheduledCommand {
+    schedule: String,
+    command: String,
+}
+
+/// parses a cron expression, with 5 fields or 6 with seconds first
+pub fn parse_schedule(schedule: &str) -> Result<Schedule, cron::error::Error> {
+    match schedule.split_whitespace().count() {
+        5 => Schedule::from_str(&format!("0 {}", schedule)),
+        _ => Schedule::from_str(schedule),
+    }
+}
+
+/// Sends each of MYCELIUM_SCHEDULED_COMMANDS to the console whenever its
+/// schedule comes around, until the server's stdin closes.
+pub fn spawn(console: Arc<Mutex<ChildStdin>>) {
+    let commands: Vec<ScheduledCommand> = match env::var("MYCELIUM_SCHEDULED_COMMANDS") {
+        Ok(commands) if !commands.is_empty() => match serde_json::from_str(&commands) {
+            Ok(commands) => commands,
+            Err(e) => return eprintln!("[runner] ignoring scheduled commands: {}", e),
+        },
+        _ => return,
+    };
+    for command in commands {
+        let schedule = match parse_schedule(&command.schedule) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                eprintln!("[runner] ignoring schedule {}: {}", command.schedule, e);
+                continue;
+            }
+        };
+        let console = console.clone();
+        thread::spawn(move || {
+            for next in schedule.upcoming(Utc) {
+                if let Ok(wait) = (next - Utc::now()).to_std() {
+                    thread::sleep(wait);
+                }
+                println!("[runner] running scheduled command `{}`", command.command);
+                let mut stdin = console.lock().unwrap();
+                if writeln!(stdin, "{}", command.command)
+                    .and_then(|_| stdin.flush())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+
+    #[test]
+    fn schedules_take_an_optional_seconds_field() {
+        let after = "2022-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let next = |schedule: &str| parse_schedule(schedule).unwrap().after(&after).next();
+        assert_eq!(
+            next("0 6 * * *"),
+            Some("2022-01-02T06:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            next("30 */15 * * * *"),
+            Some("2022-01-01T12:00:30Z".parse().unwrap())
+        );
+        assert!(parse_schedule("every morning").is_err());
+        assert!(parse_schedule("61 * * * *").is_err());
+    }
+}
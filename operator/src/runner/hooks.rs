@@ -0,0 +1,158 @@
+use std::{
+    env,
+    io::Write,
+    process::{ChildStdin, Command},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+/// a console command or shell snippet, see Hook in the operator
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Hook {
+    command: Option<String>,
+    shell: Option<String>,
+    wait_seconds: Option<u64>,
+}
+
+/// the hooks in MYCELIUM_HOOKS, see Hooks in the operator
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    post_start: Option<Vec<Hook>>,
+    pre_stop: Option<Vec<Hook>>,
+}
+
+impl Hooks {
+    pub fn from_env() -> Hooks {
+        match env::var("MYCELIUM_HOOKS") {
+            Ok(hooks) if !hooks.is_empty() => serde_json::from_str(&hooks).unwrap_or_else(|e| {
+                eprintln!("[runner] ignoring hooks: {}", e);
+                Hooks::default()
+            }),
+            _ => Hooks::default(),
+        }
+    }
+
+    /// runs the postStart hooks, once the server is done loading
+    pub fn post_start(&self, console: &Arc<Mutex<ChildStdin>>, cwd: &str) {
+        run("postStart", self.post_start.iter().flatten(), console, cwd);
+    }
+
+    /// runs the preStop hooks, before the server is stopped
+    pub fn pre_stop(&self, console: &Arc<Mutex<ChildStdin>>, cwd: &str) {
+        run("preStop", self.pre_stop.iter().flatten(), console, cwd);
+    }
+}
+
+/// Runs hooks in order. A hook that fails is reported and the rest still run,
+/// since the server starts or stops either way.
+fn run<'a>(
+    phase: &str,
+    hooks: impl Iterator<Item = &'a Hook>,
+    console: &Arc<Mutex<ChildStdin>>,
+    cwd: &str,
+) {
+    for hook in hooks {
+        if let Some(command) = &hook.command {
+            println!("[runner] running {} command `{}`", phase, command);
+            let mut stdin = console.lock().unwrap();
+            if writeln!(stdin, "{}", command)
+                .and_then(|_| stdin.flush())
+                .is_err()
+            {
+                return eprintln!(
+                    "[runner] the console closed, skipping the remaining {} hooks",
+                    phase
+                );
+            }
+        } else if let Some(shell) = &hook.shell {
+            println!("[runner] running {} hook `{}`", phase, shell);
+            match Command::new("sh")
+                .args(["-c", shell])
+                .current_dir(cwd)
+                .status()
+            {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    eprintln!("[runner] {} hook `{}` exited with {}", phase, shell, status)
+                }
+                Err(e) => eprintln!("[runner] {} hook `{}` failed: {}", phase, shell, e),
+            }
+        }
+        if let Some(wait) = hook.wait_seconds {
+            thread::sleep(Duration::from_secs(wait));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read_to_string, process::Stdio};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::temp_dir;
+
+    fn hooks(hooks: serde_json::Value) -> Hooks {
+        serde_json::from_value(hooks).unwrap()
+    }
+
+    #[test]
+    fn hooks_run_in_order() {
+        let dir = temp_dir("hooks");
+        let cwd = dir.to_str().unwrap();
+        let hooks = hooks(json!({
+            "postStart": [
+                { "shell": "echo one >> order" },
+                { "command": "say hello" },
+                // a failing hook doesn't stop the rest
+                { "shell": "exit 1" },
+                { "shell": "echo two >> order", "waitSeconds": 0 },
+                { "command": "say bye" },
+            ],
+            "preStop": [{ "shell": "echo stop >> order" }],
+        }));
+        let mut server = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let console = Arc::new(Mutex::new(server.stdin.take().unwrap()));
+
+        hooks.post_start(&console, cwd);
+        assert_eq!(read_to_string(dir.join("order")).unwrap(), "one\ntwo\n");
+        hooks.pre_stop(&console, cwd);
+        assert_eq!(
+            read_to_string(dir.join("order")).unwrap(),
+            "one\ntwo\nstop\n"
+        );
+
+        drop(console);
+        let output = server.wait_with_output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "say hello\nsay bye\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_closed_console_skips_the_remaining_hooks() {
+        let dir = temp_dir("hooks-closed");
+        let hooks = hooks(json!({
+            "preStop": [{ "command": "save-all" }, { "shell": "touch ran" }],
+        }));
+        let mut server = Command::new("true").stdin(Stdio::piped()).spawn().unwrap();
+        let console = Arc::new(Mutex::new(server.stdin.take().unwrap()));
+        server.wait().unwrap();
+
+        hooks.pre_stop(&console, dir.to_str().unwrap());
+        assert!(!dir.join("ran").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,719 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{copy, create_dir_all, metadata, read_dir, read_to_string, File},
+    io::{BufRead, BufReader, Error, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use linked_hash_map::LinkedHashMap;
+use nix::{libc::pid_t, sys::signal, unistd::Pid};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use toml_edit::{value, Array, Document, Table};
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+mod access;
+mod agones;
+mod api;
+mod backup;
+mod commands;
+mod hooks;
+mod preflight;
+mod templating;
+mod timings;
+mod world;
+
+/// created once the server is done loading, for the pod's readiness probe (see
+/// READY_FILE in the operator)
+const READY_FILE: &str = "/tmp/mycelium-ready";
+
+fn main() -> Result<(), Error> {
+    // per-replica values like ${POD_ORDINAL} are filled in before anything reads
+    // them
+    templating::expand_env();
+
+    let config_path = env::var("MYCELIUM_CONFIG_PATH").unwrap_or_else(|_| String::from("/config"));
+    let data_path = env::var("MYCELIUM_DATA_PATH").unwrap_or_else(|_| String::from("/data"));
+    let fw_token = env::var("MYCELIUM_FW_TOKEN").unwrap();
+    let server_kind = env::var("MYCELIUM_RUNNER_KIND").unwrap();
+
+    // create paths from env vars
+    let config_path: &Path = Path::new(&config_path);
+    let data_path: &Path = Path::new(&data_path);
+
+    assert!(config_path.is_dir());
+    assert!(data_path.is_dir());
+
+    // fail fast on misconfiguration instead of letting the JVM fail later
+    if env::var("MYCELIUM_SKIP_PREFLIGHT").is_err() {
+        if let Err(e) = preflight::preflight(data_path, &server_kind) {
+            eprintln!("[runner] preflight check failed: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+
+    let mut timings = timings::Timings::default();
+    let phase = Instant::now();
+
+    // copy all the files from config_path to data_path
+    copy_config(config_path, data_path)?;
+
+    // configure the server
+    match server_kind.as_str() {
+        "game" => match env::var("MYCELIUM_RUNNER_JAR_TYPE").as_deref() {
+            Ok("vanilla") => configure_vanilla(data_path),
+            _ => configure_game(fw_token, data_path),
+        },
+        "proxy" => match env::var("MYCELIUM_RUNNER_JAR_TYPE").as_deref() {
+            Ok("waterfall") => configure_waterfall(data_path),
+            _ => configure_proxy(fw_token, data_path),
+        },
+        _ => panic!("env::var(MYCELIUM_RUNNER_KIND) must be 'game' or 'proxy'"),
+    }?;
+
+    // whitelist, ops and bans from the spec
+    if server_kind == "game" {
+        access::apply_access_lists(data_path)?;
+    }
+
+    timings.configure = phase.elapsed();
+
+    // import the world on first boot
+    let phase = Instant::now();
+    if let Some(shared) = env::var("MYCELIUM_SHARED_WORLD")
+        .ok()
+        .filter(|s| !s.is_empty())
+    {
+        world::copy_shared_world(Path::new(&shared), data_path, &world::container(data_path))?;
+    }
+    if let Some(url) = env::var("MYCELIUM_WORLD_URL")
+        .ok()
+        .filter(|u| !u.is_empty())
+    {
+        world::import_world(&url, data_path, &world::container(data_path))?;
+    }
+    if let Some(key) = env::var("MYCELIUM_RESTORE_MANIFEST")
+        .ok()
+        .filter(|k| !k.is_empty())
+    {
+        let target = backup::Target::from_env()
+            .ok_or_else(|| Error::other("restoring a backup needs the backup bucket"))?;
+        backup::restore(
+            &target,
+            &key,
+            &world::container(data_path),
+            &world::level_name(data_path),
+        )?;
+    }
+    timings.world_import = phase.elapsed();
+
+    // download plugins and mods
+    let phase = Instant::now();
+    download_jars(data_path, "MYCELIUM_PLUGINS", "plugins/")?;
+    download_jars(data_path, "MYCELIUM_MODS", "mods/")?;
+
+    // plugin configs go over whatever the plugins would write on first start
+    let plugin_configs_path = env::var("MYCELIUM_PLUGIN_CONFIGS_PATH")
+        .unwrap_or_else(|_| String::from("/plugin-configs"));
+    if Path::new(&plugin_configs_path).is_dir() {
+        copy_config(Path::new(&plugin_configs_path), &data_path.join("plugins"))?;
+    }
+
+    // configure metrics
+    configure_metrics(data_path)?;
+
+    // start server
+    let jar = download_server(data_path)?;
+    timings.download = phase.elapsed();
+    // game servers can back their world up to a bucket
+    let backups = backup::Target::from_env()
+        .filter(|_| server_kind == "game")
+        .map(|target| {
+            (
+                target,
+                world::container(data_path),
+                world::level_name(data_path),
+            )
+        });
+    run_jar(data_path.to_str().unwrap(), &jar, timings, backups);
+
+    Ok(())
+}
+
+fn download_file(url: &str, path: PathBuf) -> Result<(), Error> {
+    if path.exists() {
+        println!("skipping {}", url);
+        return Ok(());
+    }
+    if let Some(cached) = cache_path(url) {
+        if !cached.exists() {
+            // download next to it and rename, so other replicas never see a partial file
+            let partial =
+                cached.with_extension(format!("{}.part", env::var("HOSTNAME").unwrap_or_default()));
+            if let Err(e) = curl(url, &partial) {
+                // never leave an error page or a truncated file where the cache could use it
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+            std::fs::rename(&partial, &cached)?;
+        }
+        if copy(&cached, &path).is_ok() {
+            println!("copied {} from the download cache", url);
+            return Ok(());
+        }
+    }
+    if let Err(e) = curl(url, &path) {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// where a URL is kept in the shared download cache, if there is one
+fn cache_path(url: &str) -> Option<PathBuf> {
+    let dir = env::var("MYCELIUM_DOWNLOAD_CACHE")
+        .ok()
+        .filter(|d| !d.is_empty())?;
+    Some(Path::new(&dir).join(format!("{:x}", Sha256::digest(url.as_bytes()))))
+}
+
+/// removes a bad download from the cache, so the next start fetches it again
+fn evict(url: &str) {
+    if let Some(cached) = cache_path(url) {
+        let _ = std::fs::remove_file(cached);
+    }
+}
+
+/// downloads a URL, failing on HTTP errors instead of saving the error page
+fn curl(url: &str, path: &Path) -> Result<(), Error> {
+    println!("downloading {}", url);
+    let path_str = path.to_str().unwrap();
+    let status = Command::new("curl")
+        .args(["-fL", url, "--output", path_str])
+        .args(api::curl_args(url))
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "downloading {} failed: curl exited with {}",
+            url, status
+        )));
+    }
+    Ok(())
+}
+
+fn run_jar(
+    cwd: &str,
+    file: &str,
+    timings: timings::Timings,
+    backups: Option<(backup::Target, PathBuf, String)>,
+) {
+    let jvm_opts = env::var("MYCELIUM_JVM_OPTS").unwrap_or_else(|_| "".into());
+    // worlds on their own volume are loaded from there instead of the server root
+    let worlds = env::var("MYCELIUM_WORLD_CONTAINER")
+        .ok()
+        .filter(|w| !w.is_empty());
+    let args: Vec<&str> = jvm_opts
+        .split_terminator(' ')
+        .chain(vec!["-jar", file])
+        .chain(worlds.iter().flat_map(|w| ["--universe", w.as_str()]))
+        .collect();
+
+    let hooks = hooks::Hooks::from_env();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).unwrap();
+    let started = Instant::now();
+    let mut minecraft = Command::new("java")
+        .args(args)
+        .current_dir(cwd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("run jar");
+
+    // pass our stdin through, so the console still works, while backups and
+    // scheduled commands write to it too
+    let console = Arc::new(Mutex::new(minecraft.stdin.take().expect("java stdin")));
+    commands::spawn(console.clone());
+    let forward = console.clone();
+    thread::spawn(move || {
+        for line in std::io::stdin().lines().map_while(Result::ok) {
+            let mut stdin = forward.lock().unwrap();
+            if writeln!(stdin, "{}", line)
+                .and_then(|_| stdin.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    // pass the output through, watching for the "Done (...)!" line both paper and
+    // velocity print once they're ready, or the "Listening on" line from
+    // waterfall
+    let stdout = minecraft.stdout.take().expect("java stdout");
+    let (saved, saves) = channel();
+    let (started_hooks, started_console, started_cwd) =
+        (hooks.clone(), console.clone(), cwd.to_string());
+    thread::spawn(move || {
+        let mut reported = false;
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("{}", line);
+            if !reported && (line.contains("Done (") || line.contains("Listening on /")) {
+                reported = true;
+                if let Err(e) = File::create(READY_FILE) {
+                    eprintln!("[runner] failed to mark the server ready: {}", e);
+                }
+                timings.report(started.elapsed());
+                agones::ready();
+                // on their own thread, so the output keeps flowing while they wait
+                let (hooks, console, cwd) = (
+                    started_hooks.clone(),
+                    started_console.clone(),
+                    started_cwd.clone(),
+                );
+                thread::spawn(move || hooks.post_start(&console, &cwd));
+            }
+            if line.contains("Saved the game") {
+                let _ = saved.send(());
+            }
+        }
+    });
+    if let Some((target, container, level)) = backups {
+        backup::spawn(
+            backup::Console::new(console.clone(), saves),
+            target,
+            container,
+            level,
+        );
+    }
+
+    let id = minecraft.id();
+    let handle = signals.handle();
+    let cwd = cwd.to_string();
+    thread::spawn(move || {
+        let mut stopping = false;
+        for _ in signals.forever() {
+            // the hooks run once, even if kubelet and someone at the console both interrupt
+            if !stopping {
+                stopping = true;
+                hooks.pre_stop(&console, &cwd);
+            }
+            println!("[runner] Caught interrupt, sending sigterm to java...");
+            signal::kill(
+                Pid::from_raw(id as pid_t),
+                nix::sys::signal::Signal::SIGTERM,
+            )
+            .expect("can't kill java");
+        }
+    });
+
+    minecraft.wait().expect("wait for jar");
+    handle.close();
+}
+
+/// a plugin or mod the operator asked for, see PluginSource in the operator
+#[derive(Deserialize)]
+struct Jar {
+    url: String,
+    sha256: Option<String>,
+    filename: Option<String>,
+}
+
+/// downloads the jars listed in an env var (MYCELIUM_PLUGINS or MYCELIUM_MODS)
+/// into a directory of the server root
+fn download_jars(data_path: &Path, var: &str, dir: &str) -> Result<(), Error> {
+    let jars_str = env::var(var).unwrap_or_else(|_| "".into());
+    // older operators send a comma separated list of URLs
+    let jars: Vec<Jar> = match serde_json::from_str(&jars_str) {
+        Ok(jars) => jars,
+        Err(_) => jars_str
+            .split_terminator(',')
+            .map(|url| Jar {
+                url: url.to_string(),
+                sha256: None,
+                filename: None,
+            })
+            .collect(),
+    };
+    if jars.is_empty() {
+        return Ok(());
+    }
+    let dir_path = data_path.join(dir);
+    create_dir_all(&dir_path)?;
+    for jar in jars {
+        let file = jar
+            .filename
+            .as_deref()
+            .unwrap_or_else(|| jar.url.split('/').next_back().unwrap());
+        let jar_path = dir_path.join(file);
+        download_file(&jar.url, jar_path.clone())?;
+
+        if let Some(sha256) = jar.sha256 {
+            if let Err(e) = verify_checksum(&jar_path, &format!("sha256:{}", sha256)) {
+                // remove it so the next start downloads it again
+                std::fs::remove_file(&jar_path)?;
+                evict(&jar.url);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// downloads the server jar, returning its file name
+fn download_server(data_path: &Path) -> Result<String, Error> {
+    let url = env::var("MYCELIUM_RUNNER_JAR_URL").unwrap();
+    let file = url.split('/').next_back().unwrap();
+    let paper_jar_path = data_path.join(file);
+    download_file(&url, paper_jar_path.clone())?;
+
+    if let Some(checksum) = env::var("MYCELIUM_RUNNER_JAR_CHECKSUM")
+        .ok()
+        .filter(|c| !c.is_empty())
+    {
+        if let Err(e) = verify_checksum(&paper_jar_path, &checksum) {
+            // remove it so the next start downloads it again
+            std::fs::remove_file(&paper_jar_path)?;
+            evict(&url);
+            return Err(e);
+        }
+    }
+
+    Ok(file.to_string())
+}
+
+/// checks a file against an `<algorithm>:<hex digest>` checksum
+fn verify_checksum(path: &Path, checksum: &str) -> Result<(), Error> {
+    let bytes = std::fs::read(path)?;
+    let (algorithm, expected) = checksum.split_once(':').unwrap_or(("", checksum));
+    let actual = match algorithm {
+        "sha1" => format!("{:x}", Sha1::digest(&bytes)),
+        "sha256" => format!("{:x}", Sha256::digest(&bytes)),
+        _ => return Err(Error::other(format!("unsupported checksum {}", checksum))),
+    };
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::other(format!(
+            "{} has {} {}, expected {}",
+            path.display(),
+            algorithm,
+            actual,
+            expected
+        )));
+    }
+    Ok(())
+}
+
+// the yaml parsing and modification in this function is horrifying
+// maybe I should've just written go
+fn configure_game(token: String, data_path: &Path) -> Result<(), Error> {
+    let paper_yaml_path = data_path.join("paper.yml");
+    let paper_yaml: String = match read_to_string(paper_yaml_path.clone()) {
+        Ok(file) => file,
+        Err(_error) => include_str!("../../defaults/paper.yml").to_string(),
+    };
+    let loaded = YamlLoader::load_from_str(&paper_yaml).expect("YAML parse");
+    let mut yaml_doc = loaded[0].as_hash().unwrap().clone();
+
+    // modify the config
+    let mut settings = yaml_doc[&Yaml::from_str("settings")]
+        .as_hash()
+        .unwrap()
+        .clone();
+    // waterfall proxies use legacy bungeecord forwarding, which is configured in
+    // spigot.yml
+    let bungeecord = env::var("MYCELIUM_FORWARDING").as_deref() == Ok("bungeecord");
+    if bungeecord {
+        enable_bungeecord(data_path)?;
+    }
+    let mut velocity_map = LinkedHashMap::new();
+    velocity_map.insert(Yaml::from_str("enabled"), Yaml::Boolean(!bungeecord));
+    velocity_map.insert(Yaml::from_str("online-mode"), Yaml::Boolean(true));
+    velocity_map.insert(Yaml::from_str("secret"), Yaml::from_str(&token));
+    settings[&Yaml::from_str("velocity-support")] = Yaml::Hash(velocity_map);
+    yaml_doc[&Yaml::from_str("settings")] = Yaml::Hash(settings);
+    let yamled = Yaml::Hash(yaml_doc);
+
+    configure_server_properties(data_path)?;
+
+    // write the modified config
+    let mut f = File::create(paper_yaml_path)?;
+    let mut out_str = String::new();
+    let mut emitter = YamlEmitter::new(&mut out_str);
+    emitter.dump(&yamled).unwrap();
+    f.write_all(out_str.as_bytes())?;
+    Ok(())
+}
+
+/// Configures a vanilla server, which has no way to take player info forwarded
+/// from a proxy. The operator sets MYCELIUM_ONLINE_MODE to false when the
+/// server sits behind one.
+fn configure_vanilla(data_path: &Path) -> Result<(), Error> {
+    configure_server_properties(data_path)?;
+    let online_mode = env::var("MYCELIUM_ONLINE_MODE").unwrap_or_else(|_| "true".into());
+    set_properties(&data_path.join("server.properties"), &[(
+        "online-mode",
+        &online_mode,
+    )])
+}
+
+/// accepts the EULA and writes server.properties, shared by paper and vanilla
+fn configure_server_properties(data_path: &Path) -> Result<(), Error> {
+    // accept the EULA
+    let eula_txt_path = data_path.join("eula.txt");
+    let mut f = File::create(eula_txt_path)?;
+    f.write_all("eula=true".as_bytes())?;
+
+    // write server props if dne
+    match read_to_string(data_path.join("server.properties")) {
+        Ok(_) => {}
+        Err(_) => {
+            let mut f = File::create(data_path.join("server.properties"))?;
+            f.write_all(include_str!("../../defaults/server.properties").as_bytes())?;
+        }
+    }
+
+    // let the operator send console commands
+    if let Some(password) = env::var("MYCELIUM_RCON_PASSWORD")
+        .ok()
+        .filter(|p| !p.is_empty())
+    {
+        set_properties(&data_path.join("server.properties"), &[
+            ("enable-rcon", "true"),
+            ("rcon.port", "25575"),
+            ("rcon.password", &password),
+        ])?;
+    }
+
+    // settings from the resource's runner options
+    for (var, key) in [
+        ("MYCELIUM_MOTD", "motd"),
+        ("MYCELIUM_MAX_PLAYERS", "max-players"),
+    ] {
+        if let Some(v) = env::var(var).ok().filter(|v| !v.is_empty()) {
+            set_properties(&data_path.join("server.properties"), &[(key, &v)])?;
+        }
+    }
+    let overrides: BTreeMap<String, String> = env::var("MYCELIUM_SERVER_PROPERTIES")
+        .ok()
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            serde_json::from_str(&p)
+                .expect("MYCELIUM_SERVER_PROPERTIES is a JSON object of strings")
+        })
+        .unwrap_or_default();
+    let overrides: Vec<(&str, &str)> = overrides
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    set_properties(&data_path.join("server.properties"), &overrides)?;
+    Ok(())
+}
+
+/// turns on bungeecord forwarding in spigot.yml, creating it if this is the
+/// first boot
+fn enable_bungeecord(data_path: &Path) -> Result<(), Error> {
+    let spigot_yaml_path = data_path.join("spigot.yml");
+    let spigot_yaml = read_to_string(&spigot_yaml_path).unwrap_or_else(|_| "settings: {}".into());
+    let loaded = YamlLoader::load_from_str(&spigot_yaml).expect("YAML parse");
+    let mut yaml_doc = loaded[0].as_hash().unwrap().clone();
+    let mut settings = yaml_doc
+        .get(&Yaml::from_str("settings"))
+        .and_then(Yaml::as_hash)
+        .cloned()
+        .unwrap_or_default();
+    settings.insert(Yaml::from_str("bungeecord"), Yaml::Boolean(true));
+    yaml_doc.insert(Yaml::from_str("settings"), Yaml::Hash(settings));
+
+    let mut out_str = String::new();
+    YamlEmitter::new(&mut out_str)
+        .dump(&Yaml::Hash(yaml_doc))
+        .unwrap();
+    let mut f = File::create(spigot_yaml_path)?;
+    f.write_all(out_str.as_bytes())?;
+    Ok(())
+}
+
+/// recursively copies the mounted configuration into the server root, following
+/// the symlinks kubelet uses for configmap keys and skipping its `..data`
+/// bookkeeping
+fn copy_config(from: &Path, to: &Path) -> Result<(), Error> {
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("..") {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if metadata(entry.path())?.is_dir() {
+            create_dir_all(&target)?;
+            copy_config(&entry.path(), &target)?;
+        } else {
+            copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// sets keys in a .properties file, keeping every other line as is
+fn set_properties(path: &Path, props: &[(&str, &str)]) -> Result<(), Error> {
+    let current = read_to_string(path)?;
+    let mut lines: Vec<String> = current
+        .lines()
+        .filter(|l| !props.iter().any(|(k, _)| l.starts_with(&format!("{}=", k))))
+        .map(String::from)
+        .collect();
+    lines.extend(props.iter().map(|(k, v)| format!("{}={}", k, v)));
+    let mut f = File::create(path)?;
+    f.write_all((lines.join("\n") + "\n").as_bytes())?;
+    Ok(())
+}
+
+fn configure_proxy(token: String, data_path: &Path) -> Result<(), Error> {
+    // read and parse velocity.toml
+    let velocity_toml_path = data_path.join("velocity.toml");
+    let velocity_toml: String = match read_to_string(velocity_toml_path.clone()) {
+        Ok(file) => file,
+        Err(_error) => include_str!("../../defaults/velocity.toml").to_string(),
+    };
+    let mut toml_doc = velocity_toml.parse::<Document>().expect("TOML parse");
+
+    // modify the config
+    toml_doc["forwarding-secret"] = value(token);
+    let mut servers = Table::default();
+    servers["try"] = value(Array::default());
+    toml_doc["servers"] = toml_edit::Item::Table(servers);
+    for (var, key) in [
+        ("MYCELIUM_PROXY_LOGIN_RATELIMIT", "login-ratelimit"),
+        ("MYCELIUM_PROXY_CONNECTION_TIMEOUT", "connection-timeout"),
+        ("MYCELIUM_PROXY_READ_TIMEOUT", "read-timeout"),
+    ] {
+        if let Some(ms) = env::var(var).ok().and_then(|v| v.parse::<i64>().ok()) {
+            toml_doc["advanced"][key] = value(ms);
+        }
+    }
+    if let Some(motd) = env::var("MYCELIUM_MOTD").ok().filter(|m| !m.is_empty()) {
+        toml_doc["motd"] = value(motd);
+    }
+    if let Some(max) = env::var("MYCELIUM_MAX_PLAYERS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+    {
+        toml_doc["show-max-players"] = value(max);
+    }
+
+    // overrides from the MinecraftProxy, except what mycelium manages itself
+    let overrides: BTreeMap<String, String> = env::var("MYCELIUM_VELOCITY_CONFIG")
+        .ok()
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default();
+    for (key, raw) in overrides {
+        if key == "forwarding-secret" || key == "servers" || key.starts_with("servers.") {
+            eprintln!(
+                "[runner] ignoring velocityConfig {}, mycelium manages it",
+                key
+            );
+            continue;
+        }
+        // values are TOML literals, anything that doesn't parse is taken as a string
+        let parsed = raw
+            .parse::<toml_edit::Value>()
+            .unwrap_or_else(|_| raw.as_str().into());
+        let mut parts = key.split('.');
+        let mut item = &mut toml_doc[parts.next().unwrap_or_default()];
+        for part in parts {
+            item = &mut item[part];
+        }
+        *item = toml_edit::Item::Value(parsed);
+    }
+
+    // write the modified config
+    let mut f = File::create(velocity_toml_path)?;
+    f.write_all(toml_doc.to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Writes waterfall's config.yml with bungeecord IP forwarding on. The mycelium
+/// plugin replaces the servers once it syncs with the operator.
+fn configure_waterfall(data_path: &Path) -> Result<(), Error> {
+    let config_yaml_path = data_path.join("config.yml");
+    let config_yaml: String = match read_to_string(config_yaml_path.clone()) {
+        Ok(file) => file,
+        Err(_error) => include_str!("../../defaults/waterfall.yml").to_string(),
+    };
+    let loaded = YamlLoader::load_from_str(&config_yaml).expect("YAML parse");
+    let mut yaml_doc = loaded[0].as_hash().unwrap().clone();
+
+    // modify the config
+    yaml_doc.insert(Yaml::from_str("ip_forward"), Yaml::Boolean(true));
+    for (var, key) in [
+        ("MYCELIUM_PROXY_LOGIN_RATELIMIT", "connection_throttle"),
+        (
+            "MYCELIUM_PROXY_CONNECTION_TIMEOUT",
+            "server_connect_timeout",
+        ),
+        ("MYCELIUM_PROXY_READ_TIMEOUT", "timeout"),
+    ] {
+        if let Some(ms) = env::var(var).ok().and_then(|v| v.parse::<i64>().ok()) {
+            yaml_doc.insert(Yaml::from_str(key), Yaml::Integer(ms));
+        }
+    }
+    let motd = env::var("MYCELIUM_MOTD").ok().filter(|m| !m.is_empty());
+    let max_players = env::var("MYCELIUM_MAX_PLAYERS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok());
+    if let Some(max) = max_players {
+        yaml_doc.insert(Yaml::from_str("player_limit"), Yaml::Integer(max));
+    }
+    if let Some(Yaml::Array(listeners)) = yaml_doc.get_mut(&Yaml::from_str("listeners")) {
+        for listener in listeners.iter_mut() {
+            if let Yaml::Hash(listener) = listener {
+                if let Some(motd) = &motd {
+                    listener.insert(Yaml::from_str("motd"), Yaml::String(motd.clone()));
+                }
+                if let Some(max) = max_players {
+                    listener.insert(Yaml::from_str("max_players"), Yaml::Integer(max));
+                }
+            }
+        }
+    }
+
+    // write the modified config
+    let mut out_str = String::new();
+    YamlEmitter::new(&mut out_str)
+        .dump(&Yaml::Hash(yaml_doc))
+        .unwrap();
+    let mut f = File::create(config_yaml_path)?;
+    f.write_all(out_str.as_bytes())?;
+    Ok(())
+}
+
+fn configure_metrics(data_path: &Path) -> Result<(), Error> {
+    let config_path = data_path.join("plugins/UnifiedMetrics/driver");
+    create_dir_all(config_path.clone())?;
+    let prom_path = config_path.join("prometheus.yml");
+    if !prom_path.exists() {
+        let mut f = File::create(prom_path)?;
+        f.write_all(include_str!("../../defaults/prometheus.yaml").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// an empty scratch directory for a test, unique to the test binary
+#[cfg(test)]
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mycelium-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
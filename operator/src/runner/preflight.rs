@@ -0,0 +1,302 @@
+use std::{
+    env,
+    fmt::{Display, Formatter},
+    fs::{read_to_string, remove_file, File},
+    path::Path,
+    process::Command,
+};
+
+use nix::sys::statvfs::statvfs;
+
+/// a failed preflight check, each class exits with its own status code so
+/// crash loops can be told apart from `kubectl get pods`
+#[derive(Debug)]
+pub enum PreflightError {
+    Disk(String),
+    Memory(String),
+    Java(String),
+    Permissions(String),
+}
+
+impl PreflightError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PreflightError::Disk(_) => 10,
+            PreflightError::Memory(_) => 11,
+            PreflightError::Java(_) => 12,
+            PreflightError::Permissions(_) => 13,
+        }
+    }
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreflightError::Disk(s) => write!(f, "disk: {}", s),
+            PreflightError::Memory(s) => write!(f, "memory: {}", s),
+            PreflightError::Java(s) => write!(f, "java: {}", s),
+            PreflightError::Permissions(s) => write!(f, "permissions: {}", s),
+        }
+    }
+}
+
+/// checks that the pod can actually run the server before anything is
+/// downloaded, so misconfiguration fails fast with a readable message
+pub fn preflight(data_path: &Path, server_kind: &str) -> Result<(), PreflightError> {
+    check_writable(data_path)?;
+    let min_free_mb = env::var("MYCELIUM_MIN_FREE_DISK_MB")
+        .ok()
+        .and_then(|s| s.parse().ok());
+    check_disk(data_path, min_free_mb)?;
+    let jvm_opts = env::var("MYCELIUM_JVM_OPTS").unwrap_or_default();
+    check_memory(&jvm_opts, cgroup_memory_limit())?;
+    if server_kind == "game" {
+        let mc_version = env::var("MYCELIUM_RUNNER_JAR_VERSION").ok();
+        let selected = env::var("MYCELIUM_JAVA_VERSION")
+            .ok()
+            .and_then(|v| v.parse().ok());
+        check_java(mc_version.as_deref(), selected)?;
+    }
+    Ok(())
+}
+
+fn check_writable(data_path: &Path) -> Result<(), PreflightError> {
+    let probe = data_path.join(".mycelium-preflight");
+    File::create(&probe).map_err(|e| {
+        PreflightError::Permissions(format!("{} is not writable ({})", data_path.display(), e))
+    })?;
+    remove_file(&probe).map_err(|e| PreflightError::Permissions(e.to_string()))?;
+    Ok(())
+}
+
+/// checks the free space on the data volume against runner.minFreeDiskMb, if
+/// it's set
+fn check_disk(data_path: &Path, min_mb: Option<u64>) -> Result<(), PreflightError> {
+    let min_mb = match min_mb {
+        Some(min_mb) => min_mb,
+        None => return Ok(()),
+    };
+    let stat = statvfs(data_path).map_err(|e| PreflightError::Disk(e.to_string()))?;
+    let free_mb = stat.blocks_available() as u64 * stat.fragment_size() as u64 / 1024 / 1024;
+    if free_mb < min_mb {
+        return Err(PreflightError::Disk(format!(
+            "only {}MiB free on {}, need at least {}MiB",
+            free_mb,
+            data_path.display(),
+            min_mb
+        )));
+    }
+    Ok(())
+}
+
+/// checks the -Xmx in the JVM options against the container memory limit
+fn check_memory(jvm_opts: &str, limit: Option<u64>) -> Result<(), PreflightError> {
+    let heap = match jvm_opts
+        .split_whitespace()
+        .find_map(|o| o.strip_prefix("-Xmx"))
+        .and_then(parse_size)
+    {
+        Some(heap) => heap,
+        None => return Ok(()),
+    };
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    if heap >= limit {
+        return Err(PreflightError::Memory(format!(
+            "-Xmx is {}MiB but the container memory limit is {}MiB, the JVM needs headroom above \
+             the heap or it will be OOMKilled",
+            heap / 1024 / 1024,
+            limit / 1024 / 1024
+        )));
+    }
+    Ok(())
+}
+
+fn check_java(mc_version: Option<&str>, selected: Option<u32>) -> Result<(), PreflightError> {
+    if mc_version.and_then(required_java).is_none() && selected.is_none() {
+        return Ok(());
+    }
+    let output = Command::new("java")
+        .arg("-version")
+        .output()
+        .map_err(|e| PreflightError::Java(format!("can't run java ({})", e)))?;
+    // java -version prints to stderr
+    let version_text = String::from_utf8_lossy(&output.stderr);
+    let found = parse_java_major(&version_text)
+        .ok_or_else(|| PreflightError::Java(format!("can't parse `{}`", version_text.trim())))?;
+    check_java_version(mc_version, selected, found)
+}
+
+/// checks the Java major version the image has against runner.javaVersion and
+/// the Minecraft version
+fn check_java_version(
+    mc_version: Option<&str>,
+    selected: Option<u32>,
+    found: u32,
+) -> Result<(), PreflightError> {
+    let required = mc_version.and_then(required_java);
+    if let Some(selected) = selected.filter(|s| *s != found) {
+        return Err(PreflightError::Java(format!(
+            "javaVersion is {}, but this image has Java {}",
+            selected, found
+        )));
+    }
+    if let (Some(mc_version), Some(required)) = (mc_version, required.filter(|r| found < *r)) {
+        return Err(PreflightError::Java(format!(
+            "Minecraft {} requires Java {} or newer, but this image has Java {}",
+            mc_version, required, found
+        )));
+    }
+    Ok(())
+}
+
+/// parses a JVM size like 4G, 512m, or 1048576 into bytes
+fn parse_size(s: &str) -> Option<u64> {
+    let (num, mult) = match s.chars().last()? {
+        'k' | 'K' => (&s[..s.len() - 1], 1024),
+        'm' | 'M' => (&s[..s.len() - 1], 1024 * 1024),
+        'g' | 'G' => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    num.parse::<u64>().ok().map(|n| n * mult)
+}
+
+/// reads the container memory limit from cgroup v2, falling back to v1
+fn cgroup_memory_limit() -> Option<u64> {
+    [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ]
+    .iter()
+    .find_map(|p| read_to_string(p).ok())
+    .and_then(|s| parse_memory_limit(&s))
+}
+
+/// parses a cgroup memory limit, v2 says "max" when there is none
+fn parse_memory_limit(s: &str) -> Option<u64> {
+    s.trim()
+        .parse::<u64>()
+        .ok()
+        // cgroup v1 reports "unlimited" as a huge number
+        .filter(|limit| *limit < (1 << 60))
+}
+
+/// minimum Java major version for a Minecraft version
+fn required_java(mc_version: &str) -> Option<u32> {
+    let mut parts = mc_version.split('.').map(|p| p.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    if major != 1 {
+        return None;
+    }
+    Some(match (minor, patch) {
+        (m, _) if m > 20 => 21,
+        (20, p) if p >= 5 => 21,
+        (m, _) if m >= 18 => 17,
+        (17, _) => 16,
+        _ => 8,
+    })
+}
+
+/// parses the major version out of `java -version` output
+fn parse_java_major(text: &str) -> Option<u32> {
+    let version = text.split('"').nth(1)?;
+    let mut parts = version.split(['.', '_', '-']);
+    match parts.next()?.parse::<u32>().ok()? {
+        // 1.8.0_292 style
+        1 => parts.next()?.parse().ok(),
+        major => Some(major),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+    use crate::temp_dir;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn each_check_has_its_own_exit_code() {
+        let dir = temp_dir("preflight");
+        check_writable(&dir).unwrap();
+        assert!(!dir.join(".mycelium-preflight").exists());
+        let unwritable = check_writable(&dir.join("missing")).unwrap_err();
+        assert_eq!(unwritable.exit_code(), 13);
+
+        check_disk(&dir, None).unwrap();
+        let full = check_disk(&dir, Some(u64::MAX)).unwrap_err();
+        assert_eq!(full.exit_code(), 10);
+
+        check_memory("-Xmx2G", Some(3 * GIB)).unwrap();
+        check_memory("-Xmx2G", None).unwrap();
+        check_memory("-Dmotd=hi", Some(GIB)).unwrap();
+        let oom = check_memory("-Xms1G -Xmx4G", Some(4 * GIB)).unwrap_err();
+        assert_eq!(oom.exit_code(), 11);
+        assert_eq!(
+            oom.to_string(),
+            "memory: -Xmx is 4096MiB but the container memory limit is 4096MiB, the JVM needs \
+             headroom above the heap or it will be OOMKilled"
+        );
+
+        // nothing to check against, so java isn't run at all
+        check_java(None, None).unwrap();
+        check_java_version(Some("1.20.4"), None, 17).unwrap();
+        check_java_version(Some("1.16.5"), Some(17), 17).unwrap();
+        let old = check_java_version(Some("1.20.5"), None, 17).unwrap_err();
+        assert_eq!(old.exit_code(), 12);
+        assert_eq!(
+            old.to_string(),
+            "java: Minecraft 1.20.5 requires Java 21 or newer, but this image has Java 17"
+        );
+        let mismatch = check_java_version(None, Some(21), 17).unwrap_err();
+        assert_eq!(mismatch.exit_code(), 12);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn minecraft_versions_require_their_java() {
+        assert_eq!(required_java("1.8.9"), Some(8));
+        assert_eq!(required_java("1.16.5"), Some(8));
+        assert_eq!(required_java("1.17.1"), Some(16));
+        assert_eq!(required_java("1.18"), Some(17));
+        assert_eq!(required_java("1.20.4"), Some(17));
+        assert_eq!(required_java("1.20.5"), Some(21));
+        assert_eq!(required_java("1.21"), Some(21));
+        // snapshots and proxies aren't known
+        assert_eq!(required_java("24w14a"), None);
+        assert_eq!(required_java("3.3.0"), None);
+    }
+
+    #[test]
+    fn java_versions_are_parsed() {
+        let modern = "openjdk version \"17.0.2\" 2022-01-18\nOpenJDK Runtime Environment";
+        assert_eq!(parse_java_major(modern), Some(17));
+        assert_eq!(parse_java_major("java version \"1.8.0_292\""), Some(8));
+        assert_eq!(
+            parse_java_major("openjdk version \"21\" 2023-09-19"),
+            Some(21)
+        );
+        assert_eq!(parse_java_major("openjdk version \"22-ea\""), Some(22));
+        assert_eq!(parse_java_major("bash: java: not found"), None);
+    }
+
+    #[test]
+    fn memory_sizes_are_parsed() {
+        assert_eq!(parse_size("4G"), Some(4 * GIB));
+        assert_eq!(parse_size("512m"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size(""), None);
+
+        assert_eq!(parse_memory_limit("2147483648\n"), Some(2 * GIB));
+        assert_eq!(parse_memory_limit("max\n"), None);
+        assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+    }
+}
@@ -0,0 +1,31 @@
+use std::env;
+
+/// the ordinal at the end of a StatefulSet pod's name
+fn ordinal(pod: &str) -> Option<&str> {
+    let (_, ordinal) = pod.rsplit_once('-')?;
+    (!ordinal.is_empty() && ordinal.chars().all(|c| c.is_ascii_digit())).then_some(ordinal)
+}
+
+/// `value` with ${POD_NAME} and ${POD_ORDINAL} filled in for `pod`, leaving
+/// ${POD_ORDINAL} alone if the pod has none (i.e. it belongs to a Deployment)
+pub fn expand(value: &str, pod: &str) -> String {
+    let value = value.replace("${POD_NAME}", pod);
+    match ordinal(pod) {
+        Some(ordinal) => value.replace("${POD_ORDINAL}", ordinal),
+        None => value,
+    }
+}
+
+/// Fills in the placeholders in every environment variable, so the options the
+/// operator passes along (and anything the server reads from its environment)
+/// can differ by replica.
+pub fn expand_env() {
+    let pod = env::var("POD_NAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_default();
+    for (key, value) in env::vars() {
+        if value.contains("${POD_") {
+            env::set_var(key, expand(&value, &pod));
+        }
+    }
+}
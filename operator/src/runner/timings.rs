@@ -0,0 +1,63 @@
+use std::{
+    env,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use crate::api;
+
+/// how long each phase of startup took, reported to the operator once the
+/// server is up
+#[derive(Default, Clone)]
+pub struct Timings {
+    pub configure: Duration,
+    pub world_import: Duration,
+    pub download: Duration,
+}
+
+impl Timings {
+    /// Sends the timings to the operator. Failures are logged and otherwise
+    /// ignored, since they shouldn't take the server down.
+    pub fn report(&self, startup: Duration) {
+        let (base, ns, name, kind) = match (
+            api::base_url(),
+            env::var("K8S_NAMESPACE"),
+            env::var("K8S_NAME"),
+            env::var("MYCELIUM_RUNNER_KIND"),
+        ) {
+            (Some(base), Ok(ns), Ok(name), Ok(kind)) => (base, ns, name, kind),
+            _ => return,
+        };
+        let body = format!(
+            r#"{{"replica":"{}","configureSeconds":{},"worldImportSeconds":{},"downloadSeconds":{},"startupSeconds":{}}}"#,
+            env::var("HOSTNAME").unwrap_or_default(),
+            self.configure.as_secs_f64(),
+            self.world_import.as_secs_f64(),
+            self.download.as_secs_f64(),
+            startup.as_secs_f64(),
+        );
+        let url = format!("{}/servers/{}/{}/timings/{}", base, ns, name, kind);
+        let mut curl = Command::new("curl");
+        curl.args([
+            "-fsS",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "-d",
+            &body,
+            &url,
+        ]);
+        curl.args(api::curl_args(&url));
+        // the operator takes the namespace's forwarding token for its own servers'
+        // timings
+        if let Ok(token) = env::var("MYCELIUM_FW_TOKEN") {
+            curl.args(["-H", &format!("Authorization: Bearer {}", token)]);
+        }
+        let status = curl.stdout(Stdio::null()).status();
+        match status {
+            Ok(s) if s.success() => println!("[runner] started in {:.1}s", startup.as_secs_f64()),
+            _ => eprintln!("[runner] failed to report startup timings to {}", url),
+        }
+    }
+}
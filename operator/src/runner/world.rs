@@ -0,0 +1,232 @@
+use std::{
+    fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, rename},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// the world directory name from server.properties (default "world")
+pub fn level_name(data_path: &Path) -> String {
+    read_to_string(data_path.join("server.properties"))
+        .ok()
+        .and_then(|props| {
+            props
+                .lines()
+                .find_map(|l| l.strip_prefix("level-name=").map(|s| s.trim().to_string()))
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "world".into())
+}
+
+/// the directory worlds live in, MYCELIUM_WORLD_CONTAINER or the server root
+pub fn container(data_path: &Path) -> PathBuf {
+    std::env::var("MYCELIUM_WORLD_CONTAINER")
+        .ok()
+        .filter(|w| !w.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| data_path.to_path_buf())
+}
+
+/// Downloads and unpacks a world archive (.zip or .tar[.gz]) into the world
+/// directory under `container`, unless a world already exists there. The
+/// archive must contain a level.dat, either at its root or inside a single top
+/// level directory.
+pub fn import_world(url: &str, data_path: &Path, container: &Path) -> Result<(), Error> {
+    let world_path = container.join(level_name(data_path));
+    if world_path.join("level.dat").exists() {
+        println!("[runner] world already exists, skipping import of {}", url);
+        return Ok(());
+    }
+
+    // staged on the same volume as the world, so it can be renamed into place
+    let staging = container.join(".mycelium-world-import");
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)?;
+    let file = url.split('/').next_back().unwrap_or("world").to_string();
+    let archive = staging.join(&file);
+    println!("[runner] importing world from {}", url);
+    run(Command::new("curl").args(["-fL", url, "--output", archive.to_str().unwrap()]))?;
+
+    let extracted = staging.join("extracted");
+    create_dir_all(&extracted)?;
+    let extracted_str = extracted.to_str().unwrap();
+    let archive_str = archive.to_str().unwrap();
+    if file.ends_with(".zip") {
+        run(Command::new("unzip").args(["-q", archive_str, "-d", extracted_str]))?;
+    } else {
+        run(Command::new("tar").args(["-xf", archive_str, "-C", extracted_str]))?;
+    }
+
+    let root = find_level_dat(&extracted)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("world archive {} does not contain a level.dat", url),
+        )
+    })?;
+    if world_path.exists() {
+        remove_dir_all(&world_path)?;
+    }
+    // paper keeps the nether and end next to the world, as in archived backups
+    let level = level_name(data_path);
+    for dimension in ["_nether", "_the_end"] {
+        let from = root.with_file_name(format!(
+            "{}{}",
+            root.file_name().unwrap().to_string_lossy(),
+            dimension
+        ));
+        let to = container.join(format!("{}{}", level, dimension));
+        if from.join("level.dat").exists() && !to.exists() {
+            rename(from, to)?;
+        }
+    }
+    rename(root, &world_path)?;
+    remove_dir_all(&staging)?;
+    println!("[runner] imported world into {}", world_path.display());
+    Ok(())
+}
+
+/// Replaces the world directory under `container` with a copy of the read-only
+/// shared world, so every start begins from the same world and whatever players
+/// changed on this replica is thrown away.
+pub fn copy_shared_world(shared: &Path, data_path: &Path, container: &Path) -> Result<(), Error> {
+    let world_path = container.join(level_name(data_path));
+    if world_path.exists() {
+        remove_dir_all(&world_path)?;
+    }
+    create_dir_all(&world_path)?;
+    println!("[runner] copying shared world from {}", shared.display());
+    let from = format!("{}/.", shared.display());
+    run(Command::new("cp").args(["-r", "--", &from, world_path.to_str().unwrap()]))?;
+    Ok(())
+}
+
+/// the directory holding level.dat, at the root or one level down (the
+/// overworld, if the nether and end are there too)
+fn find_level_dat(dir: &Path) -> Result<Option<PathBuf>, Error> {
+    if dir.join("level.dat").exists() {
+        return Ok(Some(dir.to_path_buf()));
+    }
+    let mut worlds = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && path.join("level.dat").exists() {
+            worlds.push(path);
+        }
+    }
+    let dimension = |p: &PathBuf| {
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        name.ends_with("_nether") || name.ends_with("_the_end")
+    };
+    worlds.sort_by_key(dimension);
+    Ok(worlds.into_iter().next())
+}
+
+fn run(command: &mut Command) -> Result<(), Error> {
+    let status = command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "{:?} exited with {}",
+            command, status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{write, File};
+
+    use super::*;
+    use crate::temp_dir;
+
+    fn level(dir: &Path) {
+        create_dir_all(dir).unwrap();
+        File::create(dir.join("level.dat")).unwrap();
+    }
+
+    #[test]
+    fn level_name_defaults_to_world() {
+        let dir = temp_dir("world-level-name");
+        assert_eq!(level_name(&dir), "world");
+        write(dir.join("server.properties"), "motd=hi\nlevel-name=\n").unwrap();
+        assert_eq!(level_name(&dir), "world");
+        write(dir.join("server.properties"), "level-name=survival \n").unwrap();
+        assert_eq!(level_name(&dir), "survival");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_overworld_is_found_in_an_archive() {
+        let dir = temp_dir("world-find");
+        assert_eq!(find_level_dat(&dir).unwrap(), None);
+        level(&dir.join("survival_nether"));
+        level(&dir.join("survival"));
+        level(&dir.join("survival_the_end"));
+        assert_eq!(find_level_dat(&dir).unwrap(), Some(dir.join("survival")));
+        level(&dir);
+        assert_eq!(find_level_dat(&dir).unwrap(), Some(dir.clone()));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imported_worlds_are_staged_then_moved_into_place() {
+        let dir = temp_dir("world-import");
+        let archived = dir.join("archived");
+        level(&archived.join("survival"));
+        level(&archived.join("survival_nether"));
+        let archive = dir.join("survival.tar.gz");
+        run(Command::new("tar").args([
+            "-czf",
+            archive.to_str().unwrap(),
+            "-C",
+            archived.to_str().unwrap(),
+            ".",
+        ]))
+        .unwrap();
+        let data = dir.join("data");
+        let container = dir.join("worlds");
+        create_dir_all(&data).unwrap();
+        create_dir_all(&container).unwrap();
+        write(data.join("server.properties"), "level-name=lobby\n").unwrap();
+
+        let url = format!("file://{}", archive.display());
+        import_world(&url, &data, &container).unwrap();
+        assert!(container.join("lobby/level.dat").exists());
+        assert!(container.join("lobby_nether/level.dat").exists());
+        assert!(!container.join(".mycelium-world-import").exists());
+
+        // an existing world is never replaced
+        write(container.join("lobby/played"), "").unwrap();
+        import_world(&url, &data, &container).unwrap();
+        assert!(container.join("lobby/played").exists());
+
+        // a failed download leaves the world alone and fails the start
+        remove_dir_all(container.join("lobby")).unwrap();
+        let missing = format!("file://{}", dir.join("missing.zip").display());
+        assert!(import_world(&missing, &data, &container).is_err());
+        assert!(!container.join("lobby").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_worlds_replace_the_local_copy() {
+        let dir = temp_dir("world-shared");
+        let shared = dir.join("shared");
+        level(&shared);
+        create_dir_all(shared.join("region")).unwrap();
+        let data = dir.join("data");
+        level(&data.join("world"));
+        write(data.join("world/played"), "").unwrap();
+
+        copy_shared_world(&shared, &data, &data).unwrap();
+        assert!(data.join("world/level.dat").exists());
+        assert!(data.join("world/region").is_dir());
+        assert!(!data.join("world/played").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
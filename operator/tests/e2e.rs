@@ -0,0 +1,269 @@
+//! End-to-end tests that run the controllers against a real cluster and check
+//! the objects they generate. They need a disposable cluster with the CRDs
+//! installed, so they only build with the `e2e` feature:
+//!
+//! ```sh
+//! kind create cluster
+//! cargo run --bin mycelium-crdgen | kubectl apply -f -
+//! cargo test --features e2e --test e2e
+//! ```
+#![cfg(feature = "e2e")]
+
+use std::{env, fmt::Debug, time::Duration};
+
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use k8s_openapi::api::{
+    apps::v1::{Deployment, StatefulSet},
+    core::v1::{Namespace, Secret, Service},
+};
+use kube::{
+    api::{DeleteParams, PostParams},
+    Api, Client,
+};
+use mycelium::{helpers::manager::Manager, MinecraftProxy, MinecraftSet};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+const PAPERMC_MOCK: &str = "127.0.0.1:18123";
+
+/// stands in for the PaperMC API so tests don't depend on papermc.io
+#[get("/projects/{kind}/versions/{version}")]
+async fn mock_builds(path: web::Path<(String, String)>) -> impl Responder {
+    let (kind, version) = path.into_inner();
+    HttpResponse::Ok().json(json!({
+        "project_id": kind,
+        "project_name": kind,
+        "version": version,
+        "builds": [1, 2, 3],
+    }))
+}
+
+#[get("/projects/{kind}")]
+async fn mock_versions(path: web::Path<String>) -> impl Responder {
+    let kind = path.into_inner();
+    HttpResponse::Ok().json(json!({
+        "project_id": kind,
+        "project_name": kind,
+        "version_groups": ["1.18"],
+        "versions": ["1.18.1"],
+    }))
+}
+
+async fn wait_for<K: Clone + DeserializeOwned + Debug>(api: &Api<K>, name: &str) -> K {
+    for _ in 0..60 {
+        if let Ok(obj) = api.get(name).await {
+            return obj;
+        }
+        actix_rt::time::sleep(Duration::from_secs(1)).await;
+    }
+    panic!("timed out waiting for {}", name);
+}
+
+async fn wait_gone<K: Clone + DeserializeOwned + Debug>(api: &Api<K>, name: &str) {
+    for _ in 0..120 {
+        if let Err(kube::Error::Api(e)) = api.get(name).await {
+            if e.code == 404 {
+                return;
+            }
+        }
+        actix_rt::time::sleep(Duration::from_secs(1)).await;
+    }
+    panic!("timed out waiting for {} to be deleted", name);
+}
+
+fn env_value(sts_env: &[k8s_openapi::api::core::v1::EnvVar], name: &str) -> Option<String> {
+    sts_env
+        .iter()
+        .find(|e| e.name == name)
+        .and_then(|e| e.value.clone())
+}
+
+#[actix_rt::test]
+async fn reconcilers_generate_expected_objects() {
+    env::set_var("MYCELIUM_FW_TOKEN", "e2e");
+    env::set_var(
+        "MYCELIUM_ENDPOINT",
+        "mycelium-operator.mycelium.svc.cluster.local:8080",
+    );
+    env::set_var("MYCELIUM_RUNNER_IMAGE", "mycelium/runner:e2e");
+    env::set_var("MYCELIUM_PAPERMC_API", format!("http://{}", PAPERMC_MOCK));
+
+    let mock = HttpServer::new(|| App::new().service(mock_builds).service(mock_versions))
+        .bind(PAPERMC_MOCK)
+        .expect("bind papermc mock")
+        .run();
+    actix_rt::spawn(mock);
+
+    let (_manager, set_controller, proxy_controller) = Manager::new().await;
+    actix_rt::spawn(set_controller);
+    actix_rt::spawn(proxy_controller);
+
+    let client = Client::try_default().await.expect("create client");
+    let ns = format!("mycelium-e2e-{}", chrono::Utc::now().timestamp());
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    namespaces
+        .create(
+            &PostParams::default(),
+            &serde_json::from_value(json!({ "metadata": { "name": ns } })).unwrap(),
+        )
+        .await
+        .expect("create namespace");
+
+    stateful_set(client.clone(), &ns).await;
+    stateless_set(client.clone(), &ns).await;
+    proxy(client.clone(), &ns).await;
+
+    // while the controllers still run, so any cleanup finalizers are taken off
+    delete_sets(client.clone(), &ns).await;
+    namespaces
+        .delete(&ns, &DeleteParams::default())
+        .await
+        .expect("delete namespace");
+}
+
+async fn stateful_set(client: Client, ns: &str) {
+    let mcset: MinecraftSet = serde_json::from_value(json!({
+        "apiVersion": "mycelium.njha.dev/v1beta1",
+        "kind": "MinecraftSet",
+        "metadata": { "name": "survival" },
+        "spec": {
+            "replicas": 2,
+            "runner": {
+                "jar": { "type": "paper", "version": "1.18.1", "build": "2" },
+                "jvm": "-Xmx1G",
+            },
+            "container": {
+                "volumeClaimTemplate": {
+                    "metadata": { "name": "root" },
+                    "spec": {
+                        "accessModes": ["ReadWriteOnce"],
+                        "resources": { "requests": { "storage": "1Gi" } },
+                    },
+                },
+            },
+        },
+    }))
+    .unwrap();
+    Api::<MinecraftSet>::namespaced(client.clone(), ns)
+        .create(&PostParams::default(), &mcset)
+        .await
+        .expect("create mcset");
+
+    let sts = wait_for(
+        &Api::<StatefulSet>::namespaced(client.clone(), ns),
+        "survival",
+    )
+    .await;
+    let spec = sts.spec.unwrap();
+    assert_eq!(spec.replicas, Some(2));
+    assert_eq!(spec.service_name, "survival");
+    assert_eq!(
+        spec.volume_claim_templates.unwrap()[0]
+            .metadata
+            .name
+            .as_deref(),
+        Some("root")
+    );
+    let container = &spec.template.spec.unwrap().containers[0];
+    assert_eq!(container.image.as_deref(), Some("mycelium/runner:e2e"));
+    let env = container.env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_RUNNER_KIND").as_deref(),
+        Some("game")
+    );
+    assert_eq!(
+        env_value(&env, "MYCELIUM_JVM_OPTS").as_deref(),
+        Some("-Xmx1G")
+    );
+    assert_eq!(
+        env_value(&env, "MYCELIUM_RUNNER_JAR_URL").as_deref(),
+        Some(
+            format!(
+                "http://{}/projects/paper/versions/1.18.1/builds/2/downloads/paper-1.18.1-2.jar",
+                PAPERMC_MOCK
+            )
+            .as_str()
+        )
+    );
+
+    let svc = wait_for(&Api::<Service>::namespaced(client.clone(), ns), "survival").await;
+    let svc_spec = svc.spec.unwrap();
+    assert_eq!(svc_spec.cluster_ip.as_deref(), Some("None"));
+    assert_eq!(svc_spec.ports.unwrap()[0].port, 25565);
+
+    let secret = wait_for(&Api::<Secret>::namespaced(client, ns), "survival").await;
+    assert!(secret.data.unwrap().contains_key("forwarding_token"));
+}
+
+async fn stateless_set(client: Client, ns: &str) {
+    let mcset: MinecraftSet = serde_json::from_value(json!({
+        "apiVersion": "mycelium.njha.dev/v1beta1",
+        "kind": "MinecraftSet",
+        "metadata": { "name": "lobby" },
+        "spec": {
+            "replicas": 3,
+            "runner": { "jar": { "type": "paper", "version": "1.18.1", "build": "3" } },
+            "container": { "stateful": false },
+        },
+    }))
+    .unwrap();
+    Api::<MinecraftSet>::namespaced(client.clone(), ns)
+        .create(&PostParams::default(), &mcset)
+        .await
+        .expect("create mcset");
+
+    let deploy = wait_for(&Api::<Deployment>::namespaced(client.clone(), ns), "lobby").await;
+    let spec = deploy.spec.unwrap();
+    assert_eq!(spec.replicas, Some(3));
+    let pod = spec.template.spec.unwrap();
+    assert!(pod.volumes.unwrap().iter().any(|v| v.empty_dir.is_some()));
+
+    let svc = wait_for(&Api::<Service>::namespaced(client, ns), "lobby").await;
+    assert_ne!(svc.spec.unwrap().cluster_ip.as_deref(), Some("None"));
+}
+
+async fn proxy(client: Client, ns: &str) {
+    let mcproxy: MinecraftProxy = serde_json::from_value(json!({
+        "apiVersion": "mycelium.njha.dev/v1beta1",
+        "kind": "MinecraftProxy",
+        "metadata": { "name": "proxy" },
+        "spec": {
+            "replicas": 1,
+            "runner": { "jar": { "type": "velocity", "version": "3.1.1", "build": "1" } },
+            "selector": { "matchLabels": { "mycelium.njha.dev/proxy": "cluster" } },
+        },
+    }))
+    .unwrap();
+    Api::<MinecraftProxy>::namespaced(client.clone(), ns)
+        .create(&PostParams::default(), &mcproxy)
+        .await
+        .expect("create mcproxy");
+
+    let sts = wait_for(&Api::<StatefulSet>::namespaced(client.clone(), ns), "proxy").await;
+    let container = &sts.spec.unwrap().template.spec.unwrap().containers[0];
+    let env = container.env.clone().unwrap();
+    assert_eq!(
+        env_value(&env, "MYCELIUM_RUNNER_KIND").as_deref(),
+        Some("proxy")
+    );
+    assert_eq!(env_value(&env, "K8S_NAME").as_deref(), Some("proxy"));
+
+    let svc = wait_for(&Api::<Service>::namespaced(client, ns), "proxy").await;
+    let port = &svc.spec.unwrap().ports.unwrap()[0];
+    assert_eq!(
+        port.target_port,
+        Some(k8s_openapi::apimachinery::pkg::util::intstr::IntOrString::Int(25577))
+    );
+}
+
+async fn delete_sets(client: Client, ns: &str) {
+    let api = Api::<MinecraftSet>::namespaced(client, ns);
+    for name in ["survival", "lobby"] {
+        api.delete(name, &DeleteParams::default())
+            .await
+            .expect("delete mcset");
+    }
+    for name in ["survival", "lobby"] {
+        wait_gone(&api, name).await;
+    }
+}
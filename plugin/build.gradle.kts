@@ -7,4 +7,5 @@ plugins {
 tasks.register("alljars") {
     dependsOn(":paper:shadowJar")
     dependsOn(":velocity:shadowJar")
+    dependsOn(":waterfall:shadowJar")
 }
@@ -2,3 +2,4 @@ rootProject.name = "mycelium-plugin"
 include("velocity")
 include("common")
 include("paper")
+include("waterfall")
@@ -3,13 +3,21 @@ package dev.njha.mycelium.plugin.velocity
 import com.google.gson.Gson
 import com.google.inject.Inject
 import com.typesafe.config.ConfigFactory
+import com.velocitypowered.api.event.EventTask
+import com.velocitypowered.api.event.ResultedEvent
 import com.velocitypowered.api.event.Subscribe
+import com.velocitypowered.api.event.connection.DisconnectEvent
+import com.velocitypowered.api.event.connection.LoginEvent
+import com.velocitypowered.api.event.player.KickedFromServerEvent
+import com.velocitypowered.api.event.player.PlayerChooseInitialServerEvent
+import com.velocitypowered.api.event.player.ServerPreConnectEvent
 import com.velocitypowered.api.event.proxy.ProxyInitializeEvent
 import com.velocitypowered.api.event.proxy.ProxyShutdownEvent
 import com.velocitypowered.api.plugin.Dependency
 import com.velocitypowered.api.plugin.Plugin
 import com.velocitypowered.api.plugin.annotation.DataDirectory
 import com.velocitypowered.api.proxy.ProxyServer
+import com.velocitypowered.api.proxy.server.RegisteredServer
 import com.velocitypowered.api.proxy.server.ServerInfo
 import dev.cubxity.plugins.metrics.api.UnifiedMetrics
 import dev.cubxity.plugins.metrics.api.UnifiedMetricsProvider
@@ -32,13 +40,20 @@ import io.ktor.routing.*
 import io.ktor.server.engine.*
 import io.ktor.server.netty.*
 import kotlinx.coroutines.*
+import net.kyori.adventure.text.Component
 import org.slf4j.Logger
 import org.slf4j.LoggerFactory
 import java.net.ConnectException
 import java.net.InetSocketAddress
 import java.nio.file.Path
+import java.security.KeyStore
+import java.security.cert.CertificateFactory
 import java.util.*
+import java.util.concurrent.ConcurrentHashMap
+import java.util.concurrent.ConcurrentLinkedDeque
 import java.util.concurrent.TimeUnit
+import javax.net.ssl.SSLContext
+import javax.net.ssl.TrustManagerFactory
 import kotlin.collections.set
 import kotlin.reflect.full.declaredMemberFunctions
 import kotlin.reflect.jvm.isAccessible
@@ -66,17 +81,33 @@ class Plugin {
     @DataDirectory
     lateinit var dataFolderPath: Path
 
+    // servers and their network-wide player counts as of the last sync, along with how many
+    // of those players were on this proxy, so local joins since then can be accounted for
+    @Volatile
+    private var servers: Map<String, Server> = mapOf()
+    @Volatile
+    private var localAtSync: Map<String, Int> = mapOf()
+
+    // sleeping servers that answered a ping since the last sync, so players aren't held for
+    // them until the operator catches up
+    private val woken: MutableSet<String> = ConcurrentHashMap.newKeySet()
+
+    // players waiting for a full set, by set, in the order they came
+    private val queues: ConcurrentHashMap<String, ConcurrentLinkedDeque<UUID>> = ConcurrentHashMap()
+
+    // queued players who were let in, so their connection to the set isn't queued again
+    private val admitted: MutableSet<UUID> = ConcurrentHashMap.newKeySet()
+
     private suspend fun sync() {
-        // TODO: Generate a TLS cert for the API server
-        HttpClient(Java).use { httpClient ->
+        operatorClient().use { httpClient ->
             var churn = 0
-            val endpoint = System.getenv("MYCELIUM_ENDPOINT") ?: "localhost:8181"
             val namespace = System.getenv("K8S_NAMESPACE") ?: "default"
             val name = System.getenv("K8S_NAME") ?: "proxy"
-            val url = "http://$endpoint/servers/$namespace/$name"
+            val url = "${operatorUrl()}/servers/$namespace/$name"
             try {
                 // if no env set, assume development and attempt to connect to localhost
                 val response = httpClient.get<HttpResponse>(url) {
+                    authenticate()
                     headers {
                         append("Accept", "application/json")
                     }
@@ -104,7 +135,10 @@ class Plugin {
 
                 // add servers
                 for (server in newServers.values) {
-                    tryList.add(server)
+                    // sleeping servers are only woken up for players who ask for them
+                    if (!server.asleep) {
+                        tryList.add(server)
+                    }
                     val rs = proxy.getServer(server.name)
                     if (server.host != null) {
                         if (forcedHosts.containsKey(server.host)) {
@@ -139,14 +173,74 @@ class Plugin {
                     it.call(fhClass, forcedHosts)
                 }
 
+                // remember player counts for fallbackStrategy = leastLoaded
+                servers = newServers
+                woken.retainAll(newServers.values.filter { it.asleep }.map { it.name }.toSet())
+                localAtSync = newServers.keys.associateWith { localPlayers(it) }
+
                 // record metrics
                 metrics.churn = churn
+
+                heartbeat(httpClient, url)
             } catch (e: ConnectException) {
                 log.error("failed to connect to operator - could not sync server list! (url = $url)")
             }
         }
     }
 
+    /**
+     * Pings every backend the operator knows about (including ones it is hiding
+     * from us because we have never reached them) and reports the results, so
+     * the operator can track this replica's liveness and evict dead backends.
+     */
+    private suspend fun heartbeat(httpClient: HttpClient, url: String) {
+        val response = httpClient.get<HttpResponse>("$url?all=true") {
+            authenticate()
+            headers {
+                append("Accept", "application/json")
+            }
+        }
+        val reachable = mutableListOf<String>()
+        val unreachable = mutableListOf<String>()
+        for (server in Gson().fromJson(response.readText(), Array<Server>::class.java)) {
+            val info = ServerInfo(server.name, InetSocketAddress(server.address, 25565))
+            try {
+                proxy.createRawRegisteredServer(info).ping().get(5, TimeUnit.SECONDS)
+                reachable.add(server.name)
+            } catch (e: Exception) {
+                unreachable.add(server.name)
+            }
+        }
+
+        // count players by backend, including backends that have been unregistered
+        // while they drain for a scale-down
+        val players = proxy.allPlayers
+            .mapNotNull { player -> player.currentServer.orElse(null)?.serverInfo?.name }
+            .groupingBy { it }
+            .eachCount()
+
+        // every set with a queue is reported, so an emptied queue goes back to 0
+        val queued = servers.values
+            .filter { it.queue != null }
+            .mapNotNull { it.set }
+            .associateWith { queues[it]?.size ?: 0 }
+
+        val replica = System.getenv("HOSTNAME") ?: "proxy"
+        httpClient.post<HttpResponse>("$url/heartbeat") {
+            authenticate()
+            contentType(ContentType.Application.Json)
+            body = Gson().toJson(
+                mapOf(
+                    "replica" to replica,
+                    "reachable" to reachable,
+                    "unreachable" to unreachable,
+                    "players" to players,
+                    "queues" to queued,
+                )
+            )
+        }
+    }
+
     @Subscribe
     fun onStart(event: ProxyInitializeEvent) {
         // hook into metrics ews
@@ -193,11 +287,283 @@ class Plugin {
             .repeat(1L, TimeUnit.MINUTES)
             .schedule()
 
+        // let queued players in as others leave
+        proxy.scheduler
+            .buildTask(this) { admitQueued() }
+            .repeat(QUEUE_POLL_SECONDS, TimeUnit.SECONDS)
+            .schedule()
+
         log.info("Hello, World.")
     }
 
+    private fun localPlayers(server: String): Int =
+        proxy.getServer(server).map { it.playersConnected.size }.orElse(0)
+
+    /**
+     * Players on a server across the network, going by the last sync and the joins and
+     * leaves on this proxy since.
+     */
+    private fun livePlayers(server: String): Int {
+        val network = servers[server]?.players ?: 0
+        return network - (localAtSync[server] ?: 0) + localPlayers(server)
+    }
+
+    private fun setPlayers(set: String): Int =
+        servers.values.filter { it.set == set }.sumOf { livePlayers(it.name) }
+
+    /**
+     * The least loaded server of a set that's up, or null if it has none.
+     */
+    private fun leastLoaded(set: String): RegisteredServer? =
+        servers.values
+            .filter { it.set == set && !it.asleep }
+            .mapNotNull { proxy.getServer(it.name).orElse(null) }
+            .minByOrNull { livePlayers(it.serverInfo.name) }
+
+    /**
+     * Picks a server from the try list according to the MinecraftProxy's fallbackStrategy, or
+     * null to leave Velocity's static try order (fallbackStrategy = priority) in charge.
+     */
+    private fun chooseServer(exclude: String?): RegisteredServer? {
+        val candidates = proxy.configuration.attemptConnectionOrder
+            .filter { it != exclude }
+            .mapNotNull { proxy.getServer(it).orElse(null) }
+        if (candidates.isEmpty()) {
+            return null
+        }
+        return when (System.getenv("MYCELIUM_FALLBACK_STRATEGY")) {
+            "leastLoaded" -> candidates.minByOrNull { livePlayers(it.serverInfo.name) }
+            "random" -> candidates.random()
+            else -> null
+        }
+    }
+
+    @Subscribe
+    fun onChooseInitialServer(event: PlayerChooseInitialServerEvent) {
+        chooseServer(null)?.let { event.setInitialServer(it) }
+    }
+
+    /**
+     * The operator API's base URL, https if MYCELIUM_ENDPOINT has the scheme.
+     */
+    private fun operatorUrl(): String {
+        val endpoint = System.getenv("MYCELIUM_ENDPOINT") ?: "localhost:8181"
+        return if (endpoint.contains("://")) endpoint.trimEnd('/') else "http://$endpoint"
+    }
+
+    /**
+     * An HTTP client for the operator API, trusting its CA (MYCELIUM_TLS_CA) when it serves TLS.
+     */
+    private fun operatorClient(): HttpClient = HttpClient(Java) {
+        val ca = System.getenv("MYCELIUM_TLS_CA")
+        if (!ca.isNullOrEmpty()) {
+            engine {
+                config {
+                    sslContext(trusting(ca))
+                }
+            }
+        }
+    }
+
+    /**
+     * An SSL context trusting only the certificates of a PEM bundle.
+     */
+    private fun trusting(pem: String): SSLContext {
+        val certificates = CertificateFactory.getInstance("X.509").generateCertificates(pem.byteInputStream())
+        val store = KeyStore.getInstance(KeyStore.getDefaultType())
+        store.load(null, null)
+        certificates.forEachIndexed { i, certificate -> store.setCertificateEntry("mycelium-$i", certificate) }
+        val trust = TrustManagerFactory.getInstance(TrustManagerFactory.getDefaultAlgorithm())
+        trust.init(store)
+        return SSLContext.getInstance("TLS").apply { init(null, trust.trustManagers, null) }
+    }
+
+    /**
+     * Sends the namespace's forwarding token, which the operator accepts from proxies for
+     * their own namespace.
+     */
+    private fun HttpRequestBuilder.authenticate() {
+        val token = System.getenv("MYCELIUM_FW_TOKEN") ?: System.getenv("CFG_MYCELIUM_FW_TOKEN") ?: return
+        header(HttpHeaders.Authorization, "Bearer $token")
+    }
+
+    /**
+     * Asks the operator to wake up the MinecraftSet of a server that scaled to zero.
+     */
+    private suspend fun wake(server: Server) {
+        val namespace = System.getenv("K8S_NAMESPACE") ?: "default"
+        operatorClient().use { httpClient ->
+            try {
+                httpClient.post<HttpResponse>("${operatorUrl()}/servers/$namespace/${server.set}/wake") {
+                    authenticate()
+                }
+                log.info("asked the operator to wake up ${server.set}")
+            } catch (e: Exception) {
+                log.error("failed to wake up ${server.set}: ${e.message}")
+            }
+        }
+    }
+
+    /**
+     * Pings a server until it answers or [seconds] pass, returning whether it answered.
+     */
+    private fun awaitReady(server: RegisteredServer, seconds: Long): Boolean {
+        val deadline = System.nanoTime() + TimeUnit.SECONDS.toNanos(seconds)
+        while (System.nanoTime() < deadline) {
+            try {
+                server.ping().get(5, TimeUnit.SECONDS)
+                woken.add(server.serverInfo.name)
+                return true
+            } catch (e: Exception) {
+                Thread.sleep(WAKE_POLL_MILLIS)
+            }
+        }
+        return false
+    }
+
+    /**
+     * Holds a player back from a full set, in line behind anyone already waiting for it. A
+     * player who is logging in waits on the limbo set, and one who is on another server waits
+     * there. Returns whether the player was queued.
+     */
+    private fun enqueue(event: ServerPreConnectEvent): Boolean {
+        val player = event.player
+        val entry = servers[event.originalServer.serverInfo.name] ?: return false
+        val set = entry.set ?: return false
+        val queue = entry.queue ?: return false
+        val current = player.currentServer.orElse(null)?.serverInfo?.name
+        if (admitted.remove(player.uniqueId) || (current != null && servers[current]?.set == set)) {
+            return false
+        }
+        val line = queues.computeIfAbsent(set) { ConcurrentLinkedDeque() }
+        if (!line.contains(player.uniqueId) && line.isEmpty() && setPlayers(set) < queue.capacity) {
+            return false
+        }
+        if (!line.contains(player.uniqueId)) {
+            line.add(player.uniqueId)
+        }
+        val position = line.indexOf(player.uniqueId) + 1
+        if (current != null) {
+            event.result = ServerPreConnectEvent.ServerResult.denied()
+        } else {
+            val limbo = leastLoaded(queue.limbo)
+            if (limbo == null) {
+                line.remove(player.uniqueId)
+                event.result = ServerPreConnectEvent.ServerResult.denied()
+                player.disconnect(Component.text("$set is full, try again in a bit."))
+                return true
+            }
+            event.result = ServerPreConnectEvent.ServerResult.allowed(limbo)
+        }
+        player.sendMessage(Component.text("$set is full, you're #$position in the queue."))
+        log.info("queued ${player.username} for $set at #$position")
+        return true
+    }
+
+    /**
+     * Sends the players at the front of each queue to their set while it has room. A set that
+     * no longer has a queue lets everyone in, and one with no servers up keeps them waiting.
+     */
+    private fun admitQueued() {
+        for ((set, line) in queues) {
+            val setServers = servers.values.filter { it.set == set }
+            if (setServers.isEmpty()) {
+                continue
+            }
+            val capacity = setServers.firstNotNullOfOrNull { it.queue }?.capacity ?: Int.MAX_VALUE
+            var room = capacity - setPlayers(set)
+            while (room > 0) {
+                val id = line.poll() ?: break
+                val player = proxy.getPlayer(id).orElse(null) ?: continue
+                val target = leastLoaded(set)
+                if (target == null) {
+                    line.addFirst(id)
+                    break
+                }
+                admitted.add(id)
+                player.sendMessage(Component.text("It's your turn, sending you to $set."))
+                player.createConnectionRequest(target).fireAndForget()
+                room -= 1
+            }
+        }
+    }
+
+    @Subscribe
+    fun onServerPreConnect(event: ServerPreConnectEvent): EventTask? {
+        if (enqueue(event)) {
+            return null
+        }
+        val target = event.originalServer
+        val server = servers[target.serverInfo.name]
+            ?.takeIf { it.asleep && it.set != null && !woken.contains(it.name) }
+            ?: return null
+        return EventTask.async {
+            runBlocking { wake(server) }
+            val player = event.player
+            if (player.currentServer.isPresent) {
+                // players who are already on a server wait there, and are sent over once it's up
+                event.result = ServerPreConnectEvent.ServerResult.denied()
+                player.sendMessage(Component.text("${server.set} is starting, you'll be sent there once it's up."))
+                proxy.scheduler.buildTask(this) {
+                    if (awaitReady(target, WAKE_TIMEOUT_SECONDS)) {
+                        player.createConnectionRequest(target).fireAndForget()
+                    } else {
+                        player.sendMessage(Component.text("${server.set} didn't start in time, try again later."))
+                    }
+                }.schedule()
+            } else if (!awaitReady(target, JOIN_WAIT_SECONDS)) {
+                // the client gives up on a login that takes much longer than this
+                event.result = ServerPreConnectEvent.ServerResult.denied()
+                player.disconnect(Component.text("${server.set} is starting, reconnect in a minute."))
+            }
+        }
+    }
+
+    @Subscribe
+    fun onKicked(event: KickedFromServerEvent) {
+        if (event.kickedDuringServerConnect()) {
+            return
+        }
+        chooseServer(event.server.serverInfo.name)?.let {
+            event.result = KickedFromServerEvent.RedirectPlayer.create(it)
+        }
+    }
+
+    @Subscribe
+    fun onLogin(event: LoginEvent) {
+        // per-IP connection cap from the MinecraftProxy's throttle.maxConnectionsPerIp
+        val cap = System.getenv("MYCELIUM_MAX_CONNECTIONS_PER_IP")?.toIntOrNull() ?: return
+        val address = event.player.remoteAddress.address
+        val connected = proxy.allPlayers.count { it.remoteAddress.address == address }
+        if (connected >= cap) {
+            log.info("denied login from $address, already has $connected connections")
+            event.result = ResultedEvent.ComponentResult.denied(
+                Component.text("Too many connections from your address.")
+            )
+        }
+    }
+
+    @Subscribe
+    fun onDisconnect(event: DisconnectEvent) {
+        queues.values.forEach { it.remove(event.player.uniqueId) }
+        admitted.remove(event.player.uniqueId)
+    }
+
     @Subscribe
     fun onStop(event: ProxyShutdownEvent) {
         log.info("Goodbye, World.")
     }
+
+    companion object {
+        // how long players on another server wait for a sleeping one to start
+        const val WAKE_TIMEOUT_SECONDS = 300L
+
+        // how long a player who is logging in waits for a sleeping server to start
+        const val JOIN_WAIT_SECONDS = 20L
+
+        const val WAKE_POLL_MILLIS = 2000L
+
+        // how often players at the front of a queue are checked for room on their set
+        const val QUEUE_POLL_SECONDS = 2L
+    }
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+package dev.njha.mycelium.plugin.velocity.models
+
+import kotlinx.serialization.*
+
+/**
+ * The login queue of a MinecraftSet: players wait on a server of the limbo set while the set
+ * has capacity players or more.
+ */
+@Serializable
+data class Queue(val limbo: String, val capacity: Int, val full: Boolean = false)
@@ -4,7 +4,7 @@ import kotlinx.serialization.*
 import javax.annotation.Nullable
 
 @Serializable
-data class Server(val name: String, val address: String, @Nullable val host: String?, @Nullable val priority: Int?) : Comparable<Server> {
+data class Server(val name: String, val address: String, @Nullable val host: String?, @Nullable val priority: Int?, @Nullable val players: Int? = null, @Nullable val set: String? = null, val asleep: Boolean = false, @Nullable val queue: Queue? = null) : Comparable<Server> {
     override fun compareTo(other: Server): Int {
         if (priority == null && other.priority == null) {
             return 0
@@ -0,0 +1,254 @@
+package dev.njha.mycelium.plugin.waterfall
+
+import com.google.gson.Gson
+import dev.njha.mycelium.plugin.waterfall.models.Server
+import io.ktor.client.*
+import io.ktor.client.engine.java.*
+import io.ktor.client.request.*
+import io.ktor.client.statement.*
+import io.ktor.http.*
+import kotlinx.coroutines.runBlocking
+import net.md_5.bungee.api.ServerPing
+import net.md_5.bungee.api.chat.TextComponent
+import net.md_5.bungee.api.config.ServerInfo
+import net.md_5.bungee.api.event.LoginEvent
+import net.md_5.bungee.api.event.ServerConnectEvent
+import net.md_5.bungee.api.event.ServerKickEvent
+import net.md_5.bungee.api.plugin.Listener
+import net.md_5.bungee.event.EventHandler
+import java.net.ConnectException
+import java.net.InetSocketAddress
+import java.security.KeyStore
+import java.security.cert.CertificateFactory
+import java.util.concurrent.CompletableFuture
+import java.util.concurrent.TimeUnit
+import javax.net.ssl.SSLContext
+import javax.net.ssl.TrustManagerFactory
+
+/**
+ * The Waterfall (BungeeCord) counterpart of the Velocity plugin: registers the servers the
+ * operator lists for this MinecraftProxy, reports heartbeats, and applies the proxy's
+ * fallbackStrategy and throttle.maxConnectionsPerIp.
+ */
+class Plugin : net.md_5.bungee.api.plugin.Plugin(), Listener {
+    // servers and their network-wide player counts as of the last sync, along with how many
+    // of those players were on this proxy, so local joins since then can be accounted for
+    @Volatile
+    private var servers: Map<String, Server> = mapOf()
+    @Volatile
+    private var localAtSync: Map<String, Int> = mapOf()
+
+    private fun sync() {
+        operatorClient().use { httpClient ->
+            val namespace = System.getenv("K8S_NAMESPACE") ?: "default"
+            val name = System.getenv("K8S_NAME") ?: "proxy"
+            val url = "${operatorUrl()}/servers/$namespace/$name"
+            try {
+                val parsed = runBlocking { fetch(httpClient, url) }
+                val newServers = parsed.associateBy { it.name }
+
+                // remove servers
+                for (old in proxy.config.serversCopy.keys) {
+                    if (!newServers.containsKey(old)) {
+                        proxy.config.removeServerNamed(old)
+                        logger.info("removed server $old")
+                    }
+                }
+
+                // add servers
+                for (server in newServers.values) {
+                    if (proxy.getServerInfo(server.name) == null) {
+                        proxy.config.addServer(serverInfo(server))
+                        logger.info("added server ${server.name}")
+                    }
+                }
+
+                // set the try list and forced hosts of every listener
+                val tryList = parsed.filter { it.priority != null }.sorted().map { it.name }
+                for (listener in proxy.config.listeners) {
+                    listener.serverPriority.clear()
+                    listener.serverPriority.addAll(tryList)
+                    listener.forcedHosts.clear()
+                    for (server in parsed) {
+                        server.host?.let { listener.forcedHosts[it] = server.name }
+                    }
+                }
+
+                // remember player counts for fallbackStrategy = leastLoaded
+                servers = newServers
+                localAtSync = newServers.keys.associateWith { localPlayers(it) }
+
+                runBlocking { heartbeat(httpClient, url) }
+            } catch (e: ConnectException) {
+                logger.severe("failed to connect to operator - could not sync server list! (url = $url)")
+            }
+        }
+    }
+
+    /**
+     * The operator API's base URL, https if MYCELIUM_ENDPOINT has the scheme, like the
+     * Velocity plugin.
+     */
+    private fun operatorUrl(): String {
+        val endpoint = System.getenv("MYCELIUM_ENDPOINT") ?: "localhost:8181"
+        return if (endpoint.contains("://")) endpoint.trimEnd('/') else "http://$endpoint"
+    }
+
+    /**
+     * An HTTP client for the operator API, trusting its CA (MYCELIUM_TLS_CA) when it serves TLS.
+     */
+    private fun operatorClient(): HttpClient = HttpClient(Java) {
+        val ca = System.getenv("MYCELIUM_TLS_CA")
+        if (!ca.isNullOrEmpty()) {
+            engine {
+                config {
+                    sslContext(trusting(ca))
+                }
+            }
+        }
+    }
+
+    /**
+     * An SSL context trusting only the certificates of a PEM bundle.
+     */
+    private fun trusting(pem: String): SSLContext {
+        val certificates = CertificateFactory.getInstance("X.509").generateCertificates(pem.byteInputStream())
+        val store = KeyStore.getInstance(KeyStore.getDefaultType())
+        store.load(null, null)
+        certificates.forEachIndexed { i, certificate -> store.setCertificateEntry("mycelium-$i", certificate) }
+        val trust = TrustManagerFactory.getInstance(TrustManagerFactory.getDefaultAlgorithm())
+        trust.init(store)
+        return SSLContext.getInstance("TLS").apply { init(null, trust.trustManagers, null) }
+    }
+
+    /**
+     * Sends the namespace's forwarding token, like the Velocity plugin does.
+     */
+    private fun HttpRequestBuilder.authenticate() {
+        val token = System.getenv("MYCELIUM_FW_TOKEN") ?: System.getenv("CFG_MYCELIUM_FW_TOKEN") ?: return
+        header(HttpHeaders.Authorization, "Bearer $token")
+    }
+
+    private suspend fun fetch(httpClient: HttpClient, url: String): List<Server> {
+        val response = httpClient.get<HttpResponse>(url) {
+            authenticate()
+            headers {
+                append("Accept", "application/json")
+            }
+        }
+        return Gson().fromJson(response.readText(), Array<Server>::class.java).toList()
+    }
+
+    private fun serverInfo(server: Server): ServerInfo =
+        proxy.constructServerInfo(server.name, InetSocketAddress(server.address, 25565), server.name, false)
+
+    /**
+     * Pings every backend the operator knows about and reports the results along with this
+     * replica's players, like the Velocity plugin does.
+     */
+    private suspend fun heartbeat(httpClient: HttpClient, url: String) {
+        val reachable = mutableListOf<String>()
+        val unreachable = mutableListOf<String>()
+        for (server in fetch(httpClient, "$url?all=true")) {
+            val ping = CompletableFuture<ServerPing>()
+            serverInfo(server).ping { result, error ->
+                if (error == null) ping.complete(result) else ping.completeExceptionally(error)
+            }
+            try {
+                ping.get(5, TimeUnit.SECONDS)
+                reachable.add(server.name)
+            } catch (e: Exception) {
+                unreachable.add(server.name)
+            }
+        }
+
+        val players = proxy.players
+            .mapNotNull { player -> player.server?.info?.name }
+            .groupingBy { it }
+            .eachCount()
+
+        val replica = System.getenv("HOSTNAME") ?: "proxy"
+        httpClient.post<HttpResponse>("$url/heartbeat") {
+            authenticate()
+            contentType(ContentType.Application.Json)
+            body = Gson().toJson(
+                mapOf(
+                    "replica" to replica,
+                    "reachable" to reachable,
+                    "unreachable" to unreachable,
+                    "players" to players,
+                )
+            )
+        }
+    }
+
+    override fun onEnable() {
+        proxy.pluginManager.registerListener(this, this)
+
+        // sync the servers from the operator now, and every 1 minute
+        proxy.scheduler.schedule(this, { sync() }, 0L, 1L, TimeUnit.MINUTES)
+
+        logger.info("Hello, World.")
+    }
+
+    private fun localPlayers(server: String): Int =
+        proxy.getServerInfo(server)?.players?.size ?: 0
+
+    /**
+     * Picks a server from the try list according to the MinecraftProxy's fallbackStrategy, or
+     * null to leave Waterfall's priorities (fallbackStrategy = priority) in charge.
+     */
+    private fun chooseServer(exclude: String?): ServerInfo? {
+        val candidates = proxy.config.listeners
+            .flatMap { it.serverPriority }
+            .distinct()
+            .filter { it != exclude }
+            .mapNotNull { proxy.getServerInfo(it) }
+        if (candidates.isEmpty()) {
+            return null
+        }
+        return when (System.getenv("MYCELIUM_FALLBACK_STRATEGY")) {
+            "leastLoaded" -> candidates.minByOrNull {
+                val network = servers[it.name]?.players ?: 0
+                network - (localAtSync[it.name] ?: 0) + it.players.size
+            }
+            "random" -> candidates.random()
+            else -> null
+        }
+    }
+
+    @EventHandler
+    fun onConnect(event: ServerConnectEvent) {
+        if (event.reason == ServerConnectEvent.Reason.JOIN_PROXY) {
+            chooseServer(null)?.let { event.target = it }
+        }
+    }
+
+    @EventHandler
+    fun onKicked(event: ServerKickEvent) {
+        if (event.state == ServerKickEvent.State.CONNECTING) {
+            return
+        }
+        chooseServer(event.kickedFrom.name)?.let {
+            event.cancelServer = it
+            event.isCancelled = true
+        }
+    }
+
+    @EventHandler
+    fun onLogin(event: LoginEvent) {
+        // per-IP connection cap from the MinecraftProxy's throttle.maxConnectionsPerIp
+        val cap = System.getenv("MYCELIUM_MAX_CONNECTIONS_PER_IP")?.toIntOrNull() ?: return
+        val address = (event.connection.socketAddress as? InetSocketAddress)?.address ?: return
+        val connected = proxy.players.count { (it.socketAddress as? InetSocketAddress)?.address == address }
+        if (connected >= cap) {
+            logger.info("denied login from $address, already has $connected connections")
+            event.setCancelReason(TextComponent("Too many connections from your address."))
+            event.isCancelled = true
+        }
+    }
+
+    override fun onDisable() {
+        logger.info("Goodbye, World.")
+    }
+}
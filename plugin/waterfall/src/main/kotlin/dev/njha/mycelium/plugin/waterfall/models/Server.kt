@@ -0,0 +1,14 @@
+package dev.njha.mycelium.plugin.waterfall.models
+
+data class Server(val name: String, val address: String, val host: String?, val priority: Int?, val players: Int? = null) : Comparable<Server> {
+    override fun compareTo(other: Server): Int {
+        if (priority == null && other.priority == null) {
+            return 0
+        } else if (priority == null) {
+            return -1
+        } else if (other.priority == null) {
+            return 1
+        }
+        return priority.compareTo(other.priority)
+    }
+}